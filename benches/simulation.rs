@@ -0,0 +1,79 @@
+//! Timing of the hot paths of a simulation. Run with `cargo bench`, optionally with a
+//! filter on the benchmark name, e.g. `cargo bench -- yule`.
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use cancer_sims::{Float, pmatrix::PMatrix, simulations::Simulations, tree::Phylogeny};
+use ndarray::Array1;
+
+/// Run `f` repeatedly for about a second and report the mean and best time per run
+fn bench<T, F: FnMut() -> T>(filter: &Option<String>, name: &str, mut f: F) {
+    if filter
+        .as_ref()
+        .is_some_and(|filter| !name.contains(filter.as_str()))
+    {
+        return;
+    }
+
+    // warm up
+    black_box(f());
+
+    let mut times = vec![];
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(1) || times.len() < 5 {
+        let t = Instant::now();
+        black_box(f());
+        times.push(t.elapsed());
+    }
+
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    let best = times.iter().min().unwrap();
+    println!(
+        "{name:<40} mean {mean:>12.3?}  best {best:>12.3?}  ({} runs)",
+        times.len()
+    );
+}
+
+fn main() {
+    // cargo passes `--bench` as well as any user filter
+    let filter = std::env::args().skip(1).find(|a| !a.starts_with("--"));
+
+    for g in [8, 12, 14] {
+        bench(&filter, &format!("yule_migrations/g={g}"), || {
+            Phylogeny::yule_migrations(0.2, g, 6, 0.01, 42)
+        });
+    }
+
+    for n in [6, 50, 200] {
+        let frequencies =
+            Array1::from_shape_fn(n, |i| (i + 1) as Float / (n * (n + 1) / 2) as Float);
+        bench(&filter, &format!("rescale_from_frequencies/n={n}"), || {
+            PMatrix::new_with_initial_conditions(n, 0.01)
+                .rescale_from_frequencies(frequencies.clone())
+                .unwrap()
+        });
+    }
+
+    let tree = Phylogeny::yule_migrations(0.2, 14, 6, 0.01, 42).into_tree();
+    bench(&filter, "write_csv/g=14", || {
+        let mut buf = Vec::with_capacity(1 << 20);
+        tree.write_csv(&mut buf).unwrap();
+        buf
+    });
+    bench(&filter, "write_csv_vertex_labeling/g=14", || {
+        let mut buf = Vec::with_capacity(1 << 20);
+        tree.write_csv_vertex_labeling(&mut buf).unwrap();
+        buf
+    });
+
+    // about 8 million nodes, where the layout of the nodes in memory dominates
+    bench(&filter, "yule_migrations/g=22", || {
+        Phylogeny::yule_migrations(0.2, 22, 6, 0.01, 42)
+    });
+    let tree = Phylogeny::yule_migrations(0.2, 22, 6, 0.01, 42).into_tree();
+    bench(&filter, "preorder/g=22", || tree.preorder());
+    bench(&filter, "times_from_root/g=22", || tree.times_from_root());
+    bench(&filter, "subtree_leaves/g=22", || tree.subtree_leaves());
+}
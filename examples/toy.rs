@@ -0,0 +1,50 @@
+//! The fixtures of `cancer_sims::examples` and what the analyses make of them. Run with
+//! `cargo run --example toy`.
+use std::io;
+
+use cancer_sims::{
+    analysis::{graph_shape, migration_cycles, migration_pattern, seedings},
+    examples::{
+        mcpherson_like_matrix, mcpherson_like_sites, toy_parameters, toy_phylogeny, toy_pmatrix,
+        toy_site_names,
+    },
+    output::write_matrix_csv,
+    simulations::Simulations,
+    tree::Phylogeny,
+};
+
+fn main() -> io::Result<()> {
+    let sites = toy_site_names();
+    let tree = toy_phylogeny();
+    println!("toy phylogeny with sites:");
+    tree.write_nhx(io::stdout())?;
+
+    let seedings = seedings(&tree);
+    for s in &seedings {
+        println!(
+            "  node {} seeds {} from {}, {} leaves",
+            s.node, sites[s.target], sites[s.source], s.leaves
+        );
+    }
+    println!(
+        "migration pattern: {}",
+        migration_pattern(&seedings, sites.len(), &[0])
+    );
+
+    println!("\ntoy migration probabilities:");
+    toy_pmatrix().write_csv(io::stdout(), Some(&sites))?;
+
+    let result = Phylogeny::simulate(&toy_parameters(), &mut ());
+    println!("\nmigration counts of a toy run:");
+    write_matrix_csv(io::stdout(), result.migration_matrix(), &sites)?;
+
+    println!("\nMcPherson-like seeding counts:");
+    let m = mcpherson_like_matrix();
+    write_matrix_csv(io::stdout(), &m, &mcpherson_like_sites())?;
+    println!(
+        "migration graph: {}, cycles {:?}",
+        graph_shape(&m),
+        migration_cycles(&m)
+    );
+    Ok(())
+}
@@ -0,0 +1,200 @@
+//! Distances between summary statistics, to use the simulator as the forward model of
+//! approximate Bayesian computation. A [`Distance`] picks statistics of an observed
+//! [`ReplicateSummary`], e.g. read back with [`crate::stats::read_summaries_csv`], and
+//! [`simulate_and_score`] runs one simulation and measures how far it lands. A rejection
+//! sampler draws parameters from the prior, keeps those scoring below a tolerance and
+//! usually scales the statistics first with [`Distance::with_scales`] over prior draws.
+//!
+//! Statistics missing from a summary count as 0, which is right for counts (a summary
+//! only lists pairs of sites with migrations) but not for colonization times of sites
+//! that were never colonized.
+use crate::{
+    Float,
+    simulations::{Parameters, SimulationResult, Simulations},
+    stats::ReplicateSummary,
+    tree::Phylogeny,
+};
+
+/// Migration counts per pair of sites and lineages founding each site
+pub const MIGRATION_STATISTICS: &[&str] = &["migrations", "seeding_lineages"];
+/// First arrival in each metastasis
+pub const COLONIZATION_STATISTICS: &[&str] = &["colonization_time", "colonization_generation"];
+/// Imbalance, height, branch lengths and the gamma statistic of the tree
+pub const SHAPE_STATISTICS: &[&str] = &[
+    "colless",
+    "sackin",
+    "height",
+    "total_branch_length",
+    "gamma",
+    "external_internal_ratio",
+];
+
+/// How differences of the single statistics add up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    Euclidean,
+    /// Sum of absolute differences
+    Manhattan,
+    /// Largest absolute difference
+    Chebyshev,
+}
+
+impl Metric {
+    fn combine(&self, differences: impl Iterator<Item = Float>) -> Float {
+        match self {
+            Metric::Euclidean => differences.map(|d| d * d).sum::<Float>().sqrt(),
+            Metric::Manhattan => differences.map(Float::abs).sum(),
+            Metric::Chebyshev => differences.map(Float::abs).fold(0.0, Float::max),
+        }
+    }
+}
+
+/// Distance over a fixed list of statistics, each divided by its scale
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distance {
+    pub metric: Metric,
+    /// Statistics compared as `(name, key)`
+    pub statistics: Vec<(String, String)>,
+    /// Scale of every statistic, all 1 unless set with [`Distance::with_scales`]
+    pub scales: Vec<Float>,
+}
+
+impl Distance {
+    /// Compare the statistics of `observed` with any of the `names`, all of them when
+    /// `names` is empty
+    pub fn new(metric: Metric, observed: &ReplicateSummary, names: &[&str]) -> Self {
+        let statistics: Vec<_> = observed
+            .statistics
+            .iter()
+            .filter(|s| names.is_empty() || names.contains(&s.name.as_str()))
+            .map(|s| (s.name.clone(), s.key.clone()))
+            .collect();
+        Self {
+            metric,
+            scales: vec![1.0; statistics.len()],
+            statistics,
+        }
+    }
+
+    /// Scale every statistic by its median absolute deviation over `reference`, e.g.
+    /// draws from the prior, so that statistics of large magnitude do not dominate.
+    /// Statistics that do not vary keep scale 1
+    pub fn with_scales(mut self, reference: &[ReplicateSummary]) -> Self {
+        self.scales = (0..self.statistics.len())
+            .map(|i| {
+                let values: Vec<_> = reference.iter().map(|s| self.vector(s)[i]).collect();
+                let center = median(values.clone());
+                let mad = median(values.iter().map(|v| (v - center).abs()).collect());
+                if mad > 0.0 { mad } else { 1.0 }
+            })
+            .collect();
+        self
+    }
+
+    /// Values of the compared statistics in `summary`, 0 for missing ones
+    pub fn vector(&self, summary: &ReplicateSummary) -> Vec<Float> {
+        self.statistics
+            .iter()
+            .map(|(name, key)| summary.get(name, key).unwrap_or(0.0))
+            .collect()
+    }
+
+    /// Distance between two summaries
+    pub fn between(&self, a: &ReplicateSummary, b: &ReplicateSummary) -> Float {
+        let (a, b) = (self.vector(a), self.vector(b));
+        let differences = a
+            .iter()
+            .zip(&b)
+            .zip(&self.scales)
+            .map(|((a, b), scale)| (a - b) / scale);
+        self.metric.combine(differences)
+    }
+}
+
+/// Median, 0 for no values
+fn median(mut values: Vec<Float>) -> Float {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    match n {
+        0 => 0.0,
+        _ if n % 2 == 1 => values[n / 2],
+        _ => (values[n / 2 - 1] + values[n / 2]) / 2.0,
+    }
+}
+
+/// One simulation scored against the observed summary
+#[derive(Debug)]
+pub struct Score {
+    pub distance: Float,
+    pub summary: ReplicateSummary,
+    pub result: SimulationResult<Phylogeny<usize, usize>>,
+}
+
+/// Simulate with `params` and measure the distance of its summary to `observed`
+pub fn simulate_and_score(
+    params: &Parameters,
+    observed: &ReplicateSummary,
+    distance: &Distance,
+) -> Score {
+    let result = Phylogeny::simulate(params, &mut ());
+    let summary = ReplicateSummary::new(0, &result, params, &params.site_names());
+    Score {
+        distance: distance.between(&summary, observed),
+        summary,
+        result,
+    }
+}
+
+#[test]
+fn test_distances() {
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.1,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let observed = ReplicateSummary::new(0, &result, &params, &params.site_names());
+
+    let distance = Distance::new(Metric::Euclidean, &observed, SHAPE_STATISTICS);
+    assert!(
+        distance
+            .statistics
+            .iter()
+            .any(|(name, _)| name == "colless")
+    );
+    assert!(
+        distance
+            .statistics
+            .iter()
+            .all(|(name, _)| SHAPE_STATISTICS.contains(&name.as_str()))
+    );
+    // the same seed reproduces the observation
+    assert_eq!(
+        simulate_and_score(&params, &observed, &distance).distance,
+        0.0
+    );
+    let other = Parameters {
+        seed: 7,
+        ..params.clone()
+    };
+    assert!(simulate_and_score(&other, &observed, &distance).distance > 0.0);
+
+    let mut distance = Distance::new(Metric::Manhattan, &observed, &["colless", "height"]);
+    let reference: Vec<_> = (0..5)
+        .map(|seed| {
+            let params = Parameters {
+                seed,
+                ..params.clone()
+            };
+            simulate_and_score(&params, &observed, &distance).summary
+        })
+        .collect();
+    let (a, b) = (&reference[0], &reference[1]);
+    let manhattan = distance.between(a, b);
+    distance.metric = Metric::Chebyshev;
+    assert!(distance.between(a, b) <= manhattan);
+    let scaled = distance.clone().with_scales(&reference);
+    assert!(scaled.scales.iter().all(|&s| s > 0.0));
+    assert_eq!(median(vec![3.0, 1.0, 2.0, 10.0]), 2.5);
+}
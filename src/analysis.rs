@@ -0,0 +1,894 @@
+//! Statistics of the migration history of a simulated tree, the quantities migration
+//! history inference tries to recover from real data.
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use ndarray::Array2;
+use serde::Serialize;
+
+use crate::{
+    Float,
+    output::{CsvDialect, CsvWriter},
+    tree::Phylogeny,
+};
+
+/// A lineage founding a site: the edge into `node` crosses from `source` to `target`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seeding {
+    pub node: usize,
+    pub source: usize,
+    pub target: usize,
+    /// Generation of `node`, its depth in the tree
+    pub generation: usize,
+    /// Time of `node` from the root
+    pub time: Float,
+    /// Extant leaves descending from `node` without leaving `target`, the size of the
+    /// clone it founded. Zero for seedings that left no trace in the site
+    pub leaves: usize,
+}
+
+/// Every migration edge of `tree` as a seeding of its target site, in node order. The
+/// edges from the common ancestor of several founders (`founders` metadata) are not
+/// migrations
+pub fn seedings<N>(tree: &Phylogeny<N, usize>) -> Vec<Seeding> {
+    // extant leaves below each node that stayed on the node's site
+    let mut clone = vec![0; tree.nodes.len()];
+    for idx in tree.preorder().into_iter().rev() {
+        let node = &tree.nodes[idx];
+        clone[idx] = match tree.is_leaf(idx) {
+            true => tree.is_alive(idx) as usize,
+            false => tree
+                .children(idx)
+                .filter(|&(c, _)| tree.nodes[c].label == node.label)
+                .map(|(c, _)| clone[c])
+                .sum(),
+        };
+    }
+
+    let depths = tree.depths();
+    let times = tree.times_from_root();
+    (0..tree.nodes.len())
+        .filter_map(|idx| {
+            let parent = tree.parent(idx)?;
+            let (source, target) = (tree.nodes[parent].label, tree.nodes[idx].label);
+            let founder = tree.nodes[parent].meta("founders").is_some();
+            (source != target && !founder).then_some(Seeding {
+                node: idx,
+                source,
+                target,
+                generation: depths[idx],
+                time: times[idx],
+                leaves: clone[idx],
+            })
+        })
+        .collect()
+}
+
+/// Number of seedings with extant descendants into each site: 1 for a monoclonal site,
+/// more for a polyclonal one, 0 for the primary and sites that were never seeded
+pub fn clonality(seedings: &[Seeding], sites: usize) -> Vec<usize> {
+    let mut counts = vec![0; sites];
+    for s in seedings.iter().filter(|s| s.leaves > 0) {
+        counts[s.target] += 1;
+    }
+    counts
+}
+
+/// Write one row per seeding with site names for the source and target
+pub fn write_seedings_csv<W: Write>(
+    w: W,
+    seedings: &[Seeding],
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["node", "source", "target", "generation", "time", "leaves"])?;
+    for s in seedings {
+        csv.record([
+            &s.node as &dyn std::fmt::Display,
+            &sites[s.source],
+            &sites[s.target],
+            &s.generation,
+            &s.time,
+            &s.leaves,
+        ])?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct GraphJson<'a> {
+    directed: bool,
+    multigraph: bool,
+    nodes: Vec<GraphJsonNode<'a>>,
+    links: Vec<GraphJsonLink>,
+}
+
+#[derive(Serialize)]
+struct GraphJsonNode<'a> {
+    id: usize,
+    name: &'a str,
+    leaves: usize,
+    seedings: usize,
+}
+
+#[derive(Serialize)]
+struct GraphJsonLink {
+    source: usize,
+    target: usize,
+    count: usize,
+    leaves: usize,
+    generations: Vec<usize>,
+    times: Vec<Float>,
+}
+
+/// Write the migration graph of `tree` as node-link json, the format of d3 force layouts
+/// and networkx `node_link_data`. A node per site with its name, extant leaves and the
+/// seedings into it; a link per pair of sites with migrations between them, their
+/// count, the extant leaves they founded and the generation and time of each. Every
+/// node and link also makes the `data` of a Cytoscape.js element
+pub fn write_graph_json<N, W: Write>(
+    mut w: W,
+    tree: &Phylogeny<N, usize>,
+    sites: &[String],
+) -> io::Result<()> {
+    let mut nodes: Vec<_> = sites
+        .iter()
+        .enumerate()
+        .map(|(id, name)| GraphJsonNode {
+            id,
+            name,
+            leaves: 0,
+            seedings: 0,
+        })
+        .collect();
+    for leaf in tree.extant_leaves() {
+        nodes[tree.nodes[leaf].label].leaves += 1;
+    }
+    let mut links = BTreeMap::new();
+    for s in seedings(tree) {
+        nodes[s.target].seedings += 1;
+        let link = links
+            .entry((s.source, s.target))
+            .or_insert_with(|| GraphJsonLink {
+                source: s.source,
+                target: s.target,
+                count: 0,
+                leaves: 0,
+                generations: vec![],
+                times: vec![],
+            });
+        link.count += 1;
+        link.leaves += s.leaves;
+        link.generations.push(s.generation);
+        link.times.push(s.time);
+    }
+    let doc = GraphJson {
+        directed: true,
+        multigraph: false,
+        nodes,
+        links: links.into_values().collect(),
+    };
+    serde_json::to_writer_pretty(&mut w, &doc)?;
+    writeln!(w)
+}
+
+/// How sites were seeded, the classes of migration history inference (MACHINA)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedingStructure {
+    /// Every metastasis was seeded from the primary
+    PrimarySingleSource,
+    /// Every site was seeded from a single other site, metastases seed metastases
+    SingleSource,
+    /// Some site was seeded from more than one site
+    MultiSource,
+    /// Seedings form a cycle between sites, e.g. a metastasis reseeding the primary
+    Reseeding,
+}
+
+/// Migration pattern of a history: monoclonal or polyclonal seeding and its structure.
+/// Displays as the usual short codes `mPS`, `pS`, `pM`, `mR`, ...
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationPattern {
+    /// Some site was founded by more than one lineage with extant descendants
+    pub polyclonal: bool,
+    pub structure: SeedingStructure,
+}
+
+impl std::fmt::Display for MigrationPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let clonality = if self.polyclonal { "p" } else { "m" };
+        let structure = match self.structure {
+            SeedingStructure::PrimarySingleSource => "PS",
+            SeedingStructure::SingleSource => "S",
+            SeedingStructure::MultiSource => "M",
+            SeedingStructure::Reseeding => "R",
+        };
+        write!(f, "{clonality}{structure}")
+    }
+}
+
+impl std::str::FromStr for MigrationPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let polyclonal = match s.get(..1) {
+            Some("m") => false,
+            Some("p") => true,
+            _ => return Err(format!("unknown migration pattern `{s}`")),
+        };
+        let structure = match &s[1..] {
+            "PS" => SeedingStructure::PrimarySingleSource,
+            "S" => SeedingStructure::SingleSource,
+            "M" => SeedingStructure::MultiSource,
+            "R" => SeedingStructure::Reseeding,
+            _ => return Err(format!("unknown migration pattern `{s}`")),
+        };
+        Ok(Self {
+            polyclonal,
+            structure,
+        })
+    }
+}
+
+/// Classify the seedings with extant descendants, with the founder sites as primaries
+pub fn migration_pattern(
+    seedings: &[Seeding],
+    sites: usize,
+    primaries: &[usize],
+) -> MigrationPattern {
+    let mut graph = vec![vec![false; sites]; sites];
+    for s in seedings.iter().filter(|s| s.leaves > 0) {
+        graph[s.source][s.target] = true;
+    }
+
+    // a cycle remains as long as some site with incoming seedings is left after
+    // repeatedly removing sites nothing seeds
+    let mut removed = vec![false; sites];
+    while let Some(site) =
+        (0..sites).find(|&t| !removed[t] && (0..sites).all(|s| removed[s] || !graph[s][t]))
+    {
+        removed[site] = true;
+    }
+    let sources = |t: usize| (0..sites).filter(|&s| graph[s][t]).count();
+
+    let structure = if removed.iter().any(|r| !r) {
+        SeedingStructure::Reseeding
+    } else if (0..sites).any(|t| sources(t) > 1) {
+        SeedingStructure::MultiSource
+    } else if (0..sites).all(|s| primaries.contains(&s) || !graph[s].contains(&true)) {
+        SeedingStructure::PrimarySingleSource
+    } else {
+        SeedingStructure::SingleSource
+    };
+    MigrationPattern {
+        polyclonal: clonality(seedings, sites).iter().any(|&c| c > 1),
+        structure,
+    }
+}
+
+/// Shape of a migration graph in the vocabulary of migration history inference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphShape {
+    /// Acyclic and every site seeded from at most one site, a tree of seedings
+    Tree,
+    /// Acyclic with a site seeded from several sites
+    Dag,
+    /// Sites seed each other in a cycle, e.g. a metastasis reseeding the primary
+    Cyclic,
+}
+
+impl std::fmt::Display for GraphShape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphShape::Tree => write!(f, "tree"),
+            GraphShape::Dag => write!(f, "DAG"),
+            GraphShape::Cyclic => write!(f, "cyclic"),
+        }
+    }
+}
+
+/// Strongly connected components of a migration graph and the acyclic graph between them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condensation {
+    /// Sites of every component in increasing order, components in topological order
+    pub components: Vec<Vec<usize>>,
+    /// Component of every site
+    pub component: Vec<usize>,
+    /// Edges between components, sorted and without repeats
+    pub edges: Vec<(usize, usize)>,
+}
+
+impl Condensation {
+    /// Components of more than one site, the sites seeding each other in cycles
+    pub fn cyclic_components(&self) -> impl Iterator<Item = &[usize]> {
+        self.components
+            .iter()
+            .filter(|c| c.len() > 1)
+            .map(|c| &c[..])
+    }
+}
+
+/// Sites every site migrated to in the count matrix `m`, in order. Migrations within a
+/// site are not edges
+fn adjacency(m: &Array2<i64>) -> Vec<Vec<usize>> {
+    (0..m.nrows())
+        .map(|i| {
+            (0..m.ncols())
+                .filter(|&j| i != j && m[[i, j]] > 0)
+                .collect()
+        })
+        .collect()
+}
+
+/// Strongly connected components of the migration graph of the count matrix `m` by
+/// Tarjan's algorithm, and the condensation DAG between them
+pub fn condensation(m: &Array2<i64>) -> Condensation {
+    struct Tarjan<'a> {
+        adjacency: &'a [Vec<usize>],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        stack: Vec<usize>,
+        on_stack: Vec<bool>,
+        next: usize,
+        // in reverse topological order
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next);
+            self.low[v] = self.next;
+            self.next += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+            for &w in self.adjacency[v].iter() {
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.low[v] = self.low[v].min(self.low[w]);
+                    }
+                    Some(index) if self.on_stack[w] => self.low[v] = self.low[v].min(index),
+                    Some(_) => {}
+                }
+            }
+            if Some(self.low[v]) == self.index[v] {
+                let mut component = vec![];
+                while let Some(w) = self.stack.pop() {
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                self.components.push(component);
+            }
+        }
+    }
+
+    let adjacency = adjacency(m);
+    let n = adjacency.len();
+    let mut tarjan = Tarjan {
+        adjacency: &adjacency,
+        index: vec![None; n],
+        low: vec![0; n],
+        stack: vec![],
+        on_stack: vec![false; n],
+        next: 0,
+        components: vec![],
+    };
+    for v in 0..n {
+        if tarjan.index[v].is_none() {
+            tarjan.visit(v);
+        }
+    }
+    let mut components = tarjan.components;
+    components.reverse();
+
+    let mut component = vec![0; n];
+    for (c, sites) in components.iter().enumerate() {
+        for &site in sites {
+            component[site] = c;
+        }
+    }
+    let mut edges: Vec<_> = adjacency
+        .iter()
+        .enumerate()
+        .flat_map(|(i, targets)| targets.iter().map(move |&j| (i, j)))
+        .map(|(i, j)| (component[i], component[j]))
+        .filter(|(a, b)| a != b)
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+    Condensation {
+        components,
+        component,
+        edges,
+    }
+}
+
+/// Elementary cycles of the migration graph of the count matrix `m`, the reseeding
+/// loops, each listed from its smallest site. Their number grows quickly in dense graphs,
+/// which the few sites of a patient keep small
+pub fn migration_cycles(m: &Array2<i64>) -> Vec<Vec<usize>> {
+    fn extend(
+        adjacency: &[Vec<usize>],
+        path: &mut Vec<usize>,
+        on_path: &mut [bool],
+        cycles: &mut Vec<Vec<usize>>,
+    ) {
+        let (start, last) = (path[0], path[path.len() - 1]);
+        for &next in &adjacency[last] {
+            if next == start {
+                cycles.push(path.clone());
+            } else if next > start && !on_path[next] {
+                path.push(next);
+                on_path[next] = true;
+                extend(adjacency, path, on_path, cycles);
+                on_path[next] = false;
+                path.pop();
+            }
+        }
+    }
+
+    let adjacency = adjacency(m);
+    let mut cycles = vec![];
+    let mut on_path = vec![false; adjacency.len()];
+    for start in 0..adjacency.len() {
+        on_path[start] = true;
+        extend(&adjacency, &mut vec![start], &mut on_path, &mut cycles);
+        on_path[start] = false;
+    }
+    cycles
+}
+
+/// Whether the migration graph of the count matrix `m` is a tree, a DAG or cyclic
+pub fn graph_shape(m: &Array2<i64>) -> GraphShape {
+    if condensation(m).cyclic_components().next().is_some() {
+        GraphShape::Cyclic
+    } else if adjacency(&m.t().to_owned())
+        .iter()
+        .any(|sources| sources.len() > 1)
+    {
+        GraphShape::Dag
+    } else {
+        GraphShape::Tree
+    }
+}
+
+/// When migrations between the same pair of sites count as one comigration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ComigrationWindow {
+    /// Migrations in the same generation
+    Generation,
+    /// Migrations within this much time of the first migration of the group
+    Time(Float),
+}
+
+/// Migration edges between the same ordered pair of sites close enough in time to be
+/// one migration event
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comigration {
+    pub source: usize,
+    pub target: usize,
+    /// Generation of the first migration of the group
+    pub generation: usize,
+    /// Time of the first migration of the group
+    pub time: Float,
+    /// Nodes below the migration edges
+    pub nodes: Vec<usize>,
+}
+
+impl Comigration {
+    /// Number of migration edges
+    pub fn size(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+/// Group seedings into comigrations, ordered by site pair then time. Singletons are
+/// included, so the length is the comigration number of the history
+pub fn comigrations(seedings: &[Seeding], window: ComigrationWindow) -> Vec<Comigration> {
+    let mut sorted: Vec<_> = seedings.iter().collect();
+    sorted.sort_by(|a, b| {
+        let by_window = match window {
+            ComigrationWindow::Generation => a.generation.cmp(&b.generation),
+            ComigrationWindow::Time(_) => a.time.total_cmp(&b.time),
+        };
+        (a.source, a.target)
+            .cmp(&(b.source, b.target))
+            .then(by_window)
+            .then(a.time.total_cmp(&b.time))
+    });
+
+    let mut groups: Vec<Comigration> = vec![];
+    for s in sorted {
+        let joins = groups.last().is_some_and(|g| {
+            (g.source, g.target) == (s.source, s.target)
+                && match window {
+                    ComigrationWindow::Generation => g.generation == s.generation,
+                    ComigrationWindow::Time(w) => s.time - g.time <= w,
+                }
+        });
+        match joins {
+            true => groups.last_mut().unwrap().nodes.push(s.node),
+            false => groups.push(Comigration {
+                source: s.source,
+                target: s.target,
+                generation: s.generation,
+                time: s.time,
+                nodes: vec![s.node],
+            }),
+        }
+    }
+    groups
+}
+
+/// Write one row per comigration, nodes are separated by `;`
+pub fn write_comigrations_csv<W: Write>(
+    w: W,
+    comigrations: &[Comigration],
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["source", "target", "generation", "time", "size", "nodes"])?;
+    for c in comigrations {
+        let nodes: Vec<_> = c.nodes.iter().map(|n| n.to_string()).collect();
+        csv.record([
+            &sites[c.source] as &dyn std::fmt::Display,
+            &sites[c.target],
+            &c.generation,
+            &c.time,
+            &c.size(),
+            &nodes.join(";"),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Path length through the tree between every pair of `leaves`
+pub fn patristic_distances<N>(tree: &Phylogeny<N, usize>, leaves: &[usize]) -> Array2<Float> {
+    let depths = tree.depths();
+    let times = tree.times_from_root();
+    let lca = |mut a: usize, mut b: usize| {
+        while a != b {
+            match depths[a] >= depths[b] {
+                true => a = tree.parent(a).unwrap(),
+                false => b = tree.parent(b).unwrap(),
+            }
+        }
+        a
+    };
+
+    let n = leaves.len();
+    let mut d = Array2::zeros((n, n));
+    for i in 0..n {
+        for j in i + 1..n {
+            let (a, b) = (leaves[i], leaves[j]);
+            d[[i, j]] = times[a] + times[b] - 2.0 * times[lca(a, b)];
+            d[[j, i]] = d[[i, j]];
+        }
+    }
+    d
+}
+
+/// Number of mutations two leaves differ at, for every pair of rows of `genotypes`
+pub fn hamming_distances(genotypes: &Array2<u8>) -> Array2<Float> {
+    let n = genotypes.nrows();
+    let mut d = Array2::zeros((n, n));
+    for i in 0..n {
+        for j in i + 1..n {
+            let differ = genotypes
+                .row(i)
+                .iter()
+                .zip(genotypes.row(j))
+                .filter(|(a, b)| a != b)
+                .count();
+            d[[i, j]] = differ as Float;
+            d[[j, i]] = d[[i, j]];
+        }
+    }
+    d
+}
+
+/// Population genetic summaries of leaves grouped by site
+#[derive(Debug, Clone)]
+pub struct Diversity {
+    /// Mean distance between pairs of leaves of the same site (nucleotide diversity with
+    /// mutation distances), NaN for sites with fewer than two leaves
+    pub within: Vec<Float>,
+    /// Mean distance between leaves of two sites, the diagonal is `within`
+    pub between: Array2<Float>,
+    /// Hudson's Fst analog `1 - (within_a + within_b) / 2 / between_ab`
+    pub fst: Array2<Float>,
+}
+
+/// Diversity of `leaves` grouped by site. The distance of two leaves is the sum of the
+/// `weights` of the branches between them, indexed by the node below each branch: branch
+/// lengths for tree distances, [`Mutations::per_branch`](crate::mutations::Mutations::per_branch)
+/// for mutation distances. Every branch adds its weight once for each pair of leaves it
+/// separates, so this takes O(nodes sites^2) without any pairwise distance
+pub fn diversity<N>(
+    tree: &Phylogeny<N, usize>,
+    leaves: &[usize],
+    weights: &[Float],
+    sites: usize,
+) -> Diversity {
+    let below = tree.site_counts_below(leaves, sites).mapv(|c| c as Float);
+    let totals = below.row(tree.root).to_owned();
+    // distances summed over the pairs of a leaf of the row site below a branch and a leaf
+    // of the column site above it
+    let mut sums = Array2::<Float>::zeros((sites, sites));
+    for node in (0..tree.nodes.len()).filter(|&n| tree.parent(n).is_some()) {
+        let below = below.row(node);
+        for a in 0..sites {
+            for b in 0..sites {
+                sums[[a, b]] += weights[node] * below[a] * (totals[b] - below[b]);
+            }
+        }
+    }
+    let between = Array2::from_shape_fn((sites, sites), |(a, b)| match a == b {
+        true => sums[[a, a]] / (totals[a] * (totals[a] - 1.0) / 2.0),
+        false => (sums[[a, b]] + sums[[b, a]]) / (totals[a] * totals[b]),
+    });
+    let within: Vec<_> = (0..sites).map(|s| between[[s, s]]).collect();
+    let fst = Array2::from_shape_fn((sites, sites), |(a, b)| {
+        1.0 - (within[a] + within[b]) / 2.0 / between[[a, b]]
+    });
+    Diversity {
+        within,
+        between,
+        fst,
+    }
+}
+
+/// Write one row per pair of sites with the mean distance and Fst, the rows of a site with
+/// itself hold its within-site diversity
+pub fn write_diversity_csv<W: Write>(
+    w: W,
+    diversity: &Diversity,
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["site_a", "site_b", "mean_distance", "fst"])?;
+    for ((a, b), distance) in diversity.between.indexed_iter() {
+        csv.record([
+            &sites[a] as &dyn std::fmt::Display,
+            &sites[b],
+            distance,
+            &diversity.fst[[a, b]],
+        ])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_seedings() {
+    use crate::tree::Node;
+
+    // site 1 is seeded twice from the primary, once by a lineage that went on to site 2
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 1, 1.0);
+    let b = tree.add_child(0, 2, 0, 1.0);
+    tree.add_child(a, 3, 1, 1.0);
+    tree.add_child(a, 4, 1, 1.0);
+    let c = tree.add_child(b, 5, 1, 2.0);
+    tree.add_child(b, 6, 0, 1.0);
+    tree.add_child(c, 7, 2, 1.0);
+    tree.add_child(c, 8, 2, 1.0);
+
+    let seedings = seedings(&tree);
+    assert_eq!(seedings.len(), 4);
+    assert_eq!(
+        seedings[0],
+        Seeding {
+            node: a,
+            source: 0,
+            target: 1,
+            generation: 1,
+            time: 1.0,
+            leaves: 2
+        }
+    );
+    assert_eq!((seedings[1].node, seedings[1].leaves), (c, 0));
+    assert_eq!(clonality(&seedings, 3), [0, 1, 2]);
+
+    let mut buf = vec![];
+    let sites = ["p", "m1", "m2"].map(String::from);
+    write_seedings_csv(&mut buf, &seedings, &sites, CsvDialect::default()).unwrap();
+    let csv = String::from_utf8(buf).unwrap();
+    assert!(csv.starts_with("node,source,target,generation,time,leaves\n1,p,m1,1,1,2\n"));
+}
+
+#[test]
+fn test_graph_json() {
+    use crate::examples::{toy_phylogeny, toy_site_names};
+
+    let mut tree = toy_phylogeny();
+    // a second seeding of the liver from the primary
+    tree.add_child(3, 9, 2, 1.0);
+    let mut buf = vec![];
+    write_graph_json(&mut buf, &tree, &toy_site_names()).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(json["directed"], true);
+    let nodes = json["nodes"].as_array().unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(nodes[2]["name"], "liver");
+    // leaves 5 and 6 in the lymph node, 7 and 9 in the liver
+    assert_eq!(nodes[1]["leaves"], 2);
+    assert_eq!(
+        (&nodes[2]["leaves"], &nodes[2]["seedings"]),
+        (&2.into(), &2.into())
+    );
+    let links = json["links"].as_array().unwrap();
+    let pairs: Vec<_> = links
+        .iter()
+        .map(|l| (l["source"].as_u64().unwrap(), l["target"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(pairs, [(0, 1), (0, 2), (2, 0)]);
+    assert_eq!(links[1]["count"], 2);
+    assert_eq!(links[1]["generations"], serde_json::json!([2, 3]));
+    assert_eq!(links[1]["leaves"], 2);
+    assert_eq!(links[2]["times"], serde_json::json!([3.0]));
+}
+
+#[test]
+fn test_migration_pattern() {
+    let seeding = |source, target, leaves| Seeding {
+        node: 0,
+        source,
+        target,
+        generation: 0,
+        time: 0.0,
+        leaves,
+    };
+    let pattern = |seedings: &[Seeding]| migration_pattern(seedings, 3, &[0]).to_string();
+
+    assert_eq!(pattern(&[]), "mPS");
+    assert_eq!(pattern(&[seeding(0, 1, 1), seeding(0, 2, 3)]), "mPS");
+    assert_eq!(pattern(&[seeding(0, 1, 1), seeding(1, 2, 3)]), "mS");
+    assert_eq!(pattern(&[seeding(0, 1, 1), seeding(0, 1, 2)]), "pPS");
+    // seedings without descendants are not observable
+    assert_eq!(pattern(&[seeding(0, 1, 1), seeding(2, 1, 0)]), "mPS");
+    let multi = [seeding(0, 1, 1), seeding(0, 2, 1), seeding(2, 1, 1)];
+    assert_eq!(pattern(&multi), "pM");
+    assert_eq!(pattern(&[seeding(0, 1, 1), seeding(1, 0, 1)]), "mR");
+    assert_eq!(
+        "pM".parse::<MigrationPattern>(),
+        Ok(migration_pattern(&multi, 3, &[0]))
+    );
+    assert!("xS".parse::<MigrationPattern>().is_err());
+}
+
+#[test]
+fn test_migration_graph_shape() {
+    use crate::examples::mcpherson_like_matrix;
+
+    // 0 -> 1 -> 2 with counts of staying on the diagonal
+    let mut m = Array2::from_shape_vec((3, 3), vec![5, 1, 0, 0, 3, 2, 0, 0, 4]).unwrap();
+    assert_eq!(graph_shape(&m), GraphShape::Tree);
+    assert!(migration_cycles(&m).is_empty());
+    let c = condensation(&m);
+    assert_eq!(c.components, [[0], [1], [2]]);
+    assert_eq!(c.edges, [(0, 1), (1, 2)]);
+
+    m[[0, 2]] = 1;
+    assert_eq!(graph_shape(&m), GraphShape::Dag);
+    m[[2, 0]] = 1;
+    assert_eq!(graph_shape(&m), GraphShape::Cyclic);
+    assert_eq!(migration_cycles(&m), [vec![0, 1, 2], vec![0, 2]]);
+    let c = condensation(&m);
+    assert_eq!(c.components, [[0, 1, 2]]);
+    assert!(c.edges.is_empty());
+
+    // the right ovary and omentum reseed each other, both before the other two sites
+    let m = mcpherson_like_matrix();
+    assert_eq!(graph_shape(&m).to_string(), "cyclic");
+    assert_eq!(migration_cycles(&m), [vec![0, 2]]);
+    let c = condensation(&m);
+    assert_eq!(c.cyclic_components().collect::<Vec<_>>(), [[0, 2]]);
+    assert_eq!(c.components, [vec![0, 2], vec![3], vec![1]]);
+    assert_eq!(c.edges.len(), 2);
+    assert!(c.edges.iter().all(|&(a, _)| a == 0));
+}
+
+#[test]
+fn test_comigrations() {
+    let seeding = |node, target, generation, time| Seeding {
+        node,
+        source: 0,
+        target,
+        generation,
+        time,
+        leaves: 1,
+    };
+    let seedings = [
+        seeding(3, 1, 2, 1.5),
+        seeding(4, 1, 2, 2.5),
+        seeding(5, 2, 2, 1.6),
+        seeding(9, 1, 3, 2.6),
+    ];
+
+    let by_generation = comigrations(&seedings, ComigrationWindow::Generation);
+    let sizes: Vec<_> = by_generation.iter().map(|c| (c.target, c.size())).collect();
+    assert_eq!(sizes, [(1, 2), (1, 1), (2, 1)]);
+
+    let by_time = comigrations(&seedings, ComigrationWindow::Time(0.5));
+    assert_eq!(by_time[0].nodes, [3]);
+    assert_eq!(by_time[1].nodes, [4, 9]);
+    assert_eq!(by_time.len(), 3);
+}
+
+#[test]
+fn test_diversity() {
+    use crate::{
+        mutations::Mutations,
+        simulations::{Parameters, Simulations},
+        tree::Node,
+    };
+
+    // two sites, each a cherry of leaves 1 apart, the cherries 5 apart
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 0, 2.0);
+    let b = tree.add_child(0, 2, 1, 2.0);
+    for (parent, site) in [(a, 0), (a, 0), (b, 1), (b, 1)] {
+        tree.add_child(parent, 0, site, 0.5);
+    }
+    let leaves: Vec<_> = tree.leaves().collect();
+    let d = patristic_distances(&tree, &leaves);
+    assert_eq!(d.row(0).to_vec(), [0.0, 1.0, 5.0, 5.0]);
+
+    let lengths: Vec<_> = (0..tree.nodes.len())
+        .map(|n| tree.branch_length(n))
+        .collect();
+    let div = diversity(&tree, &leaves, &lengths, 2);
+    assert_eq!(div.within, [1.0, 1.0]);
+    assert_eq!(div.between[[0, 1]], 5.0);
+    assert!((div.fst[[0, 1]] - 0.8).abs() < 1e-6);
+    assert_eq!(div.fst[[0, 0]], 0.0);
+
+    // mutations on the path between two leaves are the ones they differ at
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.2,
+        ..Default::default()
+    };
+    let tree = Phylogeny::simulate(&params, &mut ()).tree().clone();
+    let mutations = Mutations::simulate(&tree, 0.5, 1);
+    let leaves: Vec<_> = tree.extant_leaves().collect();
+    let d = hamming_distances(&mutations.genotypes(&tree, &leaves));
+    let div = diversity(&tree, &leaves, &mutations.per_branch(tree.nodes.len()), 3);
+    let (mut sums, mut pairs) = (
+        Array2::<Float>::zeros((3, 3)),
+        Array2::<Float>::zeros((3, 3)),
+    );
+    for i in 0..leaves.len() {
+        for j in i + 1..leaves.len() {
+            let (a, b) = (tree.nodes[leaves[i]].label, tree.nodes[leaves[j]].label);
+            for (x, y) in [(a, b), (b, a)] {
+                sums[[x, y]] += d[[i, j]];
+                pairs[[x, y]] += 1.0;
+            }
+        }
+    }
+    for ((ab, &sum), &n) in sums.indexed_iter().zip(&pairs) {
+        let mean = sum / n;
+        assert!(
+            (mean - div.between[ab]).abs() < 1e-3 || (mean.is_nan() && div.between[ab].is_nan()),
+            "{ab:?}: {mean} != {}",
+            div.between[ab]
+        );
+    }
+
+    let genotypes = Array2::from_shape_vec((3, 3), vec![1, 0, 0, 1, 1, 0, 0, 0, 1]).unwrap();
+    assert_eq!(
+        hamming_distances(&genotypes).row(0).to_vec(),
+        [0.0, 1.0, 2.0]
+    );
+}
@@ -0,0 +1,144 @@
+//! Per-generation snapshots of a run and a renderer drawing one SVG frame per generation,
+//! showing site frequencies next to the migration graph as it grows. Frames are numbered
+//! so they can be turned into a GIF or video with e.g.
+//! `ffmpeg -i frame_%03d.svg` or `convert -delay 50 frame_*.svg out.gif`.
+use std::f64::consts::PI;
+
+use ndarray::Array2;
+
+use crate::{tree::Phylogeny, visualizations::DotStyle};
+
+/// State of the population at the end of one generation
+#[derive(Debug, Clone)]
+pub struct GenerationState {
+    pub generation: usize,
+    /// Number of lineages in each site
+    pub site_counts: Vec<usize>,
+    /// Migrations between sites up to and including this generation
+    pub migrations: Array2<i64>,
+}
+
+/// Reconstruct the per-generation states of a simulated phylogeny, where the generation
+/// of a node is its depth
+pub fn generation_states<N>(tree: &Phylogeny<N, usize>, sites: usize) -> Vec<GenerationState> {
+    let depths = tree.depths();
+    let counts = tree.site_counts_by_generation(sites);
+
+    let mut migrations_by_generation = vec![Array2::<i64>::zeros((sites, sites)); counts.nrows()];
+    for (parent, child, _) in tree.edges() {
+        let (from, to) = (tree.nodes[parent].label, tree.nodes[child].label);
+        migrations_by_generation[depths[child]][[from, to]] += 1;
+    }
+
+    let mut migrations = Array2::zeros((sites, sites));
+    counts
+        .rows()
+        .into_iter()
+        .zip(migrations_by_generation)
+        .enumerate()
+        .map(|(generation, (row, m))| {
+            migrations = &migrations + &m;
+            GenerationState {
+                generation,
+                site_counts: row.to_vec(),
+                migrations: migrations.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Draw one frame: stacked site frequency bar on the left, migration graph with sites on
+/// a circle on the right
+pub fn frame_svg(state: &GenerationState, sites: &[String], style: &DotStyle) -> String {
+    const WIDTH: f64 = 640.0;
+    const HEIGHT: f64 = 400.0;
+    const BAR_X: f64 = 40.0;
+    const BAR_WIDTH: f64 = 60.0;
+    const BAR_HEIGHT: f64 = 320.0;
+    const RADIUS: f64 = 140.0;
+    const CENTER: (f64, f64) = (420.0, 200.0);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\">\n\
+         <rect width=\"{WIDTH}\" height=\"{HEIGHT}\" fill=\"white\"/>\n\
+         <text x=\"{BAR_X}\" y=\"25\" font-family=\"sans-serif\">generation {}</text>\n",
+        state.generation
+    );
+
+    let total = state.site_counts.iter().sum::<usize>().max(1) as f64;
+    let mut y = 40.0;
+    for (site, &count) in state.site_counts.iter().enumerate() {
+        let h = count as f64 / total * BAR_HEIGHT;
+        svg.push_str(&format!(
+            "<rect x=\"{BAR_X}\" y=\"{y:.1}\" width=\"{BAR_WIDTH}\" height=\"{h:.1}\" fill=\"{}\"/>\n",
+            style.site_color(site)
+        ));
+        y += h;
+    }
+
+    let n = sites.len();
+    let pos = |site: usize| {
+        let angle = 2.0 * PI * site as f64 / n.max(1) as f64 - PI / 2.0;
+        (
+            CENTER.0 + RADIUS * angle.cos(),
+            CENTER.1 + RADIUS * angle.sin(),
+        )
+    };
+    let max_count = state.migrations.iter().cloned().max().unwrap_or(0).max(1) as f64;
+    for ((from, to), &count) in state.migrations.indexed_iter() {
+        if from == to || count == 0 {
+            continue;
+        }
+        let ((x1, y1), (x2, y2)) = (pos(from), pos(to));
+        svg.push_str(&format!(
+            "<line x1=\"{x1:.1}\" y1=\"{y1:.1}\" x2=\"{x2:.1}\" y2=\"{y2:.1}\" stroke=\"{}\" \
+             stroke-width=\"{:.1}\" stroke-opacity=\"0.7\"/>\n",
+            style.site_color(from),
+            1.0 + 5.0 * count as f64 / max_count
+        ));
+    }
+    for (site, name) in sites.iter().enumerate() {
+        let (x, y) = pos(site);
+        let r = 8.0 + 12.0 * (state.site_counts[site] as f64 / total).sqrt();
+        svg.push_str(&format!(
+            "<circle cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"{r:.1}\" fill=\"{}\" stroke=\"black\"/>\n\
+             <text x=\"{x:.1}\" y=\"{y:.1}\" text-anchor=\"middle\" dy=\"0.35em\" \
+             font-family=\"sans-serif\">{name}</text>\n",
+            style.site_color(site)
+        ));
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// File name of the frame for a generation
+pub fn frame_name(generation: usize) -> String {
+    format!("frame_{generation:03}.svg")
+}
+
+#[test]
+fn test_generation_states() {
+    use crate::tree::Node;
+
+    let mut tree = Phylogeny::new(Node::root(0usize, 0usize), 0.0);
+    let a = tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 1.0);
+    tree.add_child(a, 3, 1, 1.0);
+    tree.add_child(a, 4, 0, 1.0);
+
+    let states = generation_states(&tree, 2);
+    assert_eq!(states.len(), 3);
+    assert_eq!(states[1].site_counts, vec![1, 1]);
+    assert_eq!(states[1].migrations[[0, 1]], 1);
+    assert_eq!(states[2].migrations[[0, 1]], 2);
+    assert_eq!(states[2].migrations[[0, 0]], 2);
+
+    let svg = frame_svg(
+        &states[2],
+        &["a".to_string(), "b".to_string()],
+        &DotStyle::default(),
+    );
+    assert!(svg.contains("generation 2"));
+    assert_eq!(svg.matches("<line").count(), 1);
+}
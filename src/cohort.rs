@@ -0,0 +1,192 @@
+//! Cohorts of patients whose parameters are drawn from priors, the design of simulation
+//! studies in method papers. Every patient is an independent run seeded like the trees of
+//! a [forest](crate::forest), with its parameters drawn in patient order from the
+//! `priors` stream of the cohort seed. The `cohort` command writes:
+//!
+//! - `patients/NNN/` - `edgelist.csv`, `vertex_labeling.csv` and `tree.json` of patient
+//!   `NNN`, the json with the drawn parameters
+//! - `summary.csv` - summary statistics and parameters of every patient, numbered in the
+//!   `replicate` column
+//! - `manifest.json` - the priors and checksums of all of the above
+use std::{fmt, str::FromStr};
+
+use rand::Rng;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    forest::tree_seed,
+    model::{Component, SimRng},
+    simulations::Parameters,
+};
+
+/// Directory of the per patient outputs
+pub const PATIENTS: &str = "patients";
+
+/// Prior distribution of one parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Prior {
+    /// Always this value
+    Fixed(f64),
+    /// Uniform between the bounds
+    Uniform(f64, f64),
+    /// Uniform between the bounds on a log scale, for rates spanning magnitudes
+    LogUniform(f64, f64),
+}
+
+impl Prior {
+    /// Draw a value
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        match *self {
+            Prior::Fixed(x) => x,
+            Prior::Uniform(low, high) if low == high => low,
+            Prior::Uniform(low, high) => rng.gen_range(low..high),
+            Prior::LogUniform(low, high) if low == high => low,
+            Prior::LogUniform(low, high) => rng.gen_range(low.ln()..high.ln()).exp(),
+        }
+    }
+
+    /// Smallest and largest value that can be drawn
+    pub fn range(&self) -> (f64, f64) {
+        match *self {
+            Prior::Fixed(x) => (x, x),
+            Prior::Uniform(low, high) | Prior::LogUniform(low, high) => (low, high),
+        }
+    }
+
+    /// Draw a whole number, uniform priors are uniform over the whole numbers between
+    /// their rounded bounds
+    pub fn sample_count<R: Rng>(&self, rng: &mut R) -> usize {
+        match *self {
+            Prior::Uniform(low, high) => {
+                rng.gen_range(low.round() as usize..=high.round() as usize)
+            }
+            _ => self.sample(rng).round() as usize,
+        }
+    }
+}
+
+impl fmt::Display for Prior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prior::Fixed(x) => write!(f, "{x}"),
+            Prior::Uniform(low, high) => write!(f, "uniform:{low}:{high}"),
+            Prior::LogUniform(low, high) => write!(f, "log-uniform:{low}:{high}"),
+        }
+    }
+}
+
+impl FromStr for Prior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |x: &str| {
+            x.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| format!("expected a finite number, got `{x}`"))
+        };
+        let bounds = |rest: &str| {
+            let (low, high) = rest
+                .split_once(':')
+                .ok_or_else(|| format!("expected `<low>:<high>` bounds, got `{rest}`"))?;
+            let (low, high) = (number(low)?, number(high)?);
+            match low <= high {
+                true => Ok((low, high)),
+                false => Err(format!("lower bound {low} is above upper bound {high}")),
+            }
+        };
+        match s.split_once(':') {
+            None => Ok(Prior::Fixed(number(s)?)),
+            Some(("uniform", rest)) => bounds(rest).map(|(low, high)| Prior::Uniform(low, high)),
+            Some(("log-uniform", rest)) => match bounds(rest)? {
+                (low, high) if low > 0.0 => Ok(Prior::LogUniform(low, high)),
+                _ => Err("log-uniform bounds must be positive".to_string()),
+            },
+            Some(_) => Err(format!(
+                "unknown prior `{s}`, expected a number, uniform:<low>:<high> or \
+                 log-uniform:<low>:<high>"
+            )),
+        }
+    }
+}
+
+impl Serialize for Prior {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Priors of the parameters that vary between patients
+#[derive(Debug, Clone, Serialize)]
+pub struct Priors {
+    pub birth_rate: Prior,
+    pub migration_probability: Prior,
+    /// Drawn as a whole number, at least 1
+    pub sites: Prior,
+}
+
+/// A cohort of patients sharing everything but the parameters drawn from `priors`
+#[derive(Debug, Clone, Serialize)]
+pub struct Cohort {
+    pub patients: usize,
+    pub priors: Priors,
+    /// Parameters of every patient before the draws, its seed is the cohort seed
+    pub base: Parameters,
+}
+
+impl Cohort {
+    /// Parameters of every patient
+    pub fn draw(&self) -> Vec<Parameters> {
+        let mut rng: SimRng = Component::Priors.rng(self.base.seed);
+        (0..self.patients)
+            .map(|patient| Parameters {
+                birth_rate: self.priors.birth_rate.sample(&mut rng),
+                migration_probability: self.priors.migration_probability.sample(&mut rng),
+                sites: self.priors.sites.sample_count(&mut rng).max(1),
+                seed: tree_seed(self.base.seed, patient),
+                ..self.base.clone()
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_priors() {
+    for s in ["0.2", "uniform:1:3", "log-uniform:0.001:0.1"] {
+        assert_eq!(s.parse::<Prior>().unwrap().to_string(), s);
+    }
+    for s in [
+        "uniform:3:1",
+        "log-uniform:0:1",
+        "normal:0:1",
+        "uniform:1",
+        "x",
+    ] {
+        assert!(s.parse::<Prior>().is_err(), "{s}");
+    }
+
+    let cohort = Cohort {
+        patients: 50,
+        priors: Priors {
+            birth_rate: Prior::Fixed(0.3),
+            migration_probability: Prior::LogUniform(0.001, 0.1),
+            sites: Prior::Uniform(2.0, 4.0),
+        },
+        base: Parameters::default(),
+    };
+    let patients = cohort.draw();
+    assert_eq!(patients.len(), 50);
+    assert!(patients.iter().all(|p| p.birth_rate == 0.3));
+    assert!(
+        patients
+            .iter()
+            .all(|p| (0.001..0.1).contains(&p.migration_probability))
+    );
+    let sites: Vec<_> = patients.iter().map(|p| p.sites).collect();
+    assert!(sites.iter().all(|s| (2..=4).contains(s)));
+    assert!((2..=4).all(|s| sites.contains(&s)));
+    assert_eq!(patients[3].seed, cohort.base.seed + 3);
+    // the draws only depend on the cohort seed
+    assert_eq!(cohort.draw()[7].sites, sites[7]);
+}
@@ -0,0 +1,183 @@
+//! Summaries of variability across stochastic runs in the standard phylogenetic way: the
+//! majority-rule consensus of replicate trees over the same leaves, and the consensus of
+//! repeated labelings of one topology, e.g. reconstructions of the ancestral sites.
+//!
+//! Clades are matched as in [`crate::diff`], leaves by their node data and internal nodes
+//! by the leaves below them. Clades found in more than half of the trees are compatible
+//! with each other, so they always form a tree.
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    Float,
+    diff::{clades, leaf_ids},
+    tree::{Node, Phylogeny},
+};
+
+pub const CONSENSUS: &str = "consensus.nhx";
+
+/// Majority-rule consensus of `trees`, which must have the same leaves: every clade found
+/// in more than half of them, under the smallest such clade containing it. A node takes
+/// the data of the clade in the first tree that has it, the label most of those trees
+/// give it and their mean branch length above it. Its metadata holds the fraction of
+/// trees with the clade as `support` and the fraction agreeing on the label as
+/// `label_support`. Unary chains collapse into the clade they lead to
+pub fn majority_rule<N: Ord + Clone, L: Ord + Clone>(
+    trees: &[&Phylogeny<N, L>],
+) -> Result<Phylogeny<N, L>, String> {
+    if trees.is_empty() {
+        return Err("a consensus needs at least one tree".to_string());
+    }
+    let ids = leaf_ids(trees.iter().copied());
+    for (t, tree) in trees.iter().enumerate() {
+        if tree.all_leaves().count() != ids.len() {
+            return Err(format!("tree {t} does not have the leaves of the others"));
+        }
+    }
+
+    // the topmost node of every clade in each tree that has it, clades in the order found
+    let mut found: HashMap<Vec<usize>, Vec<(usize, usize)>> = HashMap::new();
+    let mut order = vec![];
+    for (t, tree) in trees.iter().enumerate() {
+        let clades = clades(tree, &ids);
+        for idx in tree.preorder() {
+            let nodes = found.entry(clades[idx].clone()).or_insert_with(|| {
+                order.push(clades[idx].clone());
+                vec![]
+            });
+            if nodes.last().is_none_or(|&(last, _)| last != t) {
+                nodes.push((t, idx));
+            }
+        }
+    }
+    let mut kept: Vec<_> = order
+        .into_iter()
+        .filter(|clade| 2 * found[clade].len() > trees.len())
+        .collect();
+    // larger clades first, so a clade follows every clade containing it
+    kept.sort_by_key(|clade| std::cmp::Reverse(clade.len()));
+
+    // data, label, length, support and label support of a clade
+    let node = |clade: &[usize]| {
+        let nodes = &found[clade];
+        let (t, first) = nodes[0];
+        let (label, agreeing) = majority(nodes.iter().map(|&(t, idx)| &trees[t].nodes[idx].label));
+        let length = nodes
+            .iter()
+            .fold(0.0, |total, &(t, idx)| total + trees[t].branch_length(idx))
+            / nodes.len() as Float;
+        let support = nodes.len() as Float / trees.len() as Float;
+        let label_support = agreeing as Float / nodes.len() as Float;
+        let data = trees[t].nodes[first].data.clone();
+        (data, label, length, support, label_support)
+    };
+    // the clade of all leaves is in every tree, and the root
+    let (data, label, length, support, label_support) = node(&kept[0]);
+    let mut consensus = Phylogeny::new(Node::root(data, label), length);
+    consensus.nodes[0].set_meta("support", support);
+    consensus.nodes[0].set_meta("label_support", label_support);
+    // deepest node so far above every leaf
+    let mut deepest = vec![0; ids.len()];
+    for clade in &kept[1..] {
+        let (data, label, length, support, label_support) = node(clade);
+        let idx = consensus.add_child(deepest[clade[0]], data, label, length);
+        consensus.nodes[idx].set_meta("support", support);
+        consensus.nodes[idx].set_meta("label_support", label_support);
+        for &leaf in clade {
+            deepest[leaf] = idx;
+        }
+    }
+    Ok(consensus)
+}
+
+/// Majority label of every node across `labelings` of one topology with the fraction of
+/// labelings that agree on it. Ties go to the smaller label
+pub fn consensus_labeling<L: Ord + Clone, S: AsRef<[L]>>(
+    labelings: &[S],
+) -> Result<Vec<(L, Float)>, String> {
+    let Some(first) = labelings.first() else {
+        return Err("a consensus needs at least one labeling".to_string());
+    };
+    let nodes = first.as_ref().len();
+    if let Some(i) = labelings.iter().position(|l| l.as_ref().len() != nodes) {
+        return Err(format!(
+            "labeling {i} has {} nodes, not {nodes}",
+            labelings[i].as_ref().len()
+        ));
+    }
+    Ok((0..nodes)
+        .map(|node| {
+            let (label, agreeing) = majority(labelings.iter().map(|l| &l.as_ref()[node]));
+            (label, agreeing as Float / labelings.len() as Float)
+        })
+        .collect())
+}
+
+/// Most frequent label and its count, the smallest of tied labels
+fn majority<'a, L: Ord + Clone + 'a>(labels: impl Iterator<Item = &'a L>) -> (L, usize) {
+    let mut counts = BTreeMap::new();
+    for label in labels {
+        *counts.entry(label).or_insert(0) += 1;
+    }
+    let (label, count) = counts
+        .into_iter()
+        .rev()
+        .max_by_key(|&(_, count)| count)
+        .expect("at least one label");
+    (label.clone(), count)
+}
+
+#[test]
+fn test_consensus() {
+    // ((1,2)5,(3,4)6)0 twice and ((1,3)5,(2,4)6)0 once, with sites on 1 and 5
+    let build = |pairs: [(usize, usize); 6], sites: [usize; 2]| {
+        let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+        for (data, parent) in pairs {
+            let parent = tree.nodes.iter().position(|n| n.data == parent).unwrap();
+            let label = match data {
+                1 => sites[0],
+                5 => sites[1],
+                _ => 0,
+            };
+            tree.add_child(parent, data, label, data as Float);
+        }
+        tree
+    };
+    let a = build([(5, 0), (6, 0), (1, 5), (2, 5), (3, 6), (4, 6)], [1, 2]);
+    let b = build([(5, 0), (6, 0), (1, 5), (2, 5), (3, 6), (4, 6)], [1, 1]);
+    let c = build([(5, 0), (6, 0), (1, 5), (3, 5), (2, 6), (4, 6)], [2, 1]);
+    let consensus = majority_rule(&[&a, &b, &c]).unwrap();
+    consensus.validate().unwrap();
+    assert_eq!(consensus.to_tree(), a.to_tree());
+    let n = |data: usize| consensus.nodes.iter().position(|n| n.data == data).unwrap();
+    let support: Float = consensus.nodes[n(5)]
+        .meta("support")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(support, 2.0 / 3.0);
+    assert_eq!(consensus.nodes[n(0)].meta("support"), Some("1"));
+    // 1 is on site 1 in a and b, 5 on sites 2 and 1, tied towards the smaller
+    assert_eq!(consensus.nodes[n(1)].label, 1);
+    assert_eq!(consensus.nodes[n(5)].label, 1);
+    assert_eq!(consensus.nodes[n(5)].meta("label_support"), Some("0.5"));
+
+    // without a majority only the root and the leaves remain
+    let d = build([(5, 0), (6, 0), (1, 5), (4, 5), (2, 6), (3, 6)], [0, 0]);
+    let star = majority_rule(&[&a, &c, &d]).unwrap();
+    assert_eq!(star.nodes.len(), 5);
+    assert_eq!(star.children(0).count(), 4);
+    let leaf_4 = star.nodes.iter().position(|n| n.data == 4).unwrap();
+    assert_eq!(star.branch_length(leaf_4), 4.0);
+
+    let mut other = a.clone();
+    other.add_child(n(4), 7, 0, 1.0);
+    assert!(majority_rule(&[&a, &other]).is_err());
+    assert!(majority_rule::<usize, usize>(&[]).is_err());
+
+    let labelings = [vec![0, 1, 2], vec![0, 2, 2], vec![1, 2, 0]];
+    assert_eq!(
+        consensus_labeling(&labelings).unwrap(),
+        [(0, 2.0 / 3.0), (2, 2.0 / 3.0), (2, 2.0 / 3.0)]
+    );
+    assert!(consensus_labeling(&[vec![0], vec![0, 1]]).is_err());
+}
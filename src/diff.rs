@@ -0,0 +1,271 @@
+//! Differences between two trees over the same leaves, or between two labelings of one
+//! topology: the clades both trees have, the clades only one has, and for the nodes of
+//! shared clades the labels that disagree and how their branch lengths changed. Leaves
+//! are matched by their node data, internal nodes by the leaves below them.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    io::{self, Write},
+};
+
+use crate::{Float, tree::Phylogeny};
+
+/// A node of both trees, the same leaf or the same clade
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedNode<L> {
+    pub a: usize,
+    pub b: usize,
+    /// Leaves below the node
+    pub leaves: usize,
+    pub labels: (L, L),
+    /// Branch length above the node in `b` minus in `a`
+    pub length_delta: Float,
+}
+
+/// A node of one tree only, with the data of the leaves below it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clade<N> {
+    pub node: usize,
+    pub leaves: Vec<N>,
+}
+
+/// See [`diff`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhylogenyDiff<N, L> {
+    /// Nodes of both trees, in preorder of `a`
+    pub shared: Vec<SharedNode<L>>,
+    /// Nodes of `a` whose clade `b` does not have, in preorder
+    pub only_a: Vec<Clade<N>>,
+    /// Nodes of `b` whose clade `a` does not have, in preorder
+    pub only_b: Vec<Clade<N>>,
+}
+
+impl<N, L: PartialEq> PhylogenyDiff<N, L> {
+    /// Shared nodes labeled differently in the two trees
+    pub fn label_disagreements(&self) -> impl Iterator<Item = &SharedNode<L>> {
+        self.shared.iter().filter(|n| n.labels.0 != n.labels.1)
+    }
+
+    /// Clades of internal nodes found in only one tree, the Robinson-Foulds distance of
+    /// the rooted trees when both have the same leaves
+    pub fn robinson_foulds(&self) -> usize {
+        let internal = |clades: &[Clade<N>]| clades.iter().filter(|c| c.leaves.len() > 1).count();
+        internal(&self.only_a) + internal(&self.only_b)
+    }
+
+    /// Same clades, labels and branch lengths
+    pub fn is_identical(&self) -> bool {
+        self.only_a.is_empty()
+            && self.only_b.is_empty()
+            && self.label_disagreements().next().is_none()
+            && self.shared.iter().all(|n| n.length_delta == 0.0)
+    }
+}
+
+impl<N: Display, L: PartialEq + Display> PhylogenyDiff<N, L> {
+    /// Readable report: counts of shared and unique clades, every label disagreement and
+    /// the largest branch length changes
+    pub fn write_report<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(
+            w,
+            "clades: {} shared, {} only in a, {} only in b, Robinson-Foulds distance {}",
+            self.shared.len(),
+            self.only_a.len(),
+            self.only_b.len(),
+            self.robinson_foulds()
+        )?;
+        for (tree, clades) in [("a", &self.only_a), ("b", &self.only_b)] {
+            for clade in clades {
+                let leaves: Vec<String> = clade.leaves.iter().map(|l| l.to_string()).collect();
+                writeln!(
+                    w,
+                    "  only in {tree}: node {} over {{{}}}",
+                    clade.node,
+                    leaves.join(",")
+                )?;
+            }
+        }
+
+        let disagreements: Vec<_> = self.label_disagreements().collect();
+        writeln!(
+            w,
+            "labels: {} of {} shared nodes disagree",
+            disagreements.len(),
+            self.shared.len()
+        )?;
+        for n in disagreements {
+            writeln!(
+                w,
+                "  node {} in a, {} in b, clade of {}: {} -> {}",
+                n.a, n.b, n.leaves, n.labels.0, n.labels.1
+            )?;
+        }
+
+        let mut changed: Vec<_> = self
+            .shared
+            .iter()
+            .filter(|n| n.length_delta != 0.0)
+            .collect();
+        changed.sort_by(|x, y| y.length_delta.abs().total_cmp(&x.length_delta.abs()));
+        let total = changed
+            .iter()
+            .fold(0.0, |total, n| total + n.length_delta.abs());
+        writeln!(
+            w,
+            "branch lengths: {} of {} shared nodes changed, total absolute change {total}",
+            changed.len(),
+            self.shared.len()
+        )?;
+        for n in changed.iter().take(10) {
+            writeln!(w, "  node {} in a, {} in b: {:+}", n.a, n.b, n.length_delta)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare `a` with `b`. Nodes with the same leaves below them are paired in preorder,
+/// so chains of unary nodes pair from the top
+pub fn diff<N: Ord + Clone, L: Clone>(
+    a: &Phylogeny<N, L>,
+    b: &Phylogeny<N, L>,
+) -> PhylogenyDiff<N, L> {
+    let ids = leaf_ids([a, b]);
+    let names: Vec<&N> = ids.keys().collect();
+    let clade = |idx: usize, clades: &[Vec<usize>]| Clade {
+        node: idx,
+        leaves: clades[idx].iter().map(|&id| names[id].clone()).collect(),
+    };
+
+    let (order_a, clades_a) = (a.preorder(), clades(a, &ids));
+    let (order_b, clades_b) = (b.preorder(), clades(b, &ids));
+    let mut in_b: HashMap<&[usize], Vec<usize>> = HashMap::new();
+    for &idx in order_b.iter().rev() {
+        in_b.entry(&clades_b[idx][..]).or_default().push(idx);
+    }
+
+    let mut shared = vec![];
+    let mut only_a = vec![];
+    let mut paired = vec![false; b.nodes.len()];
+    for &idx in &order_a {
+        match in_b
+            .get_mut(&clades_a[idx][..])
+            .and_then(|nodes| nodes.pop())
+        {
+            Some(other) => {
+                paired[other] = true;
+                shared.push(SharedNode {
+                    a: idx,
+                    b: other,
+                    leaves: clades_a[idx].len(),
+                    labels: (a.nodes[idx].label.clone(), b.nodes[other].label.clone()),
+                    length_delta: b.branch_length(other) - a.branch_length(idx),
+                });
+            }
+            None => only_a.push(clade(idx, &clades_a)),
+        }
+    }
+    let only_b = order_b
+        .iter()
+        .filter(|&&idx| !paired[idx])
+        .map(|&idx| clade(idx, &clades_b))
+        .collect();
+    PhylogenyDiff {
+        shared,
+        only_a,
+        only_b,
+    }
+}
+
+/// Leaves of all `trees` numbered in the order of their data, so clades list them in that
+/// order
+pub(crate) fn leaf_ids<'a, N: Ord + Clone + 'a, L: 'a>(
+    trees: impl IntoIterator<Item = &'a Phylogeny<N, L>>,
+) -> BTreeMap<N, usize> {
+    let mut ids = BTreeMap::new();
+    for tree in trees {
+        for leaf in tree.all_leaves() {
+            ids.insert(tree.nodes[leaf].data.clone(), 0);
+        }
+    }
+    for (id, value) in ids.values_mut().enumerate() {
+        *value = id;
+    }
+    ids
+}
+
+/// Sorted ids of the leaves below every node
+pub(crate) fn clades<N: Ord, L>(
+    tree: &Phylogeny<N, L>,
+    ids: &BTreeMap<N, usize>,
+) -> Vec<Vec<usize>> {
+    let mut clades = vec![vec![]; tree.nodes.len()];
+    for idx in tree.preorder().into_iter().rev() {
+        clades[idx] = match tree.is_leaf(idx) {
+            true => vec![ids[&tree.nodes[idx].data]],
+            false => {
+                let mut leaves: Vec<usize> = tree
+                    .children(idx)
+                    .flat_map(|(child, _)| clades[child].iter().copied())
+                    .collect();
+                leaves.sort_unstable();
+                leaves
+            }
+        };
+    }
+    clades
+}
+
+#[test]
+fn test_diff() {
+    use crate::tree::Node;
+
+    // ((1,2)3,4)0 and ((1,4)3,2)0 with another site on 4
+    let build = |pairs: [(usize, usize); 4], label_4: usize, stretch_1: Float| {
+        let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+        for (data, parent) in pairs {
+            let parent = tree.nodes.iter().position(|n| n.data == parent).unwrap();
+            let label = if data == 4 { label_4 } else { 0 };
+            let length = data as Float + if data == 1 { stretch_1 } else { 0.0 };
+            tree.add_child(parent, data, label, length);
+        }
+        tree
+    };
+    let a = build([(3, 0), (4, 0), (1, 3), (2, 3)], 0, 0.0);
+    let same = diff(&a, &a);
+    assert!(same.is_identical());
+    assert_eq!(same.shared.len(), 5);
+    assert_eq!(same.robinson_foulds(), 0);
+
+    let b = build([(3, 0), (2, 0), (1, 3), (4, 3)], 1, 0.0);
+    let d = diff(&a, &b);
+    assert_eq!(d.robinson_foulds(), 2);
+    assert_eq!(d.only_a[0].leaves, [1, 2]);
+    assert_eq!(d.only_b[0].leaves, [1, 4]);
+    // the root and the three leaves are shared
+    assert_eq!(d.shared.len(), 4);
+    let disagreements: Vec<_> = d.label_disagreements().collect();
+    assert_eq!(disagreements.len(), 1);
+    assert_eq!(disagreements[0].labels, (0, 1));
+    assert_eq!(b.nodes[disagreements[0].b].data, 4);
+    // leaf 2 hangs from the root in b, with the same branch
+    assert!(d.shared.iter().all(|n| n.length_delta == 0.0));
+
+    let mut report = vec![];
+    d.write_report(&mut report).unwrap();
+    let report = String::from_utf8(report).unwrap();
+    assert!(report.starts_with("clades: 4 shared, 1 only in a, 1 only in b"));
+    assert!(report.contains("only in a: node 1 over {1,2}"));
+    assert!(report.contains("labels: 1 of 4 shared nodes disagree"));
+
+    // a labeling of the same topology with one branch stretched
+    let c = build([(3, 0), (4, 0), (1, 3), (2, 3)], 2, 0.5);
+    let d = diff(&a, &c);
+    assert_eq!(d.robinson_foulds(), 0);
+    assert_eq!(d.label_disagreements().count(), 1);
+    let changed: Vec<_> = d.shared.iter().filter(|n| n.length_delta != 0.0).collect();
+    assert_eq!(changed.len(), 1);
+    assert_eq!(
+        (a.nodes[changed[0].a].data, changed[0].length_delta),
+        (1, 0.5)
+    );
+}
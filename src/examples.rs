@@ -0,0 +1,118 @@
+//! Small fixtures whose answers are known by hand, for tests, documentation and a first
+//! look at the library without running a simulation. `cargo run --example toy` prints
+//! them with the analyses that apply.
+use ndarray::{Array2, array};
+
+use crate::{
+    pmatrix::PMatrix,
+    simulations::Parameters,
+    tree::{Node, Phylogeny},
+};
+
+/// Names of the sites of [`toy_phylogeny`], [`toy_pmatrix`] and [`toy_parameters`]
+pub fn toy_site_names() -> Vec<String> {
+    ["primary", "lymph node", "liver"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Nine nodes over the three sites of [`toy_site_names`], node data is the node index:
+///
+/// ```text
+/// ((3:1,(7:0.5,8:0.5)4:1.5)1:1,(5:1,6:1)2:2)0;
+/// ```
+///
+/// Nodes 0, 1, 3 and 8 are in the primary, 2, 5 and 6 in the lymph node, 4 and 7 in the
+/// liver. The primary seeds the lymph node (2) and the liver (4) once each and the liver
+/// reseeds the primary (8), a monoclonal reseeding pattern, `mR`
+pub fn toy_phylogeny() -> Phylogeny<usize, usize> {
+    let mut tree = Phylogeny::new(Node::root(0, 0), 0.0);
+    for (parent, site, length) in [
+        (0, 0, 1.0),
+        (0, 1, 2.0),
+        (1, 0, 1.0),
+        (1, 2, 1.5),
+        (2, 1, 1.0),
+        (2, 1, 1.0),
+        (4, 2, 0.5),
+        (4, 0, 0.5),
+    ] {
+        let idx = tree.nodes.len();
+        tree.add_child(parent, idx, site, length);
+    }
+    tree
+}
+
+/// Migration matrix of the three toy sites: stay with probability 0.9, migrate to each
+/// other site with 0.05
+pub fn toy_pmatrix() -> PMatrix {
+    PMatrix::new_with_initial_conditions(3, 0.1)
+}
+
+/// A run over the toy sites small enough to simulate instantly: 6 generations, 64
+/// leaves
+pub fn toy_parameters() -> Parameters {
+    Parameters {
+        generations: 6,
+        sites: 3,
+        names: toy_site_names(),
+        migration_probability: 0.1,
+        seed: 7,
+        ..Default::default()
+    }
+}
+
+/// Names of the sites of [`mcpherson_like_matrix`]
+pub fn mcpherson_like_sites() -> Vec<String> {
+    ["right ovary", "left ovary", "omentum", "small bowel"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Seeding counts between the sites of [`mcpherson_like_sites`], source in rows, in
+/// the shape of the high-grade serous ovarian cancers of McPherson et al. (2016): the
+/// right ovary seeds the left ovary once and the omentum twice, the omentum seeds the
+/// small bowel and reseeds the right ovary. Made up, not the counts of a patient, and
+/// without the diagonal a simulated migration matrix has
+pub fn mcpherson_like_matrix() -> Array2<i64> {
+    array![[0, 1, 2, 0], [0, 0, 0, 0], [1, 0, 0, 1], [0, 0, 0, 0]]
+}
+
+/// Frequencies of the sites of [`mcpherson_like_sites`] to start a run observed late,
+/// see [`Parameters::initial_frequencies`]
+pub fn mcpherson_like_frequencies() -> Vec<f64> {
+    vec![0.55, 0.1, 0.3, 0.05]
+}
+
+#[test]
+fn test_examples() {
+    use crate::{
+        analysis::{migration_pattern, seedings},
+        simulations::Simulations,
+    };
+
+    let tree = toy_phylogeny();
+    tree.validate().unwrap();
+    assert_eq!(tree.nodes.len(), 9);
+    let mut newick = vec![];
+    tree.write_newick(&mut newick).unwrap();
+    assert_eq!(
+        String::from_utf8(newick).unwrap(),
+        "((3:1,(7:0.5,8:0.5)4:1.5)1:1,(5:1,6:1)2:2)0:0;\n"
+    );
+    let seedings = seedings(&tree);
+    let edges: Vec<_> = seedings.iter().map(|s| (s.source, s.target)).collect();
+    assert_eq!(edges, [(0, 1), (0, 2), (2, 0)]);
+    assert_eq!(migration_pattern(&seedings, 3, &[0]).to_string(), "mR");
+
+    let p = toy_pmatrix();
+    assert!((p.as_array()[[0, 0]] - 0.9).abs() < 1e-6);
+    let result = Phylogeny::simulate(&toy_parameters(), &mut ());
+    assert_eq!(result.tree().leaves().count(), 64);
+
+    let m = mcpherson_like_matrix();
+    assert_eq!(m.dim(), (4, 4));
+    assert_eq!(m.diag().sum(), 0);
+    assert_eq!(mcpherson_like_sites().len(), 4);
+    assert!((mcpherson_like_frequencies().iter().sum::<f64>() - 1.0).abs() < 1e-12);
+}
@@ -0,0 +1,148 @@
+//! Forests of independent trees sharing the sites and migration matrix, for multifocal
+//! primaries or cohorts of patients. Tree `i` is simulated with the seed of the run plus
+//! `i`, the seeding of `--replicates`, and every table carries the tree id:
+//!
+//! - `forest.nwk` - one Newick tree per line, in tree order
+//! - `forest_edgelist.csv` - `tree,parent,child,length`
+//! - `forest_vertex_labeling.csv` - `tree,vertex,label`
+//! - `forest_migration_matrix.csv` - migrations of all trees added up
+use std::{
+    fmt::Display,
+    io::{self, Write},
+};
+
+use ndarray::Array2;
+
+use crate::{
+    output::{CsvDialect, CsvWriter},
+    simulations::{Parameters, SimulationResult, Simulations},
+    tree::Phylogeny,
+};
+
+pub const FOREST_NEWICK: &str = "forest.nwk";
+pub const FOREST_EDGELIST: &str = "forest_edgelist.csv";
+pub const FOREST_VERTEX_LABELING: &str = "forest_vertex_labeling.csv";
+pub const FOREST_MIGRATION_MATRIX: &str = "forest_migration_matrix.csv";
+
+/// Seed of tree `tree` of a forest seeded with `seed`
+pub fn tree_seed(seed: u64, tree: usize) -> u64 {
+    seed.wrapping_add(tree as u64)
+}
+
+/// Simulate `trees` independent trees with the same parameters but their own seeds
+pub fn simulate_forest(
+    params: &Parameters,
+    trees: usize,
+) -> Vec<SimulationResult<Phylogeny<usize, usize>>> {
+    (0..trees)
+        .map(|tree| {
+            let params = Parameters {
+                seed: tree_seed(params.seed, tree),
+                ..params.clone()
+            };
+            Phylogeny::simulate(&params, &mut ())
+        })
+        .collect()
+}
+
+/// Migrations of all trees of a forest added up
+pub fn forest_migration_matrix(
+    forest: &[&SimulationResult<Phylogeny<usize, usize>>],
+) -> Array2<i64> {
+    forest
+        .iter()
+        .map(|result| result.migration_matrix())
+        .fold(None, |sum: Option<Array2<i64>>, m| match sum {
+            Some(sum) => Some(sum + m),
+            None => Some(m.clone()),
+        })
+        .unwrap_or_else(|| Array2::zeros((0, 0)))
+}
+
+/// Write every tree as Newick, one per line
+pub fn write_forest_newick<W, N, L>(mut w: W, trees: &[&Phylogeny<N, L>]) -> io::Result<()>
+where
+    W: Write,
+    N: Display + Clone,
+    L: Display + Clone,
+{
+    for tree in trees {
+        tree.write_newick(&mut w)?;
+    }
+    Ok(())
+}
+
+/// Write the edges of every tree as `tree,parent,child,length`
+pub fn write_forest_edges_csv<W, N, L>(
+    w: W,
+    trees: &[&Phylogeny<N, L>],
+    dialect: CsvDialect,
+) -> io::Result<()>
+where
+    W: Write,
+    N: Display + Clone,
+    L: Display + Clone,
+{
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["tree", "parent", "child", "length"])?;
+    for (t, tree) in trees.iter().enumerate() {
+        for (p, c, len) in tree.edges() {
+            csv.record([&t as &dyn Display, &p, &c, &len])?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the label of every node of every tree as `tree,vertex,label`
+pub fn write_forest_vertex_labeling_csv<W, N, L>(
+    w: W,
+    trees: &[&Phylogeny<N, L>],
+    dialect: CsvDialect,
+) -> io::Result<()>
+where
+    W: Write,
+    L: Display,
+{
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["tree", "vertex", "label"])?;
+    for (t, tree) in trees.iter().enumerate() {
+        for (i, n) in tree.nodes.iter().enumerate() {
+            csv.record([&t as &dyn Display, &i, &n.label])?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_forest() {
+    let params = Parameters {
+        generations: 4,
+        sites: 3,
+        migration_probability: 0.2,
+        ..Default::default()
+    };
+    let forest = simulate_forest(&params, 3);
+    assert_eq!(forest.len(), 3);
+    assert_eq!(forest[1].seed(), params.seed + 1);
+    let first = Phylogeny::simulate(&params, &mut ());
+    let edges = |tree: &Phylogeny<usize, usize>| tree.edges().collect::<Vec<_>>();
+    assert_eq!(edges(forest[0].tree()), edges(first.tree()));
+
+    let total: i64 = forest_migration_matrix(&forest.iter().collect::<Vec<_>>()).sum();
+    let each: i64 = forest.iter().map(|r| r.migration_matrix().sum()).sum();
+    assert_eq!(total, each);
+
+    let trees: Vec<_> = forest.iter().map(|r| r.tree()).collect();
+    let mut newick = vec![];
+    write_forest_newick(&mut newick, &trees).unwrap();
+    let newick = String::from_utf8(newick).unwrap();
+    assert_eq!(newick.lines().count(), 3);
+    assert!(newick.lines().all(|l| l.ends_with(';')));
+
+    let mut edges = vec![];
+    write_forest_edges_csv(&mut edges, &trees, CsvDialect::default()).unwrap();
+    let edges = String::from_utf8(edges).unwrap();
+    let per_tree: usize = trees.iter().map(|t| t.nodes.len() - 1).sum();
+    assert_eq!(edges.lines().count(), per_tree + 1);
+    assert!(edges.lines().last().unwrap().starts_with("2,"));
+}
@@ -1,11 +1,93 @@
+//! Simulation of metastatic migration on phylogenies. The core (`pmatrix`, `tree`,
+//! `simulations` and the outputs) only needs `rand`, `ndarray` and `serde`; drawing is
+//! behind the `viz` feature and the command line program behind `cli`, both on by
+//! default. Embed with `default-features = false` to skip petgraph, clap and Graphviz.
+//! Trees, matrices and simulation results are `Send + Sync`, share one large result
+//! between analysis threads with `SimulationResult::into_shared`.
+pub mod abc;
+pub mod analysis;
+#[cfg(feature = "viz")]
+pub mod animation;
+pub mod cohort;
+pub mod consensus;
+pub mod diff;
+pub mod examples;
+pub mod forest;
+pub mod manifest;
+pub mod model;
+pub mod mutations;
+pub mod npy;
+pub mod output;
 pub mod pmatrix;
+pub mod progress;
+pub mod replay;
+#[cfg(feature = "report")]
+pub mod report;
+pub mod sparse;
+pub mod sql;
+pub mod stats;
 pub mod tree;
 pub mod simulations;
+pub mod site;
+#[cfg(feature = "viz")]
 pub mod visualizations;
 
+/// Floating point type of branch lengths and migration probabilities, `f32` with the
+/// `f32` feature to halve the memory of very large simulations
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+/// Modules and the most used types, `use cancer_sims::prelude::*` is enough to run and
+/// write out a simulation
 pub mod prelude {
+    pub use super::Float;
+    pub use super::model::{Budget, CancelToken, Model, StepOutcome, drive, drive_with_budget};
+    pub use super::pmatrix::{FrequencyBias, PMatrix, PMatrixError, SiteRole};
+    pub use super::simulations::{
+        MatrixStorage, MigrationMode, Observer, Parameters, Retention, RngScheme,
+        SimulationResult, Simulations,
+    };
+    pub use super::site::{Site, SiteLabel};
+    pub use super::tree::{Node, Phylogeny, Tree};
+    #[cfg(feature = "viz")]
+    pub use super::visualizations::{DotStyle, EdgeLabels, ImageFormat, Palette, RenderError};
+    pub use super::abc;
+    pub use super::analysis;
+    #[cfg(feature = "viz")]
+    pub use super::animation;
+    pub use super::cohort;
+    pub use super::consensus;
+    pub use super::diff;
+    pub use super::examples;
+    pub use super::forest;
+    pub use super::manifest;
+    pub use super::model;
+    pub use super::mutations;
+    pub use super::npy;
+    pub use super::output;
     pub use super::pmatrix;
+    pub use super::progress;
+    pub use super::replay;
+    #[cfg(feature = "report")]
+    pub use super::report;
+    pub use super::sparse;
+    pub use super::sql;
+    pub use super::stats;
     pub use super::tree;
     pub use super::simulations;
+    pub use super::site;
+    #[cfg(feature = "viz")]
     pub use super::visualizations;
-}
\ No newline at end of file
+}
+#[test]
+fn test_prelude() {
+    use prelude::*;
+
+    let result = Phylogeny::simulate(&Parameters::default(), &mut ());
+    let tree: &Phylogeny<usize, usize> = result.tree();
+    let root: &Node<usize, usize> = &tree.nodes[0];
+    assert_eq!(root.label, 0);
+    assert!(PMatrix::new(3).validate().is_ok());
+}
@@ -1,21 +1,236 @@
-use std::fs::File;
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
-use cancer_migration_sims::{
-    simulations::Simulations,
+use cancer_sims::{
+    Float,
+    analysis::{
+        ComigrationWindow, clonality, comigrations, diversity, seedings, write_comigrations_csv,
+        write_diversity_csv, write_graph_json, write_seedings_csv,
+    },
+    animation::{frame_name, frame_svg, generation_states},
+    cohort::{Cohort, PATIENTS, Prior, Priors},
+    consensus::{CONSENSUS, majority_rule},
+    diff::diff,
+    forest::{
+        FOREST_EDGELIST, FOREST_MIGRATION_MATRIX, FOREST_NEWICK, FOREST_VERTEX_LABELING,
+        forest_migration_matrix, simulate_forest, tree_seed, write_forest_edges_csv,
+        write_forest_newick, write_forest_vertex_labeling_csv,
+    },
+    manifest::Manifest,
+    mutations::Mutations,
+    npy::write_datasets,
+    output::{
+        ANCESTRAL, BULK_VCF, COMBINED_SVG, COMIGRATIONS, CsvDialect, DIVERSITY, EDGELIST,
+        EFFECTIVE_MATRICES, FASTA, FRAMES, GRAPH_JSON, ITOL_RANGES, ITOL_STRIP, JsonLinesEvents,
+        LEAF_COUNTS, LEAF_COUNTS_SVG, LEAF_LABELING, MIGRATION_GRAPH, MIGRATION_MATRIX, NEWICK,
+        NEXUS, NHX, NODE_TABLE, OutputWriter, PHYLOXML, SEEDINGS, SQL_DUMP, STATE_LABELING,
+        SUMMARY, StreamingCsv, TRANSITIONS, TREE_JSON, VCF, VERTEX_LABELING, task_dir, task_range,
+        write_leaf_counts_csv, write_matrices_csv_with, write_matrix_csv_with,
+        write_state_labeling_csv, write_transitions_csv,
+    },
+    pmatrix::{FrequencyBias, PMatrix},
+    progress::{Progress, Verbosity, estimated_tree_bytes},
+    replay::{RECORDING, Recorder, Recording},
+    simulations::{
+        BranchUnits, EffectiveMatrices, MatrixStorage, MigrationMode, Parameters, Retention,
+        RngScheme, Simulations, WaitingTime,
+    },
+    sql::SqlDump,
+    stats::{
+        ReplicateSummary, ancestral_marginals, compare, read_summaries_csv, write_ancestral_csv,
+        write_comparisons_csv, write_summaries_csv,
+    },
     tree::Phylogeny,
-    visualizations::{graph_from_edge_matrix, save_graph_png},
+    visualizations::{
+        DotStyle, EdgeLabels, ImageFormat, Palette, RankDir, RenderError, combined_svg,
+        graph_from_edge_matrix, save_graph, stacked_area_svg, write_itol_ranges, write_itol_strip,
+        write_phyloxml,
+    },
 };
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
+
+/// Artifacts that can be produced by a run
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Emit {
+    /// Edge list csv
+    Edgelist,
+    /// Newick tree
+    Newick,
+    /// NEXUS file with the tree
+    Nexus,
+    /// Newick tree with sites and metadata as NHX comments
+    Nhx,
+    /// PhyloXML tree colored by site
+    Phyloxml,
+    /// iTOL datasets coloring the leaves by site, with the Newick tree
+    Itol,
+    /// Versioned json document
+    Json,
+    /// Vertex and leaf labeling csvs, and the cell states with a cell state layer
+    Labels,
+    /// Node table csv with generation, time and subtree size
+    Nodes,
+    /// Lineages founding each site with source, time and clone size
+    Seedings,
+    /// Migration graph as node-link json for d3 and Cytoscape.js, with site sizes and the
+    /// timing of every migration
+    GraphJson,
+    /// Migrations grouped into comigrations
+    Comigrations,
+    /// Within and between site diversity and Fst, from mutations when simulated
+    Diversity,
+    /// Marginal site probabilities of the internal nodes given the leaves
+    Ancestral,
+    /// Long format summary statistics of every replicate
+    Summary,
+    /// Majority-rule consensus of the trees of every replicate as NHX, with the clade
+    /// support and the majority site of every node
+    Consensus,
+    /// Every tree of --trees as multi-tree Newick, edge and vertex tables tagged by tree
+    /// id and the migration matrix of all trees
+    Forest,
+    /// Migration count matrix csv
+    Matrix,
+    /// Migration counts between every pair of sites in every generation, long format csv
+    Transitions,
+    /// Extant lineages per site after every generation csv
+    LeafCounts,
+    /// Stacked-area chart of the extant lineages per site over time
+    LeafCountsFigure,
+    /// Site-colored tree beside its migration graph in one svg, drawn without Graphviz
+    Figure,
+    /// Per leaf and per site vcf of simulated mutations
+    Vcf,
+    /// Fasta of the variable positions of every leaf
+    Fasta,
+    /// SQL dump of the tree, migrations and parameters of every replicate, one run each,
+    /// load it with sqlite3
+    Sql,
+    /// NumPy arrays of the matrices, frequencies, edge table and genotypes, as one npz
+    Arrays,
+    /// Migration probabilities used in each generation
+    EffectiveMatrices,
+    /// Every node, death and migration matrix per generation, for the replay command
+    Recording,
+    /// Migration graph rendered with Graphviz
+    #[value(alias = "png")]
+    Graph,
+    /// One SVG frame per generation for animations
+    Frames,
+    /// Self-contained HTML report
+    #[cfg(feature = "report")]
+    Report,
+}
+
+/// Commands other than simulating
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Test whether the statistics of two summary tables (`--emit summary`) differ,
+    /// writing Mann-Whitney and Kolmogorov-Smirnov tests and effect sizes as csv to stdout
+    Compare {
+        /// Summary table of the first setting
+        a: PathBuf,
+
+        /// Summary table of the second setting
+        b: PathBuf,
+
+        /// Comma separated statistics to compare, all shared ones when left out
+        #[arg(long, value_delimiter = ',')]
+        statistics: Vec<String>,
+
+        /// Compare two trees (`tree.json`) instead, writing their shared and unique clades,
+        /// label disagreements and branch length changes as a report to stdout
+        #[arg(long, conflicts_with = "statistics")]
+        trees: bool,
+    },
+    /// Simulate a cohort of patients with parameters drawn from priors, writing the tree
+    /// of every patient and a summary table of the cohort. Priors are a number,
+    /// uniform:<low>:<high> or log-uniform:<low>:<high>
+    Cohort {
+        /// Patients to simulate
+        #[arg(long, default_value_t = 10)]
+        patients: usize,
+
+        /// Prior of the birth rate
+        #[arg(short, long, default_value_t = Prior::Fixed(0.2))]
+        birth_rate: Prior,
+
+        /// Prior of the migration probability
+        #[arg(short, long, default_value_t = Prior::Fixed(0.01))]
+        migration_probability: Prior,
+
+        /// Prior of the number of sites, uniform priors draw whole numbers
+        #[arg(short = 's', long, default_value_t = Prior::Fixed(6.0))]
+        sites: Prior,
+
+        /// Generations to simulate
+        #[arg(short, long, default_value_t = 10)]
+        generations: usize,
+
+        /// Seed of the cohort, patient i is seeded with seed + i
+        #[arg(short = 'r', long, default_value_t = 42)]
+        seed: u64,
+
+        /// Directory to write all outputs into (created if missing)
+        #[arg(short, long, default_value = "cohort")]
+        outdir: String,
+
+        /// Index of this job of an array job from 0, e.g. `$SLURM_ARRAY_TASK_ID`
+        #[arg(long, default_value_t = 0)]
+        task_index: usize,
+
+        /// Jobs of the array job, each simulates its share of the patients into
+        /// `<outdir>/task_<index>`
+        #[arg(long, default_value_t = 1)]
+        task_count: usize,
+
+        /// Only report warnings and errors
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Rebuild a run from its recording (`--emit recording`) without drawing a random
+    /// number, writing its tree, labeling and matrices
+    Replay {
+        /// Recording of the run
+        recording: PathBuf,
+
+        /// Directory to write all outputs into (created if missing)
+        #[arg(short, long, default_value = "replay")]
+        outdir: String,
+
+        /// Only report warnings and errors
+        #[arg(short, long)]
+        quiet: bool,
+    },
+}
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Birth rate for Yule model
     #[arg(short, long, default_value_t = 0.2)]
     birth_rate: f64,
 
+    /// Distribution of branch lengths, all with mean 1/birth-rate: exponential,
+    /// gamma:<shape> or weibull:<shape>
+    #[arg(long, default_value_t = WaitingTime::default())]
+    waiting_time: WaitingTime,
+
+    /// What branch lengths measure in every output: time (the sampled waiting times),
+    /// generations (one per branch) or mutations:<clock rate> (expected mutations, the
+    /// clock rate times the waiting time)
+    #[arg(long, default_value_t = BranchUnits::default())]
+    branch_units: BranchUnits,
+
     /// Migration probability between sites
     #[arg(short, long, default_value_t = 0.01)]
     migration_probability: f64,
@@ -28,48 +243,1433 @@ struct Args {
     #[arg(short = 's', long, default_value_t = 6)]
     sites: usize,
 
+    /// Comma separated names of the sites for the headers and labels of the outputs, e.g.
+    /// `breast,lymph,liver`, their index by default
+    #[arg(long, value_delimiter = ',')]
+    site_names: Vec<String>,
+
+    /// Comma separated sites of the founder lineages, several for multifocal primaries
+    /// descending from an unobserved common ancestor, e.g. `0,0,1`
+    #[arg(long, value_delimiter = ',', default_value = "0")]
+    root_sites: Vec<usize>,
+
+    /// Comma separated fraction of the founder lineages in every site, to start with
+    /// metastasis underway, e.g. `0.8,0.15,0.05`
+    #[arg(long, value_delimiter = ',', conflicts_with = "root_sites")]
+    initial_frequencies: Vec<f64>,
+
+    /// Founder lineages spread over the sites by --initial-frequencies
+    #[arg(long, default_value_t = 100)]
+    founders: usize,
+
     /// Seed for reproducible simulation
     #[arg(short = 'r', long, default_value_t = 42)]
     seed: u64,
 
-    /// File name (no file type)
+    /// Migration model: `frequency-dependent` rescaling every generation or a `fixed`
+    /// matrix (independent migration null model)
+    #[arg(long, default_value_t = MigrationMode::FrequencyDependent)]
+    migration_mode: MigrationMode,
+
+    /// How the migration matrix is stored: `dense`, or `sparse` for many sites with few
+    /// connections
+    #[arg(long, default_value_t = MatrixStorage::Dense)]
+    matrix_storage: MatrixStorage,
+
+    /// How site frequencies bias migration: exponential[:beta], inverse or none
+    #[arg(long, default_value_t = FrequencyBias::default())]
+    frequency_bias: FrequencyBias,
+
+    /// Comma separated sites that receive migrants but never seed other sites
+    #[arg(long, value_delimiter = ',')]
+    absorbing: Vec<usize>,
+
+    /// Comma separated sites that seed other sites but never receive migrants
+    #[arg(long, value_delimiter = ',')]
+    source_only: Vec<usize>,
+
+    /// Comma separated `a:b` pairs, site b can only be colonized after site a, e.g.
+    /// `0:1,1:2,1:3` for primary, lymph node, then distant organs
+    #[arg(long, value_delimiter = ',', value_parser = parse_site_pair)]
+    colonization_order: Vec<(usize, usize)>,
+
+    /// Comma separated attractiveness of every site as a migration destination, e.g.
+    /// `1,4,1` for a primary that preferentially seeds site 1
+    #[arg(long, value_delimiter = ',')]
+    organotropism: Vec<f64>,
+
+    /// Cell states of a second label layer, e.g. 2 for epithelial and mesenchymal
+    #[arg(long, default_value_t = 1)]
+    states: usize,
+
+    /// Probability that a child switches to another cell state
+    #[arg(long, default_value_t = 0.1)]
+    state_switch_probability: f64,
+
+    /// Comma separated factors on the migration probability of each cell state
+    #[arg(long, value_delimiter = ',')]
+    state_migration: Vec<f64>,
+
+    /// Probability that a generation is a migration burst, 0 for gradual migration
+    #[arg(long, default_value_t = 0.0)]
+    burst_probability: f64,
+
+    /// Factor on the migration probability in burst generations
+    #[arg(long, default_value_t = 100.0)]
+    burst_factor: f64,
+
+    /// What to keep in memory: `full` tree or only a `summary` (migration matrix and
+    /// site counts) for very large generation counts
+    #[arg(long, default_value_t = Retention::Full)]
+    retention: Retention,
+
+    /// Group migrations within this much time into one comigration, instead of by
+    /// generation
+    #[arg(long)]
+    comigration_window: Option<Float>,
+
+    /// Mutations per unit of branch length, for the vcf and fasta outputs
+    #[arg(long, default_value_t = 0.0)]
+    mutation_rate: Float,
+
+    /// Comma separated fraction of tumor cells in the bulk sample of every site, or one
+    /// fraction for all sites. The rest are normal cells diluting allele frequencies
+    #[arg(long, value_delimiter = ',')]
+    purity: Vec<Float>,
+
+    /// Children of every split, more than 2 gives polytomies
+    #[arg(long, default_value_t = 2)]
+    branching: usize,
+
+    /// Generations of founder bottleneck after a lineage enters an empty site
+    #[arg(long, default_value_t = 0)]
+    founder_generations: usize,
+
+    /// Children of every split in a site during its founder bottleneck
+    #[arg(long, default_value_t = 1)]
+    founder_branching: usize,
+
+    /// Cap on the number of extant lineages, lineages above it are culled at random
+    #[arg(long)]
+    max_population: Option<usize>,
+
+    /// Leave lineages culled by --max-population out of the tree outputs, keeping only the
+    /// ancestry of extant lineages
+    #[arg(long, conflicts_with = "stream")]
+    prune_dead: bool,
+
+    /// Extant lineages above which lineages become clones with deterministic sizes and
+    /// Poisson sub-clone migrations, for very large populations
+    #[arg(long)]
+    hybrid_threshold: Option<usize>,
+
+    /// How lineages draw random numbers: in turn from `shared` streams, or from streams
+    /// of their own keyed by seed and node with `lineage`, which can be redrawn per lineage
+    /// and split over threads. A seed gives different runs under each
+    #[arg(long, default_value_t = RngScheme::Shared)]
+    rng: RngScheme,
+
+    /// Threads drawing the lineages with --rng lineage, runs are the same for any number
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Replicates in the summary, seeded counting up from the seed. All other outputs are
+    /// of the first one
+    #[arg(long, default_value_t = 1)]
+    replicates: usize,
+
+    /// Independent trees of the forest output, e.g. multifocal primaries or patients of a
+    /// cohort, seeded counting up from the seed. All other outputs are of the first one
+    #[arg(long, default_value_t = 1)]
+    trees: usize,
+
+    /// Index of this job of an array job from 0, e.g. `$SLURM_ARRAY_TASK_ID`
+    #[arg(long, default_value_t = 0)]
+    task_index: usize,
+
+    /// Jobs of the array job, each simulates its share of the replicates into
+    /// `<outdir>/task_<index>` and the other outputs of its first replicate
+    #[arg(long, default_value_t = 1)]
+    task_count: usize,
+
+    /// Directory to write all outputs into (created if missing)
     #[arg(short, long, default_value = "out")]
-    out: String,
+    outdir: String,
+
+    /// Comma separated list of artifacts to write (the manifest is always written)
+    #[arg(
+        short,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "edgelist,labels,json,graph"
+    )]
+    emit: Vec<Emit>,
+
+    /// Image format for figures: png, svg or pdf
+    #[arg(short, long, default_value_t = ImageFormat::Png)]
+    format: ImageFormat,
+
+    /// Layout direction of the migration graph: TB, LR, BT or RL
+    #[arg(long, default_value_t = RankDir::TopBottom)]
+    rankdir: RankDir,
+
+    /// Colors of the sites in every figure: okabe-ito, tol, set3 or comma separated
+    /// `#rrggbb` colors. The default okabe-ito and tol are colorblind safe
+    #[arg(long, default_value = "okabe-ito")]
+    palette: Palette,
+
+    /// Comma separated `site:#rrggbb` colors of single sites over the palette, sites by
+    /// name or index, e.g. `liver:#d55e00,0:#999999`
+    #[arg(long, value_delimiter = ',', value_parser = parse_site_color)]
+    site_colors: Vec<(String, String)>,
+
+    /// Draw the lineages staying in a site as self-loops of the migration graph
+    #[arg(long)]
+    self_loops: bool,
+
+    /// Label the edges of the migration graph with counts or proportions of the edges out
+    /// of the source site
+    #[arg(long, default_value_t = EdgeLabels::Counts)]
+    edge_labels: EdgeLabels,
+
+    /// Leave the edges of the migration graph whose count or proportion is below this
+    /// unlabeled
+    #[arg(long, default_value_t = 0.0)]
+    label_threshold: f64,
+
+    /// Check the parameters, the migration matrix and the output directory, print the
+    /// size of the tree to expect and exit without simulating
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write the edge list and vertex labeling while simulating instead of at the end
+    #[arg(long)]
+    stream: bool,
+
+    /// Write every birth, migration and death to stdout as one JSON object per line while
+    /// simulating. Implies --quiet so stdout only carries events
+    #[arg(long)]
+    events: bool,
+
+    /// Field delimiter of the csv outputs, e.g. ';' or '\t'
+    #[arg(long, default_value_t = ',')]
+    delimiter: char,
+
+    /// Leave out the header row of the csv outputs
+    #[arg(long)]
+    no_header: bool,
+
+    /// Log per generation leaf counts and memory estimates
+    #[arg(short, long, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Only report warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+/// Parse an `a:b` pair of sites
+fn parse_site_pair(s: &str) -> Result<(usize, usize), String> {
+    let (a, b) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected a pair of sites `a:b`, got `{s}`"))?;
+    let site = |x: &str| x.trim().parse().map_err(|e| format!("{e}: `{x}`"));
+    Ok((site(a)?, site(b)?))
+}
+
+/// Parse a `site:#rrggbb` color of a site
+fn parse_site_color(s: &str) -> Result<(String, String), String> {
+    let (site, color) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected a site and its color `site:#rrggbb`, got `{s}`"))?;
+    Ok((site.trim().to_string(), color.trim().to_string()))
+}
+
+/// Run the cohort command
+fn simulate_cohort(cohort: &Cohort, tasks: Range<usize>, outdir: &str, progress: &Progress) {
+    let (low, _) = cohort.priors.birth_rate.range();
+    let (p_low, p_high) = cohort.priors.migration_probability.range();
+    let message = if low <= 0.0 {
+        Some("the birth rate must be positive")
+    } else if p_low < 0.0 || p_high > 1.0 {
+        Some("the migration probability must be between 0 and 1")
+    } else if cohort.priors.sites.range().0 < 0.0 {
+        Some("the number of sites can not be negative")
+    } else {
+        None
+    };
+    if let Some(message) = message {
+        Args::command()
+            .error(ErrorKind::ValueValidation, message)
+            .exit()
+    }
+
+    let mut manifest = Manifest::new(cohort.clone(), cohort.base.seed);
+    let mut writer = OutputWriter::new(outdir).expect("could not create output directory");
+    let mut summaries = vec![];
+    // every job draws all patients, the priors stream does not depend on the split
+    for (patient, params) in cohort.draw().into_iter().enumerate() {
+        if !tasks.contains(&patient) {
+            continue;
+        }
+        let result = Phylogeny::simulate(&params, &mut ());
+        let tree = result.tree();
+        let dir = format!("{PATIENTS}/{patient:03}");
+        let files = writer.create_dir(&dir).and_then(|path| {
+            writer.write(&format!("{dir}/{EDGELIST}"), |w| tree.write_csv(w))?;
+            writer.write(&format!("{dir}/{VERTEX_LABELING}"), |w| {
+                tree.write_csv_vertex_labeling(w)
+            })?;
+            writer.write(&format!("{dir}/{TREE_JSON}"), |w| {
+                tree.write_json(w, &params, &params.site_names())
+            })?;
+            Ok(path)
+        });
+        match files {
+            Ok(path) => progress.debug(format!("Wrote patient {patient} to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing patient {patient}")),
+        }
+        summaries.push(ReplicateSummary::new(
+            patient,
+            &result,
+            &params,
+            &params.site_names(),
+        ));
+    }
+    progress.info(format!(
+        "Simulated {} patients into {}",
+        tasks.len(),
+        writer.path(PATIENTS).display()
+    ));
+
+    match writer.write(SUMMARY, |w| {
+        write_summaries_csv(w, &summaries, CsvDialect::default())
+    }) {
+        Ok(path) => progress.info(format!("Wrote cohort summary to {}", path.display())),
+        Err(e) => progress.error(format!("{e}: while writing cohort summary")),
+    }
+    match writer.write_manifest(&mut manifest) {
+        Ok(path) => progress.info(format!("Wrote manifest to {}", path.display())),
+        Err(e) => progress.error(format!("{e}: while writing manifest")),
+    }
+}
+
+/// Output directory of job `index` of an array job of `count` jobs
+fn task_outdir(outdir: String, index: usize, count: usize) -> String {
+    if count == 0 || index >= count {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--task-index {index} is not below --task-count {count}"),
+            )
+            .exit()
+    }
+    match count {
+        1 => outdir,
+        _ => Path::new(&outdir)
+            .join(task_dir(index))
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Run the compare command
+fn compare_summaries(a: &Path, b: &Path, statistics: &[String]) {
+    let read = |path: &Path| {
+        File::open(path)
+            .and_then(|f| read_summaries_csv(BufReader::new(f), CsvDialect::default()))
+            .unwrap_or_else(|e| {
+                Args::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("{e}: while reading {}", path.display()),
+                    )
+                    .exit()
+            })
+    };
+    let comparisons = compare(&read(a), &read(b), statistics);
+    if let Err(e) = write_comparisons_csv(io::stdout().lock(), &comparisons, CsvDialect::default())
+    {
+        eprintln!("{e}: while writing comparisons");
+    }
+}
+
+fn compare_trees(a: &Path, b: &Path) {
+    let read = |path: &Path| {
+        File::open(path)
+            .map_err(|e| e.to_string())
+            .and_then(|f| Phylogeny::<usize, usize>::read_json(BufReader::new(f)))
+            .unwrap_or_else(|e| {
+                Args::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("{e}: while reading {}", path.display()),
+                    )
+                    .exit()
+            })
+    };
+    let report = diff(&read(a), &read(b)).write_report(io::stdout().lock());
+    if let Err(e) = report {
+        eprintln!("{e}: while writing the tree comparison");
+    }
+}
+
+/// Report what a run would do and exit with an error if it could not: a migration matrix
+/// whose rows are not distributions, or an output directory that can not be written
+fn check_run(params: &Parameters, outdir: &str, replicates: usize) {
+    let sites = params.site_names();
+    let pmatrix = params.pmatrix();
+    let founders = params
+        .founder_sites()
+        .expect("founders of checked parameters");
+    println!(
+        "sites: {}, generations: {}, founder lineages: {}, replicates: {replicates}",
+        params.sites,
+        params.generations,
+        founders.len()
+    );
+    println!(
+        "migration matrix of the first generation:\n{}",
+        pmatrix.named(&sites)
+    );
+    let p = pmatrix.as_array();
+    if p.dim() != (params.sites, params.sites) {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "the migration matrix is {:?} for {} sites",
+                    p.dim(),
+                    params.sites
+                ),
+            )
+            .exit()
+    }
+    if let Err(e) = pmatrix.validate() {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("{e} in the migration matrix"),
+            )
+            .exit()
+    }
+
+    let zeros: Vec<_> = p
+        .indexed_iter()
+        .filter(|&((i, j), &p)| i != j && p == 0.0)
+        .map(|((i, j), _)| format!("{} -> {}", sites[i], sites[j]))
+        .collect();
+    match zeros.is_empty() {
+        true => println!("structural zeros: none"),
+        false => println!("structural zeros: {}", zeros.join(", ")),
+    }
+    // sites reachable from the founders through migrations that can happen
+    let mut reached = vec![false; params.sites];
+    let mut stack = founders;
+    while let Some(i) = stack.pop() {
+        if !std::mem::replace(&mut reached[i], true) {
+            stack.extend((0..params.sites).filter(|&j| p[[i, j]] > 0.0));
+        }
+    }
+    for site in (0..params.sites).filter(|&s| !reached[s]) {
+        eprintln!(
+            "warning: site {} can never be colonized from the founders",
+            sites[site]
+        );
+    }
+
+    match params.retention {
+        Retention::Full if params.max_nodes(usize::MAX) == usize::MAX => {
+            println!("more nodes per tree than can be counted, try --max-population")
+        }
+        Retention::Full => {
+            let nodes = params.max_nodes(usize::MAX);
+            println!(
+                "at most {nodes} nodes per tree, ~{:.1} MiB",
+                estimated_tree_bytes(nodes) as f64 / (1024.0 * 1024.0)
+            );
+        }
+        Retention::Summary => println!("summary retention, no tree is kept"),
+    }
+
+    // the closest existing directory, where `outdir` would be created
+    let dir = Path::new(outdir);
+    let existing = dir
+        .ancestors()
+        .find(|d| d.is_dir())
+        .unwrap_or(Path::new("."));
+    let probe = existing.join(format!(".cancer_sims_dry_run_{}", std::process::id()));
+    if let Err(e) = File::create(&probe).and_then(|_| std::fs::remove_file(&probe)) {
+        Args::command()
+            .error(
+                ErrorKind::Io,
+                format!("{e}: {} is not writable", existing.display()),
+            )
+            .exit()
+    }
+    match dir.read_dir().map(|mut entries| entries.next().is_some()) {
+        Ok(true) => println!("writing to {outdir}, replacing the files already there"),
+        _ => println!("writing to {outdir}"),
+    }
+    println!("dry run, nothing was simulated");
+}
+
+fn replay_recording(path: &Path, outdir: &str, progress: &Progress) {
+    let recording = File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| Recording::read_jsonl(BufReader::new(f)))
+        .unwrap_or_else(|e| {
+            Args::command()
+                .error(
+                    ErrorKind::Io,
+                    format!("{e}: while reading {}", path.display()),
+                )
+                .exit()
+        });
+    let mut effective = EffectiveMatrices::default();
+    let result = recording
+        .replay_result(&mut effective)
+        .unwrap_or_else(|e| Args::command().error(ErrorKind::InvalidValue, e).exit());
+    let (params, tree) = (result.params(), result.tree());
+    let sites = params.site_names();
+
+    let mut manifest = Manifest::new(params.clone(), params.seed);
+    let mut writer = OutputWriter::new(outdir).expect("could not create output directory");
+    let written = writer
+        .write(EDGELIST, |w| tree.write_csv(w))
+        .and_then(|_| writer.write(VERTEX_LABELING, |w| tree.write_csv_vertex_labeling(w)))
+        .and_then(|_| writer.write(TREE_JSON, |w| tree.write_json(w, params, &sites)))
+        .and_then(|_| {
+            writer.write(MIGRATION_MATRIX, |w| {
+                write_matrix_csv_with(w, result.migration_matrix(), &sites, CsvDialect::default())
+            })
+        })
+        .and_then(|_| {
+            writer.write(EFFECTIVE_MATRICES, |w| {
+                write_matrices_csv_with(w, &effective.matrices, &sites, CsvDialect::default())
+            })
+        });
+    match written {
+        Ok(_) => progress.info(format!("Replayed {} into {outdir}", path.display())),
+        Err(e) => progress.error(format!("{e}: while writing the replay")),
+    }
+    match writer.write_manifest(&mut manifest) {
+        Ok(path) => progress.info(format!("Wrote manifest to {}", path.display())),
+        Err(e) => progress.error(format!("{e}: while writing manifest")),
+    }
 }
 
 fn main() {
     let Args {
+        command,
         birth_rate,
+        waiting_time,
+        branch_units,
         migration_probability,
         generations,
         sites,
+        site_names,
+        root_sites,
+        initial_frequencies,
+        founders,
         seed,
-        out,
+        migration_mode,
+        matrix_storage,
+        frequency_bias,
+        absorbing,
+        source_only,
+        colonization_order,
+        organotropism,
+        states,
+        state_switch_probability,
+        state_migration,
+        burst_probability,
+        burst_factor,
+        retention,
+        max_population,
+        prune_dead,
+        hybrid_threshold,
+        rng,
+        threads,
+        branching,
+        founder_generations,
+        founder_branching,
+        comigration_window,
+        mutation_rate,
+        purity,
+        replicates,
+        trees,
+        task_index,
+        task_count,
+        outdir,
+        emit,
+        format,
+        rankdir,
+        palette,
+        site_colors,
+        self_loops,
+        edge_labels,
+        label_threshold,
+        dry_run,
+        stream,
+        events,
+        delimiter,
+        no_header,
+        verbose,
+        quiet,
     } = Args::parse();
 
-    let (tree, migration_matrix) =
-        Phylogeny::yule_migrations(birth_rate, generations, sites, migration_probability, seed);
-
-    match tree.write_csv(File::create(format!("{out}_edgelist.csv")).unwrap()) {
-        Ok(_) => println!("Wrote edgelist to {out}_edgelist.csv"),
-        Err(e) => println!("{e}: while writing edgelist"),
+    match command {
+        Some(Command::Compare {
+            a, b, trees: true, ..
+        }) => {
+            return compare_trees(&a, &b);
+        }
+        Some(Command::Compare {
+            a, b, statistics, ..
+        }) => {
+            return compare_summaries(&a, &b, &statistics);
+        }
+        Some(Command::Cohort {
+            patients,
+            birth_rate,
+            migration_probability,
+            sites,
+            generations,
+            seed,
+            outdir,
+            task_index,
+            task_count,
+            quiet,
+        }) => {
+            let outdir = task_outdir(outdir, task_index, task_count);
+            let cohort = Cohort {
+                patients,
+                priors: Priors {
+                    birth_rate,
+                    migration_probability,
+                    sites,
+                },
+                base: Parameters {
+                    generations,
+                    seed,
+                    ..Default::default()
+                },
+            };
+            let progress = Progress::new(Verbosity::from_flags(false, quiet));
+            let tasks = task_range(patients, task_index, task_count);
+            return simulate_cohort(&cohort, tasks, &outdir, &progress);
+        }
+        Some(Command::Replay {
+            recording,
+            outdir,
+            quiet,
+        }) => {
+            let progress = Progress::new(Verbosity::from_flags(false, quiet));
+            return replay_recording(&recording, &outdir, &progress);
+        }
+        None => {}
     }
 
-    match tree
-        .write_csv_vertex_labeling(File::create(format!("{out}_vertex_labeling.csv")).unwrap())
+    if let Some(site) =
+        (absorbing.iter().chain(&source_only).chain(&root_sites)).find(|&&s| s >= sites)
     {
-        Ok(_) => println!("Wrote vertex labeling to {out}_vertex_labeling.csv"),
-        Err(e) => println!("{e}: while writing vertex labeling"),
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("site {site} does not exist with {sites} sites"),
+            )
+            .exit()
+    }
+
+    let purity = match purity.len() {
+        0 => vec![1.0; sites],
+        1 => vec![purity[0]; sites],
+        n if n == sites => purity,
+        n => Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--purity has {n} fractions for {sites} sites"),
+            )
+            .exit(),
+    };
+    if let Some(p) = purity.iter().find(|p| !(**p > 0.0 && **p <= 1.0)) {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("purity {p} must be in (0, 1]"),
+            )
+            .exit()
+    }
+
+    if !site_names.is_empty() && site_names.len() != sites {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--site-names has {} names for {sites} sites",
+                    site_names.len()
+                ),
+            )
+            .exit()
+    }
+    let repeated = (1..site_names.len()).find(|&i| site_names[..i].contains(&site_names[i]));
+    if let Some(i) = repeated {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("site name {} is given twice", site_names[i]),
+            )
+            .exit()
+    }
+    let mut palette = palette;
+    for (site, color) in &site_colors {
+        let index = site_names
+            .iter()
+            .position(|name| name == site)
+            .or_else(|| site.parse().ok().filter(|&i| i < sites));
+        let colored = match index {
+            Some(i) => palette.set_site_color(i, color),
+            None => Err(format!("unknown site `{site}` in --site-colors")),
+        };
+        if let Err(e) = colored {
+            Args::command().error(ErrorKind::ValueValidation, e).exit()
+        }
+    }
+
+    if !organotropism.is_empty() && organotropism.len() != sites {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--organotropism has {} weights for {sites} sites",
+                    organotropism.len()
+                ),
+            )
+            .exit()
+    }
+    if let Some(w) = organotropism.iter().find(|w| !w.is_finite() || **w < 0.0) {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("organotropism weight {w} must be finite and not negative"),
+            )
+            .exit()
+    }
+
+    if !(0.0..=1.0).contains(&migration_probability) {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--migration-probability must be between 0 and 1",
+            )
+            .exit()
+    }
+
+    if !(0.0..=1.0).contains(&burst_probability) {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--burst-probability must be between 0 and 1",
+            )
+            .exit()
+    }
+
+    if states == 0 {
+        Args::command()
+            .error(ErrorKind::ValueValidation, "--states must be at least 1")
+            .exit()
+    }
+    if !state_migration.is_empty() && state_migration.len() != states {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!(
+                    "--state-migration has {} factors for {states} states",
+                    state_migration.len()
+                ),
+            )
+            .exit()
+    }
+    if states > 1 && retention == Retention::Summary {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "cell states are only simulated with full retention",
+            )
+            .exit()
+    }
+
+    if branching == 0 {
+        Args::command()
+            .error(ErrorKind::ValueValidation, "--branching must be at least 1")
+            .exit()
+    }
+
+    if founder_branching == 0 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--founder-branching must be at least 1",
+            )
+            .exit()
+    }
+
+    if replicates == 0 {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                "--replicates must be at least 1",
+            )
+            .exit()
+    }
+
+    let outdir = task_outdir(outdir, task_index, task_count);
+    let tasks = task_range(replicates, task_index, task_count);
+    if tasks.is_empty() {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("job {task_index} has none of the {replicates} replicates"),
+            )
+            .exit()
+    }
+    // the other outputs are of the first replicate of the job
+    let seed = seed.wrapping_add(tasks.start as u64);
+
+    if threads == 0 {
+        Args::command()
+            .error(ErrorKind::ValueValidation, "--threads must be at least 1")
+            .exit()
+    }
+    if threads > 1 && rng == RngScheme::Shared {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--threads needs --rng lineage, shared streams are drawn in turn",
+            )
+            .exit()
+    }
+
+    if trees == 0 {
+        Args::command()
+            .error(ErrorKind::ValueValidation, "--trees must be at least 1")
+            .exit()
+    }
+
+    if !delimiter.is_ascii() || delimiter == '"' || delimiter == '\n' {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("{delimiter:?} can not be used as a csv delimiter"),
+            )
+            .exit()
+    }
+    let dialect = CsvDialect {
+        delimiter: delimiter as u8,
+        headers: !no_header,
+        ..Default::default()
+    };
+
+    let mut progress = Progress::new(Verbosity::from_flags(verbose, quiet || events));
+
+    let params = Parameters {
+        birth_rate,
+        waiting_time,
+        branch_units,
+        generations,
+        sites,
+        names: site_names,
+        root_sites,
+        initial_frequencies,
+        founders,
+        migration_probability,
+        seed,
+        retention,
+        max_population,
+        hybrid_threshold,
+        rng,
+        threads,
+        migration_mode,
+        matrix_storage,
+        frequency_bias,
+        absorbing,
+        source_only,
+        branching,
+        colonization_order,
+        founder_generations,
+        founder_branching,
+        organotropism,
+        states,
+        state_switch_probability,
+        state_migration,
+        burst_probability,
+        burst_factor,
+    };
+
+    if let Err(e) = params.check() {
+        Args::command().error(ErrorKind::ValueValidation, e).exit()
+    }
+
+    if dry_run {
+        return check_run(&params, &outdir, tasks.len());
+    }
+
+    // the tree is only the root when summarizing, drop outputs derived from it
+    let emit: Vec<_> = match retention {
+        Retention::Full => emit,
+        Retention::Summary => emit
+            .into_iter()
+            .filter(|e| {
+                let keep = matches!(e, Emit::Matrix | Emit::EffectiveMatrices | Emit::Graph);
+                if !keep {
+                    progress.warn(format!("{e:?} is not available with --retention summary"));
+                }
+                keep
+            })
+            .collect(),
+    };
+
+    let mut manifest = Manifest::new(params.clone(), seed);
+    let mut writer = OutputWriter::new(&outdir).expect("could not create output directory");
+
+    let mut effective = EffectiveMatrices::default();
+
+    progress.debug(format!("simulating with {params:?}"));
+    progress.debug(format!(
+        "initial migration matrix:\n{}",
+        PMatrix::new_with_initial_conditions(sites, migration_probability as Float)
+            .named(&params.site_names())
+    ));
+    let mut event_lines = events.then(|| JsonLinesEvents::new(io::stdout().lock()));
+    let mut recorder = emit
+        .contains(&Emit::Recording)
+        .then(|| Recorder::new(&params));
+    let result = match stream {
+        true => {
+            let edges = emit
+                .contains(&Emit::Edgelist)
+                .then(|| writer.create(EDGELIST))
+                .transpose()
+                .expect("could not create edgelist");
+            let vertices = emit
+                .contains(&Emit::Labels)
+                .then(|| writer.create(VERTEX_LABELING))
+                .transpose()
+                .expect("could not create vertex labeling");
+
+            let mut csv = StreamingCsv::with_dialect(edges, vertices, dialect)
+                .expect("could not write csv headers");
+            let result = Phylogeny::simulate(
+                &params,
+                &mut (
+                    &mut progress,
+                    (
+                        &mut event_lines,
+                        (&mut csv, (&mut effective, &mut recorder)),
+                    ),
+                ),
+            );
+            match csv.finish() {
+                Ok(_) => progress.info(format!("Streamed tree tables to {outdir}")),
+                Err(e) => progress.error(format!("{e}: while streaming tree tables")),
+            }
+            result
+        }
+        false => Phylogeny::simulate(
+            &params,
+            &mut (
+                &mut progress,
+                (&mut event_lines, (&mut effective, &mut recorder)),
+            ),
+        ),
+    };
+    if let Some(Err(e)) = event_lines.map(JsonLinesEvents::finish) {
+        progress.error(format!("{e}: while streaming events"))
+    }
+    if burst_probability > 0.0 {
+        progress.debug(format!("burst generations: {:?}", result.bursts()));
+    }
+    let style = DotStyle {
+        rankdir,
+        palette,
+        self_loops,
+        edge_labels,
+        label_threshold,
+        ..Default::default()
+    };
+
+    if emit.contains(&Emit::LeafCounts) {
+        match writer.write(LEAF_COUNTS, |w| {
+            write_leaf_counts_csv(
+                w,
+                result.leaf_counts_by_generation(),
+                &params.site_names(),
+                dialect,
+            )
+        }) {
+            Ok(path) => progress.info(format!("Wrote leaf counts to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing leaf counts")),
+        }
+    }
+    if emit.contains(&Emit::Transitions) {
+        match writer.write(TRANSITIONS, |w| {
+            write_transitions_csv(
+                w,
+                result.transitions_by_generation(),
+                &params.site_names(),
+                dialect,
+            )
+        }) {
+            Ok(path) => progress.info(format!("Wrote transitions to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing transitions")),
+        }
     }
+    if emit.contains(&Emit::LeafCountsFigure) {
+        let svg = stacked_area_svg(
+            result.leaf_counts_by_generation(),
+            &params.site_names(),
+            &style,
+        );
+        match writer.write(LEAF_COUNTS_SVG, |w| w.write_all(svg.as_bytes())) {
+            Ok(path) => progress.info(format!("Wrote leaf count chart to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing leaf count chart")),
+        }
+    }
+    if emit.contains(&Emit::Figure) {
+        let svg = combined_svg(result.tree(), &params.site_names(), &style);
+        match writer.write(COMBINED_SVG, |w| w.write_all(svg.as_bytes())) {
+            Ok(path) => progress.info(format!("Wrote figure to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing figure")),
+        }
+    }
+    if emit.contains(&Emit::Arrays) {
+        let mutations = Mutations::simulate(result.tree(), mutation_rate, seed);
+        match write_datasets(&mut writer, &result, &mutations) {
+            Ok(path) => progress.info(format!("Wrote arrays to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing arrays")),
+        }
+    }
+    if emit.contains(&Emit::Summary) || emit.contains(&Emit::Consensus) {
+        let site_names = params.site_names();
+        let mut summaries = vec![];
+        let mut replicates = vec![];
+        if emit.contains(&Emit::Summary) {
+            summaries.push(ReplicateSummary::new(
+                tasks.start,
+                &result,
+                &params,
+                &site_names,
+            ));
+        }
+        for replicate in tasks.start + 1..tasks.end {
+            let params = Parameters {
+                seed: seed.wrapping_add((replicate - tasks.start) as u64),
+                ..params.clone()
+            };
+            let result = Phylogeny::simulate(&params, &mut ());
+            if emit.contains(&Emit::Summary) {
+                summaries.push(ReplicateSummary::new(
+                    replicate,
+                    &result,
+                    &params,
+                    &site_names,
+                ));
+            }
+            if emit.contains(&Emit::Consensus) {
+                replicates.push(result);
+            }
+        }
+        if emit.contains(&Emit::Summary) {
+            match writer.write(SUMMARY, |w| write_summaries_csv(w, &summaries, dialect)) {
+                Ok(path) => progress.info(format!("Wrote summary to {}", path.display())),
+                Err(e) => progress.error(format!("{e}: while writing summary")),
+            }
+        }
+        if emit.contains(&Emit::Consensus) {
+            let trees: Vec<_> = std::iter::once(&result)
+                .chain(&replicates)
+                .map(|r| r.tree())
+                .collect();
+            match majority_rule(&trees) {
+                Ok(consensus) => match writer.write(CONSENSUS, |w| consensus.write_nhx(w)) {
+                    Ok(path) => progress.info(format!("Wrote consensus to {}", path.display())),
+                    Err(e) => progress.error(format!("{e}: while writing consensus")),
+                },
+                Err(e) => progress.error(format!("{e}: while building consensus")),
+            }
+        }
+    }
+    if emit.contains(&Emit::Forest) {
+        let rest = simulate_forest(
+            &Parameters {
+                seed: tree_seed(seed, 1),
+                ..params.clone()
+            },
+            trees - 1,
+        );
+        let forest: Vec<_> = std::iter::once(&result).chain(&rest).collect();
+        let forest_trees: Vec<_> = forest.iter().map(|r| r.tree()).collect();
+        match writer.write(FOREST_NEWICK, |w| write_forest_newick(w, &forest_trees)) {
+            Ok(path) => progress.info(format!("Wrote forest to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing forest newick")),
+        }
+        match writer.write(FOREST_EDGELIST, |w| {
+            write_forest_edges_csv(w, &forest_trees, dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote forest edgelist to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing forest edgelist")),
+        }
+        match writer.write(FOREST_VERTEX_LABELING, |w| {
+            write_forest_vertex_labeling_csv(w, &forest_trees, dialect)
+        }) {
+            Ok(path) => progress.info(format!(
+                "Wrote forest vertex labeling to {}",
+                path.display()
+            )),
+            Err(e) => progress.error(format!("{e}: while writing forest vertex labeling")),
+        }
+        let matrix = forest_migration_matrix(&forest);
+        match writer.write(FOREST_MIGRATION_MATRIX, |w| {
+            write_matrix_csv_with(w, &matrix, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!(
+                "Wrote forest migration matrix to {}",
+                path.display()
+            )),
+            Err(e) => progress.error(format!("{e}: while writing forest migration matrix")),
+        }
+    }
+    let mut states = result.states().map(|states| states.to_vec());
+    let (mut tree, migration_matrix) = result.into_parts();
+    if prune_dead {
+        let remap = tree.prune_dead();
+        if let Some(states) = &mut states {
+            let mut i = 0;
+            states.retain(|_| {
+                i += 1;
+                remap[i - 1].is_some()
+            });
+        }
+    }
+
+    if let Some(states) = states.filter(|_| emit.contains(&Emit::Labels)) {
+        match writer.write(STATE_LABELING, |w| {
+            write_state_labeling_csv(w, &states, dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote state labeling to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing state labeling")),
+        }
+    }
+
+    if emit.contains(&Emit::Edgelist) && !stream {
+        match writer.write(EDGELIST, |w| tree.write_csv_with(w, dialect)) {
+            Ok(path) => progress.info(format!("Wrote edgelist to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing edgelist")),
+        }
+    }
+
+    if emit.contains(&Emit::Labels) {
+        if !stream {
+            match writer.write(VERTEX_LABELING, |w| {
+                tree.write_csv_vertex_labeling_with(w, dialect)
+            }) {
+                Ok(path) => progress.info(format!("Wrote vertex labeling to {}", path.display())),
+                Err(e) => progress.error(format!("{e}: while writing vertex labeling")),
+            }
+        }
+
+        match writer.write(LEAF_LABELING, |w| {
+            tree.write_csv_leaf_labeling_with(w, dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote leaf labeling to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing leaf labeling")),
+        }
+    }
+
+    if emit.contains(&Emit::Nodes) {
+        match writer.write(NODE_TABLE, |w| tree.write_node_table_with(w, dialect)) {
+            Ok(path) => progress.info(format!("Wrote node table to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing node table")),
+        }
+    }
+
+    if emit.contains(&Emit::Json) {
+        match writer.write(TREE_JSON, |w| {
+            tree.write_json(w, &params, &params.site_names())
+        }) {
+            Ok(path) => progress.info(format!("Wrote tree to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing tree json")),
+        }
+    }
+
+    // the iTOL datasets are keyed by the leaf names of the Newick tree
+    if emit.contains(&Emit::Newick) || emit.contains(&Emit::Itol) {
+        match writer.write(NEWICK, |w| tree.write_newick(w)) {
+            Ok(path) => progress.info(format!("Wrote newick tree to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing newick tree")),
+        }
+    }
+
+    if emit.contains(&Emit::Nexus) {
+        match writer.write(NEXUS, |w| tree.write_nexus(w)) {
+            Ok(path) => progress.info(format!("Wrote NEXUS tree to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing NEXUS tree")),
+        }
+    }
+
+    if emit.contains(&Emit::Nhx) {
+        match writer.write(NHX, |w| tree.write_nhx(w)) {
+            Ok(path) => progress.info(format!("Wrote NHX tree to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing NHX tree")),
+        }
+    }
+
+    if emit.contains(&Emit::Seedings) {
+        let seedings = seedings(&tree);
+        progress.debug(format!(
+            "seeding lineages per site: {:?}",
+            clonality(&seedings, sites)
+        ));
+        match writer.write(SEEDINGS, |w| {
+            write_seedings_csv(w, &seedings, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote seedings to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing seedings")),
+        }
+    }
+
+    if emit.contains(&Emit::GraphJson) {
+        match writer.write(GRAPH_JSON, |w| {
+            write_graph_json(w, &tree, &params.site_names())
+        }) {
+            Ok(path) => progress.info(format!("Wrote graph json to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing graph json")),
+        }
+    }
+
+    if emit.contains(&Emit::Comigrations) {
+        let window = match comigration_window {
+            Some(t) => ComigrationWindow::Time(t),
+            None => ComigrationWindow::Generation,
+        };
+        let comigrations = comigrations(&seedings(&tree), window);
+        progress.debug(format!("comigration number: {}", comigrations.len()));
+        match writer.write(COMIGRATIONS, |w| {
+            write_comigrations_csv(w, &comigrations, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote comigrations to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing comigrations")),
+        }
+    }
+
+    if emit.contains(&Emit::Diversity) {
+        let leaves: Vec<_> = tree.extant_leaves().collect();
+        let weights = match mutation_rate > 0.0 {
+            true => Mutations::simulate(&tree, mutation_rate, seed).per_branch(tree.nodes.len()),
+            false => (0..tree.nodes.len())
+                .map(|n| tree.branch_length(n))
+                .collect(),
+        };
+        let diversity = diversity(&tree, &leaves, &weights, sites);
+        match writer.write(DIVERSITY, |w| {
+            write_diversity_csv(w, &diversity, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote diversity to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing diversity")),
+        }
+    }
+
+    if emit.contains(&Emit::Ancestral) {
+        // the simulation starts in the root sites, inference should not know that
+        let pmatrix = PMatrix::new_with_initial_conditions(sites, migration_probability as Float)
+            .with_roles(&params.site_roles());
+        let prior = pmatrix.stationary_distribution().to_vec();
+        let marginals = ancestral_marginals(&tree, &pmatrix, &prior);
+        match writer.write(ANCESTRAL, |w| {
+            write_ancestral_csv(w, &tree, &marginals, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!(
+                "Wrote ancestral site probabilities to {}",
+                path.display()
+            )),
+            Err(e) => progress.error(format!("{e}: while writing ancestral site probabilities")),
+        }
+    }
+
+    if emit.contains(&Emit::Matrix) {
+        match writer.write(MIGRATION_MATRIX, |w| {
+            write_matrix_csv_with(w, &migration_matrix, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!("Wrote migration matrix to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing migration matrix")),
+        }
+    }
+
+    if emit.contains(&Emit::Vcf) || emit.contains(&Emit::Fasta) {
+        if mutation_rate == 0.0 {
+            progress.warn("no mutations are simulated without --mutation-rate");
+        }
+        let mutations = Mutations::simulate(&tree, mutation_rate, seed);
+        progress.debug(format!("simulated {} mutations", mutations.len()));
+
+        if emit.contains(&Emit::Vcf) {
+            let vcfs = writer
+                .write(VCF, |w| mutations.write_vcf(w, &tree))
+                .and_then(|_| {
+                    writer.write(BULK_VCF, |w| {
+                        mutations.write_bulk_vcf_with_purity(
+                            w,
+                            &tree,
+                            &params.site_names(),
+                            &purity,
+                        )
+                    })
+                });
+            match vcfs {
+                Ok(path) => progress.info(format!("Wrote vcfs to {}", path.display())),
+                Err(e) => progress.error(format!("{e}: while writing vcf")),
+            }
+        }
+
+        if emit.contains(&Emit::Fasta) {
+            match writer.write(FASTA, |w| mutations.write_fasta(w, &tree)) {
+                Ok(path) => progress.info(format!("Wrote sequences to {}", path.display())),
+                Err(e) => progress.error(format!("{e}: while writing fasta")),
+            }
+        }
+    }
+
+    if emit.contains(&Emit::Sql) {
+        match writer.write(SQL_DUMP, |w| {
+            let mut dump = SqlDump::new(w)?;
+            dump.write_run(tasks.start, seed, &params, &tree, &migration_matrix)?;
+            // every replicate of the job is a run, numbered and seeded as in the summary
+            for replicate in tasks.start + 1..tasks.end {
+                let params = Parameters {
+                    seed: seed.wrapping_add((replicate - tasks.start) as u64),
+                    ..params.clone()
+                };
+                let (mut tree, migration_matrix) =
+                    Phylogeny::simulate(&params, &mut ()).into_parts();
+                if prune_dead {
+                    tree.prune_dead();
+                }
+                dump.write_run(replicate, params.seed, &params, &tree, &migration_matrix)?;
+            }
+            dump.finish().map(|_| ())
+        }) {
+            Ok(path) => progress.info(format!("Wrote SQL dump to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing SQL dump")),
+        }
+    }
+
+    if emit.contains(&Emit::EffectiveMatrices) {
+        match writer.write(EFFECTIVE_MATRICES, |w| {
+            write_matrices_csv_with(w, &effective.matrices, &params.site_names(), dialect)
+        }) {
+            Ok(path) => progress.info(format!(
+                "Wrote effective migration matrices to {}",
+                path.display()
+            )),
+            Err(e) => progress.error(format!("{e}: while writing effective migration matrices")),
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        match writer.write(RECORDING, |w| recorder.finish().write_jsonl(w)) {
+            Ok(path) => progress.info(format!("Wrote recording to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing recording")),
+        }
+    }
+
+    if emit.contains(&Emit::Phyloxml) {
+        match writer.write(PHYLOXML, |w| {
+            write_phyloxml(w, &tree, &params.site_names(), &style)
+        }) {
+            Ok(path) => progress.info(format!("Wrote phyloxml tree to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing phyloxml tree")),
+        }
+    }
+
+    if emit.contains(&Emit::Itol) {
+        let itol = writer
+            .write(ITOL_STRIP, |w| {
+                write_itol_strip(w, &tree, &params.site_names(), &style)
+            })
+            .and_then(|_| {
+                writer.write(ITOL_RANGES, |w| {
+                    write_itol_ranges(w, &tree, &params.site_names(), &style)
+                })
+            });
+        match itol {
+            Ok(path) => progress.info(format!("Wrote iTOL datasets to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing iTOL datasets")),
+        }
+    }
+
+    if emit.contains(&Emit::Graph) {
+        let g = graph_from_edge_matrix(migration_matrix.clone());
+        match save_graph(&g, &writer.path(MIGRATION_GRAPH), format, &style) {
+            Ok(_) => {
+                writer.record(&format!("{MIGRATION_GRAPH}.dot"));
+                writer.record(&format!("{MIGRATION_GRAPH}.{format}"));
+                progress.info(format!(
+                    "Wrote migration graph to {}",
+                    writer
+                        .path(MIGRATION_GRAPH)
+                        .with_extension(format.extension())
+                        .display()
+                ))
+            }
+            Err(RenderError::Io(e)) => {
+                progress.error(format!("{e}: while writing migration graph"))
+            }
+            Err(e) => {
+                writer.record(&format!("{MIGRATION_GRAPH}.dot"));
+                progress.warn(e)
+            }
+        }
+    }
+
+    if emit.contains(&Emit::Frames) {
+        let frames = writer.create_dir(FRAMES).and_then(|_| {
+            for state in generation_states(&tree, sites) {
+                let name = format!("{FRAMES}/{}", frame_name(state.generation));
+                writer.write(&name, |w| {
+                    write!(w, "{}", frame_svg(&state, &params.site_names(), &style))
+                })?;
+            }
+            Ok(writer.path(FRAMES))
+        });
+        match frames {
+            Ok(path) => progress.info(format!("Wrote animation frames to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing animation frames")),
+        }
+    }
+
+    #[cfg(feature = "report")]
+    if emit.contains(&Emit::Report) {
+        use cancer_sims::{output::REPORT, report::write_report};
 
-    match tree.write_csv_leaf_labeling(File::create(format!("{out}_leaf_labeling.csv")).unwrap()) {
-        Ok(_) => println!("Wrote leaf labeling to {out}_leaf_labeling.csv"),
-        Err(e) => println!("{e}: while writing leaf labeling"),
+        match writer.write(REPORT, |w| {
+            write_report(
+                w,
+                &tree,
+                &migration_matrix,
+                &params,
+                &params.site_names(),
+                &style,
+            )
+        }) {
+            Ok(path) => progress.info(format!("Wrote report to {}", path.display())),
+            Err(e) => progress.error(format!("{e}: while writing report")),
+        }
     }
 
-    let g = graph_from_edge_matrix(migration_matrix);
-    match save_graph_png(&g, &out) {
-        Ok(_) => println!("Save to {out}_migration_graph.png"),
-        Err(e) => println!("{e}"),
+    match writer.write_manifest(&mut manifest) {
+        Ok(path) => progress.info(format!("Wrote manifest to {}", path.display())),
+        Err(e) => progress.error(format!("{e}: while writing manifest")),
     }
 }
@@ -0,0 +1,208 @@
+//! Run metadata written alongside the simulation outputs so that a result can be traced
+//! back to the exact crate version and parameters that produced it.
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::model::{Component, RNG_ALGORITHM, RNG_CRATES};
+
+/// Record of a single output file
+#[derive(Debug, Clone, Serialize)]
+pub struct OutputRecord {
+    pub path: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// How the random numbers of a run were drawn
+#[derive(Debug, Clone, Serialize)]
+pub struct RngRecord {
+    pub algorithm: &'static str,
+    /// Crates and versions that define the random streams
+    pub crates: Vec<(&'static str, &'static str)>,
+    /// How the generator of every random component is seeded from the run seed
+    pub streams: Vec<StreamRecord>,
+}
+
+/// Seeding of the generator of one random component
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamRecord {
+    pub component: Component,
+    pub derivation: String,
+}
+
+impl Default for RngRecord {
+    fn default() -> Self {
+        Self {
+            algorithm: RNG_ALGORITHM,
+            crates: RNG_CRATES.to_vec(),
+            streams: Component::ALL
+                .iter()
+                .map(|&component| StreamRecord {
+                    component,
+                    derivation: component.derivation(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Manifest of one simulation run
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest<P> {
+    pub crate_name: &'static str,
+    pub crate_version: &'static str,
+    pub parameters: P,
+    pub seed: u64,
+    pub rng: RngRecord,
+    /// Seconds since the unix epoch
+    pub started_at: u64,
+    /// Seconds since the unix epoch
+    pub finished_at: u64,
+    pub outputs: Vec<OutputRecord>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl<P: Serialize> Manifest<P> {
+    /// Start a manifest - the start timestamp is taken now
+    pub fn new(parameters: P, seed: u64) -> Self {
+        let now = unix_now();
+        Self {
+            crate_name: env!("CARGO_PKG_NAME"),
+            crate_version: env!("CARGO_PKG_VERSION"),
+            parameters,
+            seed,
+            rng: RngRecord::default(),
+            started_at: now,
+            finished_at: now,
+            outputs: vec![],
+        }
+    }
+
+    /// Checksum the output file `name` in `dir` and add it to the manifest. Paths are
+    /// stored relative to `dir` so the manifest stays valid when the directory is moved
+    pub fn record<T: AsRef<Path>>(&mut self, dir: T, name: &str) -> io::Result<()> {
+        let contents = fs::read(dir.as_ref().join(name))?;
+        self.outputs.push(OutputRecord {
+            path: name.to_string(),
+            bytes: contents.len() as u64,
+            sha256: sha256_hex(&contents),
+        });
+        Ok(())
+    }
+
+    /// Stamp the finish time and write the manifest as json
+    pub fn write<W: Write>(&mut self, mut w: W) -> io::Result<()> {
+        self.finished_at = unix_now();
+        serde_json::to_writer_pretty(&mut w, self)?;
+        writeln!(w)
+    }
+}
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 digest of `data` as lowercase hex (matches `sha256sum`)
+pub fn sha256_hex(data: &[u8]) -> String {
+    sha256(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// SHA-256 digest of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+        0x5be0cd19,
+    ];
+
+    // pad with a single 1 bit, zeros, and the message length in bits
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+
+    let mut digest = [0; 32];
+    for (bytes, x) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&x.to_be_bytes());
+    }
+    digest
+}
+
+#[test]
+fn test_sha256() {
+    assert_eq!(
+        sha256_hex(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        sha256_hex(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    // two block message
+    assert_eq!(
+        sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+}
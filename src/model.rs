@@ -0,0 +1,379 @@
+//! Shared driver for generation based simulation models. A [`Model`] only knows how to
+//! set up its initial population and advance it by one generation, the driver owns the
+//! random number generators and forwards what happened to an [`Observer`], so birth-death,
+//! Moran or spatial models reuse the progress, streaming and output machinery.
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::Serialize;
+
+use crate::{
+    Float,
+    manifest::sha256,
+    pmatrix::PMatrix,
+    simulations::{GenerationReport, Observer},
+};
+
+/// Random number generator of simulations. ChaCha12 is named explicitly instead of
+/// `StdRng`, whose algorithm may change between rand versions, so a seed gives the same
+/// run on every platform and build
+pub type SimRng = ChaCha12Rng;
+
+/// Algorithm of [`SimRng`], recorded in manifests
+pub const RNG_ALGORITHM: &str = "ChaCha12";
+
+/// Crates whose versions fix the random streams, kept in step with Cargo.toml. A seed
+/// only reproduces a run with the same streams
+pub const RNG_CRATES: [(&str, &str); 3] = [
+    ("rand", "0.8.5"),
+    ("rand_chacha", "0.3.1"),
+    ("rand_distr", "0.4.3"),
+];
+
+/// Random component of a run, each draws from its own generator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Component {
+    /// Growth of the tree: branch lengths and culling
+    Tree,
+    /// Sites of the children, cell states and migration bursts
+    Migration,
+    /// Mutations dropped on the branches of the tree, the observation noise
+    Mutations,
+    /// Parameters of the patients of a cohort drawn from their priors
+    Priors,
+    /// Children of every lineage when lineages are drawn independently, streams of their
+    /// own for every lineage, see [`Streams::lineage`]
+    Lineages,
+}
+
+impl Component {
+    pub const ALL: [Component; 5] = [
+        Component::Tree,
+        Component::Migration,
+        Component::Mutations,
+        Component::Priors,
+        Component::Lineages,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Component::Tree => "tree",
+            Component::Migration => "migration",
+            Component::Mutations => "mutations",
+            Component::Priors => "priors",
+            Component::Lineages => "lineages",
+        }
+    }
+
+    /// Generator of the component in a run from `seed`. The tree grows from the seed
+    /// itself so it is the same as before components had their own streams, every other
+    /// component is seeded by [`sub_seed`]
+    pub fn rng(&self, seed: u64) -> SimRng {
+        match self {
+            Component::Tree => SimRng::seed_from_u64(seed),
+            _ => SimRng::from_seed(sub_seed(seed, self.name())),
+        }
+    }
+
+    /// How [`Component::rng`] seeds the generator, for the manifest
+    pub fn derivation(&self) -> String {
+        match self {
+            Component::Tree => "seed_from_u64(seed)".to_string(),
+            Component::Lineages => format!(
+                "from_seed(sha256(seed as u64 little endian ++ \"{}\")), stream 2 node for the \
+                 tree and 2 node + 1 for migration of the lineage ending in node",
+                self.name()
+            ),
+            _ => format!(
+                "from_seed(sha256(seed as u64 little endian ++ \"{}\"))",
+                self.name()
+            ),
+        }
+    }
+}
+
+/// Generators of the random components a model draws from, so changing how one is drawn
+/// leaves the others unchanged: with the same seed, a different migration model grows
+/// the same topology and branch lengths
+pub struct Streams {
+    pub tree: SimRng,
+    pub migration: SimRng,
+}
+
+impl Streams {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            tree: Component::Tree.rng(seed),
+            migration: Component::Migration.rng(seed),
+        }
+    }
+
+    /// Streams of the lineage ending in `node` from the generator of
+    /// [`Component::Lineages`]. ChaCha is counter based, every lineage gets streams of
+    /// its own under the same key, so a lineage draws the same numbers whichever
+    /// lineages are drawn before it or on other threads
+    pub fn lineage(lineages: &SimRng, node: usize) -> Self {
+        let stream = |i: u64| {
+            let mut rng = lineages.clone();
+            rng.set_stream(2 * node as u64 + i);
+            rng.set_word_pos(0);
+            rng
+        };
+        Self {
+            tree: stream(0),
+            migration: stream(1),
+        }
+    }
+}
+
+/// Seed of the generator of a named component, derived from the run seed by hashing so
+/// streams are independent and adding a component does not shift any other stream
+pub fn sub_seed(seed: u64, name: &str) -> <SimRng as SeedableRng>::Seed {
+    let mut data = seed.to_le_bytes().to_vec();
+    data.extend_from_slice(name.as_bytes());
+    sha256(&data)
+}
+
+/// A node created by a model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeEvent {
+    pub id: usize,
+    pub parent: Option<usize>,
+    pub label: usize,
+    pub length: Float,
+}
+
+/// What happened in one call to [`Model::init`] or [`Model::step`]
+#[derive(Debug, Clone, Default)]
+pub struct StepOutcome {
+    /// Nodes created, in creation order
+    pub nodes: Vec<NodeEvent>,
+    /// Lineages that died, e.g. culled above the population cap
+    pub deaths: Vec<usize>,
+    /// Number of extant lineages afterwards
+    pub leaves: usize,
+    /// Number of nodes kept in memory afterwards
+    pub total_nodes: usize,
+    /// No lineage is left, stepping further would not change anything
+    pub extinct: bool,
+}
+
+pub trait Model {
+    /// Create the initial population
+    fn init(&mut self, rng: &mut Streams) -> StepOutcome;
+
+    /// Advance the population by one generation
+    fn step(&mut self, rng: &mut Streams) -> StepOutcome;
+
+    /// Migration matrix used in the last step, if the model has one
+    fn pmatrix(&self) -> Option<&PMatrix> {
+        None
+    }
+}
+
+fn report_nodes<O: Observer>(observer: &mut O, outcome: &StepOutcome) {
+    for node in &outcome.nodes {
+        observer.on_node(node.id, node.parent, node.label, node.length);
+    }
+    for &id in &outcome.deaths {
+        observer.on_death(id);
+    }
+}
+
+/// Flag to stop a running simulation from another thread, e.g. when a notebook cell is
+/// interrupted. Clones share the flag
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits of a run, checked after every generation. The default has none
+#[derive(Debug, Clone, Default)]
+pub struct Budget {
+    pub cancel: Option<CancelToken>,
+    /// Wall clock time from the start of the run
+    pub time: Option<Duration>,
+    /// Nodes kept in memory
+    pub nodes: Option<usize>,
+}
+
+/// Which limit of a [`Budget`] stopped a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Limit {
+    Cancelled,
+    Time,
+    Nodes,
+}
+
+/// A run stopped by its budget before the last generation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Truncation {
+    pub limit: Limit,
+    /// Last generation that was simulated
+    pub generation: usize,
+}
+
+impl Budget {
+    fn exceeded(&self, started: Instant, nodes: usize) -> Option<Limit> {
+        if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            Some(Limit::Cancelled)
+        } else if self.time.is_some_and(|t| started.elapsed() > t) {
+            Some(Limit::Time)
+        } else if self.nodes.is_some_and(|n| nodes > n) {
+            Some(Limit::Nodes)
+        } else {
+            None
+        }
+    }
+}
+
+/// Run `model` for `generations` steps from `seed`, reporting to `observer`. Stops early
+/// when the population dies out
+pub fn drive<M: Model, O: Observer>(
+    model: &mut M,
+    generations: usize,
+    seed: u64,
+    observer: &mut O,
+) {
+    drive_with_budget(model, generations, seed, observer, &Budget::default());
+}
+
+/// [`drive`] that also stops once `budget` is exceeded, leaving the model with the
+/// generations simulated so far. Returns what stopped it, `None` for a complete run
+pub fn drive_with_budget<M: Model, O: Observer>(
+    model: &mut M,
+    generations: usize,
+    seed: u64,
+    observer: &mut O,
+    budget: &Budget,
+) -> Option<Truncation> {
+    let started = Instant::now();
+    let mut rng = Streams::new(seed);
+
+    let outcome = model.init(&mut rng);
+    report_nodes(observer, &outcome);
+    let mut nodes = outcome.total_nodes;
+
+    for generation in 1..=generations {
+        if let Some(limit) = budget.exceeded(started, nodes) {
+            return Some(Truncation {
+                limit,
+                generation: generation - 1,
+            });
+        }
+        let outcome = model.step(&mut rng);
+        if let Some(pmatrix) = observer.wants_pmatrix().then(|| model.pmatrix()).flatten() {
+            observer.on_pmatrix(generation, pmatrix);
+        }
+        report_nodes(observer, &outcome);
+        observer.on_generation(GenerationReport {
+            generation,
+            generations,
+            leaves: outcome.leaves,
+            nodes: outcome.total_nodes,
+        });
+        if outcome.extinct {
+            break;
+        }
+        nodes = outcome.total_nodes;
+    }
+    None
+}
+
+#[test]
+fn test_drive() {
+    use rand::Rng;
+
+    /// A single lineage that dies out after three generations
+    struct Countdown(usize);
+
+    impl Model for Countdown {
+        fn init(&mut self, _rng: &mut Streams) -> StepOutcome {
+            StepOutcome {
+                nodes: vec![NodeEvent {
+                    id: 0,
+                    parent: None,
+                    label: 0,
+                    length: 0.0,
+                }],
+                deaths: vec![],
+                leaves: 1,
+                total_nodes: 1,
+                extinct: false,
+            }
+        }
+
+        fn step(&mut self, rng: &mut Streams) -> StepOutcome {
+            self.0 += 1;
+            StepOutcome {
+                nodes: vec![NodeEvent {
+                    id: self.0,
+                    parent: Some(self.0 - 1),
+                    label: 0,
+                    length: rng.tree.r#gen(),
+                }],
+                deaths: vec![],
+                leaves: 1,
+                total_nodes: self.0 + 1,
+                extinct: self.0 == 3,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Log {
+        nodes: Vec<usize>,
+        generations: Vec<usize>,
+    }
+
+    impl Observer for Log {
+        fn on_node(&mut self, id: usize, _parent: Option<usize>, _label: usize, _length: Float) {
+            self.nodes.push(id)
+        }
+
+        fn on_generation(&mut self, report: GenerationReport) {
+            self.generations.push(report.generation)
+        }
+    }
+
+    let mut log = Log::default();
+    drive(&mut Countdown(0), 10, 0, &mut log);
+    assert_eq!(log.nodes, [0, 1, 2, 3]);
+    assert_eq!(log.generations, [1, 2, 3]);
+}
+
+#[test]
+fn test_component_streams() {
+    use rand::Rng;
+
+    let draw = |component: Component, seed| component.rng(seed).r#gen::<u64>();
+    assert_eq!(
+        draw(Component::Tree, 5),
+        SimRng::seed_from_u64(5).r#gen::<u64>()
+    );
+    assert_eq!(draw(Component::Mutations, 5), draw(Component::Mutations, 5));
+    assert_ne!(draw(Component::Mutations, 5), draw(Component::Mutations, 6));
+    assert_ne!(draw(Component::Mutations, 5), draw(Component::Tree, 5));
+    assert_ne!(sub_seed(5, "mutations"), sub_seed(5, "noise"));
+}
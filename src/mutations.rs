@@ -0,0 +1,351 @@
+//! Infinite sites mutations dropped on the branches of a simulated tree, and writers for
+//! the resulting genotypes as multi-sample VCF and FASTA so the output plugs into
+//! variant based phylogenetics workflows. Every mutation hits a new position and is
+//! inherited by all leaves below the branch it arose on.
+use std::io::{self, Write};
+
+use ndarray::Array2;
+use rand::Rng;
+use rand_distr::{Distribution, Poisson};
+
+use crate::{Float, model::Component, tree::Phylogeny};
+
+const BASES: [u8; 4] = *b"ACGT";
+
+/// Mutations on the branches of a tree
+#[derive(Debug, Clone, Default)]
+pub struct Mutations {
+    /// Node below the branch each mutation arose on
+    pub nodes: Vec<usize>,
+    pub reference: Vec<u8>,
+    pub alternate: Vec<u8>,
+}
+
+impl Mutations {
+    /// Drop Poisson(`rate` x branch length) mutations on every branch of `tree`, drawn
+    /// from the mutation stream of the run from `seed`
+    pub fn simulate<N: Clone, L: Clone>(tree: &Phylogeny<N, L>, rate: Float, seed: u64) -> Self {
+        let mut rng = Component::Mutations.rng(seed);
+
+        let mut mutations = Self::default();
+        for (_, child, length) in tree.edges() {
+            let lambda = rate * length;
+            if lambda <= 0.0 || !lambda.is_finite() {
+                continue;
+            }
+            let count = Poisson::new(lambda).unwrap().sample(&mut rng) as usize;
+            for _ in 0..count {
+                let reference = rng.gen_range(0..4);
+                let alternate = (reference + rng.gen_range(1..4)) % 4;
+                mutations.nodes.push(child);
+                mutations.reference.push(BASES[reference]);
+                mutations.alternate.push(BASES[alternate]);
+            }
+        }
+        mutations
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Number of mutations on the branch above every node of a tree of `nodes` nodes.
+    /// Under infinite sites two leaves differ at the mutations on the path between them
+    pub fn per_branch(&self, nodes: usize) -> Vec<Float> {
+        let mut counts = vec![0.0; nodes];
+        for &node in &self.nodes {
+            counts[node] += 1.0;
+        }
+        counts
+    }
+
+    /// Which of `leaves` carry which mutation (leaves x mutations), 1 for the alternate
+    /// allele
+    pub fn genotypes<N: Clone, L: Clone>(
+        &self,
+        tree: &Phylogeny<N, L>,
+        leaves: &[usize],
+    ) -> Array2<u8> {
+        let by_node = self.by_node(tree.nodes.len());
+        let mut genotypes = Array2::zeros((leaves.len(), self.len()));
+        for (i, &leaf) in leaves.iter().enumerate() {
+            let mut node = Some(leaf);
+            while let Some(n) = node {
+                for &m in &by_node[n] {
+                    genotypes[[i, m]] = 1;
+                }
+                node = tree.parent(n);
+            }
+        }
+        genotypes
+    }
+
+    /// Mutations on the branch above every node
+    fn by_node(&self, nodes: usize) -> Vec<Vec<usize>> {
+        let mut by_node = vec![vec![]; nodes];
+        for (m, &node) in self.nodes.iter().enumerate() {
+            by_node[node].push(m);
+        }
+        by_node
+    }
+
+    /// Write the genotypes of every extant leaf as a haploid multi-sample VCF, one sample
+    /// per leaf named `leaf_<node>`. Positions are the mutation indices from 1
+    pub fn write_vcf<N: Clone, W: Write>(
+        &self,
+        mut w: W,
+        tree: &Phylogeny<N, usize>,
+    ) -> io::Result<()> {
+        let leaves: Vec<_> = tree.extant_leaves().collect();
+        let samples: Vec<_> = leaves.iter().map(|l| format!("leaf_{l}")).collect();
+        // a leaf carries the mutations of the nodes whose preorder range holds its own
+        let order = tree.preorder();
+        let mut position = vec![0; tree.nodes.len()];
+        for (i, &node) in order.iter().enumerate() {
+            position[node] = i;
+        }
+        let mut size = vec![1; tree.nodes.len()];
+        for &node in order.iter().rev() {
+            if let Some(parent) = tree.parent(node) {
+                size[parent] += size[node];
+            }
+        }
+
+        self.write_vcf_header(&mut w, &samples, &[("GT", "String", "Genotype")], &[])?;
+        let mut gts = Vec::with_capacity(2 * leaves.len());
+        for m in 0..self.len() {
+            let node = self.nodes[m];
+            let carried = position[node]..position[node] + size[node];
+            gts.clear();
+            for &leaf in &leaves {
+                gts.extend(match carried.contains(&position[leaf]) {
+                    true => b"\t1",
+                    false => b"\t0",
+                });
+            }
+            self.write_vcf_record(&mut w, m, "GT", &gts)?;
+        }
+        Ok(())
+    }
+
+    /// Write one bulk sample per site, with the fraction of extant leaves of the site
+    /// carrying each mutation as allele frequency
+    pub fn write_bulk_vcf<N: Clone, W: Write>(
+        &self,
+        w: W,
+        tree: &Phylogeny<N, usize>,
+        sites: &[String],
+    ) -> io::Result<()> {
+        self.write_bulk_vcf_with_purity(w, tree, sites, &vec![1.0; sites.len()])
+    }
+
+    /// Write one bulk sample per site whose tumor cells make up `purity` of the sample, the
+    /// rest being normal cells without any of the mutations. `AF` is the allele frequency
+    /// of the contaminated sample and `CCF` the true fraction of cancer cells carrying the
+    /// mutation, `AF = purity x CCF`
+    pub fn write_bulk_vcf_with_purity<N: Clone, W: Write>(
+        &self,
+        mut w: W,
+        tree: &Phylogeny<N, usize>,
+        sites: &[String],
+        purity: &[Float],
+    ) -> io::Result<()> {
+        let leaves: Vec<_> = tree.extant_leaves().collect();
+        // the carriers of a mutation are the leaves below its branch
+        let carriers = tree.site_counts_below(&leaves, sites.len());
+        let totals = carriers.row(tree.root);
+
+        self.write_vcf_header(
+            &mut w,
+            sites,
+            &[
+                (
+                    "AF",
+                    "Float",
+                    "Allele frequency, with normal cell contamination",
+                ),
+                ("CCF", "Float", "Fraction of cancer cells with the mutation"),
+            ],
+            &sites
+                .iter()
+                .zip(purity)
+                .map(|(site, p)| format!("##SAMPLE=<ID={site},Purity={p}>"))
+                .collect::<Vec<_>>(),
+        )?;
+        for m in 0..self.len() {
+            let values: String = (0..sites.len())
+                .map(|s| match totals[s] {
+                    0 => "\t.:.".to_string(),
+                    t => {
+                        let ccf = carriers[[self.nodes[m], s]] as Float / t as Float;
+                        format!("\t{:.4}:{ccf:.4}", purity[s] * ccf)
+                    }
+                })
+                .collect();
+            self.write_vcf_record(&mut w, m, "AF:CCF", values.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_vcf_header<W: Write>(
+        &self,
+        w: &mut W,
+        samples: &[String],
+        formats: &[(&str, &str, &str)],
+        meta: &[String],
+    ) -> io::Result<()> {
+        writeln!(w, "##fileformat=VCFv4.2")?;
+        writeln!(w, "##source=cancer_sims")?;
+        writeln!(w, "##contig=<ID=1,length={}>", self.len())?;
+        writeln!(
+            w,
+            "##INFO=<ID=NODE,Number=1,Type=Integer,Description=\"Node below the branch the mutation arose on\">"
+        )?;
+        for (id, kind, description) in formats {
+            writeln!(
+                w,
+                "##FORMAT=<ID={id},Number=1,Type={kind},Description=\"{description}\">"
+            )?;
+        }
+        for line in meta {
+            writeln!(w, "{line}")?;
+        }
+        writeln!(
+            w,
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\t{}",
+            samples.join("\t")
+        )
+    }
+
+    /// Write the record of mutation `m`, `values` holds the sample columns each behind a
+    /// tab
+    fn write_vcf_record<W: Write>(
+        &self,
+        w: &mut W,
+        m: usize,
+        format: &str,
+        values: &[u8],
+    ) -> io::Result<()> {
+        write!(
+            w,
+            "1\t{}\tm{m}\t{}\t{}\t.\tPASS\tNODE={}\t{format}",
+            m + 1,
+            self.reference[m] as char,
+            self.alternate[m] as char,
+            self.nodes[m],
+        )?;
+        w.write_all(values)?;
+        writeln!(w)
+    }
+
+    /// Write the aligned variable positions of every extant leaf as FASTA, records are
+    /// named `leaf_<node> site=<label>`
+    pub fn write_fasta<N: Clone, W: Write>(
+        &self,
+        mut w: W,
+        tree: &Phylogeny<N, usize>,
+    ) -> io::Result<()> {
+        let by_node = self.by_node(tree.nodes.len());
+        for leaf in tree.extant_leaves() {
+            writeln!(w, ">leaf_{leaf} site={}", tree.nodes[leaf].label)?;
+            let mut sequence = self.reference.clone();
+            let mut node = Some(leaf);
+            while let Some(n) = node {
+                for &m in &by_node[n] {
+                    sequence[m] = self.alternate[m];
+                }
+                node = tree.parent(n);
+            }
+            for line in sequence.chunks(60) {
+                w.write_all(line)?;
+                writeln!(w)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_mutations() {
+    use crate::tree::Node;
+
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 0, 10.0);
+    tree.add_child(0, 2, 1, 0.0);
+    tree.add_child(a, 3, 1, 5.0);
+    tree.add_child(a, 4, 0, 0.0);
+
+    let mutations = Mutations::simulate(&tree, 1.0, 3);
+    assert!(!mutations.is_empty());
+    // no mutations on zero length branches
+    assert!(mutations.nodes.iter().all(|&n| n == 1 || n == 3));
+
+    let leaves: Vec<_> = tree.leaves().collect();
+    assert_eq!(leaves, [2, 3, 4]);
+    let genotypes = mutations.genotypes(&tree, &leaves);
+    for (m, &node) in mutations.nodes.iter().enumerate() {
+        // mutations on the branch to 1 are inherited by both of its leaves
+        let expected = match node {
+            1 => [0, 1, 1],
+            _ => [0, 1, 0],
+        };
+        assert_eq!(genotypes.column(m).to_vec(), expected);
+    }
+
+    let mut vcf = vec![];
+    mutations.write_vcf(&mut vcf, &tree).unwrap();
+    let vcf = String::from_utf8(vcf).unwrap();
+    assert!(vcf.contains("FORMAT\tleaf_2\tleaf_3\tleaf_4\n"));
+    assert_eq!(
+        vcf.lines().filter(|l| !l.starts_with('#')).count(),
+        mutations.len()
+    );
+
+    let mut fasta = vec![];
+    mutations.write_fasta(&mut fasta, &tree).unwrap();
+    let fasta = String::from_utf8(fasta).unwrap();
+    let first = fasta.lines().nth(1).unwrap();
+    assert_eq!(first.len(), mutations.len().min(60));
+    assert!(
+        first
+            .bytes()
+            .zip(&mutations.reference)
+            .all(|(a, &b)| a == b)
+    );
+
+    // site 1 holds leaves 2 and 3, a mutation on the branch to 3 is in half of its cells
+    let sites = ["0".to_string(), "1".to_string()];
+    let mut bulk = vec![];
+    mutations
+        .write_bulk_vcf_with_purity(&mut bulk, &tree, &sites, &[1.0, 0.4])
+        .unwrap();
+    let bulk = String::from_utf8(bulk).unwrap();
+    assert!(bulk.contains("##SAMPLE=<ID=1,Purity=0.4>\n#CHROM"));
+    let m = mutations.nodes.iter().position(|&n| n == 3).unwrap();
+    let record = bulk.lines().filter(|l| !l.starts_with('#')).nth(m).unwrap();
+    assert!(record.ends_with("AF:CCF\t0.0000:0.0000\t0.2000:0.5000"));
+    let genotype = vcf.lines().filter(|l| !l.starts_with('#')).nth(m).unwrap();
+    assert!(genotype.ends_with("GT\t0\t1\t0"));
+
+    // a culled lineage is no cell of the sample
+    tree.kill(2);
+    let mut bulk = vec![];
+    mutations
+        .write_bulk_vcf_with_purity(&mut bulk, &tree, &sites, &[1.0, 0.4])
+        .unwrap();
+    let bulk = String::from_utf8(bulk).unwrap();
+    let record = bulk.lines().filter(|l| !l.starts_with('#')).nth(m).unwrap();
+    assert!(record.ends_with("AF:CCF\t0.0000:0.0000\t0.4000:1.0000"));
+    let mut vcf = vec![];
+    mutations.write_vcf(&mut vcf, &tree).unwrap();
+    assert!(String::from_utf8(vcf).unwrap().contains("FORMAT\tleaf_3\tleaf_4\n"));
+    let mut fasta = vec![];
+    mutations.write_fasta(&mut fasta, &tree).unwrap();
+    let fasta = String::from_utf8(fasta).unwrap();
+    assert_eq!(fasta.matches('>').count(), 2);
+    assert!(!fasta.contains(">leaf_2"));
+}
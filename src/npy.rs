@@ -0,0 +1,303 @@
+//! Export of matrices and tables as NumPy `.npy` arrays, bundled as named datasets of
+//! one `.npz` file, so downstream scientific pipelines can `numpy.load` them without
+//! parsing csv. The format is version 1.0: a short header with dtype and shape followed
+//! by the little endian values in row major order. The `.npz` is an uncompressed zip of
+//! the arrays, as written by `numpy.savez`.
+//!
+//! Datasets written by [`write_datasets`] into `arrays.npz`:
+//!
+//! - `migration_matrix.npy` - `int64`, sites x sites
+//! - `frequencies.npy` - site frequencies at each generation, generations + 1 x sites
+//! - `leaf_counts.npy` - `uint64` extant lineages per site at each generation, in the
+//!   layout of `frequencies.npy`
+//! - `transitions.npy` - `int64` migration matrix of each generation, generations + 1 x
+//!   sites x sites
+//! - `edges.npy` - `uint64` parent and child of every edge, edges x 2
+//! - `lengths.npy` - branch length of every edge, in the order of `edges.npy`
+//! - `labels.npy` - `uint64` site label of every node
+//! - `leaves.npy` - `uint64` node of every extant leaf, in the order of `genotypes.npy`
+//! - `genotypes.npy` - `uint8` leaves x mutations, 1 where the leaf carries the
+//!   mutation, see [`Mutations::genotypes`]
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use ndarray::{Array1, Array2, ArrayBase, Data, Dimension};
+
+use crate::{
+    Float, mutations::Mutations, output::OutputWriter, simulations::SimulationResult,
+    tree::Phylogeny,
+};
+
+/// Bundle of the arrays in the output directory
+pub const ARRAYS: &str = "arrays.npz";
+
+/// Element types that can be stored in an `.npy` array
+pub trait NpyElement: Copy {
+    /// NumPy dtype string
+    const DESCR: &'static str;
+
+    fn write_le<W: Write>(self, w: &mut W) -> io::Result<()>;
+}
+
+macro_rules! npy_element {
+    ($($t:ty => $descr:literal),*) => {
+        $(impl NpyElement for $t {
+            const DESCR: &'static str = $descr;
+
+            fn write_le<W: Write>(self, w: &mut W) -> io::Result<()> {
+                w.write_all(&self.to_le_bytes())
+            }
+        })*
+    };
+}
+
+npy_element!(u8 => "|u1", i32 => "<i4", i64 => "<i8", u64 => "<u8", f32 => "<f4", f64 => "<f8");
+
+/// Write `a` as an `.npy` array
+pub fn write_npy<W, S, D>(mut w: W, a: &ArrayBase<S, D>) -> io::Result<()>
+where
+    W: Write,
+    S: Data,
+    S::Elem: NpyElement,
+    D: Dimension,
+{
+    let shape = match a.shape() {
+        [n] => format!("({n},)"),
+        shape => {
+            let dims: Vec<_> = shape.iter().map(|d| d.to_string()).collect();
+            format!("({})", dims.join(", "))
+        }
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {shape}, }}",
+        S::Elem::DESCR
+    );
+    // magic, version and header length take 10 bytes, the data starts 64 byte aligned
+    let padding = 63 - (10 + header.len()) % 64;
+    header.extend(std::iter::repeat_n(' ', padding));
+    header.push('\n');
+
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    // iterates in logical row major order whatever the memory layout
+    for &e in a.iter() {
+        e.write_le(&mut w)?;
+    }
+    Ok(())
+}
+
+/// CRC-32 of a zip entry, reflected polynomial 0xEDB88320
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                c = match c & 1 {
+                    1 => 0xEDB8_8320 ^ (c >> 1),
+                    _ => c >> 1,
+                };
+                bit += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0, |c, &b| {
+        TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+/// `n` as a 32 bit zip field
+fn zip_u32(n: usize) -> io::Result<u32> {
+    u32::try_from(n).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "npz bundle over 4 GiB, zip64 is not supported",
+        )
+    })
+}
+
+/// Fields shared by the local and central headers of a stored entry, from the version
+/// needed to extract it to the length of its extra field. Times are the 1980 epoch so
+/// a bundle only depends on its arrays
+fn entry_fields(name: &str, crc: u32, size: usize) -> io::Result<Vec<u8>> {
+    let size = zip_u32(size)?;
+    let mut fields = vec![];
+    // version 2.0, no flags, stored, 00:00 on 1980-01-01
+    for field in [20u16, 0, 0, 0, 0x21] {
+        fields.extend(field.to_le_bytes());
+    }
+    for field in [crc, size, size] {
+        fields.extend(field.to_le_bytes());
+    }
+    fields.extend((name.len() as u16).to_le_bytes());
+    fields.extend(0u16.to_le_bytes());
+    Ok(fields)
+}
+
+/// Writes `.npy` arrays as the named entries of an `.npz`, a zip archive of stored
+/// entries. Without zip64 the bundle must stay under 4 GiB
+pub struct NpzWriter<W: Write> {
+    w: W,
+    offset: usize,
+    /// Central directory header of every entry written
+    directory: Vec<u8>,
+    entries: u16,
+}
+
+impl<W: Write> NpzWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            offset: 0,
+            directory: vec![],
+            entries: 0,
+        }
+    }
+
+    /// Add `a` as the array `name`, the key `numpy.load` lists it under
+    pub fn add<S, D>(&mut self, name: &str, a: &ArrayBase<S, D>) -> io::Result<()>
+    where
+        S: Data,
+        S::Elem: NpyElement,
+        D: Dimension,
+    {
+        let name = format!("{name}.npy");
+        let mut data = vec![];
+        write_npy(&mut data, a)?;
+        let fields = entry_fields(&name, crc32(&data), data.len())?;
+
+        let mut local = 0x0403_4b50u32.to_le_bytes().to_vec();
+        local.extend(&fields);
+        local.extend(name.as_bytes());
+        self.w.write_all(&local)?;
+        self.w.write_all(&data)?;
+
+        self.directory.extend(0x0201_4b50u32.to_le_bytes());
+        // made by version 2.0
+        self.directory.extend(20u16.to_le_bytes());
+        self.directory.extend(&fields);
+        // no comment, disk 0 and no attributes
+        self.directory.extend([0; 10]);
+        self.directory.extend(zip_u32(self.offset)?.to_le_bytes());
+        self.directory.extend(name.as_bytes());
+        self.entries += 1;
+        self.offset += local.len() + data.len();
+        Ok(())
+    }
+
+    /// Write the central directory, the archive is complete afterwards
+    pub fn finish(mut self) -> io::Result<W> {
+        let mut end = 0x0605_4b50u32.to_le_bytes().to_vec();
+        // the only disk
+        end.extend([0; 4]);
+        end.extend(self.entries.to_le_bytes());
+        end.extend(self.entries.to_le_bytes());
+        end.extend(zip_u32(self.directory.len())?.to_le_bytes());
+        end.extend(zip_u32(self.offset)?.to_le_bytes());
+        // no comment
+        end.extend([0; 2]);
+        self.w.write_all(&self.directory)?;
+        self.w.write_all(&end)?;
+        Ok(self.w)
+    }
+}
+
+/// Write the datasets listed in the [module docs](self) into `arrays.npz` of the output
+/// directory, with the genotypes of `mutations` when there are any
+pub fn write_datasets(
+    writer: &mut OutputWriter,
+    result: &SimulationResult<Phylogeny<usize, usize>>,
+    mutations: &Mutations,
+) -> io::Result<PathBuf> {
+    let tree = result.tree();
+
+    let edges: Vec<_> = tree.edges().collect();
+    let pairs = Array2::from_shape_fn((edges.len(), 2), |(i, j)| match j {
+        0 => edges[i].0 as u64,
+        _ => edges[i].1 as u64,
+    });
+    let lengths: Array1<Float> = edges.iter().map(|&(_, _, l)| l).collect();
+    let labels: Array1<u64> = tree.nodes.iter().map(|n| n.label as u64).collect();
+    let leaves: Vec<_> = tree.extant_leaves().collect();
+    let genotypes = mutations.genotypes(tree, &leaves);
+    let leaves: Array1<u64> = leaves.iter().map(|&l| l as u64).collect();
+    let leaf_counts = result.leaf_counts_by_generation().mapv(|c| c as u64);
+
+    writer.write(ARRAYS, |w| {
+        let mut npz = NpzWriter::new(w);
+        npz.add("migration_matrix", result.migration_matrix())?;
+        npz.add("frequencies", result.frequencies_by_generation())?;
+        npz.add("leaf_counts", &leaf_counts)?;
+        npz.add("transitions", result.transitions_by_generation())?;
+        npz.add("edges", &pairs)?;
+        npz.add("lengths", &lengths)?;
+        npz.add("labels", &labels)?;
+        npz.add("leaves", &leaves)?;
+        npz.add("genotypes", &genotypes)?;
+        npz.finish().map(|_| ())
+    })
+}
+
+#[test]
+fn test_write_npy() {
+    let m = Array2::from_shape_vec((2, 3), vec![1i32, 2, 3, 4, 5, 6]).unwrap();
+    let mut buf = vec![];
+    // transposed, so the values are not in memory order
+    write_npy(&mut buf, &m.t()).unwrap();
+
+    assert_eq!(&buf[..8], b"\x93NUMPY\x01\x00");
+    let header_len = u16::from_le_bytes([buf[8], buf[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0);
+    let header = std::str::from_utf8(&buf[10..10 + header_len]).unwrap();
+    assert!(header.starts_with("{'descr': '<i4', 'fortran_order': False, 'shape': (3, 2), }"));
+    assert!(header.ends_with(" \n"));
+
+    let data: Vec<i32> = buf[10 + header_len..]
+        .chunks(4)
+        .map(|c| i32::from_le_bytes(c.try_into().unwrap()))
+        .collect();
+    assert_eq!(data, [1, 4, 2, 5, 3, 6]);
+}
+
+#[test]
+fn test_npz() {
+    // the check value of CRC-32
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let m = Array2::from_shape_vec((2, 2), vec![1u8, 0, 0, 1]).unwrap();
+    let v = Array1::from_vec(vec![0.5f64]);
+    let mut npz = NpzWriter::new(vec![]);
+    npz.add("m", &m).unwrap();
+    npz.add("v", &v).unwrap();
+    let zip = npz.finish().unwrap();
+
+    // every entry is its .npy stored as is, behind its local header
+    let mut npy = vec![];
+    write_npy(&mut npy, &m).unwrap();
+    assert_eq!(&zip[..4], b"PK\x03\x04");
+    assert_eq!(&zip[30..35], b"m.npy");
+    assert_eq!(&zip[35..35 + npy.len()], npy);
+    let u32_at = |i: usize| u32::from_le_bytes(zip[i..i + 4].try_into().unwrap());
+    assert_eq!(u32_at(14), crc32(&npy));
+    assert_eq!(u32_at(18) as usize, npy.len());
+
+    // the end record finds both entries in the central directory
+    let end = zip.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    assert_eq!(u16::from_le_bytes([zip[end + 10], zip[end + 11]]), 2);
+    let (size, start) = (u32_at(end + 12) as usize, u32_at(end + 16) as usize);
+    assert_eq!(start + size, end);
+    assert_eq!(u32_at(start), 0x0201_4b50);
+    assert_eq!(u32_at(start + 42), 0);
+    let second = start + 46 + "m.npy".len();
+    assert_eq!(&zip[second + 46..second + 51], b"v.npy");
+    assert_eq!(u32_at(second + 42) as usize, 35 + npy.len());
+}
@@ -0,0 +1,769 @@
+//! File naming for the outputs of a run. Every run writes into its own directory with
+//! fixed file names:
+//!
+//! - `edgelist.csv` - parent, child and branch length of every edge
+//! - `vertex_labeling.csv` - site label of every vertex
+//! - `leaf_labeling.csv` - site label of every leaf
+//! - `state_labeling.csv` - cell state of every vertex, with a cell state layer
+//! - `nodes.csv` - parent, label, generation, time and leaf count of every node
+//! - `tree.json` - versioned json document with parameters and the tree
+//! - `tree.nwk` - the tree in Newick format
+//! - `tree.nex` - the tree in a NEXUS trees block
+//! - `tree.nhx` - the tree in New Hampshire eXtended format with sites and metadata
+//! - `tree.xml` - the tree in PhyloXML with clades colored by site
+//! - `itol_strip.txt` / `itol_ranges.txt` - iTOL datasets coloring the leaves of
+//!   `tree.nwk` by site
+//! - `seedings.csv` - every lineage founding a site, with its source and clone size
+//! - `comigrations.csv` - migrations between the same pair of sites grouped in time
+//! - `diversity.csv` - within and between site diversity and Fst
+//! - `ancestral.csv` - marginal probability of each site at every internal node
+//! - `summary.csv` - long format summary statistics of every replicate
+//! - `migration_matrix.csv` - number of migrations between each pair of sites
+//! - `transitions.csv` - number of migrations between each pair of sites in every
+//!   generation
+//! - `leaf_counts.csv` / `leaf_counts.svg` - extant lineages per site after every
+//!   generation, and as a stacked-area chart
+//! - `mutations.vcf` / `bulk.vcf` - genotypes of every leaf, and allele frequencies and
+//!   cancer cell fractions of every site, see [`crate::mutations`]
+//! - `sequences.fasta` - variable positions of every leaf
+//! - `run.sql` - SQL dump of the run, see [`crate::sql`]
+//! - `effective_matrices.csv` - migration probabilities used in each generation
+//! - `migration_graph.dot` / `migration_graph.{png,svg,pdf}` - migration graph between sites
+//! - `migration_graph.json` - migration graph as node-link json for d3 and Cytoscape.js
+//! - `figure.svg` - the site-colored tree beside its migration graph with a shared legend
+//! - `arrays.npz` - matrices, tables and genotypes as NumPy arrays, see [`crate::npy`]
+//! - `frames/frame_NNN.svg` - one frame per generation for animations
+//! - `report.html` - self-contained report of the run (`report` feature)
+//! - `manifest.json` - run metadata and checksums of all of the above
+//!
+//! Array jobs splitting the replicates with `--task-count` each write into their own
+//! `task_NNN` directory below the output directory, see [`task_range`].
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use ndarray::{Array2, Array3};
+use serde::Serialize;
+
+use crate::{
+    Float,
+    manifest::Manifest,
+    simulations::{GenerationReport, Observer},
+};
+
+pub const EDGELIST: &str = "edgelist.csv";
+pub const VERTEX_LABELING: &str = "vertex_labeling.csv";
+pub const LEAF_LABELING: &str = "leaf_labeling.csv";
+pub const STATE_LABELING: &str = "state_labeling.csv";
+pub const NODE_TABLE: &str = "nodes.csv";
+pub const TREE_JSON: &str = "tree.json";
+pub const NEWICK: &str = "tree.nwk";
+pub const NEXUS: &str = "tree.nex";
+pub const NHX: &str = "tree.nhx";
+pub const PHYLOXML: &str = "tree.xml";
+pub const ITOL_STRIP: &str = "itol_strip.txt";
+pub const ITOL_RANGES: &str = "itol_ranges.txt";
+pub const SEEDINGS: &str = "seedings.csv";
+pub const COMIGRATIONS: &str = "comigrations.csv";
+pub const DIVERSITY: &str = "diversity.csv";
+pub const ANCESTRAL: &str = "ancestral.csv";
+pub const SUMMARY: &str = "summary.csv";
+pub const MIGRATION_MATRIX: &str = "migration_matrix.csv";
+pub const TRANSITIONS: &str = "transitions.csv";
+pub const LEAF_COUNTS: &str = "leaf_counts.csv";
+pub const LEAF_COUNTS_SVG: &str = "leaf_counts.svg";
+pub const COMBINED_SVG: &str = "figure.svg";
+pub const VCF: &str = "mutations.vcf";
+pub const BULK_VCF: &str = "bulk.vcf";
+pub const FASTA: &str = "sequences.fasta";
+pub const SQL_DUMP: &str = "run.sql";
+pub const EFFECTIVE_MATRICES: &str = "effective_matrices.csv";
+/// Stem of the migration graph files, the extension is set by the renderer
+pub const MIGRATION_GRAPH: &str = "migration_graph";
+pub const GRAPH_JSON: &str = "migration_graph.json";
+/// Directory holding the animation frames
+pub const FRAMES: &str = "frames";
+pub const REPORT: &str = "report.html";
+pub const MANIFEST: &str = "manifest.json";
+
+/// Items out of `total` that job `index` of an array job of `count` jobs handles:
+/// contiguous blocks differing in size by at most one, together covering every item once
+pub fn task_range(total: usize, index: usize, count: usize) -> Range<usize> {
+    total * index / count..total * (index + 1) / count
+}
+
+/// Directory of the outputs of job `index` of an array job, below the output directory
+pub fn task_dir(index: usize) -> String {
+    format!("task_{index:03}")
+}
+
+/// Writes the outputs of a run into a single directory and keeps track of what was written
+#[derive(Debug)]
+pub struct OutputWriter {
+    dir: PathBuf,
+    written: Vec<String>,
+}
+
+impl OutputWriter {
+    /// Create the output directory if it does not exist yet
+    pub fn new<T: AsRef<Path>>(dir: T) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            written: vec![],
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Full path of an output file
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Create a sub directory of the output directory
+    pub fn create_dir(&self, name: &str) -> io::Result<PathBuf> {
+        let path = self.path(name);
+        fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    /// Create `name` in the output directory for writing over a longer period, the
+    /// caller is responsible for flushing
+    pub fn create(&mut self, name: &str) -> io::Result<BufWriter<File>> {
+        let w = BufWriter::new(File::create(self.path(name))?);
+        self.written.push(name.to_string());
+        Ok(w)
+    }
+
+    /// Create `name` in the output directory and hand a buffered writer to `f`
+    pub fn write<F>(&mut self, name: &str, f: F) -> io::Result<PathBuf>
+    where
+        F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+    {
+        let path = self.path(name);
+        let mut w = BufWriter::new(File::create(&path)?);
+        f(&mut w)?;
+        w.flush()?;
+        self.written.push(name.to_string());
+        Ok(path)
+    }
+
+    /// Register a file that was written into the output directory by someone else
+    pub fn record(&mut self, name: &str) {
+        self.written.push(name.to_string())
+    }
+
+    /// Names of all files written so far
+    pub fn written(&self) -> &[String] {
+        &self.written
+    }
+
+    /// Checksum every written file into the manifest and write the manifest itself
+    pub fn write_manifest<P: Serialize>(
+        &mut self,
+        manifest: &mut Manifest<P>,
+    ) -> io::Result<PathBuf> {
+        for name in &self.written {
+            manifest.record(&self.dir, name)?;
+        }
+        self.write(MANIFEST, |w| manifest.write(w))
+    }
+}
+
+/// When fields of a csv are quoted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Quote {
+    /// Only fields containing the delimiter, a quote or a line break
+    #[default]
+    Necessary,
+    Always,
+    /// Fields are written as is, the output may not parse back
+    Never,
+}
+
+/// Delimiter, quoting and header row of csv outputs. The default is RFC 4180: comma
+/// separated, quoted when necessary, with a header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    pub quote: Quote,
+    pub headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            quote: Quote::Necessary,
+            headers: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Tab separated with a header
+    pub fn tsv() -> Self {
+        Self {
+            delimiter: b'\t',
+            ..Default::default()
+        }
+    }
+}
+
+/// Writes records in a [`CsvDialect`], quoting and escaping fields as needed
+pub struct CsvWriter<W: Write> {
+    w: W,
+    dialect: CsvDialect,
+    /// Reused buffer each field is formatted into
+    field: String,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(w: W, dialect: CsvDialect) -> Self {
+        Self {
+            w,
+            dialect,
+            field: String::new(),
+        }
+    }
+
+    /// Write the header row, unless the dialect has no headers
+    pub fn header<I>(&mut self, fields: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Display,
+    {
+        match self.dialect.headers {
+            true => self.record(fields),
+            false => Ok(()),
+        }
+    }
+
+    /// Write one row, use `&dyn Display` items for fields of different types
+    pub fn record<I>(&mut self, fields: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Display,
+    {
+        let delimiter = self.dialect.delimiter as char;
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                write!(self.w, "{delimiter}")?;
+            }
+            self.field.clear();
+            fmt::Write::write_fmt(&mut self.field, format_args!("{field}"))
+                .expect("formatting into a string cannot fail");
+            let quote = match self.dialect.quote {
+                Quote::Always => true,
+                Quote::Never => false,
+                Quote::Necessary => self.field.contains([delimiter, '"', '\n', '\r']),
+            };
+            match quote {
+                true => write!(self.w, "\"{}\"", self.field.replace('"', "\"\""))?,
+                false => self.w.write_all(self.field.as_bytes())?,
+            }
+        }
+        writeln!(self.w)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+}
+
+/// Split one line written by a [`CsvWriter`] with `delimiter` back into its fields, undoing
+/// the quoting. Quoted fields spanning several lines are not supported
+pub fn split_csv_record(line: &str, delimiter: u8) -> Vec<String> {
+    let delimiter = delimiter as char;
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (c, false) if c == delimiter => fields.push(std::mem::take(&mut field)),
+            (c, _) => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Write the cell state of every vertex, like the vertex labeling of the tree
+pub fn write_state_labeling_csv<W: Write>(
+    w: W,
+    states: &[usize],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["vertex", "state"])?;
+    for (vertex, state) in states.iter().enumerate() {
+        csv.record([vertex, *state])?;
+    }
+    Ok(())
+}
+
+/// Write a generations x sites count matrix with a leading generation column
+pub fn write_leaf_counts_csv<W: Write>(
+    w: W,
+    counts: &Array2<usize>,
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(std::iter::once("generation").chain(sites.iter().map(|s| s.as_str())))?;
+    for (generation, row) in counts.rows().into_iter().enumerate() {
+        csv.record(std::iter::once(generation).chain(row.iter().copied()))?;
+    }
+    Ok(())
+}
+
+/// Write a generations x sites x sites count array in long format, a generation, source
+/// site, target site and count per row. Pairs without children in a generation are left
+/// out
+pub fn write_transitions_csv<W: Write>(
+    w: W,
+    counts: &Array3<i64>,
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(["generation", "from", "to", "count"])?;
+    for ((generation, from, to), &count) in counts.indexed_iter() {
+        if count != 0 {
+            csv.record([
+                &generation as &dyn Display,
+                &sites[from],
+                &sites[to],
+                &count,
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a site by site matrix as csv with the site names as header and first column
+pub fn write_matrix_csv<W: Write, T: Display>(
+    w: W,
+    m: &Array2<T>,
+    sites: &[String],
+) -> io::Result<()> {
+    write_matrix_csv_with(w, m, sites, CsvDialect::default())
+}
+
+/// [`write_matrix_csv`] in another dialect
+pub fn write_matrix_csv_with<W: Write, T: Display>(
+    w: W,
+    m: &Array2<T>,
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(std::iter::once("site").chain(sites.iter().map(|s| s.as_str())))?;
+    for (site, row) in sites.iter().zip(m.rows()) {
+        csv.record(
+            std::iter::once(site as &dyn Display).chain(row.iter().map(|e| e as &dyn Display)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Write one site by site matrix per generation as a single csv, each block in the layout
+/// of [`write_matrix_csv`] with a leading generation column
+pub fn write_matrices_csv<W: Write, T: Display>(
+    w: W,
+    matrices: &[Array2<T>],
+    sites: &[String],
+) -> io::Result<()> {
+    write_matrices_csv_with(w, matrices, sites, CsvDialect::default())
+}
+
+/// [`write_matrices_csv`] in another dialect
+pub fn write_matrices_csv_with<W: Write, T: Display>(
+    w: W,
+    matrices: &[Array2<T>],
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(
+        ["generation", "site"]
+            .into_iter()
+            .chain(sites.iter().map(|s| s.as_str())),
+    )?;
+    for (generation, m) in (1..).zip(matrices) {
+        for (site, row) in sites.iter().zip(m.rows()) {
+            let fields = [&generation as &dyn Display, site]
+                .into_iter()
+                .chain(row.iter().map(|e| e as &dyn Display));
+            csv.record(fields)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the edge list and vertex labeling as nodes are created instead of after the
+/// simulation. Rows are in creation order, which for simulated trees is the same order
+/// as [`Phylogeny::write_csv`](crate::tree::Phylogeny::write_csv) and
+/// [`Phylogeny::write_csv_vertex_labeling`](crate::tree::Phylogeny::write_csv_vertex_labeling)
+pub struct StreamingCsv<E: Write, V: Write> {
+    edges: Option<CsvWriter<E>>,
+    vertices: Option<CsvWriter<V>>,
+    /// First error hit while streaming, observers cannot fail so it is reported at the end
+    error: Option<io::Error>,
+}
+
+impl<E: Write, V: Write> StreamingCsv<E, V> {
+    /// Stream to either or both writers, headers are written immediately
+    pub fn new(edges: Option<E>, vertices: Option<V>) -> io::Result<Self> {
+        Self::with_dialect(edges, vertices, CsvDialect::default())
+    }
+
+    pub fn with_dialect(
+        edges: Option<E>,
+        vertices: Option<V>,
+        dialect: CsvDialect,
+    ) -> io::Result<Self> {
+        let mut s = Self {
+            edges: edges.map(|w| CsvWriter::new(w, dialect)),
+            vertices: vertices.map(|w| CsvWriter::new(w, dialect)),
+            error: None,
+        };
+        if let Some(w) = s.edges.as_mut() {
+            w.header(["parent", "child", "length"])?;
+        }
+        if let Some(w) = s.vertices.as_mut() {
+            w.header(["vertex", "label"])?;
+        }
+        Ok(s)
+    }
+
+    fn keep_error(&mut self, r: io::Result<()>) {
+        if let (Err(e), None) = (r, &self.error) {
+            self.error = Some(e)
+        }
+    }
+
+    /// Flush both writers and report the first error hit while streaming
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        if let Some(w) = self.edges.as_mut() {
+            w.w.flush()?;
+        }
+        if let Some(w) = self.vertices.as_mut() {
+            w.w.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: Write, V: Write> Observer for StreamingCsv<E, V> {
+    fn wants_pmatrix(&self) -> bool {
+        false
+    }
+
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        if let (Some(w), Some(parent)) = (self.edges.as_mut(), parent) {
+            let r = w.record([&parent as &dyn Display, &id, &length]);
+            self.keep_error(r);
+        }
+        if let Some(w) = self.vertices.as_mut() {
+            let r = w.record([id, label]);
+            self.keep_error(r);
+        }
+    }
+}
+
+/// One event of a running simulation, as written by [`JsonLinesEvents`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum SimulationEvent {
+    /// A node in the site of its parent, or the root
+    Birth {
+        generation: usize,
+        node: usize,
+        parent: Option<usize>,
+        site: usize,
+        length: Float,
+    },
+    /// A node in another site than its parent
+    Migration {
+        generation: usize,
+        node: usize,
+        parent: usize,
+        from: usize,
+        to: usize,
+        length: Float,
+    },
+    Death {
+        generation: usize,
+        node: usize,
+    },
+    /// End of a generation
+    Generation {
+        generation: usize,
+        leaves: usize,
+        nodes: usize,
+    },
+}
+
+/// Writes every event of a simulation as one JSON object per line while it runs, for
+/// piping into `jq` or live dashboards. Every line has an `event` field: `birth`,
+/// `migration`, `death` or `generation`. The generation of a node is its depth, as in
+/// `tree.json`
+pub struct JsonLinesEvents<W: Write> {
+    w: W,
+    /// Site and generation of every node so far, to tell migrations from births
+    nodes: Vec<(usize, usize)>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesEvents<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w,
+            nodes: vec![],
+            error: None,
+        }
+    }
+
+    fn emit(&mut self, event: SimulationEvent) {
+        if self.error.is_some() {
+            return;
+        }
+        let r = serde_json::to_writer(&mut self.w, &event)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(self.w));
+        if let Err(e) = r {
+            self.error = Some(e)
+        }
+    }
+
+    /// Flush the writer and report the first error hit while streaming
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.w.flush(),
+        }
+    }
+}
+
+impl<W: Write> Observer for JsonLinesEvents<W> {
+    fn wants_pmatrix(&self) -> bool {
+        false
+    }
+
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        let generation = parent.map_or(0, |p| self.nodes[p].1 + 1);
+        if self.nodes.len() <= id {
+            self.nodes.resize(id + 1, (0, 0));
+        }
+        self.nodes[id] = (label, generation);
+        let event = match parent.map(|p| (p, self.nodes[p].0)) {
+            Some((parent, from)) if from != label => SimulationEvent::Migration {
+                generation,
+                node: id,
+                parent,
+                from,
+                to: label,
+                length,
+            },
+            _ => SimulationEvent::Birth {
+                generation,
+                node: id,
+                parent,
+                site: label,
+                length,
+            },
+        };
+        self.emit(event)
+    }
+
+    fn on_death(&mut self, id: usize) {
+        self.emit(SimulationEvent::Death {
+            generation: self.nodes[id].1,
+            node: id,
+        })
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        self.emit(SimulationEvent::Generation {
+            generation: report.generation,
+            leaves: report.leaves,
+            nodes: report.nodes,
+        })
+    }
+}
+
+#[test]
+fn test_output_writer() {
+    let dir = std::env::temp_dir().join("cancer_sims_test_output_writer");
+    let mut writer = OutputWriter::new(&dir).unwrap();
+    let path = writer
+        .write(EDGELIST, |w| writeln!(w, "parent,child,length"))
+        .unwrap();
+
+    assert_eq!(path, dir.join(EDGELIST));
+    assert_eq!(writer.written(), [EDGELIST]);
+
+    let mut manifest = Manifest::new((), 0);
+    writer.write_manifest(&mut manifest).unwrap();
+    assert_eq!(manifest.outputs[0].path, EDGELIST);
+    assert!(dir.join(MANIFEST).exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_write_matrix_csv() {
+    let m = Array2::from_shape_vec((2, 2), vec![3, 1, 0, 2]).unwrap();
+    let mut buf = vec![];
+    write_matrix_csv(&mut buf, &m, &["a".to_string(), "b".to_string()]).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), "site,a,b\na,3,1\nb,0,2\n");
+
+    let mut buf = vec![];
+    write_matrices_csv(
+        &mut buf,
+        &[m.clone(), m],
+        &["a".to_string(), "b".to_string()],
+    )
+    .unwrap();
+    let csv = String::from_utf8(buf).unwrap();
+    assert!(csv.starts_with("generation,site,a,b\n1,a,3,1\n"));
+    assert!(csv.ends_with("2,b,0,2\n"));
+}
+
+#[test]
+fn test_streaming_csv() {
+    use crate::{
+        simulations::{Parameters, Simulations},
+        tree::Phylogeny,
+    };
+
+    let params = Parameters {
+        birth_rate: 0.2,
+        generations: 4,
+        sites: 3,
+        migration_probability: 0.1,
+        seed: 3,
+        ..Default::default()
+    };
+    let (mut edges, mut vertices) = (vec![], vec![]);
+    let mut stream = StreamingCsv::new(Some(&mut edges), Some(&mut vertices)).unwrap();
+    let tree = Phylogeny::simulate(&params, &mut stream).into_tree();
+    stream.finish().unwrap();
+
+    let (mut expected_edges, mut expected_vertices) = (vec![], vec![]);
+    tree.write_csv(&mut expected_edges).unwrap();
+    tree.write_csv_vertex_labeling(&mut expected_vertices)
+        .unwrap();
+    assert_eq!(edges, expected_edges);
+    assert_eq!(vertices, expected_vertices);
+}
+
+#[test]
+fn test_csv_dialect() {
+    let sites = ["lung, left".to_string(), "say \"liver\"".to_string()];
+    let m = Array2::from_shape_vec((2, 2), vec![3, 1, 0, 2]).unwrap();
+
+    let mut buf = vec![];
+    write_matrix_csv(&mut buf, &m, &sites).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "site,\"lung, left\",\"say \"\"liver\"\"\"\n\"lung, left\",3,1\n\"say \"\"liver\"\"\",0,2\n"
+    );
+
+    let dialect = CsvDialect {
+        delimiter: b';',
+        quote: Quote::Always,
+        headers: false,
+    };
+    let mut buf = vec![];
+    write_matrix_csv_with(&mut buf, &m, &["a".to_string(), "b".to_string()], dialect).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "\"a\";\"3\";\"1\"\n\"b\";\"0\";\"2\"\n"
+    );
+
+    // quoting round trips
+    let mut buf = vec![];
+    write_matrix_csv(&mut buf, &m, &sites).unwrap();
+    let first = String::from_utf8(buf)
+        .unwrap()
+        .lines()
+        .nth(1)
+        .unwrap()
+        .to_string();
+    assert_eq!(split_csv_record(&first, b','), [&sites[0], "3", "1"]);
+    assert_eq!(split_csv_record("\"a\";\"\";b", b';'), ["a", "", "b"]);
+}
+
+#[test]
+fn test_task_range() {
+    for (total, count) in [(10, 3), (2, 4), (7, 7), (0, 2)] {
+        let ranges: Vec<_> = (0..count).map(|i| task_range(total, i, count)).collect();
+        assert_eq!(ranges[0].start, 0);
+        assert_eq!(ranges[count - 1].end, total);
+        assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+        assert!(
+            ranges
+                .iter()
+                .all(|r| r.len() == total / count || r.len() == total / count + 1)
+        );
+    }
+    assert_eq!(task_dir(7), "task_007");
+}
+
+#[test]
+fn test_json_lines_events() {
+    use crate::simulations::{Parameters, Simulations};
+    use crate::tree::Phylogeny;
+
+    let params = Parameters {
+        generations: 5,
+        sites: 3,
+        migration_probability: 0.2,
+        max_population: Some(10),
+        ..Default::default()
+    };
+    let mut events = JsonLinesEvents::new(vec![]);
+    let result = Phylogeny::simulate(&params, &mut events);
+    assert!(events.error.is_none());
+    let out = String::from_utf8(events.w).unwrap();
+    let events: Vec<serde_json::Value> = out
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    let count = |kind: &str| events.iter().filter(|e| e["event"] == kind).count();
+
+    let tree = result.tree();
+    assert_eq!(count("birth") + count("migration"), tree.nodes.len());
+    assert_eq!(
+        count("death"),
+        (0..tree.nodes.len()).filter(|&i| !tree.is_alive(i)).count()
+    );
+    assert_eq!(count("generation"), 5);
+    assert_eq!(events[0]["parent"], serde_json::Value::Null);
+    let last = events.iter().rfind(|e| e["event"] != "generation").unwrap();
+    assert_eq!(last["generation"], 5);
+    let migrations = result.migration_matrix().indexed_iter();
+    let migrations: i64 = migrations
+        .filter(|((a, b), _)| a != b)
+        .map(|(_, c)| c)
+        .sum();
+    assert_eq!(count("migration"), migrations as usize);
+}
@@ -1,24 +1,448 @@
 use std::{
-    fmt::Display,
+    fmt::{self, Display},
+    io::{self, Write},
     ops::{Div, Mul},
+    str::FromStr,
+    sync::OnceLock,
 };
 
 use ndarray::{Array1, Array2, Axis};
-use rand::RngCore;
+use rand::{Rng, RngCore};
 use rand_distr::{Distribution, WeightedIndex};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    Float,
+    output::{CsvDialect, CsvWriter, write_matrix_csv},
+};
 
 #[derive(Debug)]
 pub struct PMatrix {
-    p: Array2<f64>,
+    p: Array2<Float>,
+    /// Per row sampling distributions, built on first use so sampling does not rebuild
+    /// a `WeightedIndex` for every draw
+    samplers: OnceLock<Vec<Result<WeightedIndex<Float>, PMatrixError>>>,
+    /// Structural role of every site, kept through rescaling
+    roles: Vec<SiteRole>,
+}
+
+/// Structural constraints of a site in the migration graph
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SiteRole {
+    /// Sends and receives migrants
+    #[default]
+    Free,
+    /// Receives migrants but never seeds other sites
+    Absorbing,
+    /// Seeds other sites but never receives migrants
+    SourceOnly,
+}
+
+/// How the frequency of a site maps to its attractiveness as a migration destination
+/// when rescaling. Lower frequency sites get a larger weight so underpopulated sites
+/// attract migrants
+#[derive(Debug, Clone, Copy)]
+pub enum FrequencyBias {
+    /// `exp(-beta * f)`, `beta = 1` is the default. `beta` is finite and not negative
+    Exponential { beta: Float },
+    /// `1 / (f + eps)` - very strong pull towards empty sites
+    Inverse,
+    /// All sites weighted equally
+    None,
+    /// Any weight function of the frequency
+    Custom(fn(Float) -> Float),
+}
+
+impl Default for FrequencyBias {
+    fn default() -> Self {
+        FrequencyBias::Exponential { beta: 1.0 }
+    }
+}
+
+impl FrequencyBias {
+    /// Weight of a site with frequency `f` on its own
+    pub fn weight(&self, f: Float) -> Float {
+        match self {
+            FrequencyBias::Exponential { beta } => (-beta * f + Float::EPSILON).exp(),
+            FrequencyBias::Inverse => 1.0.div(f + Float::EPSILON),
+            FrequencyBias::None => 1.0,
+            FrequencyBias::Custom(weight) => weight(f),
+        }
+    }
+
+    /// Weights of sites with `frequencies`. Exponential weights are taken relative to the
+    /// least frequent site, whose weight is 1, and kept above machine epsilon, so a large
+    /// `beta` can not underflow the weight of a populated site to 0 and empty its column
+    pub fn weights(&self, frequencies: &[Float]) -> Vec<Float> {
+        match self {
+            FrequencyBias::Exponential { beta } => {
+                let least = frequencies
+                    .iter()
+                    .copied()
+                    .fold(Float::INFINITY, Float::min);
+                frequencies
+                    .iter()
+                    .map(|&f| (-beta * (f - least)).exp().max(Float::EPSILON))
+                    .collect()
+            }
+            _ => frequencies.iter().map(|&f| self.weight(f)).collect(),
+        }
+    }
+}
+
+impl Display for FrequencyBias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrequencyBias::Exponential { beta } => write!(f, "exponential:{beta}"),
+            FrequencyBias::Inverse => write!(f, "inverse"),
+            FrequencyBias::None => write!(f, "none"),
+            FrequencyBias::Custom(_) => write!(f, "custom"),
+        }
+    }
+}
+
+/// Parses `exponential`, `exponential:<beta>`, `inverse` or `none`
+impl FromStr for FrequencyBias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("exponential", beta)) => match beta.parse::<Float>() {
+                Ok(b) if b.is_finite() && b >= 0.0 => Ok(FrequencyBias::Exponential { beta: b }),
+                Ok(_) => Err(format!("beta `{beta}` must be finite and not negative")),
+                Err(e) => Err(format!("invalid beta `{beta}`: {e}")),
+            },
+            None if s == "exponential" => Ok(FrequencyBias::default()),
+            None if s == "inverse" => Ok(FrequencyBias::Inverse),
+            None if s == "none" => Ok(FrequencyBias::None),
+            _ => Err(format!(
+                "unknown frequency bias `{s}`, expected exponential[:beta], inverse or none"
+            )),
+        }
+    }
+}
+
+impl Serialize for FrequencyBias {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Reads what [`Serialize`] writes, except a custom weight function
+impl<'de> Deserialize<'de> for FrequencyBias {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Errors from building or balancing a [`PMatrix`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum PMatrixError {
+    /// Row without any mass - the matrix cannot be balanced or sampled from
+    ZeroRow(usize),
+    /// Column without any mass - the matrix cannot be balanced
+    ZeroColumn(usize),
+    /// Negative transition probability
+    Negative {
+        row: usize,
+        col: usize,
+        value: Float,
+    },
+    /// NaN or infinite transition probability
+    NotFinite {
+        row: usize,
+        col: usize,
+        value: Float,
+    },
+    /// Row that is not a probability distribution
+    RowSum { row: usize, sum: Float },
+    /// Site index outside of the matrix
+    OutOfRange { row: usize, sites: usize },
+}
+
+impl Display for PMatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PMatrixError::ZeroRow(i) => write!(f, "row {i} is all zero, cannot balance"),
+            PMatrixError::ZeroColumn(j) => write!(f, "column {j} is all zero, cannot balance"),
+            PMatrixError::Negative { row, col, value } => {
+                write!(f, "entry ({row}, {col}) is negative: {value}")
+            }
+            PMatrixError::NotFinite { row, col, value } => {
+                write!(f, "entry ({row}, {col}) is not finite: {value}")
+            }
+            PMatrixError::RowSum { row, sum } => write!(f, "row {row} sums to {sum}, not 1"),
+            PMatrixError::OutOfRange { row, sites } => {
+                write!(f, "row {row} out of range for {sites} sites")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PMatrixError {}
+
+/// Stopping rule for Sinkhorn-Knopp balancing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sinkhorn {
+    /// Stop once every row and column sum is within this of 1
+    pub tolerance: Float,
+    pub max_iters: usize,
+}
+
+impl Default for Sinkhorn {
+    fn default() -> Self {
+        Self {
+            tolerance: 1e-6,
+            max_iters: 1000,
+        }
+    }
+}
+
+/// How close a balanced matrix is to doubly stochastic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BalanceDiagnostics {
+    pub iterations: usize,
+    /// Largest distance of a row sum from 1
+    pub max_row_deviation: Float,
+    /// Largest distance of a column sum from 1, or from the common sum of the free
+    /// columns when some sites are absorbing or source-only
+    pub max_col_deviation: Float,
+    pub converged: bool,
+}
+
+/// Walker alias table for drawing from one row of a [`PMatrix`] in constant time
+#[derive(Debug, Clone)]
+pub struct AliasSampler {
+    prob: Vec<Float>,
+    alias: Vec<usize>,
+}
+
+impl AliasSampler {
+    /// Build the table with Vose's method, weights do not need to be normalized
+    pub fn new(weights: &[Float]) -> Self {
+        let n = weights.len();
+        let total: Float = weights.iter().sum();
+        let mut scaled: Vec<Float> = weights.iter().map(|w| w * n as Float / total).collect();
+
+        let mut prob = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..n).partition(|&i| scaled[i] < 1.0);
+
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] -= 1.0 - scaled[s];
+            if scaled[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+
+        Self { prob, alias }
+    }
+
+    pub fn sample<R: RngCore>(&self, rng: &mut R) -> usize {
+        // drawn as u64 so 32 and 64 bit platforms see the same stream
+        let i = rng.gen_range(0..self.prob.len() as u64) as usize;
+        match rng.r#gen::<Float>() < self.prob[i] {
+            true => i,
+            false => self.alias[i],
+        }
+    }
 }
 
+/// Largest distance of a row sum from 1 accepted by [`PMatrix::validate`]
+const ROW_SUM_TOLERANCE: Float = 1e-4;
+const SPECTRAL_TOLERANCE: Float = 1e-12;
+const SPECTRAL_MAX_ITERS: usize = 10_000;
+/// Taylor terms of the matrix exponential, after scaling the norm is at most 1/2
+const EXPM_TERMS: usize = 16;
+
 impl Display for PMatrix {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.p)
     }
 }
 
+/// Table of a [`PMatrix`] with site names on both axes, see [`PMatrix::named`]
+pub struct NamedPMatrix<'a> {
+    pmatrix: &'a PMatrix,
+    sites: &'a [String],
+}
+
+impl Display for NamedPMatrix<'_> {
+    /// Rows are origins and columns destinations, `{:.2}` sets the number of decimals
+    /// (4 by default)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(4);
+        let cells: Vec<Vec<String>> = self
+            .pmatrix
+            .p
+            .rows()
+            .into_iter()
+            .map(|row| row.iter().map(|p| format!("{p:.precision$}")).collect())
+            .collect();
+        let name_width = self.sites.iter().map(|s| s.len()).max().unwrap_or(0);
+        let width = cells
+            .iter()
+            .flatten()
+            .map(|c| c.len())
+            .chain(self.sites.iter().map(|s| s.len()))
+            .max()
+            .unwrap_or(0);
+
+        write!(f, "{:name_width$}", "")?;
+        for site in self.sites {
+            write!(f, " {site:>width$}")?;
+        }
+        for (site, row) in self.sites.iter().zip(&cells) {
+            write!(f, "\n{site:<name_width$}")?;
+            for c in row {
+                write!(f, " {c:>width$}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct PMatrixJson<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sites: Option<&'a [String]>,
+    p: Vec<Vec<Float>>,
+}
+
 impl PMatrix {
+    pub fn from_array(p: Array2<Float>) -> Self {
+        let roles = vec![SiteRole::Free; p.nrows()];
+        Self {
+            p,
+            samplers: OnceLock::new(),
+            roles,
+        }
+    }
+
+    /// `p` whose entries already have the zeros of the site `roles`
+    pub(crate) fn from_array_with_roles(p: Array2<Float>, roles: &[SiteRole]) -> Self {
+        Self {
+            p,
+            samplers: OnceLock::new(),
+            roles: roles.to_vec(),
+        }
+    }
+
+    /// New probabilities for the same sites, keeping their roles
+    fn with_same_roles(&self, p: Array2<Float>) -> Self {
+        Self {
+            p,
+            samplers: OnceLock::new(),
+            roles: self.roles.clone(),
+        }
+    }
+
+    /// Give sites structural roles: absorbing rows only keep their diagonal, source-only
+    /// columns only keep theirs. Rows are renormalized and rescaling keeps these zeros
+    pub fn with_roles(mut self, roles: &[SiteRole]) -> Self {
+        assert_eq!(roles.len(), self.p.nrows(), "one role per site");
+        for (site, role) in roles.iter().enumerate() {
+            match role {
+                SiteRole::Free => {}
+                SiteRole::Absorbing => {
+                    let mut row = self.p.row_mut(site);
+                    row.fill(0.0);
+                    row[site] = 1.0;
+                }
+                SiteRole::SourceOnly => {
+                    for (i, p) in self.p.column_mut(site).iter_mut().enumerate() {
+                        if i != site {
+                            *p = 0.0;
+                        }
+                    }
+                }
+            }
+        }
+        for mut row in self.p.rows_mut() {
+            let sum = row.sum();
+            if sum > 0.0 {
+                row /= sum;
+            }
+        }
+
+        self.roles = roles.to_vec();
+        self.samplers = OnceLock::new();
+        self
+    }
+
+    /// Weight migrations into each site by its attractiveness in `weights`. The
+    /// probability of staying is kept, the migrations of every row are renormalized
+    pub fn with_organotropism(&self, weights: &[Float]) -> Self {
+        let mut p = self.p.clone();
+        for (i, mut row) in p.rows_mut().into_iter().enumerate() {
+            let before = row.sum() - row[i];
+            for (j, &w) in weights.iter().enumerate() {
+                if j != i {
+                    row[j] *= w;
+                }
+            }
+            let after = row.sum() - row[i];
+            match after > 0.0 {
+                true => {
+                    for j in (0..weights.len()).filter(|&j| j != i) {
+                        row[j] *= before / after;
+                    }
+                }
+                // no attractive destination left, the migrants stay
+                false => row[i] += before,
+            }
+        }
+        self.with_same_roles(p)
+    }
+
+    /// Multiply the probability of migrating from every site by `factor`, at most all
+    /// of the row migrates. Where migrants go is unchanged
+    pub fn with_migration_scale(&self, factor: Float) -> Self {
+        let mut p = self.p.clone();
+        for (i, mut row) in p.rows_mut().into_iter().enumerate() {
+            let total = row.sum();
+            let migrating = total - row[i];
+            if migrating <= 0.0 {
+                continue;
+            }
+            let scaled = (migrating * factor).min(total);
+            row *= scaled / migrating;
+            row[i] = total - scaled;
+        }
+        self.with_same_roles(p)
+    }
+
+    /// Forbid migrating into the `locked` sites, the probability of every row to move
+    /// there is added to staying put
+    pub fn without_destinations(&self, locked: &[bool]) -> Self {
+        let mut p = self.p.clone();
+        for (i, mut row) in p.rows_mut().into_iter().enumerate() {
+            for (j, &locked) in locked.iter().enumerate() {
+                if locked && i != j {
+                    row[i] += row[j];
+                    row[j] = 0.0;
+                }
+            }
+        }
+        self.with_same_roles(p)
+    }
+
+    pub fn roles(&self) -> &[SiteRole] {
+        &self.roles
+    }
+
+    /// The transition probabilities, row `i` is the distribution of the next site from `i`
+    pub fn as_array(&self) -> &Array2<Float> {
+        &self.p
+    }
+
     pub fn new(n: usize) -> Self {
         let mut pmatrix = Array2::zeros((n, n));
 
@@ -27,15 +451,15 @@ impl PMatrix {
                 if i == j {
                     pmatrix[[i, j]] = 0.5;
                 } else {
-                    pmatrix[[i, j]] = 0.5.div(n as f64 - 1.0)
+                    pmatrix[[i, j]] = 0.5.div(n as Float - 1.0)
                 }
             }
         }
 
-        PMatrix { p: pmatrix }
+        Self::from_array(pmatrix)
     }
 
-    pub fn new_with_initial_conditions(n: usize, migration_probability: f64) -> Self {
+    pub fn new_with_initial_conditions(n: usize, migration_probability: Float) -> Self {
         let mut pmatrix = Array2::zeros((n, n));
 
         let no_mig = 1.0 - migration_probability;
@@ -45,77 +469,336 @@ impl PMatrix {
                 if i == j {
                     pmatrix[[i, j]] = no_mig;
                 } else {
-                    pmatrix[[i, j]] = migration_probability.div(n as f64 - 1.0)
+                    pmatrix[[i, j]] = migration_probability.div(n as Float - 1.0)
                 }
             }
         }
 
-        PMatrix { p: pmatrix }
+        Self::from_array(pmatrix)
     }
 
-    pub fn from_vector(v: Vec<f64>, n: usize) -> Self {
+    pub fn from_vector(v: Vec<Float>, n: usize) -> Self {
         let p = Array2::from_shape_vec((n, n), v).unwrap();
 
-        Self { p }
+        Self::from_array(p)
     }
 
-    pub fn exponentiate(self, migration_rate: f64, branch_length: f64) -> Self {
-        let pmatrix = self.p.mul(migration_rate * branch_length);
+    pub fn exponentiate(self, migration_rate: Float, branch_length: Float) -> Self {
+        let pmatrix = (&self.p).mul(migration_rate * branch_length);
 
-        PMatrix { p: pmatrix.exp() }
+        self.with_same_roles(pmatrix.exp())
     }
 
-    // Rescale matrix via Sinkhorn-knupp algorithm - iterative proportion fitting
-    fn rescale(&self, iters: usize) -> Self {
-        let n = self.p.nrows();
+    // Rescale matrix via Sinkhorn-knupp algorithm - iterative proportion fitting.
+    // Columns of absorbing and source-only sites are not balanced, all other columns are
+    // balanced to a common sum (1 without such sites)
+    pub fn rescale(&self, sinkhorn: Sinkhorn) -> Result<(Self, BalanceDiagnostics), PMatrixError> {
+        let p = &self.p;
+        let pinned: Vec<bool> = self.roles.iter().map(|&r| r != SiteRole::Free).collect();
+        if let Some(i) = p.rows().into_iter().position(|row| row.sum() <= 0.0) {
+            return Err(PMatrixError::ZeroRow(i));
+        }
+        if let Some(j) = p
+            .columns()
+            .into_iter()
+            .zip(&pinned)
+            .position(|(col, &pinned)| !pinned && col.sum() <= 0.0)
+        {
+            return Err(PMatrixError::ZeroColumn(j));
+        }
+        let free = pinned.iter().filter(|&&p| !p).count().max(1) as Float;
+
+        let n = p.nrows();
         let mut r = Array1::ones(n);
         let mut c = Array1::ones(n);
 
-        let p = &self.p;
-        for _ in 0..iters {
+        let mut diagnostics = BalanceDiagnostics {
+            iterations: 0,
+            max_row_deviation: Float::INFINITY,
+            max_col_deviation: Float::INFINITY,
+            converged: false,
+        };
+        while diagnostics.iterations < sinkhorn.max_iters && !diagnostics.converged {
             let row_sums = p.dot(&c);
             r.assign(&row_sums.mapv(|e| 1.0 / e));
 
+            // pinned columns keep their mass, the free ones share what is left equally
             let col_sums = p.t().dot(&r);
-            c.assign(&col_sums.mapv(|e| 1.0 / e));
+            let target = match pinned.contains(&true) {
+                false => 1.0,
+                true => {
+                    (&col_sums * &c)
+                        .iter()
+                        .zip(&pinned)
+                        .filter(|&(_, &pinned)| !pinned)
+                        .map(|(s, _)| s)
+                        .sum::<Float>()
+                        / free
+                }
+            };
+            for (j, c) in c.iter_mut().enumerate() {
+                if !pinned[j] {
+                    *c = target / col_sums[j];
+                }
+            }
+
+            // after the column step columns are balanced up to rounding, rows may not
+            let deviation = |sums: Array1<Float>, target: Float, skip: &[bool]| {
+                sums.iter()
+                    .zip(skip)
+                    .filter(|&(_, &skip)| !skip)
+                    .fold(0.0, |m: Float, (s, _)| m.max((s - target).abs()))
+            };
+            diagnostics.iterations += 1;
+            diagnostics.max_row_deviation = deviation(p.dot(&c) * &r, 1.0, &vec![false; n]);
+            diagnostics.max_col_deviation = deviation(p.t().dot(&r) * &c, target, &pinned);
+            diagnostics.converged = diagnostics.max_row_deviation <= sinkhorn.tolerance
+                && diagnostics.max_col_deviation <= sinkhorn.tolerance;
         }
 
         let q = p * &r.insert_axis(Axis(1)) * &c.insert_axis(Axis(0));
 
-        Self { p: q }
+        Ok((self.with_same_roles(q), diagnostics))
     }
 
-    fn diag_mul(&self, v: Array1<f64>) -> Self {
-        Self {
-            p: &self.p * &v.insert_axis(Axis(0)),
+    /// Display with site names on the rows and columns instead of ndarray's formatting
+    pub fn named<'a>(&'a self, sites: &'a [String]) -> NamedPMatrix<'a> {
+        NamedPMatrix {
+            pmatrix: self,
+            sites,
+        }
+    }
+
+    /// Write as csv, with a header row and first column of site names when given
+    pub fn write_csv<W: Write>(&self, w: W, sites: Option<&[String]>) -> io::Result<()> {
+        match sites {
+            Some(sites) => write_matrix_csv(w, &self.p, sites),
+            None => {
+                let dialect = CsvDialect {
+                    headers: false,
+                    ..Default::default()
+                };
+                let mut csv = CsvWriter::new(w, dialect);
+                for row in self.p.rows() {
+                    csv.record(row)?;
+                }
+                Ok(())
+            }
         }
     }
 
-    // A (close to) doubly stochastic matrix p
-    pub fn sample<R: RngCore>(&self, i: usize, rng: &mut R) -> usize {
-        let probabilities = self.p.row(i);
-        let dist = WeightedIndex::new(&probabilities).unwrap();
+    /// Write as json `{"sites": [...], "p": [[...], ...]}`, rows are origins
+    pub fn write_json<W: Write>(&self, w: W, sites: Option<&[String]>) -> io::Result<()> {
+        let doc = PMatrixJson {
+            sites,
+            p: self.p.rows().into_iter().map(|row| row.to_vec()).collect(),
+        };
+        serde_json::to_writer_pretty(w, &doc)?;
+        Ok(())
+    }
 
-        dist.sample(rng)
+    /// Distribution `pi` over sites with `pi P = pi`, found by power iteration from the
+    /// uniform distribution. For reducible matrices this is the limit reached from there
+    pub fn stationary_distribution(&self) -> Array1<Float> {
+        let n = self.p.nrows();
+        let mut pi = Array1::from_elem(n, 1.0 / n as Float);
+        for _ in 0..SPECTRAL_MAX_ITERS {
+            let next = self.p.t().dot(&pi);
+            let change = (&next - &pi).mapv(Float::abs).sum();
+            pi = next;
+            if change < SPECTRAL_TOLERANCE {
+                break;
+            }
+        }
+        &pi / pi.sum()
     }
 
-    pub fn rescale_from_frequencies(self, frequencies: Array1<f64>) -> Self {
-        let updated_freqs = frequencies
-            .iter()
-            //  can either use e^(-f + epsilon) or 1 / (f + eps)
-            .map(|f| (-f + f64::EPSILON).exp())
-            // .map(|e| 1.0.div(e + f64::EPSILON))
-            .collect::<Vec<_>>();
-        let sum: f64 = updated_freqs.iter().sum();
+    /// Modulus of the second largest eigenvalue, the rate at which a distribution over
+    /// sites forgets where it started - close to 1 mixes slowly, close to 0 mixes fast
+    pub fn second_eigenvalue(&self) -> Float {
+        let n = self.p.nrows();
+        if n < 2 {
+            return 0.0;
+        }
+
+        // power iteration on P with the stationary component projected out
+        let pi = self.stationary_distribution();
+        let deflate = |x: Array1<Float>| {
+            let y = self.p.t().dot(&x);
+            let mass = x.sum();
+            y - &pi * mass
+        };
+        let norm = |x: &Array1<Float>| x.dot(x).sqrt();
+
+        // a start vector with weight on every eigenvector but the first
+        let mut x = Array1::from_shape_fn(n, |i| (i as Float + 1.0).sqrt());
+        x = &x - &pi * x.sum();
+        let mut growth = Vec::with_capacity(SPECTRAL_MAX_ITERS);
+        for _ in 0..SPECTRAL_MAX_ITERS {
+            let size = norm(&x);
+            if size < Float::MIN_POSITIVE.sqrt() {
+                return 0.0;
+            }
+            x = deflate(x / size);
+            growth.push(norm(&x).ln());
+        }
+
+        // complex eigenvalue pairs make single steps oscillate, average over the tail
+        let tail = &growth[growth.len() / 2..];
+        (tail.iter().sum::<Float>() / tail.len() as Float).exp()
+    }
+
+    /// Shannon entropy (nats) of each row, 0 when a site never migrates and `ln n` when
+    /// every destination is equally likely
+    pub fn row_entropies(&self) -> Array1<Float> {
+        self.p
+            .rows()
+            .into_iter()
+            .map(|row| {
+                -row.iter()
+                    .filter(|&&p| p > 0.0)
+                    .map(|&p| p * p.ln())
+                    .sum::<Float>()
+            })
+            .collect()
+    }
+
+    /// Entropy rate of the chain, the row entropies weighted by the stationary distribution
+    pub fn entropy_rate(&self) -> Float {
+        self.stationary_distribution().dot(&self.row_entropies())
+    }
+
+    /// Transition probabilities over a branch of length `t` when the matrix is read as a
+    /// continuous time chain with rate matrix `P - I`, `exp((P - I) t)`. Rows sum to 1 and
+    /// `t = 0` gives the identity
+    pub fn transition_probabilities(&self, t: Float) -> Array2<Float> {
+        let n = self.p.nrows();
+        let q = (&self.p - &Array2::<Float>::eye(n)) * t;
+
+        // scaling and squaring: halve until the Taylor series converges fast, then square
+        let norm = q
+            .rows()
+            .into_iter()
+            .map(|row| row.mapv(Float::abs).sum())
+            .fold(0.0, Float::max);
+        let squarings = match norm > 0.5 {
+            true => (norm / 0.5).log2().ceil() as i32,
+            false => 0,
+        };
+        let q = q / Float::powi(2.0, squarings);
+
+        let mut exp = Array2::<Float>::eye(n);
+        let mut term = Array2::<Float>::eye(n);
+        for k in 1..=EXPM_TERMS {
+            term = term.dot(&q) / k as Float;
+            exp += &term;
+        }
+        for _ in 0..squarings {
+            exp = exp.dot(&exp);
+        }
+        exp
+    }
+
+    fn diag_mul(&self, v: Array1<Float>) -> Self {
+        self.with_same_roles(&self.p * &v.insert_axis(Axis(0)))
+    }
+
+    /// Check that row `i` can be sampled from: finite, non-negative and not all zero
+    fn check_row(&self, i: usize) -> Result<(), PMatrixError> {
+        let sites = self.p.nrows();
+        if i >= sites {
+            return Err(PMatrixError::OutOfRange { row: i, sites });
+        }
+        for (col, &value) in self.p.row(i).iter().enumerate() {
+            if !value.is_finite() {
+                return Err(PMatrixError::NotFinite { row: i, col, value });
+            }
+            if value < 0.0 {
+                return Err(PMatrixError::Negative { row: i, col, value });
+            }
+        }
+        match self.p.row(i).sum() > 0.0 {
+            true => Ok(()),
+            false => Err(PMatrixError::ZeroRow(i)),
+        }
+    }
+
+    /// Check that every row is a probability distribution: finite, non-negative entries
+    /// summing to 1
+    pub fn validate(&self) -> Result<(), PMatrixError> {
+        for (row, probabilities) in self.p.rows().into_iter().enumerate() {
+            self.check_row(row)?;
+            let sum = probabilities.sum();
+            if (sum - 1.0).abs() > ROW_SUM_TOLERANCE {
+                return Err(PMatrixError::RowSum { row, sum });
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw the destination of a migrant from site `i`. Rows do not need to be normalized
+    pub fn sample<R: RngCore>(&self, i: usize, rng: &mut R) -> Result<usize, PMatrixError> {
+        let sites = self.p.nrows();
+        if i >= sites {
+            return Err(PMatrixError::OutOfRange { row: i, sites });
+        }
+        let samplers = self.samplers.get_or_init(|| {
+            (0..self.p.nrows())
+                .map(|row| {
+                    self.check_row(row)?;
+                    Ok(WeightedIndex::new(self.p.row(row)).unwrap())
+                })
+                .collect()
+        });
+
+        samplers[i]
+            .as_ref()
+            .map(|sampler| sampler.sample(rng))
+            .map_err(|e| e.clone())
+    }
+
+    /// Reusable constant time sampler for row `i`
+    pub fn sampler(&self, i: usize) -> Result<AliasSampler, PMatrixError> {
+        self.check_row(i)?;
+        Ok(AliasSampler::new(&self.p.row(i).to_vec()))
+    }
+
+    /// Draw `k` destinations from row `i`
+    pub fn sample_many<R: RngCore>(
+        &self,
+        i: usize,
+        k: usize,
+        rng: &mut R,
+    ) -> Result<Vec<usize>, PMatrixError> {
+        let sampler = self.sampler(i)?;
+        Ok((0..k).map(|_| sampler.sample(rng)).collect())
+    }
+
+    pub fn rescale_from_frequencies(
+        self,
+        frequencies: Array1<Float>,
+    ) -> Result<Self, PMatrixError> {
+        self.rescale_from_frequencies_with(frequencies, FrequencyBias::default())
+    }
+
+    /// Weight destination sites by `bias` of their frequency and rebalance
+    pub fn rescale_from_frequencies_with(
+        self,
+        frequencies: Array1<Float>,
+        bias: FrequencyBias,
+    ) -> Result<Self, PMatrixError> {
+        let updated_freqs = bias.weights(&frequencies.to_vec());
+        let sum: Float = updated_freqs.iter().sum();
         let weights = updated_freqs.iter().map(|e| e / sum).collect();
 
-        self.diag_mul(weights).rescale(3)
+        let (balanced, _) = self.diag_mul(weights).rescale(Sinkhorn::default())?;
+        Ok(balanced)
     }
 }
 
 #[test]
 fn test_rescaling() {
-    use rand::thread_rng;    
+    use rand::thread_rng;
 
     let p = vec![0.8, 0.1, 0.1, 0.3, 0.4, 0.3, 0.2, 0.1, 0.7];
     let mut rng = thread_rng();
@@ -123,26 +806,31 @@ fn test_rescaling() {
     let pmatrix = PMatrix::from_vector(p, 3);
     let mut count = vec![0; 3];
     for _ in 0..10000 {
-        let i = pmatrix.sample(1, &mut rng);
+        let i = pmatrix.sample(1, &mut rng).unwrap();
         count[i] += 1;
     }
     println!("{:?}", count);
 
-    let freqs = vec![1.0, 0.0, 0.0];
+    let freqs = [1.0, 0.0, 0.0];
     let updated_freqs = freqs
         .iter()
-        .map(|e| 1.0.div(e + f64::EPSILON as f64))
+        .map(|e| 1.0.div(e + Float::EPSILON))
         .collect::<Vec<_>>();
-    let sum: f64 = updated_freqs.iter().sum();
+    let sum: Float = updated_freqs.iter().sum();
     let weights = updated_freqs.iter().map(|e| e / sum).collect();
 
     let biased_p = pmatrix.diag_mul(weights);
-    let pmatrix = biased_p.rescale(10);
+    let (pmatrix, _) = biased_p
+        .rescale(Sinkhorn {
+            max_iters: 10,
+            ..Default::default()
+        })
+        .unwrap();
 
     println!("After rescaling");
     let mut count = vec![0; 3];
     for _ in 0..10000 {
-        let i = pmatrix.sample(1, &mut rng);
+        let i = pmatrix.sample(1, &mut rng).unwrap();
         count[i] += 1;
     }
     println!("{:?}", count);
@@ -158,3 +846,241 @@ fn test_diag_mul() {
         Array2::from_shape_vec((2, 2), vec![4.0, 3.0, 8.0, 5.0]).unwrap()
     );
 }
+
+#[test]
+fn test_alias_sampler() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let pmatrix = PMatrix::from_vector(vec![0.8, 0.1, 0.1, 0.3, 0.4, 0.3, 0.0, 0.5, 0.5], 3);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    for row in 0..3 {
+        let k = 100_000;
+        let mut count = [0.0; 3];
+        for i in pmatrix.sample_many(row, k, &mut rng).unwrap() {
+            count[i] += 1.0;
+        }
+        for (c, p) in count.iter().zip(pmatrix.p.row(row)) {
+            assert!((c / k as Float - p).abs() < 0.01);
+        }
+    }
+}
+
+#[test]
+fn test_sinkhorn_convergence() {
+    let pmatrix = PMatrix::from_vector(vec![0.8, 0.1, 0.1, 0.3, 0.4, 0.3, 0.2, 0.1, 0.7], 3);
+    let (balanced, diagnostics) = pmatrix.rescale(Sinkhorn::default()).unwrap();
+
+    assert!(diagnostics.converged);
+    assert!(diagnostics.iterations < Sinkhorn::default().max_iters);
+    for row in balanced.p.rows() {
+        assert!((row.sum() - 1.0).abs() < 1e-6);
+    }
+
+    let (_, capped) = pmatrix
+        .rescale(Sinkhorn {
+            tolerance: 0.0,
+            max_iters: 2,
+        })
+        .unwrap();
+    assert_eq!(capped.iterations, 2);
+    assert!(!capped.converged);
+
+    let zero_col = PMatrix::from_vector(vec![0.5, 0.0, 0.5, 0.0], 2);
+    assert_eq!(
+        zero_col.rescale(Sinkhorn::default()).unwrap_err(),
+        PMatrixError::ZeroColumn(1)
+    );
+}
+
+#[test]
+fn test_frequency_bias() {
+    assert!(matches!(
+        "exponential:2.5".parse(),
+        Ok(FrequencyBias::Exponential { beta: 2.5 })
+    ));
+    assert!(matches!("none".parse(), Ok(FrequencyBias::None)));
+    assert!("exponential:x".parse::<FrequencyBias>().is_err());
+    assert!("exponential:-1".parse::<FrequencyBias>().is_err());
+    assert!("exponential:inf".parse::<FrequencyBias>().is_err());
+    assert_eq!(FrequencyBias::Inverse.to_string(), "inverse");
+
+    // the empty site is weighted up more strongly the larger beta is
+    let weak = FrequencyBias::Exponential { beta: 1.0 };
+    let strong = FrequencyBias::Exponential { beta: 5.0 };
+    assert!(strong.weight(0.0) / strong.weight(1.0) > weak.weight(0.0) / weak.weight(1.0));
+    assert_eq!(FrequencyBias::Custom(|f| 2.0 - f).weight(0.5), 1.5);
+
+    // relative to the emptiest site, a steep bias keeps a weight of 1
+    let steep = FrequencyBias::Exponential { beta: 1000.0 };
+    let weights = steep.weights(&[0.5, 0.3, 0.2]);
+    assert_eq!(weights[2], 1.0);
+    assert!(weights[0] <= weights[1]);
+    // every lineage in the primary, exp(-1000) alone underflows to 0
+    assert_eq!(steep.weights(&[1.0, 0.0, 0.0])[0], Float::EPSILON);
+    for frequencies in [vec![0.5, 0.3, 0.2], vec![1.0, 0.0, 0.0]] {
+        let p = PMatrix::new_with_initial_conditions(3, 0.1);
+        assert!(
+            p.rescale_from_frequencies_with(Array1::from_vec(frequencies), steep)
+                .is_ok()
+        );
+    }
+}
+
+#[test]
+fn test_spectral() {
+    // two state chain with known stationary distribution (b, a) / (a + b) and eigenvalue
+    // 1 - a - b
+    let (a, b) = (0.1, 0.3);
+    let pmatrix = PMatrix::from_vector(vec![1.0 - a, a, b, 1.0 - b], 2);
+
+    let tol = Float::EPSILON.sqrt();
+    let pi = pmatrix.stationary_distribution();
+    assert!((pi[0] - 0.75).abs() < tol);
+    assert!((pi[1] - 0.25).abs() < tol);
+    assert!((pmatrix.second_eigenvalue() - 0.6).abs() < tol);
+
+    let uniform = PMatrix::from_vector(vec![0.5; 4], 2);
+    assert!(uniform.second_eigenvalue() < tol);
+    assert!((uniform.entropy_rate() - Float::ln(2.0)).abs() < tol);
+    assert_eq!(
+        PMatrix::new_with_initial_conditions(3, 0.0)
+            .row_entropies()
+            .sum(),
+        0.0
+    );
+}
+
+#[test]
+fn test_pmatrix_export() {
+    let pmatrix = PMatrix::from_vector(vec![0.75, 0.25, 0.5, 0.5], 2);
+    let sites = ["breast".to_string(), "lung".to_string()];
+
+    let mut buf = vec![];
+    pmatrix.write_csv(&mut buf, Some(&sites)).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "site,breast,lung\nbreast,0.75,0.25\nlung,0.5,0.5\n"
+    );
+
+    let mut buf = vec![];
+    pmatrix.write_json(&mut buf, None).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(json, serde_json::json!({"p": [[0.75, 0.25], [0.5, 0.5]]}));
+
+    assert_eq!(
+        format!("{:.2}", pmatrix.named(&sites)),
+        "       breast   lung\nbreast   0.75   0.25\nlung     0.50   0.50"
+    );
+}
+
+#[test]
+fn test_validate() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    assert!(PMatrix::new(3).validate().is_ok());
+
+    let negative = PMatrix::from_vector(vec![1.0, 0.0, -0.5, 1.5], 2);
+    assert_eq!(
+        negative.validate(),
+        Err(PMatrixError::Negative {
+            row: 1,
+            col: 0,
+            value: -0.5
+        })
+    );
+    // the broken row does not stop sampling from the others
+    assert!(negative.sample(0, &mut rng).is_ok());
+    assert!(negative.sample(1, &mut rng).is_err());
+    assert_eq!(
+        negative.sample(2, &mut rng),
+        Err(PMatrixError::OutOfRange { row: 2, sites: 2 })
+    );
+
+    let nan = PMatrix::from_vector(vec![Float::NAN, 1.0, 0.0, 0.0], 2);
+    assert!(matches!(
+        nan.sample(0, &mut rng),
+        Err(PMatrixError::NotFinite { row: 0, col: 0, .. })
+    ));
+    assert_eq!(nan.sampler(1).err(), Some(PMatrixError::ZeroRow(1)));
+
+    let unnormalized = PMatrix::from_vector(vec![2.0, 0.0, 0.0, 1.0], 2);
+    assert!(matches!(
+        unnormalized.validate(),
+        Err(PMatrixError::RowSum { row: 0, .. })
+    ));
+}
+
+#[test]
+fn test_site_roles() {
+    let roles = [
+        SiteRole::SourceOnly,
+        SiteRole::Free,
+        SiteRole::Free,
+        SiteRole::Absorbing,
+    ];
+    let pmatrix = PMatrix::new_with_initial_conditions(4, 0.3).with_roles(&roles);
+    assert!(pmatrix.validate().is_ok());
+
+    let frequencies = Array1::from_vec(vec![0.4, 0.3, 0.2, 0.1]);
+    let rescaled = pmatrix.rescale_from_frequencies(frequencies).unwrap();
+    assert_eq!(rescaled.roles(), roles);
+    assert!(rescaled.validate().is_ok());
+
+    let p = rescaled.as_array();
+    // nothing migrates into the primary, nothing leaves the absorbing site
+    assert_eq!(p.column(0).sum(), p[[0, 0]]);
+    assert_eq!(p.row(3).to_vec(), vec![0.0, 0.0, 0.0, 1.0]);
+    // the primary still seeds
+    assert!(p[[0, 1]] > 0.0 && p[[0, 3]] > 0.0);
+    // the free columns are balanced to a common sum
+    assert!((p.column(1).sum() - p.column(2).sum()).abs() < Float::EPSILON.sqrt());
+}
+
+#[test]
+fn test_sample_chi_square() {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    // 99.9% quantile of the chi-square distribution with 3 degrees of freedom
+    const CRITICAL: Float = 16.27;
+
+    let pmatrix = PMatrix::from_vector(
+        vec![
+            0.7, 0.1, 0.1, 0.1, //
+            0.25, 0.25, 0.25, 0.25, //
+            0.05, 0.05, 0.3, 0.6, //
+            0.4, 0.0, 0.1, 0.5,
+        ],
+        4,
+    );
+    let k = 20_000;
+    let chi_square = |row: usize, draws: Vec<usize>| {
+        let mut observed = [0.0; 4];
+        for i in draws {
+            observed[i] += 1.0;
+        }
+        pmatrix
+            .p
+            .row(row)
+            .iter()
+            .zip(observed)
+            .filter(|&(&p, _)| p > 0.0)
+            .map(|(&p, o)| {
+                let expected = p * k as Float;
+                (o - expected).powi(2) / expected
+            })
+            .sum::<Float>()
+    };
+
+    let mut rng = ChaCha12Rng::seed_from_u64(11);
+    for row in 0..4 {
+        let weighted = (0..k)
+            .map(|_| pmatrix.sample(row, &mut rng).unwrap())
+            .collect();
+        let alias = pmatrix.sample_many(row, k, &mut rng).unwrap();
+        assert!(chi_square(row, weighted) < CRITICAL, "row {row}");
+        assert!(chi_square(row, alias) < CRITICAL, "row {row}");
+    }
+}
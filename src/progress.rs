@@ -0,0 +1,127 @@
+//! Console feedback for long runs: a progress bar on stderr keyed to generations and
+//! leveled log messages controlled by `--verbose` / `--quiet`.
+use std::{
+    fmt::Display,
+    io::{IsTerminal, Write, stderr},
+    mem::size_of,
+    time::Instant,
+};
+
+use crate::{
+    simulations::{GenerationReport, Observer},
+    tree::Node,
+};
+
+/// How much the console output should say
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Warnings and errors only
+    Quiet,
+    /// Progress bar and written files
+    Normal,
+    /// Per generation leaf counts and memory estimates
+    Verbose,
+}
+
+impl Verbosity {
+    pub fn from_flags(verbose: bool, quiet: bool) -> Self {
+        match (verbose, quiet) {
+            (_, true) => Verbosity::Quiet,
+            (true, false) => Verbosity::Verbose,
+            (false, false) => Verbosity::Normal,
+        }
+    }
+}
+
+/// Logger and progress bar of a run
+#[derive(Debug)]
+pub struct Progress {
+    verbosity: Verbosity,
+    started: Instant,
+    /// Draw the bar in place - only when stderr is a terminal
+    draw_bar: bool,
+}
+
+const BAR_WIDTH: usize = 30;
+
+impl Progress {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self {
+            verbosity,
+            started: Instant::now(),
+            draw_bar: verbosity == Verbosity::Normal && stderr().is_terminal(),
+        }
+    }
+
+    /// Messages about the normal course of a run, e.g. written files
+    pub fn info<T: Display>(&self, msg: T) {
+        if self.verbosity >= Verbosity::Normal {
+            println!("{msg}")
+        }
+    }
+
+    /// Detailed messages only shown with `--verbose`
+    pub fn debug<T: Display>(&self, msg: T) {
+        if self.verbosity >= Verbosity::Verbose {
+            eprintln!("[{:>8.2}s] {msg}", self.started.elapsed().as_secs_f64())
+        }
+    }
+
+    /// Problems that do not stop the run, always shown
+    pub fn warn<T: Display>(&self, msg: T) {
+        eprintln!("warning: {msg}")
+    }
+
+    /// Failures, e.g. an output that could not be written, always shown
+    pub fn error<T: Display>(&self, msg: T) {
+        eprintln!("error: {msg}")
+    }
+}
+
+/// Rough size of the tree in memory - nodes plus their child lists
+pub fn estimated_tree_bytes(nodes: usize) -> usize {
+    nodes.saturating_mul(size_of::<Node<usize, usize>>() + size_of::<(usize, f64)>())
+}
+
+impl Observer for Progress {
+    fn wants_pmatrix(&self) -> bool {
+        false
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        let GenerationReport {
+            generation,
+            generations,
+            leaves,
+            nodes,
+        } = report;
+
+        self.debug(format!(
+            "generation {generation}/{generations}: {leaves} leaves, {nodes} nodes, ~{:.1} MiB",
+            estimated_tree_bytes(nodes) as f64 / (1024.0 * 1024.0)
+        ));
+
+        if self.draw_bar {
+            let filled = BAR_WIDTH * generation / generations.max(1);
+            let mut err = stderr();
+            let _ = write!(
+                err,
+                "\r[{}{}] generation {generation}/{generations}, {leaves} leaves, {:.1}s",
+                "#".repeat(filled),
+                " ".repeat(BAR_WIDTH - filled),
+                self.started.elapsed().as_secs_f64()
+            );
+            if generation == generations {
+                let _ = writeln!(err);
+            }
+            let _ = err.flush();
+        }
+    }
+}
+
+#[test]
+fn test_verbosity() {
+    assert_eq!(Verbosity::from_flags(true, true), Verbosity::Quiet);
+    assert_eq!(Verbosity::from_flags(true, false), Verbosity::Verbose);
+    assert!(Verbosity::Verbose > Verbosity::Normal);
+}
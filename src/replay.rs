@@ -0,0 +1,490 @@
+//! Record of the decisions of a simulation run and its replay. A [`Recorder`] observes a
+//! run and keeps every node, death and migration matrix per generation. Replaying the
+//! record drives observers and rebuilds the result without drawing a random number, so
+//! an interesting run can be reproduced and dissected even after code changes alter the
+//! order of the draws.
+//!
+//! `recording.jsonl` holds one JSON object per line: the format version and parameters,
+//! then every generation from 0, the initial population, as a [`RecordedStep`].
+use std::{
+    collections::{BTreeSet, HashSet},
+    io::{self, BufRead, Write},
+};
+
+use ndarray::{Array2, Array3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Float,
+    model::{Model, NodeEvent, StepOutcome, Streams, drive},
+    pmatrix::PMatrix,
+    simulations::{
+        GenerationReport, MigrationEvent, Observer, Parameters, Retention, SimulationResult,
+    },
+    tree::{Node, Phylogeny},
+};
+
+pub const RECORDING: &str = "recording.jsonl";
+
+/// Version of the format written by [`Recording::write_jsonl`]
+pub const RECORDING_FORMAT_VERSION: u32 = 1;
+
+/// What happened in one generation of a recorded run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedStep {
+    pub generation: usize,
+    /// `(id, parent, site, length)` of every node created, in creation order
+    pub nodes: Vec<(usize, Option<usize>, usize, Float)>,
+    pub deaths: Vec<usize>,
+    /// Extant lineages afterwards
+    pub leaves: usize,
+    /// Nodes kept in memory afterwards
+    pub total_nodes: usize,
+    /// Rows of the migration matrix the generation was drawn from, none for generation 0
+    pub pmatrix: Option<Vec<Vec<Float>>>,
+}
+
+impl RecordedStep {
+    fn new(generation: usize) -> Self {
+        Self {
+            generation,
+            nodes: vec![],
+            deaths: vec![],
+            leaves: 0,
+            total_nodes: 0,
+            pmatrix: None,
+        }
+    }
+
+    fn outcome(&self) -> StepOutcome {
+        StepOutcome {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|&(id, parent, label, length)| NodeEvent {
+                    id,
+                    parent,
+                    label,
+                    length,
+                })
+                .collect(),
+            deaths: self.deaths.clone(),
+            leaves: self.leaves,
+            total_nodes: self.total_nodes,
+            extinct: self.leaves == 0,
+        }
+    }
+}
+
+/// Parameters and generations of a recorded run
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub params: Parameters,
+    pub steps: Vec<RecordedStep>,
+}
+
+/// First line of `recording.jsonl`
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format_version: u32,
+    parameters: Parameters,
+}
+
+impl Recording {
+    /// Write the header and one line per generation
+    pub fn write_jsonl<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let header = Header {
+            format_version: RECORDING_FORMAT_VERSION,
+            parameters: self.params.clone(),
+        };
+        serde_json::to_writer(&mut w, &header)?;
+        writeln!(w)?;
+        for step in &self.steps {
+            serde_json::to_writer(&mut w, step)?;
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    /// Read what [`Recording::write_jsonl`] wrote
+    pub fn read_jsonl<R: BufRead>(r: R) -> Result<Self, String> {
+        let mut lines = r.lines().enumerate();
+        let header: Header = match lines.next() {
+            Some((_, line)) => serde_json::from_str(&line.map_err(|e| e.to_string())?)
+                .map_err(|e| format!("line 1: {e}"))?,
+            None => return Err("the recording is empty".to_string()),
+        };
+        if header.format_version != RECORDING_FORMAT_VERSION {
+            return Err(format!(
+                "recording format {} is not {RECORDING_FORMAT_VERSION}",
+                header.format_version
+            ));
+        }
+        let steps = lines
+            .map(|(i, line)| {
+                let line = line.map_err(|e| e.to_string())?;
+                serde_json::from_str(&line).map_err(|e| format!("line {}: {e}", i + 1))
+            })
+            .collect::<Result<Vec<RecordedStep>, String>>()?;
+        let recording = Self {
+            params: header.parameters,
+            steps,
+        };
+        recording.check()?;
+        Ok(recording)
+    }
+
+    /// Check that the steps describe a tree over the sites of the parameters: a root
+    /// first, parents and dead nodes created before, labels of existing sites and square
+    /// migration matrices of every site
+    fn check(&self) -> Result<(), String> {
+        let sites = self.params.sites;
+        if self.steps.is_empty() {
+            return Err("the recording has no generations".to_string());
+        }
+        let mut created = HashSet::new();
+        for (generation, step) in self.steps.iter().enumerate() {
+            if let Some(rows) = &step.pmatrix
+                && (rows.len() != sites || rows.iter().any(|row| row.len() != sites))
+            {
+                return Err(format!(
+                    "generation {generation}: the migration matrix is not {sites} x {sites}"
+                ));
+            }
+            for &(id, parent, label, _) in &step.nodes {
+                match parent {
+                    Some(_) if created.is_empty() => {
+                        return Err(format!(
+                            "generation {generation}: node {id} comes before the root"
+                        ));
+                    }
+                    Some(parent) if !created.contains(&parent) => {
+                        return Err(format!(
+                            "generation {generation}: node {id} has the unknown parent {parent}"
+                        ));
+                    }
+                    None if !created.is_empty() => {
+                        return Err(format!("generation {generation}: a second root {id}"));
+                    }
+                    _ => {}
+                }
+                if label >= sites {
+                    return Err(format!(
+                        "generation {generation}: node {id} is in site {label} of {sites}"
+                    ));
+                }
+                if !created.insert(id) {
+                    return Err(format!(
+                        "generation {generation}: node {id} is created twice"
+                    ));
+                }
+            }
+            if created.is_empty() {
+                return Err(format!("generation {generation}: no root"));
+            }
+            if let Some(id) = step.deaths.iter().find(|id| !created.contains(id)) {
+                return Err(format!("generation {generation}: unknown node {id} died"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drive `observer` through the recorded run, as the run itself did
+    pub fn replay<O: Observer>(&self, observer: &mut O) {
+        let mut model = Replay::new(self);
+        drive(&mut model, self.steps.len() - 1, self.params.seed, observer);
+    }
+
+    /// Replay into `observer` and rebuild the tree, migration matrices, migration events
+    /// and site counts of the run. Cell states and burst generations are not observed and so
+    /// not recorded. Only full retention runs without a hybrid threshold record every
+    /// migration as a node
+    pub fn replay_result<O: Observer>(
+        &self,
+        observer: &mut O,
+    ) -> Result<SimulationResult<Phylogeny<usize, usize>>, String> {
+        if self.params.retention != Retention::Full || self.params.hybrid_threshold.is_some() {
+            return Err(
+                "only full retention runs without a hybrid threshold can be rebuilt".to_string(),
+            );
+        }
+        self.check()?;
+        // the tree numbers its nodes in the order they are added
+        let ids = self.steps.iter().flat_map(|step| &step.nodes);
+        if let Some((expected, &(id, ..))) = ids.enumerate().find(|&(i, node)| node.0 != i) {
+            return Err(format!(
+                "node {id} is recorded where node {expected} should be"
+            ));
+        }
+        self.replay(observer);
+        Ok(rebuild(self))
+    }
+}
+
+/// Observer keeping the record of a run. A generation starts with its migration matrix,
+/// which every model of [`crate::simulations::Simulations`] reports
+pub struct Recorder {
+    params: Parameters,
+    steps: Vec<RecordedStep>,
+}
+
+impl Recorder {
+    pub fn new(params: &Parameters) -> Self {
+        Self {
+            params: params.clone(),
+            steps: vec![RecordedStep::new(0)],
+        }
+    }
+
+    pub fn finish(mut self) -> Recording {
+        let initial = &mut self.steps[0];
+        initial.leaves = (self.params.founder_sites())
+            .expect("a recorded run has founders")
+            .len();
+        initial.total_nodes = initial.nodes.len();
+        Recording {
+            params: self.params,
+            steps: self.steps,
+        }
+    }
+
+    fn current(&mut self) -> &mut RecordedStep {
+        self.steps.last_mut().unwrap()
+    }
+}
+
+impl Observer for Recorder {
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        self.current().nodes.push((id, parent, label, length))
+    }
+
+    fn on_death(&mut self, id: usize) {
+        self.current().deaths.push(id)
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        let step = self.current();
+        step.leaves = report.leaves;
+        step.total_nodes = report.nodes;
+    }
+
+    fn on_pmatrix(&mut self, generation: usize, pmatrix: &PMatrix) {
+        let mut step = RecordedStep::new(generation);
+        let rows = pmatrix.as_array().rows().into_iter();
+        step.pmatrix = Some(rows.map(|row| row.to_vec()).collect());
+        self.steps.push(step);
+    }
+}
+
+/// Model stepping through a recording
+struct Replay<'a> {
+    steps: &'a [RecordedStep],
+    pmatrices: Vec<Option<PMatrix>>,
+    generation: usize,
+}
+
+impl<'a> Replay<'a> {
+    fn new(recording: &'a Recording) -> Self {
+        let pmatrix = |rows: &Vec<Vec<Float>>| {
+            let n = rows.len();
+            let p = Array2::from_shape_vec((n, n), rows.concat()).expect("square matrix");
+            PMatrix::from_array(p)
+        };
+        Self {
+            steps: &recording.steps,
+            pmatrices: recording
+                .steps
+                .iter()
+                .map(|step| step.pmatrix.as_ref().map(pmatrix))
+                .collect(),
+            generation: 0,
+        }
+    }
+}
+
+impl Model for Replay<'_> {
+    fn init(&mut self, _rng: &mut Streams) -> StepOutcome {
+        self.generation = 0;
+        self.steps[0].outcome()
+    }
+
+    fn step(&mut self, _rng: &mut Streams) -> StepOutcome {
+        self.generation += 1;
+        self.steps[self.generation].outcome()
+    }
+
+    fn pmatrix(&self) -> Option<&PMatrix> {
+        self.pmatrices[self.generation].as_ref()
+    }
+}
+
+/// Build the tree, matrices, events and site counts of a full retention run from its steps
+fn rebuild(recording: &Recording) -> SimulationResult<Phylogeny<usize, usize>> {
+    let sites = recording.params.sites;
+    let mut tree: Option<Phylogeny<usize, usize>> = None;
+    let mut migration_matrix = Array2::zeros((sites, sites));
+    let mut events = vec![];
+    let mut extant = BTreeSet::new();
+    let mut leaf_counts = Array2::zeros((recording.steps.len(), sites));
+    let mut transitions = Array3::zeros((recording.steps.len(), sites, sites));
+    for (generation, step) in recording.steps.iter().enumerate() {
+        for &(id, parent, label, length) in &step.nodes {
+            let Some(parent) = parent else {
+                tree = Some(Phylogeny::new(Node::root(id, label), length));
+                extant.insert(id);
+                continue;
+            };
+            let tree = tree.as_mut().expect("the root comes first");
+            let from = tree.nodes[parent].label;
+            assert_eq!(tree.add_child(parent, id, label, length), id);
+            extant.remove(&parent);
+            extant.insert(id);
+            // the founders descend from an unobserved ancestor, not by migration
+            if generation > 0 {
+                migration_matrix[[from, label]] += 1;
+                transitions[[generation, from, label]] += 1;
+                if from != label {
+                    events.push(MigrationEvent {
+                        generation,
+                        from,
+                        to: label,
+                        node: id,
+                    });
+                }
+            }
+        }
+        let tree = tree.as_mut().expect("a run has a root");
+        for &id in &step.deaths {
+            tree.kill(id);
+            extant.remove(&id);
+        }
+        for &leaf in &extant {
+            leaf_counts[[generation, tree.nodes[leaf].label]] += 1;
+        }
+    }
+    let mut frequencies = leaf_counts.mapv(|c: usize| c as Float);
+    for mut row in frequencies.rows_mut() {
+        let total = row.sum();
+        row /= total;
+    }
+    SimulationResult::new(
+        tree.expect("a run has a root"),
+        migration_matrix,
+        frequencies,
+        events,
+        recording.params.clone(),
+    )
+    .with_leaf_counts(leaf_counts)
+    .with_transitions(transitions)
+}
+
+#[test]
+fn test_replay() {
+    use crate::simulations::{EffectiveMatrices, Simulations};
+
+    let params = Parameters {
+        generations: 7,
+        sites: 4,
+        migration_probability: 0.1,
+        max_population: Some(40),
+        root_sites: vec![0, 2],
+        ..Default::default()
+    };
+    let mut recorder = Recorder::new(&params);
+    let mut effective = EffectiveMatrices::default();
+    let result = Phylogeny::simulate(&params, &mut (&mut recorder, &mut effective));
+    let recording = recorder.finish();
+    assert_eq!(recording.steps.len(), 8);
+
+    let mut jsonl = vec![];
+    recording.write_jsonl(&mut jsonl).unwrap();
+    let read = Recording::read_jsonl(&jsonl[..]).unwrap();
+    assert_eq!(read.steps, recording.steps);
+    assert_eq!(read.params.root_sites, [0, 2]);
+
+    let mut replayed = EffectiveMatrices::default();
+    let rebuilt = read.replay_result(&mut replayed).unwrap();
+    assert_eq!(replayed.matrices, effective.matrices);
+    let (a, b) = (result.tree(), rebuilt.tree());
+    assert!(a.edges().eq(b.edges()));
+    assert!(
+        a.nodes
+            .iter()
+            .zip(&b.nodes)
+            .all(|(x, y)| x.label == y.label)
+    );
+    assert!((0..a.nodes.len()).all(|i| a.is_alive(i) == b.is_alive(i)));
+    assert_eq!(rebuilt.migration_matrix(), result.migration_matrix());
+    assert_eq!(rebuilt.events(), result.events());
+    assert_eq!(
+        rebuilt.leaf_counts_by_generation(),
+        result.leaf_counts_by_generation()
+    );
+    assert_eq!(
+        rebuilt.frequencies_by_generation(),
+        result.frequencies_by_generation()
+    );
+    assert_eq!(
+        rebuilt.transitions_by_generation(),
+        result.transitions_by_generation()
+    );
+
+    assert!(Recording::read_jsonl(&b""[..]).is_err());
+    let summary = Recording {
+        params: Parameters {
+            retention: Retention::Summary,
+            ..params
+        },
+        steps: read.steps,
+    };
+    assert!(summary.replay_result(&mut ()).is_err());
+}
+
+#[test]
+fn test_corrupted_recording() {
+    use crate::simulations::Simulations;
+
+    let params = Parameters {
+        generations: 3,
+        sites: 2,
+        migration_probability: 0.2,
+        ..Default::default()
+    };
+    let mut recorder = Recorder::new(&params);
+    Phylogeny::simulate(&params, &mut recorder);
+    let recording = recorder.finish();
+    let corrupt = |edit: &dyn Fn(&mut Vec<RecordedStep>)| {
+        let mut steps = recording.steps.clone();
+        edit(&mut steps);
+        let mut jsonl = vec![];
+        Recording {
+            params: params.clone(),
+            steps,
+        }
+        .write_jsonl(&mut jsonl)
+        .unwrap();
+        Recording::read_jsonl(&jsonl[..]).map(|_| ())
+    };
+    assert_eq!(corrupt(&|_| {}), Ok(()));
+    assert!(
+        corrupt(&|steps| {
+            steps[1].pmatrix.as_mut().unwrap()[0].pop();
+        })
+        .is_err()
+    );
+    assert!(corrupt(&|steps| steps[1].nodes[0].1 = Some(99)).is_err());
+    assert!(corrupt(&|steps| steps[0].nodes[0].1 = Some(0)).is_err());
+    assert!(corrupt(&|steps| steps[1].nodes[0].2 = 2).is_err());
+    assert!(corrupt(&|steps| steps[2].deaths.push(99)).is_err());
+    assert!(corrupt(&|steps| steps[1].nodes[1].1 = None).is_err());
+
+    // read, but not a tree the rebuild can number
+    let mut steps = recording.steps.clone();
+    let step = steps.last_mut().unwrap();
+    step.deaths.clear();
+    let last = step.nodes.len() - 1;
+    step.nodes[last].0 += 1000;
+    let shifted = Recording { params, steps };
+    assert!(shifted.check().is_ok());
+    assert!(shifted.replay_result(&mut ()).is_err());
+}
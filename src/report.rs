@@ -0,0 +1,133 @@
+//! Self-contained HTML report of a single run: parameters, the migration graph, the
+//! site-colored tree, site frequencies over time and summary tables in one file.
+use std::io::{self, Write};
+
+use ndarray::Array2;
+use serde::Serialize;
+
+use crate::{
+    tree::Phylogeny,
+    visualizations::{
+        DotStyle, dot_to_svg, frequency_svg, graph_from_edge_matrix, to_dot, tree_svg,
+    },
+};
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write the report for one run. The migration graph is embedded as SVG when Graphviz
+/// is available and as DOT source otherwise
+pub fn write_report<W: Write, N: Clone, P: Serialize>(
+    mut w: W,
+    tree: &Phylogeny<N, usize>,
+    migration_matrix: &Array2<i64>,
+    parameters: &P,
+    sites: &[String],
+    style: &DotStyle,
+) -> io::Result<()> {
+    writeln!(w, "<!DOCTYPE html>")?;
+    writeln!(
+        w,
+        "<html><head><meta charset=\"utf-8\"><title>Simulation report</title>"
+    )?;
+    writeln!(
+        w,
+        "<style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         td,th{{border:1px solid #ccc;padding:2px 8px;text-align:right}}</style>"
+    )?;
+    writeln!(w, "</head><body>")?;
+    writeln!(w, "<h1>Simulation report</h1>")?;
+
+    writeln!(w, "<h2>Parameters</h2>\n<table>")?;
+    if let serde_json::Value::Object(params) = serde_json::to_value(parameters)? {
+        for (k, v) in params {
+            writeln!(
+                w,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                escape(&k),
+                escape(&v.to_string())
+            )?;
+        }
+    }
+    writeln!(w, "</table>")?;
+
+    writeln!(w, "<h2>Sites</h2>\n<table>")?;
+    writeln!(w, "<tr><th>site</th><th>color</th><th>leaves</th></tr>")?;
+    let mut leaf_counts = vec![0; sites.len()];
+    for leaf in tree.leaves() {
+        leaf_counts[tree.nodes[leaf].label] += 1;
+    }
+    for (i, (site, count)) in sites.iter().zip(&leaf_counts).enumerate() {
+        writeln!(
+            w,
+            "<tr><th>{}</th><td style=\"background:{}\"></td><td>{count}</td></tr>",
+            escape(site),
+            style.site_color(i)
+        )?;
+    }
+    writeln!(w, "</table>")?;
+
+    writeln!(w, "<h2>Migrations</h2>\n<table>")?;
+    write!(w, "<tr><th>from \\ to</th>")?;
+    for site in sites {
+        write!(w, "<th>{}</th>", escape(site))?;
+    }
+    writeln!(w, "</tr>")?;
+    for (site, row) in sites.iter().zip(migration_matrix.rows()) {
+        write!(w, "<tr><th>{}</th>", escape(site))?;
+        for c in row {
+            write!(w, "<td>{c}</td>")?;
+        }
+        writeln!(w, "</tr>")?;
+    }
+    writeln!(w, "</table>")?;
+
+    writeln!(w, "<h2>Migration graph</h2>")?;
+    let dot = to_dot(&graph_from_edge_matrix(migration_matrix.clone()), style);
+    match dot_to_svg(&dot) {
+        Ok(svg) => writeln!(w, "{svg}")?,
+        Err(e) => writeln!(
+            w,
+            "<p>{}</p>\n<pre>{}</pre>",
+            escape(&e.to_string()),
+            escape(&dot)
+        )?,
+    }
+
+    writeln!(w, "<h2>Site frequencies</h2>")?;
+    let counts = tree.site_counts_by_generation(sites.len());
+    writeln!(w, "{}", frequency_svg(&counts, sites, style))?;
+
+    writeln!(w, "<h2>Tree</h2>")?;
+    writeln!(w, "{}", tree_svg(tree, style))?;
+
+    writeln!(w, "</body></html>")
+}
+
+#[test]
+fn test_write_report() {
+    use crate::simulations::Simulations;
+
+    let (tree, migration_matrix) = Phylogeny::yule_migrations(0.2, 4, 3, 0.1, 7).into_parts();
+    let sites = ["a", "b", "c"].map(String::from);
+
+    let mut buf = vec![];
+    write_report(
+        &mut buf,
+        &tree,
+        &migration_matrix,
+        &"params",
+        &sites,
+        &DotStyle::default(),
+    )
+    .unwrap();
+
+    let html = String::from_utf8(buf).unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.trim_end().ends_with("</body></html>"));
+    assert!(html.matches("<svg").count() >= 2);
+}
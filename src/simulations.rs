@@ -1,94 +1,3153 @@
-use std::ops::Div;
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Div,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use ndarray::{Array1, Array2, Array3, Axis};
+
+use rand::{Rng, RngCore, seq::index};
+use rand_distr::{Distribution, Exp, Gamma, Poisson, Weibull};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Float,
+    model::{
+        Budget, Component, Model, NodeEvent, SimRng, StepOutcome, Streams, Truncation,
+        drive_with_budget, sub_seed,
+    },
+    pmatrix::{AliasSampler, FrequencyBias, PMatrix, PMatrixError, Sinkhorn, SiteRole},
+    site::SiteLabel,
+    sparse::SparsePMatrix,
+    tree::{Node, Phylogeny},
+};
+
+/// What a simulation keeps in memory while it runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Retention {
+    /// Build the whole tree, O(2^g) nodes
+    #[default]
+    Full,
+    /// Only track the number of lineages per site and the migration matrix, O(sites^2).
+    /// The returned tree is just the root. Draws are made per site rather than per
+    /// lineage, so results for a seed differ from [`Retention::Full`]
+    Summary,
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Retention::Full => write!(f, "full"),
+            Retention::Summary => write!(f, "summary"),
+        }
+    }
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Retention::Full),
+            "summary" => Ok(Retention::Summary),
+            _ => Err(format!("unknown retention `{s}`, expected full or summary")),
+        }
+    }
+}
+
+/// How a run stores the migration matrix it draws from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatrixStorage {
+    /// Every pair of sites, `O(sites^2)` per generation
+    #[default]
+    Dense,
+    /// Only the pairs of sites that are connected, see [`SparsePMatrix`]. Pays off for
+    /// many sites with few connections, e.g. with absorbing or source-only sites. Rows
+    /// are sampled over their stored entries, so results for a seed differ from
+    /// [`MatrixStorage::Dense`]
+    Sparse,
+}
+
+impl fmt::Display for MatrixStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixStorage::Dense => write!(f, "dense"),
+            MatrixStorage::Sparse => write!(f, "sparse"),
+        }
+    }
+}
+
+impl FromStr for MatrixStorage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dense" => Ok(MatrixStorage::Dense),
+            "sparse" => Ok(MatrixStorage::Sparse),
+            _ => Err(format!(
+                "unknown matrix storage `{s}`, expected dense or sparse"
+            )),
+        }
+    }
+}
+
+/// How lineages draw their random numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RngScheme {
+    /// Lineages draw in turn from one stream per component, see [`Streams::new`]
+    #[default]
+    Shared,
+    /// Every lineage draws from counter based streams keyed by the seed and its node, see
+    /// [`Streams::lineage`], so the children of any lineage can be drawn again on their
+    /// own and runs do not depend on the order lineages are drawn in. Culling and the root
+    /// branch still draw from the shared streams
+    Lineage,
+}
+
+impl fmt::Display for RngScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RngScheme::Shared => write!(f, "shared"),
+            RngScheme::Lineage => write!(f, "lineage"),
+        }
+    }
+}
+
+impl FromStr for RngScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(RngScheme::Shared),
+            "lineage" => Ok(RngScheme::Lineage),
+            _ => Err(format!(
+                "unknown rng scheme `{s}`, expected shared or lineage"
+            )),
+        }
+    }
+}
+
+/// How the migration matrix evolves over a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MigrationMode {
+    /// Rescale the matrix by site frequencies every generation
+    #[default]
+    FrequencyDependent,
+    /// Keep the initial matrix for the whole run - the independent migration null model
+    Fixed,
+}
+
+impl fmt::Display for MigrationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationMode::FrequencyDependent => write!(f, "frequency-dependent"),
+            MigrationMode::Fixed => write!(f, "fixed"),
+        }
+    }
+}
+
+impl FromStr for MigrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frequency-dependent" => Ok(MigrationMode::FrequencyDependent),
+            "fixed" => Ok(MigrationMode::Fixed),
+            _ => Err(format!(
+                "unknown migration mode `{s}`, expected frequency-dependent or fixed"
+            )),
+        }
+    }
+}
+
+/// Distribution of the waiting time of a lineage before it splits, i.e. of the branch
+/// lengths. Every distribution has mean `1 / birth_rate`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WaitingTime {
+    /// Memoryless waiting times of a Markovian birth process
+    #[default]
+    Exponential,
+    /// Gamma waiting times, above shape 1 cell cycles are more regular than exponential
+    Gamma { shape: f64 },
+    /// Weibull waiting times, above shape 1 the split rate increases with the age of a cell
+    Weibull { shape: f64 },
+}
+
+impl WaitingTime {
+    /// Shape parameter of the distribution, 1 for exponential
+    pub fn shape(&self) -> f64 {
+        match self {
+            WaitingTime::Exponential => 1.0,
+            WaitingTime::Gamma { shape } | WaitingTime::Weibull { shape } => *shape,
+        }
+    }
+
+    fn distribution(&self, rate: f64) -> WaitingDistribution {
+        match *self {
+            WaitingTime::Exponential => {
+                WaitingDistribution::Exponential(Exp::new(rate as Float).unwrap())
+            }
+            WaitingTime::Gamma { shape } => WaitingDistribution::Gamma(
+                Gamma::new(shape as Float, (1.0 / (rate * shape)) as Float).unwrap(),
+            ),
+            WaitingTime::Weibull { shape } => WaitingDistribution::Weibull(
+                Weibull::new(
+                    (1.0 / (rate * gamma_function(1.0 + 1.0 / shape))) as Float,
+                    shape as Float,
+                )
+                .unwrap(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for WaitingTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitingTime::Exponential => write!(f, "exponential"),
+            WaitingTime::Gamma { shape } => write!(f, "gamma:{shape}"),
+            WaitingTime::Weibull { shape } => write!(f, "weibull:{shape}"),
+        }
+    }
+}
+
+/// Parses `exponential`, `gamma:<shape>` or `weibull:<shape>`
+impl FromStr for WaitingTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let shape = |shape: &str| match shape.parse::<f64>() {
+            Ok(shape) if shape.is_finite() && shape > 0.0 => Ok(shape),
+            Ok(_) => Err(format!("shape must be positive, got `{shape}`")),
+            Err(e) => Err(format!("invalid shape `{shape}`: {e}")),
+        };
+        match s.split_once(':') {
+            Some(("gamma", k)) => shape(k).map(|shape| WaitingTime::Gamma { shape }),
+            Some(("weibull", k)) => shape(k).map(|shape| WaitingTime::Weibull { shape }),
+            None if s == "exponential" => Ok(WaitingTime::Exponential),
+            _ => Err(format!(
+                "unknown waiting time `{s}`, expected exponential, gamma:<shape> or weibull:<shape>"
+            )),
+        }
+    }
+}
+
+impl Serialize for WaitingTime {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for WaitingTime {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the branch lengths of a run measure. The waiting times are drawn whatever the
+/// units, so a run in other units has the same tree, sites and random stream
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BranchUnits {
+    /// The sampled waiting times
+    #[default]
+    Time,
+    /// One per branch, so a node lies its generation away from the root
+    Generations,
+    /// Expected mutations along the branch, the waiting time times the clock rate
+    Mutations { clock_rate: f64 },
+}
+
+impl BranchUnits {
+    /// Length of a branch with waiting time `time`
+    pub fn length(&self, time: Float) -> Float {
+        match *self {
+            BranchUnits::Time => time,
+            BranchUnits::Generations => 1.0,
+            BranchUnits::Mutations { clock_rate } => time * clock_rate as Float,
+        }
+    }
+}
+
+impl fmt::Display for BranchUnits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BranchUnits::Time => write!(f, "time"),
+            BranchUnits::Generations => write!(f, "generations"),
+            BranchUnits::Mutations { clock_rate } => write!(f, "mutations:{clock_rate}"),
+        }
+    }
+}
+
+/// Parses `time`, `generations` or `mutations:<clock rate>`
+impl FromStr for BranchUnits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("mutations", rate)) => match rate.parse::<f64>() {
+                Ok(clock_rate) if clock_rate.is_finite() && clock_rate > 0.0 => {
+                    Ok(BranchUnits::Mutations { clock_rate })
+                }
+                Ok(_) => Err(format!("clock rate must be positive, got `{rate}`")),
+                Err(e) => Err(format!("invalid clock rate `{rate}`: {e}")),
+            },
+            None if s == "time" => Ok(BranchUnits::Time),
+            None if s == "generations" => Ok(BranchUnits::Generations),
+            _ => Err(format!(
+                "unknown branch units `{s}`, expected time, generations or mutations:<clock rate>"
+            )),
+        }
+    }
+}
+
+impl Serialize for BranchUnits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BranchUnits {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Sampler of a [`WaitingTime`] at a given birth rate
+enum WaitingDistribution {
+    Exponential(Exp<Float>),
+    Gamma(Gamma<Float>),
+    Weibull(Weibull<Float>),
+}
+
+impl Distribution<Float> for WaitingDistribution {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Float {
+        match self {
+            WaitingDistribution::Exponential(d) => d.sample(rng),
+            WaitingDistribution::Gamma(d) => d.sample(rng),
+            WaitingDistribution::Weibull(d) => d.sample(rng),
+        }
+    }
+}
+
+/// Gamma function by the Lanczos approximation (g = 7), for positive `x`
+fn gamma_function(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection formula
+        return std::f64::consts::PI / ((std::f64::consts::PI * x).sin() * gamma_function(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    (2.0 * std::f64::consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+}
+
+/// Parameters of a simulation run, recorded alongside the outputs. Fields missing when
+/// read back take their default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Parameters {
+    pub birth_rate: f64,
+    /// Distribution of branch lengths, each with mean `1 / birth_rate`
+    pub waiting_time: WaitingTime,
+    /// What the branch lengths of the tree measure
+    pub branch_units: BranchUnits,
+    pub generations: usize,
+    pub sites: usize,
+    /// Names of the sites in output, empty to name them by index
+    pub names: Vec<String>,
+    /// Site of every founder lineage. Several founders, e.g. for multifocal primaries,
+    /// descend from an unobserved common ancestor whose edges are not migrations
+    pub root_sites: Vec<usize>,
+    /// Fraction of the founder lineages in every site, empty to found from `root_sites`.
+    /// Starts a run observed late, with metastasis already underway
+    pub initial_frequencies: Vec<f64>,
+    /// Founder lineages spread over the sites by `initial_frequencies`
+    pub founders: usize,
+    pub migration_probability: f64,
+    pub seed: u64,
+    pub retention: Retention,
+    /// Cap on the number of extant lineages, above it lineages are culled at random
+    pub max_population: Option<usize>,
+    /// Extant lineages above which a full retention run stops simulating every cell:
+    /// each lineage becomes a clone whose size per site grows deterministically, with
+    /// Poisson migrations of whole sub-clones. `None` to simulate every cell
+    pub hybrid_threshold: Option<usize>,
+    /// How lineages draw their random numbers, a seed gives different runs under each
+    pub rng: RngScheme,
+    /// Threads drawing the children of the lineages with [`RngScheme::Lineage`], results
+    /// are the same for any number. Only used with full retention
+    pub threads: usize,
+    pub migration_mode: MigrationMode,
+    /// How the migration matrix is stored while the run draws from it
+    pub matrix_storage: MatrixStorage,
+    /// How site frequencies bias migration each generation, unused with a fixed matrix
+    pub frequency_bias: FrequencyBias,
+    /// Sites that receive migrants but never seed other sites
+    pub absorbing: Vec<usize>,
+    /// Sites that seed other sites but never receive migrants
+    pub source_only: Vec<usize>,
+    /// Number of children of every split, above 2 the tree has polytomies
+    pub branching: usize,
+    /// Prerequisites of colonization as `(a, b)` pairs: site `b` can only be colonized
+    /// after site `a`, and after all its other prerequisites. Must form a DAG
+    pub colonization_order: Vec<(usize, usize)>,
+    /// Generations of founder bottleneck after a lineage enters an empty site, 0 for none
+    pub founder_generations: usize,
+    /// Children of every split in a site during its founder bottleneck
+    pub founder_branching: usize,
+    /// Attractiveness of every site as a migration destination, empty for all equal
+    pub organotropism: Vec<f64>,
+    /// Cell states of a second label layer (e.g. epithelial and mesenchymal), 1 for none.
+    /// Only simulated with full retention
+    pub states: usize,
+    /// Probability that a child switches to another cell state
+    pub state_switch_probability: f64,
+    /// Factor on the migration probability of the children of each cell state, empty for
+    /// all 1
+    pub state_migration: Vec<f64>,
+    /// Probability that a generation is a migration burst, 0 for gradual migration
+    pub burst_probability: f64,
+    /// Factor on the migration probability in burst generations
+    pub burst_factor: f64,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            birth_rate: 0.2,
+            waiting_time: WaitingTime::default(),
+            branch_units: BranchUnits::default(),
+            generations: 10,
+            sites: 6,
+            names: vec![],
+            root_sites: vec![0],
+            initial_frequencies: vec![],
+            founders: 100,
+            migration_probability: 0.01,
+            seed: 42,
+            retention: Retention::default(),
+            max_population: None,
+            hybrid_threshold: None,
+            rng: RngScheme::default(),
+            threads: 1,
+            migration_mode: MigrationMode::default(),
+            matrix_storage: MatrixStorage::default(),
+            frequency_bias: FrequencyBias::default(),
+            absorbing: vec![],
+            source_only: vec![],
+            branching: <Phylogeny<usize, usize> as Simulations>::BRANCHING,
+            colonization_order: vec![],
+            founder_generations: 0,
+            founder_branching: 1,
+            organotropism: vec![],
+            states: 1,
+            state_switch_probability: 0.1,
+            state_migration: vec![],
+            burst_probability: 0.0,
+            burst_factor: 100.0,
+        }
+    }
+}
+
+impl Parameters {
+    /// Names of the simulated sites, their index unless [`Parameters::names`] are given
+    pub fn site_names(&self) -> Vec<String> {
+        match self.names.is_empty() {
+            true => (0..self.sites).map(|i| i.to_string()).collect(),
+            false => self.names.clone(),
+        }
+    }
+
+    /// Check that `initial_frequencies`, when given, are a fraction for every site that
+    /// can apportion the founders
+    pub fn check_initial_frequencies(&self) -> Result<(), String> {
+        let frequencies = &self.initial_frequencies;
+        if frequencies.is_empty() {
+            return Ok(());
+        }
+        if frequencies.len() != self.sites {
+            return Err(format!(
+                "initial frequencies has {} fractions for {} sites",
+                frequencies.len(),
+                self.sites
+            ));
+        }
+        if frequencies.iter().any(|f| !f.is_finite() || *f < 0.0) {
+            return Err("initial frequencies must be finite and not negative".to_string());
+        }
+        if frequencies.iter().sum::<f64>() <= 0.0 {
+            return Err("initial frequencies must not all be zero".to_string());
+        }
+        match self.founders {
+            0 => Err("founders must be at least 1".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Site of every founder lineage: the `root_sites`, or `founders` lineages apportioned
+    /// by `initial_frequencies` with the largest remainders rounded up, ties to the lower
+    /// site, so the initial frequencies are as close as the lineages allow. The error of
+    /// [`Parameters::check_initial_frequencies`] when they can not apportion the founders
+    pub fn founder_sites(&self) -> Result<Vec<usize>, String> {
+        self.check_initial_frequencies()?;
+        if self.initial_frequencies.is_empty() {
+            return Ok(self.root_sites.clone());
+        }
+        let total: f64 = self.initial_frequencies.iter().sum();
+        let quotas: Vec<f64> = self
+            .initial_frequencies
+            .iter()
+            .map(|f| f / total * self.founders as f64)
+            .collect();
+        let mut counts: Vec<usize> = quotas.iter().map(|q| q.floor() as usize).collect();
+        let remainder = |site: usize| quotas[site] - counts[site] as f64;
+        let mut order: Vec<usize> = (0..counts.len()).collect();
+        order.sort_by(|&a, &b| remainder(b).total_cmp(&remainder(a)));
+        let missing = self.founders - counts.iter().sum::<usize>();
+        for &site in &order[..missing] {
+            counts[site] += 1;
+        }
+        Ok(counts
+            .iter()
+            .enumerate()
+            .flat_map(|(site, &count)| std::iter::repeat_n(site, count))
+            .collect())
+    }
+
+    /// Structural role of every site
+    pub fn site_roles(&self) -> Vec<SiteRole> {
+        let mut roles = vec![SiteRole::Free; self.sites];
+        for &site in &self.absorbing {
+            roles[site] = SiteRole::Absorbing;
+        }
+        for &site in &self.source_only {
+            roles[site] = SiteRole::SourceOnly;
+        }
+        roles
+    }
+
+    /// Migration matrix of the first generation: the migration probability split evenly
+    /// over the other sites, the zeros of the site roles and the organotropism weights
+    pub fn pmatrix(&self) -> PMatrix {
+        let p = self.base_pmatrix();
+        match self.organotropism.is_empty() {
+            true => p,
+            false => {
+                let weights: Vec<_> = self.organotropism.iter().map(|&w| w as Float).collect();
+                p.with_organotropism(&weights)
+            }
+        }
+    }
+
+    /// Migration matrix before organotropism, the one rescaled by frequencies
+    fn base_pmatrix(&self) -> PMatrix {
+        PMatrix::new_with_initial_conditions(self.sites, self.migration_probability as Float)
+            .with_roles(&self.site_roles())
+    }
+
+    /// Check that every row of the first migration matrix is a distribution and, when
+    /// it is rescaled by site frequencies, that it can be balanced
+    pub fn check_pmatrix(&self) -> Result<(), String> {
+        self.pmatrix()
+            .validate()
+            .map_err(|e| format!("migration matrix: {e}"))?;
+        if self.migration_mode == MigrationMode::FrequencyDependent {
+            self.base_pmatrix()
+                .rescale(Sinkhorn::default())
+                .map_err(|e| format!("migration matrix: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Most nodes a run can build, counted up to `limit`: every lineage splits into at
+    /// most `branching` children a generation, 2^(g+1) - 1 nodes for a Yule tree of `g`
+    /// generations, the population cap bounds the lineages kept and no node is added once
+    /// the lineages pass the hybrid threshold. `usize::MAX` when the count saturates, only
+    /// the root when the founders can not be placed
+    pub fn max_nodes(&self, limit: usize) -> usize {
+        let branching = self.branching.max(self.founder_branching);
+        let founders = self.founder_sites().map_or(0, |f| f.len());
+        let mut lineages = founders;
+        let mut nodes = 1 + if founders > 1 { founders } else { 0 };
+        for _ in 0..self.generations {
+            if nodes >= limit || self.hybrid_threshold.is_some_and(|t| lineages > t) {
+                break;
+            }
+            lineages = lineages.saturating_mul(branching);
+            nodes = nodes.saturating_add(lineages);
+            lineages = lineages.min(self.max_population.unwrap_or(usize::MAX));
+        }
+        nodes.min(limit)
+    }
+
+    /// Sites that must be colonized before each site
+    pub fn prerequisites(&self) -> Vec<Vec<usize>> {
+        let mut prerequisites = vec![vec![]; self.sites];
+        for &(a, b) in &self.colonization_order {
+            prerequisites[b].push(a);
+        }
+        prerequisites
+    }
+
+    /// Check that the colonization order only names existing sites and has no cycle
+    pub fn check_colonization_order(&self) -> Result<(), String> {
+        if let Some(&(a, b)) = self
+            .colonization_order
+            .iter()
+            .find(|&&(a, b)| a >= self.sites || b >= self.sites)
+        {
+            return Err(format!(
+                "colonization order {a}:{b} names a site that does not exist with {} sites",
+                self.sites
+            ));
+        }
+        // remove sites whose prerequisites are all removed until none is left
+        let prerequisites = self.prerequisites();
+        let mut removed = vec![false; self.sites];
+        while let Some(site) =
+            (0..self.sites).find(|&s| !removed[s] && prerequisites[s].iter().all(|&p| removed[p]))
+        {
+            removed[site] = true;
+        }
+        match removed.iter().all(|&r| r) {
+            true => Ok(()),
+            false => Err("colonization order has a cycle".to_string()),
+        }
+    }
+
+    /// Check everything a run draws from: the sites, founders, probabilities, branching,
+    /// colonization order and migration matrix. Parameters that pass run without panicking,
+    /// except with a [`FrequencyBias::Custom`] whose weights empty the matrix
+    pub fn check(&self) -> Result<(), String> {
+        let sites = self.sites;
+        if sites == 0 {
+            return Err("sites must be at least 1".to_string());
+        }
+        if !self.names.is_empty() && self.names.len() != sites {
+            return Err(format!(
+                "names has {} names for {sites} sites",
+                self.names.len()
+            ));
+        }
+        if let Some(site) = (self.absorbing.iter())
+            .chain(&self.source_only)
+            .chain(&self.root_sites)
+            .find(|&&s| s >= sites)
+        {
+            return Err(format!("site {site} does not exist with {sites} sites"));
+        }
+        self.check_initial_frequencies()?;
+        if self.initial_frequencies.is_empty() && self.root_sites.is_empty() {
+            return Err("a simulation needs a root site".to_string());
+        }
+        if !self.organotropism.is_empty() && self.organotropism.len() != sites {
+            return Err(format!(
+                "organotropism has {} weights for {sites} sites",
+                self.organotropism.len()
+            ));
+        }
+        if let Some(w) = (self.organotropism.iter()).find(|w| !w.is_finite() || **w < 0.0) {
+            return Err(format!(
+                "organotropism weight {w} must be finite and not negative"
+            ));
+        }
+        for (name, p) in [
+            ("migration probability", self.migration_probability),
+            ("state switch probability", self.state_switch_probability),
+            ("burst probability", self.burst_probability),
+        ] {
+            if !(0.0..=1.0).contains(&p) {
+                return Err(format!("{name} must be between 0 and 1"));
+            }
+        }
+        if let FrequencyBias::Exponential { beta } = self.frequency_bias
+            && !(beta.is_finite() && beta >= 0.0)
+        {
+            return Err(format!(
+                "frequency bias beta {beta} must be finite and not negative"
+            ));
+        }
+        if self.states == 0 {
+            return Err("states must be at least 1".to_string());
+        }
+        if !self.state_migration.is_empty() && self.state_migration.len() != self.states {
+            return Err(format!(
+                "state migration has {} factors for {} states",
+                self.state_migration.len(),
+                self.states
+            ));
+        }
+        if self.branching == 0 || self.founder_branching == 0 {
+            return Err("branching and founder branching must be at least 1".to_string());
+        }
+        self.check_colonization_order()?;
+        self.check_pmatrix()
+    }
+}
+
+/// Progress of a simulation after one generation
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationReport {
+    pub generation: usize,
+    pub generations: usize,
+    /// Number of extant lineages
+    pub leaves: usize,
+    /// Number of nodes in the tree so far
+    pub nodes: usize,
+}
+
+/// Hooks called by a simulation while it runs
+pub trait Observer {
+    /// Called for every node as it is created, including the root
+    fn on_node(&mut self, _id: usize, _parent: Option<usize>, _label: usize, _length: Float) {}
+
+    /// Called for every lineage that dies, after the nodes created in the same generation
+    fn on_death(&mut self, _id: usize) {}
+
+    fn on_generation(&mut self, _report: GenerationReport) {}
+
+    /// Called with the migration matrix used to draw the children of `generation`
+    fn on_pmatrix(&mut self, _generation: usize, _pmatrix: &PMatrix) {}
+
+    /// Whether [`Observer::on_pmatrix`] is used, so runs with a sparse matrix only build
+    /// the dense one for observers that look at it
+    fn wants_pmatrix(&self) -> bool {
+        true
+    }
+}
+
+impl Observer for () {
+    fn wants_pmatrix(&self) -> bool {
+        false
+    }
+}
+
+/// Forward to both observers
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        self.0.on_node(id, parent, label, length);
+        self.1.on_node(id, parent, label, length);
+    }
+
+    fn on_death(&mut self, id: usize) {
+        self.0.on_death(id);
+        self.1.on_death(id);
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        self.0.on_generation(report);
+        self.1.on_generation(report);
+    }
+
+    fn on_pmatrix(&mut self, generation: usize, pmatrix: &PMatrix) {
+        self.0.on_pmatrix(generation, pmatrix);
+        self.1.on_pmatrix(generation, pmatrix);
+    }
+
+    fn wants_pmatrix(&self) -> bool {
+        self.0.wants_pmatrix() || self.1.wants_pmatrix()
+    }
+}
+
+impl<O: Observer> Observer for &mut O {
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        (**self).on_node(id, parent, label, length)
+    }
+
+    fn on_death(&mut self, id: usize) {
+        (**self).on_death(id)
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        (**self).on_generation(report)
+    }
+
+    fn on_pmatrix(&mut self, generation: usize, pmatrix: &PMatrix) {
+        (**self).on_pmatrix(generation, pmatrix)
+    }
+
+    fn wants_pmatrix(&self) -> bool {
+        (**self).wants_pmatrix()
+    }
+}
+
+/// Forward to the observer if there is one
+impl<O: Observer> Observer for Option<O> {
+    fn on_node(&mut self, id: usize, parent: Option<usize>, label: usize, length: Float) {
+        if let Some(o) = self {
+            o.on_node(id, parent, label, length)
+        }
+    }
+
+    fn on_death(&mut self, id: usize) {
+        if let Some(o) = self {
+            o.on_death(id)
+        }
+    }
+
+    fn on_generation(&mut self, report: GenerationReport) {
+        if let Some(o) = self {
+            o.on_generation(report)
+        }
+    }
+
+    fn on_pmatrix(&mut self, generation: usize, pmatrix: &PMatrix) {
+        if let Some(o) = self {
+            o.on_pmatrix(generation, pmatrix)
+        }
+    }
+
+    fn wants_pmatrix(&self) -> bool {
+        self.as_ref().is_some_and(|o| o.wants_pmatrix())
+    }
+}
+
+/// Records the migration matrix of every generation. With frequency dependent migration
+/// these are the realized transition matrices after rescaling
+#[derive(Debug, Clone, Default)]
+pub struct EffectiveMatrices {
+    pub matrices: Vec<Array2<Float>>,
+}
+
+impl EffectiveMatrices {
+    /// The matrices stacked along the first axis, indexed by `[generation - 1, from, to]`
+    pub fn to_array3(&self) -> Array3<Float> {
+        let views: Vec<_> = self.matrices.iter().map(|m| m.view()).collect();
+        ndarray::stack(Axis(0), &views).unwrap_or_else(|_| Array3::zeros((0, 0, 0)))
+    }
+}
+
+impl Observer for EffectiveMatrices {
+    fn on_pmatrix(&mut self, _generation: usize, pmatrix: &PMatrix) {
+        self.matrices.push(pmatrix.as_array().clone())
+    }
+}
+
+/// A migration between two different sites
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MigrationEvent {
+    pub generation: usize,
+    pub from: usize,
+    pub to: usize,
+    /// Node founded by the migrant
+    pub node: usize,
+}
+
+/// Everything a simulation run produced
+#[derive(Debug, Clone)]
+pub struct SimulationResult<T> {
+    tree: T,
+    migration_matrix: Array2<i64>,
+    frequencies_by_generation: Array2<Float>,
+    leaf_counts_by_generation: Array2<usize>,
+    transitions_by_generation: Array3<i64>,
+    events: Vec<MigrationEvent>,
+    params: Parameters,
+    states: Vec<usize>,
+    bursts: Vec<usize>,
+    truncation: Option<Truncation>,
+}
+
+impl<T> SimulationResult<T> {
+    pub fn new(
+        tree: T,
+        migration_matrix: Array2<i64>,
+        frequencies_by_generation: Array2<Float>,
+        events: Vec<MigrationEvent>,
+        params: Parameters,
+    ) -> Self {
+        Self {
+            tree,
+            migration_matrix,
+            frequencies_by_generation,
+            leaf_counts_by_generation: Array2::zeros((0, 0)),
+            transitions_by_generation: Array3::zeros((0, 0, 0)),
+            events,
+            params,
+            states: vec![],
+            bursts: vec![],
+            truncation: None,
+        }
+    }
+
+    /// Attach the generations that were migration bursts
+    pub fn with_bursts(mut self, bursts: Vec<usize>) -> Self {
+        self.bursts = bursts;
+        self
+    }
+
+    /// Mark the result as partial, stopped by its budget
+    pub fn with_truncation(mut self, truncation: Option<Truncation>) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Attach the number of extant lineages in each site after each generation
+    pub fn with_leaf_counts(mut self, counts: Array2<usize>) -> Self {
+        self.leaf_counts_by_generation = counts;
+        self
+    }
+
+    /// Attach the children drawn between each pair of sites in each generation
+    pub fn with_transitions(mut self, transitions: Array3<i64>) -> Self {
+        self.transitions_by_generation = transitions;
+        self
+    }
+
+    /// Attach the cell state of every node
+    pub fn with_states(mut self, states: Vec<usize>) -> Self {
+        self.states = states;
+        self
+    }
+
+    /// The simulated tree, only the root with summary retention
+    pub fn tree(&self) -> &T {
+        &self.tree
+    }
+
+    /// Number of children drawn in each site (columns) from each site (rows)
+    pub fn migration_matrix(&self) -> &Array2<i64> {
+        &self.migration_matrix
+    }
+
+    /// Fraction of extant lineages in each site (columns) after each generation (rows),
+    /// row 0 is the initial population
+    pub fn frequencies_by_generation(&self) -> &Array2<Float> {
+        &self.frequencies_by_generation
+    }
+
+    /// Extant lineages in each site (columns) after each generation (rows), row 0 is the
+    /// initial population. Unlike [`Phylogeny::site_counts_by_generation`] culled
+    /// lineages are gone, and it is kept with summary retention
+    pub fn leaf_counts_by_generation(&self) -> &Array2<usize> {
+        &self.leaf_counts_by_generation
+    }
+
+    /// [`Self::migration_matrix`] of every generation, generations + 1 x sites x sites.
+    /// Generation 0 is the initial population and has no children, the sum over all
+    /// generations is the migration matrix
+    pub fn transitions_by_generation(&self) -> &Array3<i64> {
+        &self.transitions_by_generation
+    }
+
+    /// Migrations between different sites in the order they happened, empty with summary
+    /// retention where there are no nodes to refer to
+    pub fn events(&self) -> &[MigrationEvent] {
+        &self.events
+    }
+
+    /// Generations that were migration bursts, in order
+    pub fn bursts(&self) -> &[usize] {
+        &self.bursts
+    }
+
+    /// What stopped a run before its last generation, `None` for a complete run
+    pub fn truncation(&self) -> Option<Truncation> {
+        self.truncation
+    }
+
+    /// Cell state of every node, `None` without a cell state layer
+    pub fn states(&self) -> Option<&[usize]> {
+        (!self.states.is_empty()).then_some(&self.states[..])
+    }
+
+    pub fn params(&self) -> &Parameters {
+        &self.params
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.params.seed
+    }
+
+    pub fn into_tree(self) -> T {
+        self.tree
+    }
+
+    /// The tree and migration matrix, the return value of simulations before results
+    pub fn into_parts(self) -> (T, Array2<i64>) {
+        (self.tree, self.migration_matrix)
+    }
+
+    /// Read-only handle to hand to analysis threads, cloning it does not copy the tree
+    pub fn into_shared(self) -> SharedResult<T> {
+        Arc::new(self)
+    }
+}
+
+impl SimulationResult<Phylogeny<usize, usize>> {
+    /// Grow the subtree below `node` again under `params`, keeping every other node, site
+    /// and branch, for counterfactuals such as a higher migration probability in one
+    /// lineage after its generation. The subtree grows as a run of its own from the site of
+    /// `node` over the generations left, drawing from lineage streams keyed by
+    /// `params.seed` and `node`, so regrowing under other parameters reuses the same random
+    /// numbers. Frequency dependent migration only sees the lineages of the subtree, cell
+    /// states restart in the first state and its bursts are not recorded. `params` must
+    /// keep the generations and sites; the result keeps the original parameters and nodes
+    /// are renumbered, with the regrown ones last
+    pub fn resimulate_subtree(&self, node: usize, params: &Parameters) -> Self {
+        assert_eq!(
+            (params.generations, params.sites),
+            (self.params.generations, self.params.sites),
+            "a subtree is regrown over the same generations and sites"
+        );
+        let generation = self.tree.generation(node);
+        let key = sub_seed(params.seed, &format!("subtree {node}"));
+        let grown = Phylogeny::simulate(
+            &Parameters {
+                generations: params.generations.saturating_sub(generation),
+                root_sites: vec![self.tree.nodes[node].label],
+                initial_frequencies: vec![],
+                seed: u64::from_le_bytes(key[..8].try_into().unwrap()),
+                retention: Retention::Full,
+                rng: RngScheme::Lineage,
+                ..params.clone()
+            },
+            &mut (),
+        );
+
+        let mut tree = self.tree.clone();
+        let remap = tree.remove_descendants(node);
+        let index = tree.graft(remap[node].unwrap(), grown.tree());
+        for (i, node) in tree.nodes.iter_mut().enumerate() {
+            node.data = i;
+        }
+
+        // take out what the old subtree contributed and add the regrown one
+        let depths = self.tree.depths();
+        let mut migration_matrix = self.migration_matrix.clone();
+        let mut leaf_counts = self.leaf_counts_by_generation.clone();
+        let mut transitions = self.transitions_by_generation.clone();
+        let label = |i: usize| self.tree.nodes[i].label;
+        for (parent, child, _) in self.tree.edges().filter(|&(_, c, _)| remap[c].is_none()) {
+            migration_matrix[[label(parent), label(child)]] -= 1;
+            if depths[child] < transitions.len_of(Axis(0)) {
+                transitions[[depths[child], label(parent), label(child)]] -= 1;
+            }
+            if self.tree.is_alive(child) && depths[child] < leaf_counts.nrows() {
+                leaf_counts[[depths[child], label(child)]] -= 1;
+            }
+        }
+        migration_matrix += grown.migration_matrix();
+        let regrown = grown.leaf_counts_by_generation.rows().into_iter().skip(1);
+        for (mut row, grown) in leaf_counts
+            .rows_mut()
+            .into_iter()
+            .skip(generation + 1)
+            .zip(regrown)
+        {
+            row += &grown;
+        }
+        let regrown = grown.transitions_by_generation.outer_iter().skip(1);
+        for (mut counts, grown) in transitions
+            .outer_iter_mut()
+            .skip(generation + 1)
+            .zip(regrown)
+        {
+            counts += &grown;
+        }
+        let mut frequencies = leaf_counts.mapv(|c| c as Float);
+        for mut row in frequencies.rows_mut() {
+            let total = row.sum();
+            row.mapv_inplace(|c| c.div(total));
+        }
+
+        let mut events: Vec<_> = self
+            .events
+            .iter()
+            .filter_map(|e| {
+                Some(MigrationEvent {
+                    node: remap[e.node]?,
+                    ..*e
+                })
+            })
+            .chain(grown.events().iter().map(|e| MigrationEvent {
+                node: index[e.node],
+                generation: e.generation + generation,
+                ..*e
+            }))
+            .collect();
+        events.sort_by_key(|e| e.generation);
+        let states = match (self.states(), grown.states()) {
+            (Some(states), Some(grown)) => (0..states.len())
+                .filter(|&i| remap[i].is_some())
+                .map(|i| states[i])
+                .chain(grown[1..].iter().copied())
+                .collect(),
+            _ => vec![],
+        };
+
+        SimulationResult::new(
+            tree,
+            migration_matrix,
+            frequencies,
+            events,
+            self.params.clone(),
+        )
+        .with_leaf_counts(leaf_counts)
+        .with_transitions(transitions)
+        .with_states(states)
+        .with_bursts(self.bursts.clone())
+        .with_truncation(self.truncation)
+    }
+}
+
+/// A simulation result shared between threads, see [`SimulationResult::into_shared`]
+pub type SharedResult<T = Phylogeny<usize, usize>> = Arc<SimulationResult<T>>;
+
+// results, trees and matrices are analyzed from many threads at once, keep them so
+const _: () = {
+    const fn send_sync<T: Send + Sync>() {}
+    send_sync::<Phylogeny<usize, usize>>();
+    send_sync::<PMatrix>();
+    send_sync::<Parameters>();
+    send_sync::<SimulationResult<Phylogeny<usize, usize>>>();
+    send_sync::<crate::model::Budget>();
+};
+
+pub trait Simulations {
+    /// Default of [`Parameters::branching`]
+    const BRANCHING: usize = 2;
+    fn yule_migrations(
+        lambda: f64,
+        g: usize,
+        n: usize,
+        m_prob: f64,
+        seed: u64,
+    ) -> SimulationResult<Self>
+    where
+        Self: Sized,
+    {
+        let params = Parameters {
+            birth_rate: lambda,
+            generations: g,
+            sites: n,
+            migration_probability: m_prob,
+            seed,
+            ..Default::default()
+        };
+        Self::simulate(&params, &mut ())
+    }
+
+    /// Run the simulation reporting progress to `observer`. For fixed parameters the run
+    /// is reproducible: node indices follow creation order (parents before children,
+    /// leaves in the order of the previous generation) and the random stream does not
+    /// depend on the platform, so every writer produces identical bytes
+    ///
+    /// # Panics
+    ///
+    /// If `params` are rejected by [`Parameters::check`], see [`Simulations::try_simulate`]
+    fn simulate<O: Observer>(params: &Parameters, observer: &mut O) -> SimulationResult<Self>
+    where
+        Self: Sized,
+    {
+        Self::simulate_with_budget(params, observer, &Budget::default())
+    }
+
+    /// [`Simulations::simulate`] that stops once `budget` is exceeded, returning the
+    /// generations simulated so far marked with [`SimulationResult::truncation`]
+    ///
+    /// # Panics
+    ///
+    /// If `params` are rejected by [`Parameters::check`]
+    fn simulate_with_budget<O: Observer>(
+        params: &Parameters,
+        observer: &mut O,
+        budget: &Budget,
+    ) -> SimulationResult<Self>
+    where
+        Self: Sized;
+
+    /// [`Simulations::simulate`] returning the error of [`Parameters::check`] instead of
+    /// panicking on parameters that can not be simulated
+    fn try_simulate<O: Observer>(
+        params: &Parameters,
+        observer: &mut O,
+    ) -> Result<SimulationResult<Self>, String>
+    where
+        Self: Sized,
+    {
+        Self::try_simulate_with_budget(params, observer, &Budget::default())
+    }
+
+    /// [`Simulations::simulate_with_budget`] returning the error of [`Parameters::check`]
+    fn try_simulate_with_budget<O: Observer>(
+        params: &Parameters,
+        observer: &mut O,
+        budget: &Budget,
+    ) -> Result<SimulationResult<Self>, String>
+    where
+        Self: Sized,
+    {
+        params.check()?;
+        Ok(Self::simulate_with_budget(params, observer, budget))
+    }
+}
+
+/// Cells of one lineage of the tree in one site, once a run tracks clone sizes
+#[derive(Debug, Clone, Copy)]
+struct Subclone {
+    /// Leaf of the tree standing for the clone
+    node: usize,
+    site: usize,
+    /// Extant lineage at the switch the clone descends from
+    lineage: usize,
+    cells: Float,
+}
+
+/// Most nodes reserved before a simulation, about a gigabyte
+const MAX_RESERVED_NODES: usize = 1 << 24;
+
+/// Fewest lineages worth a thread of their own when drawing lineages in parallel
+const MIN_LEAVES_PER_THREAD: usize = 4096;
+
+/// Migration matrix of a run in its [`MatrixStorage`]
+enum Migrations {
+    Dense(PMatrix),
+    /// With the dense matrix handed to observers, built on first use
+    Sparse(SparsePMatrix, OnceLock<PMatrix>),
+}
+
+impl Migrations {
+    fn new(pmatrix: PMatrix, storage: MatrixStorage) -> Self {
+        match storage {
+            MatrixStorage::Dense => Migrations::Dense(pmatrix),
+            MatrixStorage::Sparse => Self::sparse(SparsePMatrix::from_dense(&pmatrix)),
+        }
+    }
+
+    fn sparse(pmatrix: SparsePMatrix) -> Self {
+        Migrations::Sparse(pmatrix, OnceLock::new())
+    }
+
+    /// Apply `dense` or `sparse` to the matrix, keeping its storage
+    fn map(
+        &self,
+        dense: impl FnOnce(&PMatrix) -> PMatrix,
+        sparse: impl FnOnce(&SparsePMatrix) -> SparsePMatrix,
+    ) -> Self {
+        match self {
+            Migrations::Dense(p) => Migrations::Dense(dense(p)),
+            Migrations::Sparse(p, _) => Self::sparse(sparse(p)),
+        }
+    }
+
+    fn rescale_from_frequencies_with(
+        self,
+        frequencies: Array1<Float>,
+        bias: FrequencyBias,
+    ) -> Result<Self, PMatrixError> {
+        match self {
+            Migrations::Dense(p) => p
+                .rescale_from_frequencies_with(frequencies, bias)
+                .map(Migrations::Dense),
+            Migrations::Sparse(p, _) => p
+                .rescale_from_frequencies_with(frequencies, bias)
+                .map(Self::sparse),
+        }
+    }
+
+    fn with_organotropism(&self, weights: &[Float]) -> Self {
+        self.map(
+            |p| p.with_organotropism(weights),
+            |p| p.with_organotropism(weights),
+        )
+    }
+
+    fn without_destinations(&self, locked: &[bool]) -> Self {
+        self.map(
+            |p| p.without_destinations(locked),
+            |p| p.without_destinations(locked),
+        )
+    }
+
+    fn with_migration_scale(&self, factor: Float) -> Self {
+        self.map(
+            |p| p.with_migration_scale(factor),
+            |p| p.with_migration_scale(factor),
+        )
+    }
+
+    fn sample<R: RngCore>(&self, i: usize, rng: &mut R) -> Result<usize, PMatrixError> {
+        match self {
+            Migrations::Dense(p) => p.sample(i, rng),
+            Migrations::Sparse(p, _) => p.sample(i, rng),
+        }
+    }
+
+    /// Reusable constant time sampler for row `i`
+    fn sampler(&self, i: usize) -> Result<RowSampler, PMatrixError> {
+        match self {
+            Migrations::Dense(p) => Ok(RowSampler {
+                alias: p.sampler(i)?,
+                sites: None,
+            }),
+            Migrations::Sparse(p, _) => Ok(RowSampler {
+                alias: p.sampler(i)?,
+                sites: Some(p.row(i).map(|(j, _)| j).collect()),
+            }),
+        }
+    }
+
+    /// `(to, p)` entries of row `i`, every site with dense storage
+    fn row(&self, i: usize) -> Vec<(usize, Float)> {
+        match self {
+            Migrations::Dense(p) => p.as_array().row(i).iter().copied().enumerate().collect(),
+            Migrations::Sparse(p, _) => p.row(i).collect(),
+        }
+    }
+
+    fn dense(&self) -> &PMatrix {
+        match self {
+            Migrations::Dense(p) => p,
+            Migrations::Sparse(p, dense) => dense.get_or_init(|| p.to_dense()),
+        }
+    }
+}
+
+/// Destinations of one row of [`Migrations`], drawn in constant time
+struct RowSampler {
+    alias: AliasSampler,
+    /// Site of every entry of a sparse row, the entries are the sites of a dense one
+    sites: Option<Vec<usize>>,
+}
+
+impl RowSampler {
+    fn sample<R: RngCore>(&self, rng: &mut R) -> usize {
+        let entry = self.alias.sample(rng);
+        self.sites.as_ref().map_or(entry, |sites| sites[entry])
+    }
+}
+
+/// Draws of a child of a lineage
+#[derive(Debug, Clone, Copy)]
+struct ChildDraw {
+    label: usize,
+    length: Float,
+    state: usize,
+}
+
+/// Yule process with migration: every generation each lineage splits into `branching`
+/// children whose sites are drawn from the migration matrix of its own site. Nodes are
+/// labeled with `L`, the site indices by default
+pub struct YuleMigrations<L = usize> {
+    waiting_time: WaitingDistribution,
+    branch_units: BranchUnits,
+    branching: usize,
+    founder_generations: usize,
+    founder_branching: usize,
+    /// Last generation of the founder bottleneck of each site
+    bottleneck_until: Vec<Option<usize>>,
+    retention: Retention,
+    max_population: Option<usize>,
+    hybrid_threshold: Option<usize>,
+    /// Generator of the lineage streams with [`RngScheme::Lineage`]
+    lineages: Option<SimRng>,
+    threads: usize,
+    /// Clones replacing the extant lineages past the hybrid threshold
+    subclones: Vec<Subclone>,
+    /// Index of the clone of every lineage and site
+    subclone_index: HashMap<(usize, usize), usize>,
+    migration_mode: MigrationMode,
+    frequency_bias: FrequencyBias,
+    pmatrix: Migrations,
+    /// Attractiveness of every site as a migration destination, empty for none
+    organotropism: Vec<Float>,
+    /// `pmatrix` weighted by organotropism and without migrations into sites whose
+    /// prerequisites are not colonized, when either applies
+    effective: Option<Migrations>,
+    prerequisites: Vec<Vec<usize>>,
+    /// Sites that ever held a lineage
+    colonized: Vec<bool>,
+    burst_probability: f64,
+    burst_factor: Float,
+    /// Generations that were migration bursts
+    bursts: Vec<usize>,
+    /// Transitions between cell states, with more than one state
+    state_matrix: Option<PMatrix>,
+    state_migration: Vec<Float>,
+    /// The current migration matrix with the migration factor of each cell state
+    state_pmatrices: Vec<Migrations>,
+    /// Cell state of every node, with more than one state
+    states: Vec<usize>,
+    migration_matrix: Array2<i64>,
+    /// `migration_matrix` of every generation
+    transitions: Vec<Array2<i64>>,
+    /// Label of every site
+    labels: Vec<L>,
+    tree: Phylogeny<usize, L>,
+    /// Extant lineages and their sites, only tracked with full retention
+    leaves: Vec<(usize, usize)>,
+    /// Buffer of the previous generation's lineages, reused for the next one
+    next_leaves: Vec<(usize, usize)>,
+    /// Extant lineages per site
+    counts: Vec<usize>,
+    frequencies: Vec<Vec<Float>>,
+    /// `counts` after every generation
+    leaf_counts: Vec<Vec<usize>>,
+    events: Vec<MigrationEvent>,
+    generation: usize,
+    params: Parameters,
+}
+
+impl<L: SiteLabel> YuleMigrations<L> {
+    pub fn new(params: &Parameters, branching: usize) -> Self {
+        let n = params.sites;
+        let labels: Vec<L> = params
+            .site_names()
+            .iter()
+            .enumerate()
+            .map(|(i, name)| L::from_site(i, name))
+            .collect();
+        Self {
+            waiting_time: params.waiting_time.distribution(params.birth_rate),
+            branch_units: params.branch_units,
+            branching,
+            founder_generations: params.founder_generations,
+            founder_branching: params.founder_branching,
+            bottleneck_until: vec![None; n],
+            retention: params.retention,
+            max_population: params.max_population,
+            hybrid_threshold: params.hybrid_threshold,
+            lineages: (params.rng == RngScheme::Lineage)
+                .then(|| Component::Lineages.rng(params.seed)),
+            threads: params.threads.max(1),
+            subclones: vec![],
+            subclone_index: HashMap::new(),
+            migration_mode: params.migration_mode,
+            frequency_bias: params.frequency_bias,
+            pmatrix: Migrations::new(params.base_pmatrix(), params.matrix_storage),
+            organotropism: params.organotropism.iter().map(|&w| w as Float).collect(),
+            effective: None,
+            prerequisites: params.prerequisites(),
+            colonized: vec![false; n],
+            burst_probability: params.burst_probability,
+            burst_factor: params.burst_factor as Float,
+            bursts: vec![],
+            state_matrix: (params.states > 1).then(|| {
+                PMatrix::new_with_initial_conditions(
+                    params.states,
+                    params.state_switch_probability as Float,
+                )
+            }),
+            state_migration: match params.state_migration.is_empty() {
+                true => vec![1.0; params.states],
+                false => params.state_migration.iter().map(|&f| f as Float).collect(),
+            },
+            state_pmatrices: vec![],
+            states: vec![],
+            migration_matrix: Array2::zeros((n, n)),
+            transitions: vec![],
+            // replaced by the founders in `init`
+            tree: Phylogeny::new(Node::root(0, L::from_site(0, "")), 0.0),
+            labels,
+            leaves: vec![],
+            next_leaves: vec![],
+            counts: vec![0; n],
+            frequencies: vec![],
+            leaf_counts: vec![],
+            events: vec![],
+            generation: 0,
+            params: params.clone(),
+        }
+    }
+
+    pub fn finish(self) -> SimulationResult<Phylogeny<usize, L>> {
+        let sites = self.counts.len();
+        let frequencies = Array2::from_shape_vec(
+            (self.frequencies.len(), sites),
+            self.frequencies.into_iter().flatten().collect(),
+        )
+        .unwrap();
+        let leaf_counts = Array2::from_shape_vec(
+            (self.leaf_counts.len(), sites),
+            self.leaf_counts.into_iter().flatten().collect(),
+        )
+        .unwrap();
+        let transitions = Array3::from_shape_vec(
+            (self.transitions.len(), sites, sites),
+            self.transitions.iter().flatten().copied().collect(),
+        )
+        .unwrap();
+        let mut tree = self.tree;
+        for (node, &state) in tree.nodes.iter_mut().zip(&self.states) {
+            node.set_meta("state", state);
+        }
+        for subclone in &self.subclones {
+            tree.nodes[subclone.node].set_meta("cells", subclone.cells.round());
+        }
+        SimulationResult::new(
+            tree,
+            self.migration_matrix,
+            frequencies,
+            self.events,
+            self.params,
+        )
+        .with_leaf_counts(leaf_counts)
+        .with_transitions(transitions)
+        .with_states(self.states)
+        .with_bursts(self.bursts)
+    }
+
+    fn site_frequencies(&self) -> Vec<Float> {
+        let total = self.counts.iter().sum::<usize>() as Float;
+        self.counts
+            .iter()
+            .map(|&c| (c as Float).div(total))
+            .collect()
+    }
+
+    /// Count `children` drawn in `to` from `from` this generation. Hybrid runs count
+    /// cells, so the counts are 64 bit and only saturate past 2^63 cells
+    fn count(&mut self, from: usize, to: usize, children: i64) {
+        let total = &mut self.migration_matrix[[from, to]];
+        *total = total.saturating_add(children);
+        let generation = &mut self.transitions[self.generation][[from, to]];
+        *generation = generation.saturating_add(children);
+    }
+
+    fn record(&mut self, from: usize, to: usize, node: usize) {
+        self.count(from, to, 1);
+        if from != to {
+            self.events.push(MigrationEvent {
+                generation: self.generation,
+                from,
+                to,
+                node,
+            });
+        }
+    }
+
+    fn rescale(&mut self) {
+        if self.migration_mode == MigrationMode::FrequencyDependent {
+            let frequencies = self.site_frequencies();
+            let pmatrix = std::mem::replace(&mut self.pmatrix, Migrations::Dense(PMatrix::new(0)));
+            self.pmatrix = pmatrix
+                .rescale_from_frequencies_with(Array1::from_vec(frequencies), self.frequency_bias)
+                .expect("migration matrix cannot be balanced");
+        }
+    }
+
+    /// Weight destinations by organotropism, mask migrations into sites that are still
+    /// locked by the colonization order and scale migration up in a burst
+    fn update_effective(&mut self, burst: bool) {
+        for (site, &c) in self.counts.iter().enumerate() {
+            self.colonized[site] |= c > 0;
+        }
+        let locked: Vec<bool> = (0..self.counts.len())
+            .map(|s| {
+                !self.colonized[s] && self.prerequisites[s].iter().any(|&p| !self.colonized[p])
+            })
+            .collect();
+        // after rescaling, which would balance the organotropism weights away
+        let weighted = (!self.organotropism.is_empty())
+            .then(|| self.pmatrix.with_organotropism(&self.organotropism));
+        self.effective = match locked.contains(&true) {
+            true => Some(
+                weighted
+                    .as_ref()
+                    .unwrap_or(&self.pmatrix)
+                    .without_destinations(&locked),
+            ),
+            false => weighted,
+        };
+        if burst {
+            let current = self.effective.as_ref().unwrap_or(&self.pmatrix);
+            self.effective = Some(current.with_migration_scale(self.burst_factor));
+        }
+        if self.state_matrix.is_some() {
+            let current = self.effective.as_ref().unwrap_or(&self.pmatrix);
+            self.state_pmatrices = self
+                .state_migration
+                .iter()
+                .map(|&f| current.with_migration_scale(f))
+                .collect();
+        }
+    }
+
+    /// Waiting time of a lineage, in the branch units of the run
+    fn branch_length<R: Rng + ?Sized>(&self, rng: &mut R) -> Float {
+        self.branch_units.length(self.waiting_time.sample(rng))
+    }
+
+    /// Children of every split of a lineage in `site` this generation
+    fn branching_of(&self, site: usize) -> usize {
+        match self.bottleneck_until[site] {
+            Some(until) if self.generation <= until => self.founder_branching,
+            _ => self.branching,
+        }
+    }
+
+    /// Most nodes the tree can reach, to reserve it up front. Stops counting at
+    /// [`MAX_RESERVED_NODES`], a run that large grows the tree as it goes
+    fn node_capacity(&self) -> usize {
+        self.params.max_nodes(MAX_RESERVED_NODES)
+    }
+
+    /// Start the founder bottleneck of the sites that were empty before this generation
+    fn found(&mut self, before: &[usize]) {
+        if self.founder_generations == 0 {
+            return;
+        }
+        for (site, (&was, &is)) in before.iter().zip(&self.counts).enumerate() {
+            if was == 0 && is > 0 {
+                self.bottleneck_until[site] = Some(self.generation + self.founder_generations);
+            }
+        }
+    }
 
-use ndarray::{Array1, Array2};
+    /// Migration matrix to draw from this generation
+    fn current(&self) -> &Migrations {
+        self.effective.as_ref().unwrap_or(&self.pmatrix)
+    }
 
-use rand::{SeedableRng, rngs::StdRng};
-use rand_distr::{Distribution, Exp};
+    /// Lineages per site replace the list of leaves
+    fn step_summary(&mut self, rng: &mut Streams) -> StepOutcome {
+        let n = self.counts.len();
+        let mut new_counts = vec![0; n];
+        for (label, c) in self.counts.clone().into_iter().enumerate() {
+            if c == 0 {
+                continue;
+            }
+            let sampler = self
+                .current()
+                .sampler(label)
+                .expect("migration matrix row cannot be sampled");
+            for _ in 0..c * self.branching_of(label) {
+                let next_label = sampler.sample(&mut rng.migration);
+                new_counts[next_label] += 1;
+                self.count(label, next_label, 1);
+            }
+        }
+        self.counts = new_counts;
 
-use crate::{
-    pmatrix::PMatrix,
-    tree::{Node, Phylogeny},
-};
+        // cull one lineage at a time from a site chosen by its current size
+        if let Some(cap) = self.max_population {
+            let mut total: usize = self.counts.iter().sum();
+            while total > cap {
+                // drawn as u64 so 32 and 64 bit platforms see the same stream
+                let mut r = rng.tree.gen_range(0..total as u64) as usize;
+                let site = self
+                    .counts
+                    .iter()
+                    .position(|&c| {
+                        let hit = r < c;
+                        r = r.saturating_sub(c);
+                        hit
+                    })
+                    .unwrap();
+                self.counts[site] -= 1;
+                total -= 1;
+            }
+        }
 
-pub trait Simulations {
-    const BRANCHING: usize = 2;
-    fn yule_migrations(
-        lambda: f64,
-        g: usize,
-        n: usize,
-        m_prob: f64,
-        seed: u64,
-    ) -> (Self, Array2<i32>)
-    where
-        Self: Sized;
-}
+        let leaves = self.counts.iter().sum();
+        StepOutcome {
+            nodes: vec![],
+            leaves,
+            total_nodes: 1,
+            deaths: vec![],
+            extinct: leaves == 0,
+        }
+    }
 
-impl Simulations for Phylogeny<usize, usize> {
-    fn yule_migrations(
-        lambda: f64,
-        g: usize,
-        n: usize,
-        m_prob: f64,
-        seed: u64,
-    ) -> (Self, Array2<i32>) {
-        let exp_dist = Exp::new(lambda).unwrap();
-        let mut rng = StdRng::seed_from_u64(seed);
+    /// Replace the extant lineages by clones of one cell each
+    fn switch_to_subclones(&mut self) {
+        for (leaf, site) in std::mem::take(&mut self.leaves) {
+            self.subclone_index
+                .insert((leaf, site), self.subclones.len());
+            self.subclones.push(Subclone {
+                node: leaf,
+                site,
+                lineage: leaf,
+                cells: 1.0,
+            });
+        }
+    }
 
-        let mut migration_matrix: Array2<i32> = Array2::zeros((n, n));
+    /// Clone sizes replace the lineages past the hybrid threshold: the children of a clone
+    /// stay in its site deterministically, except for Poisson numbers of migrants to
+    /// every other site which join the clone of the same lineage there. A new clone
+    /// splits the node of its source so the genealogy of the lineages stays exact
+    fn step_subclones(&mut self, rng: &mut Streams) -> StepOutcome {
+        let mut nodes = vec![];
+        let mut cells: Vec<Float> = vec![0.0; self.subclones.len()];
+        for i in 0..self.subclones.len() {
+            let Subclone {
+                site,
+                lineage,
+                cells: size,
+                ..
+            } = self.subclones[i];
+            let state = self
+                .states
+                .get(self.subclones[i].node)
+                .copied()
+                .unwrap_or(0);
+            let pmatrix = match self.state_matrix {
+                Some(_) => &self.state_pmatrices[state],
+                None => self.current(),
+            };
+            let row = pmatrix.row(site);
+            let total: Float = row.iter().map(|&(_, p)| p).sum();
+            let offspring = size * self.branching_of(site) as Float;
+            let mut stay = offspring;
+            for (to, p) in row {
+                let lambda = offspring * p / total;
+                if to == site || lambda <= 0.0 || stay <= 0.0 {
+                    continue;
+                }
+                let migrants = Poisson::new(lambda)
+                    .unwrap()
+                    .sample(&mut rng.migration)
+                    .min(stay);
+                if migrants == 0.0 {
+                    continue;
+                }
+                stay -= migrants;
+                self.count(site, to, migrants as i64);
+                let target = match self.subclone_index.get(&(lineage, to)) {
+                    Some(&target) => target,
+                    None => {
+                        let source = self.subclones[i].node;
+                        let mut split = |label: usize, yule: &mut Self| {
+                            let length = yule.branch_length(&mut rng.tree);
+                            let idx = yule.tree.nodes.len();
+                            let site = yule.labels[label].clone();
+                            yule.tree.add_child(source, idx, site, length);
+                            if yule.state_matrix.is_some() {
+                                yule.states.push(state);
+                            }
+                            nodes.push(NodeEvent {
+                                id: idx,
+                                parent: Some(source),
+                                label,
+                                length,
+                            });
+                            idx
+                        };
+                        self.subclones[i].node = split(site, self);
+                        let node = split(to, self);
+                        self.events.push(MigrationEvent {
+                            generation: self.generation,
+                            from: site,
+                            to,
+                            node,
+                        });
+                        self.subclone_index
+                            .insert((lineage, to), self.subclones.len());
+                        self.subclones.push(Subclone {
+                            node,
+                            site: to,
+                            lineage,
+                            cells: 0.0,
+                        });
+                        cells.push(0.0);
+                        self.subclones.len() - 1
+                    }
+                };
+                cells[target] += migrants;
+            }
+            self.count(site, site, stay as i64);
+            cells[i] += stay;
+        }
 
-        let mut pmatrix = PMatrix::new_with_initial_conditions(n, m_prob);
+        // cull every clone by the same fraction
+        let total: Float = cells.iter().sum();
+        if let Some(cap) = self.max_population.filter(|&cap| total > cap as Float) {
+            let keep = cap as Float / total;
+            cells.iter_mut().for_each(|c| *c *= keep);
+        }
 
-        let root = Node::root(0usize, 0);
-        let mut tree: Phylogeny<usize, usize> = Phylogeny::new(root, exp_dist.sample(&mut rng));
+        self.counts.fill(0);
+        for (subclone, cells) in self.subclones.iter_mut().zip(cells) {
+            subclone.cells = cells;
+            self.counts[subclone.site] += cells.round() as usize;
+        }
+        let leaves = self.counts.iter().sum();
+        StepOutcome {
+            nodes,
+            leaves,
+            total_nodes: self.tree.nodes.len(),
+            deaths: vec![],
+            extinct: leaves == 0,
+        }
+    }
 
-        let mut idx = 1usize;
-        let mut leaves: Vec<(usize, usize)> = vec![(0, 0)];
-        let mut frequencies = vec![0.0; n];
-        frequencies[0] = 1.0;
+    /// Site, branch length and cell state of every child of the lineages, drawn from
+    /// the stream of each lineage. Lineages are split into one block per thread
+    fn draw_lineages(&self, leaves: &[(usize, usize)], children: usize) -> Vec<ChildDraw> {
+        let Some(lineages) = &self.lineages else {
+            unreachable!("only called with lineage streams")
+        };
+        let draw_block = |block: &[(usize, usize)]| {
+            let mut draws = Vec::with_capacity(block.len() * children / leaves.len().max(1));
+            for &(leaf, label) in block {
+                let mut rng = Streams::lineage(lineages, leaf);
+                let state = self.states.get(leaf).copied().unwrap_or(0);
+                for _ in 0..self.branching_of(label) {
+                    draws.push(self.draw_child(label, state, &mut rng));
+                }
+            }
+            draws
+        };
+        // threads only pay off for large generations
+        if self.threads == 1 || leaves.len() < MIN_LEAVES_PER_THREAD * 2 {
+            return draw_block(leaves);
+        }
+        let block = leaves
+            .len()
+            .div_ceil(self.threads)
+            .max(MIN_LEAVES_PER_THREAD);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = leaves
+                .chunks(block)
+                .map(|block| scope.spawn(move || draw_block(block)))
+                .collect();
+            let mut draws = Vec::with_capacity(children);
+            for handle in handles {
+                draws.extend(handle.join().expect("a branching thread panicked"));
+            }
+            draws
+        })
+    }
 
-        for _ in 0..g {
-            pmatrix = pmatrix.rescale_from_frequencies(Array1::from_vec(frequencies));
+    /// Site, branch length and cell state of a child of a lineage in `label` and `state`
+    fn draw_child(&self, label: usize, state: usize, rng: &mut Streams) -> ChildDraw {
+        let pmatrix = match self.state_matrix {
+            Some(_) => &self.state_pmatrices[state],
+            None => self.current(),
+        };
+        let label = pmatrix
+            .sample(label, &mut rng.migration)
+            .expect("migration matrix row cannot be sampled");
+        let length = self.branch_length(&mut rng.tree);
+        let state = self.state_matrix.as_ref().map_or(0, |states| {
+            states
+                .sample(state, &mut rng.migration)
+                .expect("cell state matrix row cannot be sampled")
+        });
+        ChildDraw {
+            label,
+            length,
+            state,
+        }
+    }
 
-            let mut new_counts = vec![0; n];
-            let mut new_leaves = vec![];
-            for &(leaf, label) in &leaves {
-                for _ in 0..Self::BRANCHING {
-                    let next_label = pmatrix.sample(label, &mut rng);
-                    new_counts[next_label] += 1;
+    fn step_full(&mut self, rng: &mut Streams) -> StepOutcome {
+        let children = self.leaves.len() * self.branching.max(self.founder_branching);
+        let mut nodes = Vec::with_capacity(children);
+        let mut new_counts = vec![0; self.counts.len()];
+        let mut new_leaves = std::mem::take(&mut self.next_leaves);
+        new_leaves.clear();
+        new_leaves.reserve(children);
+        let leaves = std::mem::take(&mut self.leaves);
+        let mut drawn = match self.lineages {
+            Some(_) => self.draw_lineages(&leaves, children),
+            None => vec![],
+        }
+        .into_iter();
+        for &(leaf, label) in &leaves {
+            let state = self.states.get(leaf).copied().unwrap_or(0);
+            for _ in 0..self.branching_of(label) {
+                let child = match self.lineages {
+                    Some(_) => drawn.next().unwrap(),
+                    None => self.draw_child(label, state, rng),
+                };
+                let (next_label, length) = (child.label, child.length);
+                new_counts[next_label] += 1;
 
-                    tree.add_child(leaf, idx, next_label, exp_dist.sample(&mut rng));
-                    new_leaves.push((idx, next_label));
+                let idx = self.tree.nodes.len();
+                let site = self.labels[next_label].clone();
+                self.tree.add_child(leaf, idx, site, length);
+                if self.state_matrix.is_some() {
+                    self.states.push(child.state);
+                }
+                nodes.push(NodeEvent {
+                    id: idx,
+                    parent: Some(leaf),
+                    label: next_label,
+                    length,
+                });
+                new_leaves.push((idx, next_label));
 
-                    migration_matrix[[label, next_label]] += 1;
-                    idx += 1;
+                self.record(label, next_label, idx);
+            }
+        }
+        let mut deaths = vec![];
+        if let Some(cap) = self.max_population.filter(|&cap| new_leaves.len() > cap) {
+            // keep a random subset of lineages, in their original order
+            let mut keep = vec![false; new_leaves.len()];
+            for i in index::sample(&mut rng.tree, new_leaves.len(), cap) {
+                keep[i] = true;
+            }
+            let mut i = 0;
+            new_leaves.retain(|&(leaf, label)| {
+                let kept = keep[i];
+                i += 1;
+                if !kept {
+                    self.tree.kill(leaf);
+                    deaths.push(leaf);
+                    new_counts[label] -= 1;
                 }
+                kept
+            });
+        }
+
+        self.counts = new_counts;
+        self.leaves = new_leaves;
+        self.next_leaves = leaves;
+
+        StepOutcome {
+            nodes,
+            deaths,
+            leaves: self.leaves.len(),
+            total_nodes: self.tree.nodes.len(),
+            extinct: self.leaves.is_empty(),
+        }
+    }
+}
+
+impl<L: SiteLabel> Model for YuleMigrations<L> {
+    fn init(&mut self, rng: &mut Streams) -> StepOutcome {
+        let founders = (self.params.founder_sites()).expect("founders of checked parameters");
+        assert!(!founders.is_empty(), "a simulation needs a root site");
+        let root_length = self.branch_length(&mut rng.tree);
+        let root = Node::root(0, self.labels[founders[0]].clone());
+        self.tree = Phylogeny::new(root, root_length);
+        if self.retention == Retention::Full {
+            let capacity = self.node_capacity();
+            self.tree.reserve(capacity);
+            if self.state_matrix.is_some() {
+                self.states.reserve(capacity);
             }
-            frequencies = new_counts
-                .iter()
-                .map(|&c| (c as f64).div(new_leaves.len() as f64))
-                .collect();
-            leaves = new_leaves;
         }
+        self.counts.fill(0);
+        self.colonized.fill(false);
+        self.bottleneck_until.fill(None);
+        self.bursts.clear();
+        self.subclones.clear();
+        self.subclone_index.clear();
+        self.leaves.clear();
+        let mut nodes = vec![NodeEvent {
+            id: 0,
+            parent: None,
+            label: founders[0],
+            length: root_length,
+        }];
 
-        (tree, migration_matrix)
+        if let [site] = founders[..] {
+            self.counts[site] = 1;
+            if self.retention == Retention::Full {
+                self.leaves.push((0, site));
+            }
+        } else {
+            self.tree.nodes[0].set_meta("founders", founders.len());
+            for &site in &founders {
+                self.counts[site] += 1;
+                if self.retention == Retention::Full {
+                    let length = self.branch_length(&mut rng.tree);
+                    let label = self.labels[site].clone();
+                    let id = self.tree.add_child(0, self.tree.nodes.len(), label, length);
+                    self.leaves.push((id, site));
+                    nodes.push(NodeEvent {
+                        id,
+                        parent: Some(0),
+                        label: site,
+                        length,
+                    });
+                }
+            }
+        }
+        if self.retention == Retention::Full && self.state_matrix.is_some() {
+            // every founder starts in the first cell state
+            self.states.clear();
+            self.states.resize(self.tree.nodes.len(), 0);
+        }
+        self.frequencies = vec![self.site_frequencies()];
+        self.leaf_counts = vec![self.counts.clone()];
+        let n = self.counts.len();
+        self.transitions = vec![Array2::zeros((n, n))];
+
+        StepOutcome {
+            leaves: founders.len(),
+            total_nodes: nodes.len(),
+            nodes,
+            deaths: vec![],
+            extinct: false,
+        }
+    }
+
+    fn step(&mut self, rng: &mut Streams) -> StepOutcome {
+        self.generation += 1;
+        let n = self.counts.len();
+        self.transitions.push(Array2::zeros((n, n)));
+        self.rescale();
+        // only drawn with bursts on, so gradual runs keep their random stream
+        let burst = self.burst_probability > 0.0 && rng.migration.gen_bool(self.burst_probability);
+        if burst {
+            self.bursts.push(self.generation);
+        }
+        self.update_effective(burst);
+        let before = self.counts.clone();
+        let outcome = match self.retention {
+            Retention::Full if !self.subclones.is_empty() => self.step_subclones(rng),
+            Retention::Full => {
+                let outcome = self.step_full(rng);
+                if self.hybrid_threshold.is_some_and(|t| self.leaves.len() > t) {
+                    self.switch_to_subclones();
+                }
+                outcome
+            }
+            Retention::Summary => self.step_summary(rng),
+        };
+        self.found(&before);
+        self.frequencies.push(self.site_frequencies());
+        self.leaf_counts.push(self.counts.clone());
+        outcome
+    }
+
+    fn pmatrix(&self) -> Option<&PMatrix> {
+        Some(self.current().dense())
+    }
+}
+
+impl Simulations for Phylogeny<usize, usize> {
+    fn simulate_with_budget<O: Observer>(
+        params: &Parameters,
+        observer: &mut O,
+        budget: &Budget,
+    ) -> SimulationResult<Self> {
+        simulate_yule(params, observer, budget)
+    }
+}
+
+impl<L: SiteLabel> Phylogeny<usize, L> {
+    /// [`Simulations::simulate`] labeling nodes with `L`, e.g. [`Site`](crate::site::Site) to carry the
+    /// [`Parameters::names`]. The tree, matrices and random draws are those of the run
+    /// labeled by index. Panics like [`Simulations::simulate`]
+    pub fn simulate_labeled<O: Observer>(
+        params: &Parameters,
+        observer: &mut O,
+    ) -> SimulationResult<Self> {
+        simulate_yule(params, observer, &Budget::default())
+    }
+}
+
+fn simulate_yule<L: SiteLabel, O: Observer>(
+    params: &Parameters,
+    observer: &mut O,
+    budget: &Budget,
+) -> SimulationResult<Phylogeny<usize, L>> {
+    if let Err(e) = params.check() {
+        panic!("invalid parameters: {e}");
     }
+    let mut model = YuleMigrations::<L>::new(params, params.branching);
+    let truncation = drive_with_budget(
+        &mut model,
+        params.generations,
+        params.seed,
+        observer,
+        budget,
+    );
+    model.finish().with_truncation(truncation)
 }
 
 #[test]
 fn test_yule_migrations() {
-    use crate::visualizations::{graph_from_edge_matrix, save_graph_png};
-
     let sites = 6;
-    let (_tree, migration_matrix) = Phylogeny::yule_migrations(0.2, 10, sites, 0.015, 42);
+    let (_tree, migration_matrix) =
+        Phylogeny::yule_migrations(0.2, 10, sites, 0.015, 42).into_parts();
     for i in 0..sites {
         for j in 0..sites {
             println!("{i} -> {j}: {}", migration_matrix[[i, j]])
         }
     }
 
-    let g = graph_from_edge_matrix(migration_matrix);
-    match save_graph_png(&g, "test_mig_graph.png") {
-        Ok(_) => println!("Save to test_mig_graph.png"),
-        Err(e) => println!("{e}"),
+    #[cfg(feature = "viz")]
+    {
+        use crate::visualizations::{graph_from_edge_matrix, save_graph_png};
+
+        let g = graph_from_edge_matrix(migration_matrix);
+        let stem = std::env::temp_dir().join("test_mig_graph");
+        match save_graph_png(&g, &stem) {
+            Ok(_) => println!("Save to {}", stem.with_extension("png").display()),
+            Err(e) => println!("{e}"),
+        }
+    }
+}
+
+#[test]
+fn test_max_nodes() {
+    let params = crate::examples::toy_parameters();
+    let result = Phylogeny::simulate(&params, &mut ());
+    assert_eq!(params.max_nodes(usize::MAX), result.tree().nodes.len());
+    assert_eq!(params.max_nodes(100), 100);
+
+    let params = Parameters {
+        max_population: Some(10),
+        ..params
+    };
+    // 2, 4, 8 then 16 born from the 10 kept of every generation
+    assert_eq!(params.max_nodes(usize::MAX), 1 + 2 + 4 + 8 + 16 + 20 + 20);
+    let huge = Parameters {
+        generations: 2_000_000_000,
+        max_population: None,
+        ..params
+    };
+    // stops counting once saturated
+    assert_eq!(huge.max_nodes(usize::MAX), usize::MAX);
+    // no nodes past 1000 lineages, the 1024 leaves of 2^11 - 1 nodes
+    let hybrid = Parameters {
+        hybrid_threshold: Some(1000),
+        ..huge
+    };
+    assert_eq!(hybrid.max_nodes(usize::MAX), (1 << 11) - 1);
+
+    let p = Parameters {
+        organotropism: vec![1.0, 3.0, 0.0],
+        ..crate::examples::toy_parameters()
+    }
+    .pmatrix();
+    p.validate().unwrap();
+    assert_eq!(p.as_array()[[0, 2]], 0.0);
+    assert!((p.as_array()[[0, 1]] - 0.1).abs() < 1e-6);
+}
+
+#[test]
+fn test_summary_retention() {
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.2,
+        retention: Retention::Summary,
+        ..Default::default()
+    };
+    let (tree, migration_matrix) = Phylogeny::simulate(&params, &mut ()).into_parts();
+
+    assert_eq!(tree.nodes.len(), 1);
+    // every lineage of every generation is counted once: 2 + 4 + ... + 2^6
+    assert_eq!(migration_matrix.sum(), (1 << 7) - 2);
+}
+
+#[test]
+fn test_max_population() {
+    for retention in [Retention::Full, Retention::Summary] {
+        let params = Parameters {
+            generations: 8,
+            max_population: Some(20),
+            retention,
+            ..Default::default()
+        };
+        let mut leaves = vec![];
+        struct Leaves<'a>(&'a mut Vec<usize>);
+        impl Observer for Leaves<'_> {
+            fn on_generation(&mut self, report: GenerationReport) {
+                self.0.push(report.leaves)
+            }
+        }
+        let tree = Phylogeny::simulate(&params, &mut Leaves(&mut leaves)).into_tree();
+
+        assert_eq!(leaves, vec![2, 4, 8, 16, 20, 20, 20, 20]);
+        if retention == Retention::Full {
+            let alive = tree.extant_leaves().count();
+            assert_eq!(alive, 20);
+        }
+    }
+}
+
+#[test]
+fn test_fixed_migration() {
+    let params = Parameters {
+        generations: 8,
+        migration_probability: 0.1,
+        migration_mode: MigrationMode::Fixed,
+        ..Default::default()
+    };
+    let (_, migration_matrix) = Phylogeny::simulate(&params, &mut ()).into_parts();
+
+    // with a fixed matrix every draw migrates with probability m_prob, here out of 510 draws
+    let migrations = migration_matrix.sum() - migration_matrix.diag().sum();
+    assert!((25..80).contains(&migrations), "{migrations}");
+}
+
+#[test]
+fn test_effective_matrices() {
+    let params = Parameters {
+        generations: 5,
+        sites: 3,
+        migration_probability: 0.1,
+        ..Default::default()
+    };
+    let mut effective = EffectiveMatrices::default();
+    Phylogeny::simulate(&params, &mut effective);
+
+    let stacked = effective.to_array3();
+    assert_eq!(stacked.dim(), (5, 3, 3));
+    // rescaling keeps every matrix stochastic while changing it between generations
+    for m in &effective.matrices {
+        for row in m.rows() {
+            assert!((row.sum() - 1.0).abs() < 1e-6);
+        }
+    }
+    assert_ne!(effective.matrices[0], effective.matrices[4]);
+}
+
+#[test]
+fn test_source_only_primary() {
+    let params = Parameters {
+        generations: 8,
+        sites: 4,
+        migration_probability: 0.2,
+        source_only: vec![0],
+        absorbing: vec![3],
+        ..Default::default()
+    };
+    let (_, migration_matrix) = Phylogeny::simulate(&params, &mut ()).into_parts();
+
+    // one-way seeding: nothing returns to the primary and the absorbing site seeds nothing
+    assert_eq!(migration_matrix.column(0).sum(), migration_matrix[[0, 0]]);
+    assert_eq!(migration_matrix.row(3).sum(), migration_matrix[[3, 3]]);
+    assert!(migration_matrix.row(0).sum() > migration_matrix[[0, 0]]);
+}
+
+#[test]
+fn test_sparse_storage() {
+    let params = Parameters {
+        generations: 8,
+        sites: 5,
+        migration_probability: 0.2,
+        source_only: vec![0],
+        absorbing: vec![3],
+        organotropism: vec![1.0, 1.0, 2.0, 1.0, 0.0],
+        burst_probability: 0.3,
+        matrix_storage: MatrixStorage::Sparse,
+        ..Default::default()
+    };
+    let mut effective = EffectiveMatrices::default();
+    let result = Phylogeny::simulate(&params, &mut effective);
+    let m = result.migration_matrix();
+
+    // the roles and the zero organotropism hold as with a dense matrix
+    assert_eq!(m.column(0).sum(), m[[0, 0]]);
+    assert_eq!(m.row(3).sum(), m[[3, 3]]);
+    assert_eq!(m.column(4).sum(), m[[4, 4]]);
+    assert!(m.row(0).sum() > m[[0, 0]]);
+    assert_eq!(effective.matrices.len(), params.generations);
+    for p in &effective.matrices {
+        PMatrix::from_array(p.clone()).validate().unwrap();
+        assert_eq!(p[[1, 0]], 0.0);
+        assert!((p[[3, 3]] - 1.0).abs() < 1e-4);
+        assert_eq!(p[[1, 4]], 0.0);
+    }
+
+    // without an observer of the matrix, and drawing per site and per clone
+    for params in [
+        Parameters {
+            retention: Retention::Summary,
+            ..params.clone()
+        },
+        Parameters {
+            hybrid_threshold: Some(20),
+            ..params.clone()
+        },
+    ] {
+        let m = Phylogeny::simulate(&params, &mut ())
+            .migration_matrix()
+            .clone();
+        assert_eq!(m.column(0).sum(), m[[0, 0]]);
+        assert_eq!(m.row(3).sum(), m[[3, 3]]);
+        assert!(m.row(0).sum() > m[[0, 0]]);
+    }
+}
+
+#[test]
+fn test_simulation_result() {
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.2,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+
+    assert_eq!(result.seed(), params.seed);
+    let frequencies = result.frequencies_by_generation();
+    assert_eq!(frequencies.dim(), (7, 3));
+    assert_eq!(frequencies.row(0).to_vec(), vec![1.0, 0.0, 0.0]);
+    let counts = result.leaf_counts_by_generation();
+    assert_eq!(counts.dim(), (7, 3));
+    for (g, row) in counts.rows().into_iter().enumerate() {
+        assert_eq!(row.sum(), 1 << g);
+    }
+
+    // every off diagonal count is one event founding a node in the target site
+    let m = result.migration_matrix();
+    assert_eq!(result.events().len() as i64, m.sum() - m.diag().sum());
+    let depths = result.tree().depths();
+    for event in result.events() {
+        assert_eq!(result.tree().nodes[event.node].label, event.to);
+        assert_eq!(depths[event.node], event.generation);
+    }
+
+    // the matrix of every generation counts its children, and they add up to the total
+    let transitions = result.transitions_by_generation();
+    assert_eq!(transitions.dim(), (7, 3, 3));
+    assert_eq!(transitions.sum_axis(Axis(0)), m);
+    for (g, counts) in transitions.outer_iter().enumerate().skip(1) {
+        assert_eq!(counts.sum(), 1 << g);
+        let migrations = result.events().iter().filter(|e| e.generation == g).count();
+        assert_eq!(counts.sum() - counts.diag().sum(), migrations as i64);
+    }
+    assert_eq!(transitions.index_axis(Axis(0), 0).sum(), 0);
+}
+
+#[test]
+fn test_deterministic_outputs() {
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.2,
+        seed: 7,
+        max_population: Some(40),
+        ..Default::default()
+    };
+    let outputs = || {
+        let (tree, migration_matrix) = Phylogeny::simulate(&params, &mut ()).into_parts();
+        let mut buf = vec![];
+        tree.write_csv(&mut buf).unwrap();
+        tree.write_csv_vertex_labeling(&mut buf).unwrap();
+        tree.write_csv_leaf_labeling(&mut buf).unwrap();
+        #[cfg(feature = "viz")]
+        {
+            use crate::visualizations::{DotStyle, graph_from_edge_matrix, to_dot};
+
+            let g = graph_from_edge_matrix(migration_matrix);
+            buf.extend(to_dot(&g, &DotStyle::default()).bytes());
+        }
+        #[cfg(not(feature = "viz"))]
+        buf.extend(format!("{migration_matrix}").bytes());
+        buf
+    };
+
+    // same seed, same bytes - and the same bytes as on every other platform and build
+    let first = outputs();
+    assert_eq!(first, outputs());
+    #[cfg(all(feature = "viz", not(feature = "f32")))]
+    assert_eq!(
+        crate::manifest::sha256_hex(&first),
+        "bee6b25f62dab648d181145f3b985c816b939d24ad148ca0d916b48d0706ca26"
+    );
+}
+
+#[test]
+fn test_independent_streams() {
+    // the migration model draws from its own stream, the tree is the same under each
+    let lengths = |migration_probability, burst_probability| {
+        let params = Parameters {
+            migration_probability,
+            burst_probability,
+            ..Default::default()
+        };
+        let result = Phylogeny::simulate(&params, &mut ());
+        let edges: Vec<_> = result.tree().edges().collect();
+        (edges, result.events().len())
+    };
+    let (edges, events) = lengths(0.01, 0.0);
+    let (other, other_events) = lengths(0.3, 0.5);
+    assert_eq!(edges, other);
+    assert!(other_events > events);
+}
+
+#[test]
+fn test_yule_statistics() {
+    // every lineage splits each generation, so without culling there are 2^g leaves
+    let g = 10;
+    let params = Parameters {
+        generations: g,
+        sites: 4,
+        migration_probability: 0.05,
+        migration_mode: MigrationMode::Fixed,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    assert_eq!(tree.leaves().count(), 1 << g);
+
+    // with a fixed matrix each of the 2^(g+1) - 2 draws migrates with probability m_prob
+    let draws = ((1 << (g + 1)) - 2) as f64;
+    let m = result.migration_matrix();
+    let migrations = (m.sum() - m.diag().sum()) as f64;
+    let p = params.migration_probability;
+    let z = (migrations - draws * p) / (draws * p * (1.0 - p)).sqrt();
+    assert!(z.abs() < 4.0, "z = {z}");
+
+    // branch lengths are Exp(lambda): Kolmogorov-Smirnov distance to its cdf, with the
+    // 99.9% critical value 1.95 / sqrt(n)
+    let mut lengths: Vec<Float> = tree.edges().map(|(_, _, l)| l).collect();
+    lengths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = lengths.len() as Float;
+    let d = lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &l)| {
+            let cdf = 1.0 - (-(params.birth_rate as Float) * l).exp();
+            (cdf - i as Float / n)
+                .abs()
+                .max((i as Float + 1.0) / n - cdf)
+        })
+        .fold(0.0, Float::max);
+    assert!(d < 1.95 / n.sqrt(), "D = {d}");
+}
+
+#[test]
+fn test_simulation_properties() {
+    use crate::model::SimRng;
+    use rand::SeedableRng;
+
+    // invariants over randomly drawn parameters
+    let mut rng = SimRng::seed_from_u64(0);
+    for _ in 0..50 {
+        let params = Parameters {
+            birth_rate: rng.gen_range(0.05..2.0),
+            generations: rng.gen_range(0..8),
+            sites: rng.gen_range(2..8),
+            migration_probability: rng.gen_range(0.0..0.5),
+            seed: rng.r#gen(),
+            max_population: rng.gen_bool(0.5).then(|| rng.gen_range(1..100)),
+            ..Default::default()
+        };
+        let result = Phylogeny::simulate(&params, &mut ());
+        let tree = result.tree();
+
+        // every node but the root was drawn once from its parent's row
+        assert_eq!(
+            result.migration_matrix().sum() as usize,
+            tree.nodes.len() - 1
+        );
+        assert_eq!(tree.edges().count(), tree.nodes.len() - 1);
+        let extant = tree.extant_leaves().count();
+        if let Some(cap) = params.max_population {
+            assert!(extant <= cap);
+        }
+        for row in result.frequencies_by_generation().rows() {
+            assert!((row.sum() - 1.0).abs() < 1e-4);
+        }
+    }
+}
+
+#[test]
+fn test_multifurcating() {
+    let params = Parameters {
+        generations: 4,
+        sites: 3,
+        migration_probability: 0.1,
+        branching: 3,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    assert_eq!(tree.leaves().count(), 81);
+    assert!(
+        (0..tree.nodes.len())
+            .all(|i| tree.is_leaf(i) || tree.child_count(i) == 3 && tree.is_alive(i))
+    );
+    assert_eq!(
+        result.migration_matrix().sum() as usize,
+        tree.nodes.len() - 1
+    );
+    assert_eq!(tree.subtree_leaves()[0], 81);
+}
+
+#[test]
+fn test_colonization_order() {
+    let mut params = Parameters {
+        generations: 7,
+        sites: 4,
+        migration_probability: 0.3,
+        colonization_order: vec![(0, 1), (1, 2)],
+        ..Default::default()
+    };
+    assert!(params.check_colonization_order().is_ok());
+    for seed in 0..5 {
+        params.seed = seed;
+        let result = Phylogeny::simulate(&params, &mut ());
+        let first = |site| {
+            result
+                .events()
+                .iter()
+                .filter(|e| e.to == site)
+                .map(|e| e.generation)
+                .min()
+        };
+        // site 2 opens the generation after site 1 is colonized
+        match (first(1), first(2)) {
+            (Some(one), Some(two)) => assert!(one < two),
+            (one, two) => assert!(two.is_none(), "{one:?} {two:?}"),
+        }
+        assert!(first(3).is_some());
+    }
+
+    params.colonization_order.push((2, 1));
+    assert!(params.check_colonization_order().is_err());
+    params.colonization_order = vec![(0, 4)];
+    assert!(params.check_colonization_order().is_err());
+}
+
+#[test]
+fn test_check_pmatrix() {
+    let params = Parameters {
+        sites: 2,
+        source_only: vec![1],
+        migration_probability: 1.0,
+        ..Default::default()
+    };
+    // the primary can only migrate into the source-only site
+    assert!(params.check_pmatrix().is_err());
+    assert!(
+        Parameters {
+            migration_probability: 0.5,
+            ..params
+        }
+        .check_pmatrix()
+        .is_ok()
+    );
+}
+
+#[test]
+fn test_founder_bottleneck() {
+    let params = Parameters {
+        generations: 8,
+        sites: 2,
+        migration_probability: 0.2,
+        migration_mode: MigrationMode::Fixed,
+        founder_generations: 3,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    let depths = tree.depths();
+
+    // lineages of site 1 in each generation, without culling every node is extant in
+    // the generation of its depth
+    let mut counts = [0; 9];
+    for (i, node) in tree.nodes.iter().enumerate() {
+        counts[depths[i]] += (node.label == 1) as usize;
+    }
+    let mut until = None;
+    let mut bottlenecked = [false; 9];
+    for g in 1..9 {
+        if counts[g - 1] == 0 && counts[g] > 0 {
+            until = Some(g + 3);
+        }
+        // a node of generation g splits in generation g + 1
+        bottlenecked[g] = until.is_some_and(|u| g < u);
+    }
+
+    for (i, node) in tree.nodes.iter().enumerate() {
+        let children = tree.child_count(i);
+        if node.label == 1 && children > 0 {
+            let expected = if bottlenecked[depths[i]] { 1 } else { 2 };
+            assert_eq!(children, expected, "node {i}");
+        }
+    }
+    assert!(bottlenecked.contains(&true) && counts[8] > 0);
+}
+
+#[test]
+fn test_organotropism() {
+    let params = Parameters {
+        generations: 8,
+        sites: 3,
+        migration_probability: 0.2,
+        organotropism: vec![1.0, 9.0, 1.0],
+        ..Default::default()
+    };
+    let p = PMatrix::new_with_initial_conditions(3, 0.2).with_organotropism(&[1.0, 9.0, 1.0]);
+    let expected = [0.8, 0.18, 0.02];
+    assert!(
+        p.as_array()
+            .row(0)
+            .iter()
+            .zip(expected)
+            .all(|(a, b)| (a - b).abs() < 1e-6)
+    );
+
+    let (_, m) = Phylogeny::simulate(&params, &mut ()).into_parts();
+    // site 1 attracts nine in ten migrants leaving the primary
+    assert!(m[[0, 1]] > 4 * m[[0, 2]], "{m}");
+}
+
+#[test]
+fn test_cell_states() {
+    let params = Parameters {
+        generations: 7,
+        sites: 3,
+        migration_probability: 0.2,
+        states: 2,
+        state_switch_probability: 0.2,
+        state_migration: vec![0.0, 3.0],
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    let states = result.states().unwrap();
+    assert_eq!(states.len(), tree.nodes.len());
+    assert!(states.contains(&0) && states.contains(&1));
+    assert_eq!(
+        tree.nodes[5].meta("state"),
+        Some(states[5].to_string().as_str())
+    );
+
+    // state 0 never migrates, every migrant has a parent in state 1
+    assert!(!result.events().is_empty());
+    for event in result.events() {
+        assert_eq!(states[tree.parent(event.node).unwrap()], 1);
+    }
+    assert!(
+        Phylogeny::simulate(&Parameters::default(), &mut ())
+            .states()
+            .is_none()
+    );
+}
+
+#[test]
+fn test_burst_migration() {
+    let params = Parameters {
+        generations: 10,
+        sites: 3,
+        migration_probability: 0.001,
+        migration_mode: MigrationMode::Fixed,
+        burst_probability: 0.3,
+        burst_factor: 300.0,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let bursts = result.bursts();
+    assert!(!bursts.is_empty() && bursts.len() < 10, "{bursts:?}");
+    let in_bursts = result
+        .events()
+        .iter()
+        .filter(|e| bursts.contains(&e.generation))
+        .count();
+    // roughly 30% of the lineages of a burst generation migrate, 0.1% otherwise
+    assert!(in_bursts > 5 * (result.events().len() - in_bursts).max(1));
+}
+
+#[test]
+fn test_waiting_time() {
+    assert!((gamma_function(5.0) - 24.0).abs() < 1e-9);
+    assert!((gamma_function(0.5) - std::f64::consts::PI.sqrt()).abs() < 1e-9);
+    for s in ["exponential", "gamma:4", "weibull:2.5"] {
+        assert_eq!(s.parse::<WaitingTime>().unwrap().to_string(), s);
+    }
+    assert!("gamma:0".parse::<WaitingTime>().is_err());
+    assert!("lognormal".parse::<WaitingTime>().is_err());
+
+    // mean 1 / birth_rate = 5 for all, variance 25 exponential and 25 / 4 gamma:4
+    let moments = |waiting_time| {
+        let params = Parameters {
+            waiting_time,
+            ..Default::default()
+        };
+        let tree = Phylogeny::simulate(&params, &mut ()).tree;
+        let lengths: Vec<Float> = (0..tree.nodes.len())
+            .flat_map(|i| tree.children(i).map(|(_, length)| length))
+            .collect();
+        let n = lengths.len() as Float;
+        let mean = lengths.iter().sum::<Float>() / n;
+        let variance = lengths.iter().map(|l| (l - mean).powi(2)).sum::<Float>() / n;
+        (mean, variance)
+    };
+    let (mean, variance) = moments(WaitingTime::Exponential);
+    assert!((mean - 5.0).abs() < 0.5 && (variance - 25.0).abs() < 5.0);
+    let (mean, variance) = moments(WaitingTime::Gamma { shape: 4.0 });
+    assert!((mean - 5.0).abs() < 0.5 && (variance - 6.25).abs() < 1.5);
+    let (mean, _) = moments(WaitingTime::Weibull { shape: 2.0 });
+    assert!((mean - 5.0).abs() < 0.5);
+}
+
+#[test]
+fn test_branch_units() {
+    for s in ["time", "generations", "mutations:0.5"] {
+        assert_eq!(s.parse::<BranchUnits>().unwrap().to_string(), s);
+    }
+    assert!("mutations:-1".parse::<BranchUnits>().is_err());
+    assert!("mutations".parse::<BranchUnits>().is_err());
+
+    let run = |branch_units| {
+        let params = Parameters {
+            generations: 6,
+            sites: 3,
+            migration_probability: 0.2,
+            root_sites: vec![0, 1],
+            branch_units,
+            ..Default::default()
+        };
+        Phylogeny::simulate(&params, &mut ())
+    };
+    let time = run(BranchUnits::Time);
+    let generations = run(BranchUnits::Generations);
+    let mutations = run(BranchUnits::Mutations { clock_rate: 0.5 });
+    // the same run, only the lengths differ
+    for other in [&generations, &mutations] {
+        assert!(
+            time.tree()
+                .edges()
+                .map(|(p, c, _)| (p, c))
+                .eq(other.tree().edges().map(|(p, c, _)| (p, c)))
+        );
+        assert_eq!(other.migration_matrix(), time.migration_matrix());
+    }
+    let tree = generations.tree();
+    assert!((0..tree.nodes.len()).all(|i| tree.branch_length(i) == 1.0));
+    let depths = tree.depths();
+    let times = tree.times_from_root();
+    assert!((0..tree.nodes.len()).all(|i| times[i] == depths[i] as Float));
+    let (a, b) = (time.tree(), mutations.tree());
+    assert!((0..a.nodes.len()).all(|i| b.branch_length(i) == a.branch_length(i) * 0.5));
+}
+
+#[test]
+fn test_multiple_founders() {
+    let params = Parameters {
+        generations: 4,
+        sites: 3,
+        root_sites: vec![1, 1, 2],
+        migration_probability: 0.0,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    assert_eq!(tree.nodes[tree.root].meta("founders"), Some("3"));
+    assert_eq!(tree.child_count(tree.root), 3);
+    // without migration every founder keeps its site and nothing is a seeding
+    let mut sites: Vec<usize> = tree.extant_leaves().map(|l| tree.nodes[l].label).collect();
+    sites.sort();
+    assert_eq!(sites, [vec![1; 32], vec![2; 16]].concat());
+    assert!(crate::analysis::seedings(tree).is_empty());
+    assert_eq!(
+        result.leaf_counts_by_generation().row(0).to_vec(),
+        [0, 2, 1]
+    );
+
+    let params = Parameters {
+        root_sites: vec![2],
+        ..params
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    assert!(tree.extant_leaves().all(|l| tree.nodes[l].label == 2));
+}
+
+#[test]
+fn test_initial_frequencies() {
+    let params = Parameters {
+        generations: 3,
+        sites: 3,
+        initial_frequencies: vec![0.6, 0.3, 0.1],
+        founders: 10,
+        migration_probability: 0.05,
+        ..Default::default()
+    };
+    assert_eq!(
+        params.founder_sites(),
+        Ok(vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 2])
+    );
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    assert_eq!(tree.child_count(tree.root), 10);
+    assert_eq!(tree.nodes[tree.root].meta("founders"), Some("10"));
+    assert_eq!(
+        result.leaf_counts_by_generation().row(0).to_vec(),
+        [6, 3, 1]
+    );
+    let first = result.frequencies_by_generation().row(0);
+    assert!((first[1] - 0.3).abs() < 1e-6);
+    assert_eq!(tree.extant_leaves().count(), 80);
+
+    // the largest remainders are rounded up, ties to the lower site
+    let params = Parameters {
+        sites: 4,
+        initial_frequencies: vec![1.0, 1.0, 1.0, 3.0],
+        founders: 4,
+        ..params
+    };
+    assert_eq!(params.founder_sites(), Ok(vec![0, 1, 3, 3]));
+
+    // frequencies that can not apportion the founders are rejected, not replaced by the
+    // root sites
+    for (initial_frequencies, founders) in [
+        (vec![0.0, 0.0, 0.0, 0.0], 4),
+        (vec![1.0, f64::NAN, 0.0, 0.0], 4),
+        (vec![1.0, -1.0, 1.0, 0.0], 4),
+        (vec![1.0, 1.0], 4),
+        (vec![1.0, 1.0, 1.0, 3.0], 0),
+    ] {
+        let params = Parameters {
+            initial_frequencies,
+            founders,
+            root_sites: vec![2],
+            ..params.clone()
+        };
+        let error = params.check_initial_frequencies().unwrap_err();
+        assert_eq!(params.founder_sites(), Err(error.clone()));
+        assert_eq!(params.check(), Err(error));
+    }
+    let zero = Parameters {
+        initial_frequencies: vec![0.0; 3],
+        generations: 3,
+        sites: 3,
+        ..Default::default()
+    };
+    assert_eq!(
+        zero.check_initial_frequencies(),
+        Err("initial frequencies must not all be zero".to_string())
+    );
+    assert_eq!(
+        Phylogeny::try_simulate(&zero, &mut ()).err(),
+        Some("initial frequencies must not all be zero".to_string())
+    );
+}
+
+#[test]
+fn test_check() {
+    let params = Parameters {
+        generations: 4,
+        sites: 3,
+        migration_probability: 0.1,
+        ..Default::default()
+    };
+    assert_eq!(params.check(), Ok(()));
+    assert!(Phylogeny::try_simulate(&params, &mut ()).is_ok());
+    for (invalid, error) in [
+        (
+            Parameters {
+                root_sites: vec![3],
+                ..params.clone()
+            },
+            "site 3 does not exist with 3 sites",
+        ),
+        (
+            Parameters {
+                initial_frequencies: vec![1.0, 1.0],
+                ..params.clone()
+            },
+            "initial frequencies has 2 fractions for 3 sites",
+        ),
+        (
+            Parameters {
+                migration_probability: 1.5,
+                ..params.clone()
+            },
+            "migration probability must be between 0 and 1",
+        ),
+        (
+            Parameters {
+                sites: 2,
+                source_only: vec![1],
+                migration_probability: 1.0,
+                ..params.clone()
+            },
+            "migration matrix: row 0 is all zero, cannot balance",
+        ),
+        (
+            Parameters {
+                colonization_order: vec![(1, 2), (2, 1)],
+                ..params.clone()
+            },
+            "colonization order has a cycle",
+        ),
+    ] {
+        assert_eq!(
+            Phylogeny::try_simulate(&invalid, &mut ()).err(),
+            Some(error.to_string())
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "invalid parameters: states must be at least 1")]
+fn test_simulate_invalid() {
+    let params = Parameters {
+        states: 0,
+        ..Default::default()
+    };
+    Phylogeny::simulate(&params, &mut ());
+}
+
+#[test]
+fn test_parallel_lineages() {
+    let params = Parameters {
+        generations: 14,
+        sites: 3,
+        migration_probability: 0.05,
+        states: 2,
+        rng: RngScheme::Lineage,
+        ..Default::default()
+    };
+    let sequential = Phylogeny::simulate(&params, &mut ());
+    let parallel = Phylogeny::simulate(
+        &Parameters {
+            threads: 3,
+            ..params.clone()
+        },
+        &mut (),
+    );
+    let (a, b) = (sequential.tree(), parallel.tree());
+    a.validate().unwrap();
+    assert_eq!(a.nodes.len(), (1 << 15) - 1);
+    assert!(a.edges().eq(b.edges()));
+    assert!(
+        a.nodes
+            .iter()
+            .zip(&b.nodes)
+            .all(|(x, y)| x.label == y.label)
+    );
+    assert_eq!(sequential.states(), parallel.states());
+    assert_eq!(sequential.migration_matrix(), parallel.migration_matrix());
+
+    // the children of any lineage are drawn again from its own streams alone
+    let lineages = Component::Lineages.rng(params.seed);
+    let waiting = Exp::new(params.birth_rate as Float).unwrap();
+    for node in [0, 5, 1000, 20000] {
+        let mut streams = Streams::lineage(&lineages, node);
+        for (_, length) in a.children(node) {
+            assert_eq!(length, waiting.sample(&mut streams.tree));
+        }
+    }
+}
+
+#[test]
+fn test_resimulate_subtree() {
+    let params = Parameters {
+        generations: 7,
+        sites: 3,
+        migration_probability: 0.02,
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    // a lineage of generation 3, with 30 nodes below it
+    let node = tree.depths().iter().position(|&d| d == 3).unwrap();
+
+    let higher = Parameters {
+        migration_probability: 0.5,
+        ..params.clone()
+    };
+    let regrown = result.resimulate_subtree(node, &higher);
+    let again = result.resimulate_subtree(node, &higher);
+    let new = regrown.tree();
+    tree.validate().unwrap();
+    new.validate().unwrap();
+    assert!(new.edges().eq(again.tree().edges()));
+    assert_eq!(new.nodes.len(), tree.nodes.len());
+    assert!(new.nodes.iter().enumerate().all(|(i, n)| n.data == i));
+    // nodes outside the subtree come first, in their order and with their sites
+    let mut below = vec![false; tree.nodes.len()];
+    for i in tree.preorder() {
+        below[i] = tree.parent(i).is_some_and(|p| p == node || below[p]);
+    }
+    let outside: Vec<_> = (0..tree.nodes.len()).filter(|&i| !below[i]).collect();
+    assert_eq!(outside.len(), tree.nodes.len() - 30);
+    assert!(
+        outside
+            .iter()
+            .enumerate()
+            .all(|(i, &o)| new.nodes[i].label == tree.nodes[o].label)
+    );
+    assert_eq!(
+        regrown.migration_matrix().sum() as usize,
+        new.nodes.len() - 1
+    );
+    assert!(regrown.migration_matrix() != result.migration_matrix());
+    for event in regrown.events() {
+        assert_eq!(new.nodes[event.node].label, event.to);
+        assert_eq!(new.generation(event.node), event.generation);
+    }
+    let last = regrown.leaf_counts_by_generation().row(7).to_owned();
+    let mut extant = Array1::<usize>::zeros(3);
+    for leaf in new.extant_leaves() {
+        extant[new.nodes[leaf].label] += 1;
+    }
+    assert_eq!(last, extant);
+    assert_eq!(
+        regrown.leaf_counts_by_generation().row(3),
+        result.leaf_counts_by_generation().row(3)
+    );
+    let transitions = regrown.transitions_by_generation();
+    assert_eq!(transitions.sum_axis(Axis(0)), regrown.migration_matrix());
+    assert_eq!(
+        transitions.index_axis(Axis(0), 3),
+        result.transitions_by_generation().index_axis(Axis(0), 3)
+    );
+}
+
+#[test]
+fn test_node_capacity() {
+    let params = Parameters {
+        generations: 6,
+        sites: 2,
+        ..Default::default()
+    };
+    let model = YuleMigrations::<usize>::new(&params, 2);
+    assert_eq!(model.node_capacity(), 127);
+    let capped = Parameters {
+        max_population: Some(10),
+        ..params.clone()
+    };
+    // 1 + 2 + 4 + 8 + 16, then 20 children of the 10 kept lineages twice
+    assert_eq!(YuleMigrations::<usize>::new(&capped, 2).node_capacity(), 71);
+    let tree = Phylogeny::simulate(&capped, &mut ()).into_tree();
+    assert_eq!(tree.nodes.len(), 71);
+    let huge = Parameters {
+        generations: 60,
+        ..params
+    };
+    assert_eq!(
+        YuleMigrations::<usize>::new(&huge, 2).node_capacity(),
+        MAX_RESERVED_NODES
+    );
+}
+
+#[test]
+fn test_shared_result() {
+    use crate::analysis::seedings;
+
+    let params = Parameters {
+        generations: 8,
+        sites: 3,
+        migration_probability: 0.1,
+        ..Default::default()
+    };
+    let shared = Phylogeny::simulate(&params, &mut ()).into_shared();
+    let expected = seedings(shared.tree()).len();
+    let counts: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let result = Arc::clone(&shared);
+                scope.spawn(move || {
+                    (
+                        seedings(result.tree()).len(),
+                        result.tree().extant_leaves().count(),
+                    )
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+    assert!(counts.iter().all(|&c| c == (expected, 256)));
+    assert_eq!(Arc::strong_count(&shared), 1);
+}
+
+#[test]
+fn test_budget() {
+    use crate::model::{CancelToken, Limit};
+
+    let params = Parameters {
+        generations: 10,
+        sites: 3,
+        ..Default::default()
+    };
+    let budget = Budget {
+        nodes: Some(100),
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate_with_budget(&params, &mut (), &budget);
+    // 127 nodes after generation 6 are over the budget
+    let truncation = result.truncation().unwrap();
+    assert_eq!((truncation.limit, truncation.generation), (Limit::Nodes, 6));
+    assert_eq!(result.tree().nodes.len(), 127);
+    assert_eq!(result.leaf_counts_by_generation().nrows(), 7);
+
+    let cancel = CancelToken::new();
+    cancel.clone().cancel();
+    let budget = Budget {
+        cancel: Some(cancel),
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate_with_budget(&params, &mut (), &budget);
+    assert_eq!(result.truncation().unwrap().limit, Limit::Cancelled);
+    assert_eq!(result.tree().nodes.len(), 1);
+
+    assert!(Phylogeny::simulate(&params, &mut ()).truncation().is_none());
+}
+
+#[test]
+fn test_hybrid_simulation() {
+    let params = Parameters {
+        generations: 25,
+        sites: 3,
+        migration_probability: 0.001,
+        hybrid_threshold: Some(500),
+        ..Default::default()
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let tree = result.tree();
+    // every cell of 2^25 is accounted for, by far fewer nodes
+    let cells: Float = tree
+        .nodes
+        .iter()
+        .filter_map(|n| n.meta("cells"))
+        .map(|c| c.parse::<Float>().unwrap())
+        .sum();
+    assert!((cells / (1 << 25) as Float - 1.0).abs() < 1e-3, "{cells}");
+    assert!(tree.nodes.len() < 10_000);
+    assert!(result.migration_matrix().iter().all(|&c| c >= 0));
+    assert_eq!(
+        result.transitions_by_generation().sum_axis(Axis(0)),
+        result.migration_matrix()
+    );
+
+    // every leaf is a clone and every migration founds a node in its destination
+    assert!(
+        tree.leaves()
+            .all(|leaf| tree.nodes[leaf].meta("cells").is_some())
+    );
+    assert!(!result.events().is_empty());
+    for event in result.events() {
+        assert_eq!(tree.nodes[event.node].label, event.to);
     }
+    // the children of a generation outgrow an i32 without the counts clamping
+    let params = Parameters {
+        generations: 33,
+        hybrid_threshold: Some(1000),
+        ..params
+    };
+    let result = Phylogeny::simulate(&params, &mut ());
+    let last = result
+        .transitions_by_generation()
+        .index_axis(Axis(0), 33)
+        .sum();
+    assert!(
+        (last as Float / (1u64 << 33) as Float - 1.0).abs() < 1e-3,
+        "{last}"
+    );
+    assert!(result.migration_matrix().sum() > i32::MAX as i64);
 }
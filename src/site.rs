@@ -0,0 +1,105 @@
+//! Labels of the nodes of simulated trees. The simulation draws sites by index, a
+//! [`SiteLabel`] carries that index together with the name the site is written under,
+//! so named sites reach the tree without a table mapping indices to names afterwards.
+use std::{fmt, sync::Arc};
+
+use serde::{Serialize, Serializer};
+
+/// Label of a simulated node, built once per site and cloned into every node of it
+pub trait SiteLabel: Clone + Send + Sync {
+    /// Label of site `index`, named `name`
+    fn from_site(index: usize, name: &str) -> Self;
+
+    /// Index of the site, below [`crate::simulations::Parameters::sites`]
+    fn index(&self) -> usize;
+
+    /// Name of the site in output
+    fn name(&self) -> String;
+}
+
+/// Sites by index alone, the labels of [`crate::simulations::Simulations`]
+impl SiteLabel for usize {
+    fn from_site(index: usize, _name: &str) -> Self {
+        index
+    }
+
+    fn index(&self) -> usize {
+        *self
+    }
+
+    fn name(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Site with its name, displayed and serialized by name. Nodes of one site share the
+/// name, so a clone does not allocate
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Site {
+    pub index: usize,
+    pub name: Arc<str>,
+}
+
+impl SiteLabel for Site {
+    fn from_site(index: usize, name: &str) -> Self {
+        Self {
+            index,
+            name: name.into(),
+        }
+    }
+
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Serialize for Site {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+#[test]
+fn test_named_sites() {
+    use crate::{
+        simulations::{Parameters, Simulations},
+        tree::Phylogeny,
+    };
+
+    let params = Parameters {
+        generations: 6,
+        sites: 3,
+        migration_probability: 0.1,
+        names: vec!["breast".into(), "lymph node".into(), "liver".into()],
+        ..Default::default()
+    };
+    let indexed = Phylogeny::simulate(&params, &mut ());
+    let named = Phylogeny::<usize, Site>::simulate_labeled(&params, &mut ());
+    let (a, b) = (indexed.tree(), named.tree());
+    assert!(a.edges().eq(b.edges()));
+    for (x, y) in a.nodes.iter().zip(&b.nodes) {
+        assert_eq!(x.label, y.label.index());
+        assert_eq!(*y.label.name, params.names[x.label]);
+    }
+    assert_eq!(indexed.migration_matrix(), named.migration_matrix());
+    assert!(b.nodes.iter().any(|n| n.label.name() == "lymph node"));
+
+    let mut newick = vec![];
+    b.write_nhx(&mut newick).unwrap();
+    assert!(String::from_utf8(newick).unwrap().contains("site=breast"));
+    assert_eq!(
+        serde_json::to_value(&b.nodes[0].label).unwrap(),
+        serde_json::json!("breast")
+    );
+    assert_eq!(usize::from_site(2, "liver"), 2);
+}
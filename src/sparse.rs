@@ -0,0 +1,527 @@
+//! Compressed sparse row (CSR) storage of a migration matrix for topologies where most
+//! pairs of sites are not connected, e.g. lattices or hierarchies of hundreds of sites.
+//! [`SparsePMatrix`] mirrors the sampling and rescaling API of [`PMatrix`] but only ever
+//! touches the stored entries, so a step costs `O(nnz)` instead of `O(n^2)`. Runs draw
+//! from it with [`MatrixStorage::Sparse`](crate::simulations::MatrixStorage::Sparse).
+use std::sync::OnceLock;
+
+use ndarray::{Array1, Array2};
+use rand::RngCore;
+
+use crate::{
+    Float,
+    pmatrix::{
+        AliasSampler, BalanceDiagnostics, FrequencyBias, PMatrix, PMatrixError, Sinkhorn, SiteRole,
+    },
+};
+
+#[derive(Debug)]
+pub struct SparsePMatrix {
+    n: usize,
+    /// Row `i` is stored in `indptr[i]..indptr[i + 1]` of `indices` and `values`
+    indptr: Vec<usize>,
+    indices: Vec<usize>,
+    values: Vec<Float>,
+    /// Per row alias tables over the stored entries, or why the row can not be sampled,
+    /// built on first use
+    samplers: OnceLock<Vec<Result<AliasSampler, PMatrixError>>>,
+    /// Structural role of every site, kept through rescaling
+    roles: Vec<SiteRole>,
+}
+
+impl SparsePMatrix {
+    /// Build an `n` by `n` matrix from `(from, to, p)` entries, duplicates are summed and
+    /// zeros are dropped
+    pub fn from_triplets(n: usize, triplets: &[(usize, usize, Float)]) -> Self {
+        let mut triplets = triplets.to_vec();
+        triplets.sort_by_key(|&(i, j, _)| (i, j));
+
+        let mut indptr = vec![0; n + 1];
+        let mut indices: Vec<usize> = vec![];
+        let mut values: Vec<Float> = vec![];
+        let mut last = None;
+        for (i, j, p) in triplets {
+            assert!(
+                i < n && j < n,
+                "entry ({i}, {j}) outside of a {n} site matrix"
+            );
+            if last == Some((i, j)) {
+                *values.last_mut().unwrap() += p;
+                continue;
+            }
+            indptr[i + 1] += 1;
+            indices.push(j);
+            values.push(p);
+            last = Some((i, j));
+        }
+        for i in 0..n {
+            indptr[i + 1] += indptr[i];
+        }
+
+        let mut m = Self::from_csr(n, indptr, indices, values);
+        m.prune();
+        m
+    }
+
+    /// Keep the non-zero entries and the site roles of a dense matrix
+    pub fn from_dense(pmatrix: &PMatrix) -> Self {
+        let p = pmatrix.as_array();
+        let triplets: Vec<_> = p
+            .indexed_iter()
+            .filter(|&(_, &v)| v != 0.0)
+            .map(|((i, j), &v)| (i, j, v))
+            .collect();
+        let mut m = Self::from_triplets(p.nrows(), &triplets);
+        m.roles = pmatrix.roles().to_vec();
+        m
+    }
+
+    fn from_csr(n: usize, indptr: Vec<usize>, indices: Vec<usize>, values: Vec<Float>) -> Self {
+        Self {
+            n,
+            indptr,
+            indices,
+            values,
+            samplers: OnceLock::new(),
+            roles: vec![SiteRole::Free; n],
+        }
+    }
+
+    /// New entries for the same sites, keeping their roles. Entries of a row are summed
+    /// by column and zeros are dropped
+    fn with_same_roles(&self, rows: Vec<Vec<(usize, Float)>>) -> Self {
+        let triplets: Vec<_> = rows
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, row)| row.into_iter().map(move |(j, v)| (i, j, v)))
+            .collect();
+        let mut m = Self::from_triplets(self.n, &triplets);
+        m.roles = self.roles.clone();
+        m
+    }
+
+    /// Stored entries of every row, for building a changed matrix
+    fn rows(&self) -> Vec<Vec<(usize, Float)>> {
+        (0..self.n).map(|i| self.row(i).collect()).collect()
+    }
+
+    /// Give sites structural roles, see [`PMatrix::with_roles`]
+    pub fn with_roles(self, roles: &[SiteRole]) -> Self {
+        assert_eq!(roles.len(), self.n, "one role per site");
+        let mut rows = self.rows();
+        for (i, row) in rows.iter_mut().enumerate() {
+            match roles[i] {
+                SiteRole::Absorbing => *row = vec![(i, 1.0)],
+                _ => row.retain(|&(j, _)| j == i || roles[j] != SiteRole::SourceOnly),
+            }
+            let sum: Float = row.iter().map(|&(_, v)| v).sum();
+            if sum > 0.0 {
+                row.iter_mut().for_each(|(_, v)| *v /= sum);
+            }
+        }
+        let mut m = self.with_same_roles(rows);
+        m.roles = roles.to_vec();
+        m
+    }
+
+    pub fn roles(&self) -> &[SiteRole] {
+        &self.roles
+    }
+
+    /// Weight migrations into each site by its attractiveness in `weights`, see
+    /// [`PMatrix::with_organotropism`]
+    pub fn with_organotropism(&self, weights: &[Float]) -> Self {
+        let mut rows = self.rows();
+        for (i, row) in rows.iter_mut().enumerate() {
+            let migrating = |row: &[(usize, Float)]| -> Float {
+                row.iter().filter(|&&(j, _)| j != i).map(|&(_, v)| v).sum()
+            };
+            let before = migrating(row);
+            for (j, v) in row.iter_mut() {
+                if *j != i {
+                    *v *= weights[*j];
+                }
+            }
+            let after = migrating(row);
+            match after > 0.0 {
+                true => {
+                    for (j, v) in row.iter_mut() {
+                        if *j != i {
+                            *v *= before / after;
+                        }
+                    }
+                }
+                // no attractive destination left, the migrants stay
+                false => row.push((i, before)),
+            }
+        }
+        self.with_same_roles(rows)
+    }
+
+    /// Multiply the probability of migrating from every site by `factor`, see
+    /// [`PMatrix::with_migration_scale`]
+    pub fn with_migration_scale(&self, factor: Float) -> Self {
+        let mut rows = self.rows();
+        for (i, row) in rows.iter_mut().enumerate() {
+            let total: Float = row.iter().map(|&(_, v)| v).sum();
+            let stay: Float = row.iter().filter(|&&(j, _)| j == i).map(|&(_, v)| v).sum();
+            let migrating = total - stay;
+            if migrating <= 0.0 {
+                continue;
+            }
+            let scaled = (migrating * factor).min(total);
+            row.retain(|&(j, _)| j != i);
+            row.iter_mut().for_each(|(_, v)| *v *= scaled / migrating);
+            row.push((i, total - scaled));
+        }
+        self.with_same_roles(rows)
+    }
+
+    /// Forbid migrating into the `locked` sites, see [`PMatrix::without_destinations`]
+    pub fn without_destinations(&self, locked: &[bool]) -> Self {
+        let mut rows = self.rows();
+        for (i, row) in rows.iter_mut().enumerate() {
+            // moved onto the diagonal, where the entries are summed
+            for (j, _) in row.iter_mut() {
+                if locked[*j] && *j != i {
+                    *j = i;
+                }
+            }
+        }
+        self.with_same_roles(rows)
+    }
+
+    /// Drop stored zeros
+    fn prune(&mut self) {
+        let mut indptr = vec![0; self.n + 1];
+        let (mut indices, mut values) = (vec![], vec![]);
+        for i in 0..self.n {
+            for (j, v) in self.row(i) {
+                if v != 0.0 {
+                    indices.push(j);
+                    values.push(v);
+                }
+            }
+            indptr[i + 1] = indices.len();
+        }
+        *self = Self::from_csr(self.n, indptr, indices, values);
+    }
+
+    pub fn to_dense(&self) -> PMatrix {
+        let mut p = Array2::zeros((self.n, self.n));
+        for i in 0..self.n {
+            for (j, v) in self.row(i) {
+                p[[i, j]] = v;
+            }
+        }
+        PMatrix::from_array_with_roles(p, &self.roles)
+    }
+
+    /// Number of sites
+    pub fn sites(&self) -> usize {
+        self.n
+    }
+
+    /// Number of stored entries
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Stored `(to, p)` entries of row `i`
+    pub fn row(&self, i: usize) -> impl Iterator<Item = (usize, Float)> + '_ {
+        let range = self.indptr[i]..self.indptr[i + 1];
+        self.indices[range.clone()]
+            .iter()
+            .cloned()
+            .zip(self.values[range].iter().cloned())
+    }
+
+    /// Sums of every row with every column `j` weighted by `c[j]`
+    fn row_sums(&self, c: &Array1<Float>) -> Array1<Float> {
+        (0..self.n)
+            .map(|i| self.row(i).map(|(j, v)| v * c[j]).sum())
+            .collect()
+    }
+
+    /// Sums of every column with every row `i` weighted by `r[i]`
+    fn col_sums(&self, r: &Array1<Float>) -> Array1<Float> {
+        let mut sums = Array1::zeros(self.n);
+        for i in 0..self.n {
+            for (j, v) in self.row(i) {
+                sums[j] += v * r[i];
+            }
+        }
+        sums
+    }
+
+    /// Same Sinkhorn-Knopp balancing as [`PMatrix::rescale`], structural zeros stay zero
+    /// and the columns of absorbing and source-only sites are not balanced
+    pub fn rescale(&self, sinkhorn: Sinkhorn) -> Result<(Self, BalanceDiagnostics), PMatrixError> {
+        let pinned: Vec<bool> = self.roles.iter().map(|&r| r != SiteRole::Free).collect();
+        let ones = Array1::ones(self.n);
+        if let Some(i) = self.row_sums(&ones).iter().position(|&s| s <= 0.0) {
+            return Err(PMatrixError::ZeroRow(i));
+        }
+        if let Some(j) = self
+            .col_sums(&ones)
+            .iter()
+            .zip(&pinned)
+            .position(|(&s, &pinned)| !pinned && s <= 0.0)
+        {
+            return Err(PMatrixError::ZeroColumn(j));
+        }
+        let free = pinned.iter().filter(|&&p| !p).count().max(1) as Float;
+
+        let mut r = Array1::ones(self.n);
+        let mut c: Array1<Float> = Array1::ones(self.n);
+
+        let mut diagnostics = BalanceDiagnostics {
+            iterations: 0,
+            max_row_deviation: Float::INFINITY,
+            max_col_deviation: Float::INFINITY,
+            converged: false,
+        };
+        while diagnostics.iterations < sinkhorn.max_iters && !diagnostics.converged {
+            r = self.row_sums(&c).mapv(|e| 1.0 / e);
+
+            // pinned columns keep their mass, the free ones share what is left equally
+            let col_sums = self.col_sums(&r);
+            let target = match pinned.contains(&true) {
+                false => 1.0,
+                true => {
+                    (&col_sums * &c)
+                        .iter()
+                        .zip(&pinned)
+                        .filter(|&(_, &pinned)| !pinned)
+                        .map(|(s, _)| s)
+                        .sum::<Float>()
+                        / free
+                }
+            };
+            for (j, c) in c.iter_mut().enumerate() {
+                if !pinned[j] {
+                    *c = target / col_sums[j];
+                }
+            }
+
+            let deviation = |sums: Array1<Float>, target: Float, skip: &[bool]| {
+                sums.iter()
+                    .zip(skip)
+                    .filter(|&(_, &skip)| !skip)
+                    .fold(0.0, |m: Float, (s, _)| m.max((s - target).abs()))
+            };
+            diagnostics.iterations += 1;
+            diagnostics.max_row_deviation =
+                deviation(self.row_sums(&c) * &r, 1.0, &vec![false; self.n]);
+            diagnostics.max_col_deviation = deviation(self.col_sums(&r) * &c, target, &pinned);
+            diagnostics.converged = diagnostics.max_row_deviation <= sinkhorn.tolerance
+                && diagnostics.max_col_deviation <= sinkhorn.tolerance;
+        }
+
+        let mut values = self.values.clone();
+        for i in 0..self.n {
+            for k in self.indptr[i]..self.indptr[i + 1] {
+                values[k] *= r[i] * c[self.indices[k]];
+            }
+        }
+        let mut balanced =
+            Self::from_csr(self.n, self.indptr.clone(), self.indices.clone(), values);
+        balanced.roles = self.roles.clone();
+
+        Ok((balanced, diagnostics))
+    }
+
+    /// Weight destination sites by `bias` of their frequency and rebalance, see
+    /// [`PMatrix::rescale_from_frequencies_with`]
+    pub fn rescale_from_frequencies_with(
+        self,
+        frequencies: Array1<Float>,
+        bias: FrequencyBias,
+    ) -> Result<Self, PMatrixError> {
+        let weights = bias.weights(&frequencies.to_vec());
+        let sum: Float = weights.iter().sum();
+
+        let mut biased = self;
+        for (k, &j) in biased.indices.iter().enumerate() {
+            biased.values[k] *= weights[j] / sum;
+        }
+        biased.samplers = OnceLock::new();
+
+        let (balanced, _) = biased.rescale(Sinkhorn::default())?;
+        Ok(balanced)
+    }
+
+    pub fn rescale_from_frequencies(
+        self,
+        frequencies: Array1<Float>,
+    ) -> Result<Self, PMatrixError> {
+        self.rescale_from_frequencies_with(frequencies, FrequencyBias::default())
+    }
+
+    /// Check that row `i` can be sampled from: finite, non-negative and not all zero
+    fn check_row(&self, i: usize) -> Result<(), PMatrixError> {
+        if i >= self.n {
+            return Err(PMatrixError::OutOfRange {
+                row: i,
+                sites: self.n,
+            });
+        }
+        for (col, value) in self.row(i) {
+            if !value.is_finite() {
+                return Err(PMatrixError::NotFinite { row: i, col, value });
+            }
+            if value < 0.0 {
+                return Err(PMatrixError::Negative { row: i, col, value });
+            }
+        }
+        match self.row(i).map(|(_, v)| v).sum::<Float>() > 0.0 {
+            true => Ok(()),
+            false => Err(PMatrixError::ZeroRow(i)),
+        }
+    }
+
+    /// Check that every row is a probability distribution, see [`PMatrix::validate`]
+    pub fn validate(&self) -> Result<(), PMatrixError> {
+        self.to_dense().validate()
+    }
+
+    /// Draw the destination of a migrant from site `i`
+    pub fn sample<R: RngCore>(&self, i: usize, rng: &mut R) -> Result<usize, PMatrixError> {
+        if i >= self.n {
+            return Err(PMatrixError::OutOfRange {
+                row: i,
+                sites: self.n,
+            });
+        }
+        let samplers = self.samplers.get_or_init(|| {
+            (0..self.n)
+                .map(|i| {
+                    self.check_row(i)?;
+                    Ok(AliasSampler::new(
+                        &self.values[self.indptr[i]..self.indptr[i + 1]],
+                    ))
+                })
+                .collect()
+        });
+
+        match &samplers[i] {
+            Ok(sampler) => Ok(self.indices[self.indptr[i] + sampler.sample(rng)]),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Reusable constant time sampler for row `i`, drawing the position of an entry in
+    /// [`SparsePMatrix::row`]
+    pub fn sampler(&self, i: usize) -> Result<AliasSampler, PMatrixError> {
+        self.check_row(i)?;
+        Ok(AliasSampler::new(
+            &self.values[self.indptr[i]..self.indptr[i + 1]],
+        ))
+    }
+
+    /// Draw `k` destinations from row `i`
+    pub fn sample_many<R: RngCore>(
+        &self,
+        i: usize,
+        k: usize,
+        rng: &mut R,
+    ) -> Result<Vec<usize>, PMatrixError> {
+        (0..k).map(|_| self.sample(i, rng)).collect()
+    }
+}
+
+#[test]
+fn test_sparse_matches_dense() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    // a ring of 5 sites, each only connected to itself and its neighbours
+    let n = 5;
+    let triplets: Vec<_> = (0..n)
+        .flat_map(|i| {
+            [
+                (i, i, 0.8),
+                (i, (i + 1) % n, 0.1),
+                (i, (i + n - 1) % n, 0.1),
+            ]
+        })
+        .collect();
+    let sparse = SparsePMatrix::from_triplets(n, &triplets);
+    assert_eq!(sparse.nnz(), 3 * n);
+
+    let frequencies = Array1::from_vec(vec![0.5, 0.2, 0.1, 0.1, 0.1]);
+    let dense = sparse
+        .to_dense()
+        .rescale_from_frequencies(frequencies.clone())
+        .unwrap();
+    let sparse = sparse.rescale_from_frequencies(frequencies).unwrap();
+
+    let tol = Float::EPSILON.sqrt();
+    for (a, b) in sparse.to_dense().as_array().iter().zip(dense.as_array()) {
+        assert!((a - b).abs() < tol);
+    }
+    // never sampled outside of the ring
+    let mut rng = StdRng::seed_from_u64(1);
+    for to in sparse.sample_many(0, 1000, &mut rng).unwrap() {
+        assert!([0, 1, 4].contains(&to));
+    }
+    assert_eq!(
+        sparse.sample(n, &mut rng),
+        Err(PMatrixError::OutOfRange { row: n, sites: n })
+    );
+    // the errors of every row are kept with the samplers
+    let broken = SparsePMatrix::from_triplets(2, &[(0, 1, 1.0), (1, 0, -1.0)]);
+    assert!(broken.sample(0, &mut rng).is_ok());
+    assert!(matches!(
+        broken.sample(1, &mut rng),
+        Err(PMatrixError::Negative { row: 1, .. })
+    ));
+    let empty = SparsePMatrix::from_triplets(2, &[(0, 1, 1.0)]);
+    assert_eq!(empty.sample(1, &mut rng), Err(PMatrixError::ZeroRow(1)));
+}
+
+#[test]
+fn test_sparse_roles() {
+    let roles = [
+        SiteRole::SourceOnly,
+        SiteRole::Free,
+        SiteRole::Free,
+        SiteRole::Absorbing,
+    ];
+    let dense = PMatrix::new_with_initial_conditions(4, 0.3).with_roles(&roles);
+    let sparse =
+        SparsePMatrix::from_dense(&PMatrix::new_with_initial_conditions(4, 0.3)).with_roles(&roles);
+    assert_eq!(sparse.roles(), roles);
+    // the zeros of the roles are not stored
+    assert_eq!(sparse.nnz(), 4 + 3 + 3 + 1);
+
+    let tol = Float::EPSILON.sqrt();
+    let same = |sparse: &SparsePMatrix, dense: &PMatrix| {
+        assert_eq!(sparse.to_dense().roles(), dense.roles());
+        for (a, b) in sparse.to_dense().as_array().iter().zip(dense.as_array()) {
+            assert!((a - b).abs() < tol, "{a} != {b}");
+        }
+    };
+    same(&sparse, &dense);
+    let frequencies = Array1::from_vec(vec![0.4, 0.3, 0.2, 0.1]);
+    same(
+        &SparsePMatrix::from_dense(&dense)
+            .rescale_from_frequencies(frequencies.clone())
+            .unwrap(),
+        &dense.rescale_from_frequencies(frequencies).unwrap(),
+    );
+    let dense = PMatrix::new_with_initial_conditions(4, 0.3).with_roles(&roles);
+    let weights = [1.0, 0.0, 2.0, 1.0];
+    same(
+        &sparse.with_organotropism(&weights),
+        &dense.with_organotropism(&weights),
+    );
+    same(
+        &sparse.with_migration_scale(2.0),
+        &dense.with_migration_scale(2.0),
+    );
+    let locked = [false, false, true, false];
+    same(
+        &sparse.without_destinations(&locked),
+        &dense.without_destinations(&locked),
+    );
+}
@@ -0,0 +1,193 @@
+//! SQL dump of runs for querying large sweeps with SQL instead of thousands of csvs. The
+//! dump is plain SQL text in the SQLite dialect, load it with
+//! `sqlite3 runs.sqlite < run.sql`. Every row carries the number of its run, so the dumps
+//! of a whole sweep can be written into one file or loaded into the same database.
+//!
+//! Tables:
+//!
+//! - `runs(run, seed, parameters)` - parameters as json
+//! - `nodes(run, node, parent, label, generation, time, leaves, is_leaf)`
+//! - `edges(run, parent, child, length)`
+//! - `migrations(run, source, target, count)` - non-zero entries of the migration matrix
+//! - `leaf_labels(run, leaf, label)` - view of the leaves in `nodes`
+use std::{
+    fmt::Display,
+    io::{self, Write},
+};
+
+use ndarray::Array2;
+use serde::Serialize;
+
+use crate::{Float, tree::Phylogeny};
+
+/// Rows per `INSERT` statement
+const BATCH: usize = 500;
+
+const SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS runs (run INTEGER PRIMARY KEY, seed TEXT NOT NULL, parameters TEXT NOT NULL);
+CREATE TABLE IF NOT EXISTS nodes (run INTEGER NOT NULL, node INTEGER NOT NULL, parent INTEGER, label INTEGER NOT NULL, generation INTEGER NOT NULL, time REAL, leaves INTEGER NOT NULL, is_leaf INTEGER NOT NULL, PRIMARY KEY (run, node));
+CREATE TABLE IF NOT EXISTS edges (run INTEGER NOT NULL, parent INTEGER NOT NULL, child INTEGER NOT NULL, length REAL);
+CREATE TABLE IF NOT EXISTS migrations (run INTEGER NOT NULL, source INTEGER NOT NULL, target INTEGER NOT NULL, count INTEGER NOT NULL);
+CREATE VIEW IF NOT EXISTS leaf_labels AS SELECT run, node AS leaf, label FROM nodes WHERE is_leaf;
+";
+
+/// A single SQL value
+enum Value<'a> {
+    Int(i64),
+    Real(Float),
+    Text(&'a str),
+    Null,
+}
+
+impl Display for Value<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            // SQLite has no literal for infinities or NaN
+            Value::Real(x) if !x.is_finite() => write!(f, "NULL"),
+            Value::Real(x) => write!(f, "{x:?}"),
+            Value::Text(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Value::Null => write!(f, "NULL"),
+        }
+    }
+}
+
+/// Writes runs as one SQL transaction, see the [module docs](self) for the schema
+pub struct SqlDump<W: Write> {
+    w: W,
+}
+
+impl<W: Write> SqlDump<W> {
+    /// Write the schema and open the transaction
+    pub fn new(mut w: W) -> io::Result<Self> {
+        write!(w, "{SCHEMA}")?;
+        writeln!(w, "BEGIN TRANSACTION;")?;
+        Ok(Self { w })
+    }
+
+    fn insert<'a, I>(&mut self, table: &str, rows: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Vec<Value<'a>>>,
+    {
+        let mut rows = rows.into_iter().peekable();
+        while rows.peek().is_some() {
+            write!(self.w, "INSERT INTO {table} VALUES")?;
+            for (i, row) in rows.by_ref().take(BATCH).enumerate() {
+                let row: Vec<_> = row.iter().map(|v| v.to_string()).collect();
+                let sep = if i == 0 { "" } else { "," };
+                write!(self.w, "{sep}\n({})", row.join(","))?;
+            }
+            writeln!(self.w, ";")?;
+        }
+        Ok(())
+    }
+
+    /// Add one simulation as run number `run`
+    pub fn write_run<P: Serialize>(
+        &mut self,
+        run: usize,
+        seed: u64,
+        parameters: &P,
+        tree: &Phylogeny<usize, usize>,
+        migration_matrix: &Array2<i64>,
+    ) -> io::Result<()> {
+        let run = run as i64;
+        let seed = seed.to_string();
+        let parameters = serde_json::to_string(parameters)?;
+        self.insert(
+            "runs",
+            [vec![
+                Value::Int(run),
+                Value::Text(&seed),
+                Value::Text(&parameters),
+            ]],
+        )?;
+
+        let depths = tree.depths();
+        let times = tree.times_from_root();
+        let leaves = tree.subtree_leaves();
+        self.insert(
+            "nodes",
+            tree.nodes.iter().enumerate().map(|(i, n)| {
+                vec![
+                    Value::Int(run),
+                    Value::Int(i as i64),
+                    tree.parent(i).map_or(Value::Null, |p| Value::Int(p as i64)),
+                    Value::Int(n.label as i64),
+                    Value::Int(depths[i] as i64),
+                    Value::Real(times[i]),
+                    Value::Int(leaves[i] as i64),
+                    Value::Int(tree.is_leaf(i) as i64),
+                ]
+            }),
+        )?;
+
+        self.insert(
+            "edges",
+            tree.edges().map(|(p, c, len)| {
+                vec![
+                    Value::Int(run),
+                    Value::Int(p as i64),
+                    Value::Int(c as i64),
+                    Value::Real(len),
+                ]
+            }),
+        )?;
+
+        self.insert(
+            "migrations",
+            migration_matrix
+                .indexed_iter()
+                .filter(|&(_, &count)| count != 0)
+                .map(|((from, to), &count)| {
+                    vec![
+                        Value::Int(run),
+                        Value::Int(from as i64),
+                        Value::Int(to as i64),
+                        Value::Int(count),
+                    ]
+                }),
+        )
+    }
+
+    /// Commit the transaction and hand back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        writeln!(self.w, "COMMIT;")?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+}
+
+#[test]
+fn test_sql_dump() {
+    use crate::simulations::{Parameters, Simulations};
+
+    let params = Parameters {
+        generations: 3,
+        sites: 2,
+        migration_probability: 0.3,
+        seed: 5,
+        ..Default::default()
+    };
+    let mut dump = SqlDump::new(vec![]).unwrap();
+    for run in 0..2 {
+        let result = Phylogeny::simulate(&params, &mut ());
+        dump.write_run(
+            run,
+            result.seed(),
+            result.params(),
+            result.tree(),
+            result.migration_matrix(),
+        )
+        .unwrap();
+    }
+    let sql = String::from_utf8(dump.finish().unwrap()).unwrap();
+
+    assert!(sql.starts_with("CREATE TABLE IF NOT EXISTS runs"));
+    assert!(sql.ends_with("COMMIT;\n"));
+    assert!(sql.contains("INSERT INTO runs VALUES\n(1,'5','{\"birth_rate\""));
+    // root of the second run, it has no parent and all 8 leaves below it
+    assert!(sql.contains("\n(1,0,NULL,0,0,0.0,8,0)"));
+    assert_eq!(sql.matches("INSERT INTO edges").count(), 2);
+    assert_eq!(Value::Text("it's").to_string(), "'it''s'");
+}
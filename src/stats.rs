@@ -0,0 +1,756 @@
+//! Statistics for comparing simulations with each other and with inference.
+//!
+//! Likelihoods of site labelings under the migration model score the true labeling of a
+//! simulated tree against the labelings reconstruction methods propose. The migration
+//! matrix is taken as fixed over the whole tree, frequency dependent runs rescale it
+//! every generation and only the initial matrix is scored.
+//!
+//! [`ReplicateSummary`] collects the summary statistics of one replicate and
+//! [`write_summaries_csv`] writes many of them as one long format table, a row per
+//! statistic with the parameters as columns, ready for plotting sweeps in R or pandas.
+//! [`compare`] tests whether the statistics of two such tables, e.g. two migration
+//! probabilities, differ with Mann-Whitney and Kolmogorov-Smirnov tests and effect sizes.
+use std::io::{self, BufRead, Write};
+
+use ndarray::{Array1, Array2};
+use serde::Serialize;
+
+use crate::{
+    Float,
+    analysis::{MigrationPattern, clonality, migration_cycles, migration_pattern, seedings},
+    output::{CsvDialect, CsvWriter, split_csv_record},
+    pmatrix::PMatrix,
+    simulations::SimulationResult,
+    tree::Phylogeny,
+};
+
+/// Log probability of the site labels of `tree` given the label of the root, every branch
+/// moves from the site of the parent to the site of the child with the probability in
+/// `pmatrix`. Labelings with an impossible transition have likelihood `-inf`
+pub fn labeling_log_likelihood<N>(tree: &Phylogeny<N, usize>, pmatrix: &PMatrix) -> Float {
+    let p = pmatrix.as_array();
+    tree.edges()
+        .map(|(parent, child, _)| p[[tree.nodes[parent].label, tree.nodes[child].label]].ln())
+        .sum()
+}
+
+/// Marginal posterior probability of every site at every node given the sites of the
+/// leaves (nodes x sites), by Felsenstein's pruning and the matching pass from the root.
+/// Sites change along branches as the continuous time chain of
+/// [`PMatrix::transition_probabilities`] over the branch length, the root site is drawn
+/// from `root_prior`. Leaf rows are their observed site
+pub fn ancestral_marginals<N>(
+    tree: &Phylogeny<N, usize>,
+    pmatrix: &PMatrix,
+    root_prior: &[Float],
+) -> Array2<Float> {
+    let n = tree.nodes.len();
+    let sites = root_prior.len();
+    let preorder = tree.preorder();
+    let transitions: Vec<_> = (0..n)
+        .map(|i| match tree.parent(i) {
+            Some(_) => pmatrix.transition_probabilities(tree.branch_length(i)),
+            None => Array2::eye(sites),
+        })
+        .collect();
+
+    // partial likelihood below every node, and the message each node sends its parent.
+    // Vectors are rescaled to sum 1 to avoid underflow, the marginals are normalized anyway
+    let normalize = |v: Array1<Float>| {
+        let sum = v.sum();
+        if sum > 0.0 { v / sum } else { v }
+    };
+    let mut below = vec![Array1::<Float>::ones(sites); n];
+    let mut up = vec![Array1::<Float>::ones(sites); n];
+    for &i in preorder.iter().rev() {
+        if tree.is_leaf(i) {
+            below[i] = Array1::zeros(sites);
+            below[i][tree.nodes[i].label] = 1.0;
+        } else {
+            let mut l = Array1::ones(sites);
+            for (c, _) in tree.children(i) {
+                l *= &up[c];
+            }
+            below[i] = normalize(l);
+        }
+        up[i] = normalize(transitions[i].dot(&below[i]));
+    }
+
+    // probability of every site at a node given everything outside its subtree
+    let mut above = vec![Array1::<Float>::ones(sites); n];
+    above[0] = Array1::from(root_prior.to_vec());
+    let mut marginals = Array2::zeros((n, sites));
+    for &i in &preorder {
+        marginals
+            .row_mut(i)
+            .assign(&normalize(&above[i] * &below[i]));
+        for (c, _) in tree.children(i) {
+            let mut outside = above[i].clone();
+            for (s, _) in tree.children(i) {
+                if s != c {
+                    outside *= &up[s];
+                }
+            }
+            above[c] = normalize(transitions[c].t().dot(&outside));
+        }
+    }
+    marginals
+}
+
+/// Write the marginals of the internal nodes, a node column followed by one column per
+/// site
+pub fn write_ancestral_csv<N, W: Write>(
+    w: W,
+    tree: &Phylogeny<N, usize>,
+    marginals: &Array2<Float>,
+    sites: &[String],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header(std::iter::once("node").chain(sites.iter().map(String::as_str)))?;
+    for i in (0..tree.nodes.len()).filter(|&i| !tree.is_leaf(i)) {
+        let node = i.to_string();
+        let probabilities = marginals.row(i).map(|p| p.to_string());
+        csv.record(std::iter::once(&node).chain(probabilities.iter()))?;
+    }
+    Ok(())
+}
+
+/// One summary statistic of a replicate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistic {
+    pub name: String,
+    /// Site or `source->target` pair the statistic is about, empty for whole tree ones
+    pub key: String,
+    pub value: Float,
+}
+
+/// Summary statistics of one replicate of a simulation
+#[derive(Debug, Clone)]
+pub struct ReplicateSummary {
+    pub replicate: usize,
+    pub seed: u64,
+    /// Parameters by name, lists joined with `;`
+    pub parameters: Vec<(String, String)>,
+    pub pattern: MigrationPattern,
+    pub statistics: Vec<Statistic>,
+}
+
+impl ReplicateSummary {
+    /// Summarize a simulation:
+    ///
+    /// - `leaves` - extant leaves
+    /// - `migrations` - migrations in total and per `source->target` pair
+    /// - `migration_cycles` - elementary cycles of the migration graph, reseeding loops
+    /// - `seeding_lineages` - lineages with extant descendants founding each metastasis
+    /// - `colonization_time` and `colonization_generation` - first arrival in each
+    ///   colonized metastasis
+    /// - `colless` and `sackin` - tree imbalance
+    pub fn new<P: Serialize>(
+        replicate: usize,
+        result: &SimulationResult<Phylogeny<usize, usize>>,
+        parameters: &P,
+        sites: &[String],
+    ) -> Self {
+        let tree = result.tree();
+        let seedings = seedings(tree);
+        let mut statistics = vec![];
+        let mut push = |name: &str, key: String, value| {
+            statistics.push(Statistic {
+                name: name.to_string(),
+                key,
+                value,
+            });
+        };
+
+        let extant = tree.extant_leaves().count();
+        push("leaves", String::new(), extant as Float);
+
+        let matrix = result.migration_matrix();
+        let off_diagonal = matrix.indexed_iter().filter(|&((a, b), _)| a != b);
+        let total: i64 = off_diagonal.clone().map(|(_, &c)| c).sum();
+        push("migrations", String::new(), total as Float);
+        for ((a, b), &count) in off_diagonal.filter(|&(_, &c)| c > 0) {
+            let key = format!("{}->{}", sites[a], sites[b]);
+            push("migrations", key, count as Float);
+        }
+        let cycles = migration_cycles(matrix).len();
+        push("migration_cycles", String::new(), cycles as Float);
+
+        let lineages = clonality(&seedings, sites.len());
+        let primaries = &result.params().root_sites;
+        for (site, &count) in lineages.iter().enumerate() {
+            if primaries.contains(&site) {
+                continue;
+            }
+            push("seeding_lineages", sites[site].clone(), count as Float);
+        }
+
+        // seedings are in node order, which is not time order
+        for (site, name) in sites.iter().enumerate() {
+            if primaries.contains(&site) {
+                continue;
+            }
+            let first = seedings
+                .iter()
+                .filter(|s| s.target == site)
+                .min_by(|a, b| a.time.total_cmp(&b.time));
+            if let Some(first) = first {
+                push("colonization_time", name.clone(), first.time);
+                let generation = first.generation as Float;
+                push("colonization_generation", name.clone(), generation);
+            }
+        }
+
+        push("colless", String::new(), tree.colless_index() as Float);
+        push("sackin", String::new(), tree.sackin_index() as Float);
+        push("height", String::new(), tree.height());
+        push(
+            "total_branch_length",
+            String::new(),
+            tree.total_branch_length(),
+        );
+        if let Some(gamma) = tree.gamma_statistic() {
+            push("gamma", String::new(), gamma);
+        }
+        if let Some(ratio) = tree.external_internal_ratio() {
+            push("external_internal_ratio", String::new(), ratio);
+        }
+
+        Self {
+            replicate,
+            seed: result.seed(),
+            parameters: flatten_parameters(parameters),
+            pattern: migration_pattern(&seedings, sites.len(), primaries),
+            statistics,
+        }
+    }
+
+    /// Value of the statistic `name` about `key`
+    pub fn get(&self, name: &str, key: &str) -> Option<Float> {
+        self.statistics
+            .iter()
+            .find(|s| s.name == name && s.key == key)
+            .map(|s| s.value)
+    }
+}
+
+/// Fields of `parameters` by name, lists joined with `;`. The seed has its own column
+fn flatten_parameters<P: Serialize>(parameters: &P) -> Vec<(String, String)> {
+    let value = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Null => String::new(),
+        v => v.to_string(),
+    };
+    match serde_json::to_value(parameters) {
+        Ok(serde_json::Value::Object(fields)) => fields
+            .iter()
+            .filter(|&(name, _)| name != "seed")
+            .map(|(name, v)| {
+                let v = match v {
+                    serde_json::Value::Array(items) => {
+                        items.iter().map(value).collect::<Vec<_>>().join(";")
+                    }
+                    v => value(v),
+                };
+                (name.clone(), v)
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Trailing columns of a summary table, after the parameters
+const SUMMARY_COLUMNS: [&str; 4] = ["pattern", "statistic", "key", "value"];
+
+/// Write `summaries` as one long format table with columns `replicate`, `seed`, the
+/// parameters of the first summary, `pattern`, `statistic`, `key` and `value`
+pub fn write_summaries_csv<W: Write>(
+    w: W,
+    summaries: &[ReplicateSummary],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    let parameters = summaries.first().map_or(&[][..], |s| &s.parameters[..]);
+    csv.header(
+        ["replicate", "seed"]
+            .into_iter()
+            .chain(parameters.iter().map(|(name, _)| name.as_str()))
+            .chain(SUMMARY_COLUMNS),
+    )?;
+    for summary in summaries {
+        let replicate = summary.replicate.to_string();
+        let seed = summary.seed.to_string();
+        let pattern = summary.pattern.to_string();
+        for statistic in &summary.statistics {
+            let value = statistic.value.to_string();
+            csv.record(
+                [&replicate, &seed]
+                    .into_iter()
+                    .chain(summary.parameters.iter().map(|(_, v)| v))
+                    .chain([&pattern, &statistic.name, &statistic.key, &value]),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Read back a table written by [`write_summaries_csv`] with a header row
+pub fn read_summaries_csv<R: BufRead>(
+    r: R,
+    dialect: CsvDialect,
+) -> io::Result<Vec<ReplicateSummary>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut lines = r.lines();
+    let header = match lines.next() {
+        Some(line) => split_csv_record(&line?, dialect.delimiter),
+        None => return Ok(vec![]),
+    };
+    let n = header.len();
+    if n < 6 || header[..2] != ["replicate", "seed"] || header[n - 4..] != SUMMARY_COLUMNS {
+        return Err(invalid(format!("not a summary table header: {header:?}")));
+    }
+    let parameters = &header[2..n - 4];
+
+    let mut summaries: Vec<ReplicateSummary> = vec![];
+    for (row, line) in lines.enumerate() {
+        let fields = split_csv_record(&line?, dialect.delimiter);
+        if fields.len() != n {
+            return Err(invalid(format!(
+                "row {} has {} fields",
+                row + 1,
+                fields.len()
+            )));
+        }
+        let parse_error = |e: &dyn std::fmt::Display| invalid(format!("row {}: {e}", row + 1));
+        let replicate = fields[0].parse().map_err(|e| parse_error(&e))?;
+        let seed = fields[1].parse().map_err(|e| parse_error(&e))?;
+        let statistic = Statistic {
+            name: fields[n - 3].clone(),
+            key: fields[n - 2].clone(),
+            value: fields[n - 1].parse().map_err(|e| parse_error(&e))?,
+        };
+
+        // the rows of a replicate are consecutive
+        match summaries.last_mut() {
+            Some(s) if s.replicate == replicate && s.seed == seed => s.statistics.push(statistic),
+            _ => summaries.push(ReplicateSummary {
+                replicate,
+                seed,
+                parameters: parameters
+                    .iter()
+                    .cloned()
+                    .zip(fields[2..n - 4].iter().cloned())
+                    .collect(),
+                pattern: fields[n - 4].parse().map_err(|e| parse_error(&e))?,
+                statistics: vec![statistic],
+            }),
+        }
+    }
+    Ok(summaries)
+}
+
+/// Comparison of one statistic between two sets of replicates `a` and `b`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub statistic: String,
+    pub key: String,
+    /// Replicates with the statistic in `a` and `b`
+    pub n: (usize, usize),
+    pub mean: (Float, Float),
+    /// Mann-Whitney U of `a`
+    pub mann_whitney_u: Float,
+    /// Two sided p-value of the normal approximation with tie correction
+    pub mann_whitney_p: Float,
+    /// Largest distance between the empirical distribution functions
+    pub ks_d: Float,
+    /// Asymptotic two sided p-value of the Kolmogorov-Smirnov test
+    pub ks_p: Float,
+    /// Probability that a value of `a` is larger minus that it is smaller than one of
+    /// `b`, from -1 to 1
+    pub cliffs_delta: Float,
+    /// Difference of the means in pooled standard deviations
+    pub cohens_d: Float,
+}
+
+/// Compare `a` with `b` for the statistics named in `statistics` (all keys of each),
+/// every statistic found in both when empty. Statistics are in the order of `a`
+pub fn compare(
+    a: &[ReplicateSummary],
+    b: &[ReplicateSummary],
+    statistics: &[String],
+) -> Vec<Comparison> {
+    let mut pairs: Vec<(&str, &str)> = vec![];
+    for s in a.iter().flat_map(|s| &s.statistics) {
+        let pair = (s.name.as_str(), s.key.as_str());
+        if (statistics.is_empty() || statistics.contains(&s.name)) && !pairs.contains(&pair) {
+            pairs.push(pair);
+        }
+    }
+    let values = |summaries: &[ReplicateSummary], (name, key): (&str, &str)| {
+        summaries
+            .iter()
+            .filter_map(|s| s.get(name, key))
+            .collect::<Vec<_>>()
+    };
+
+    pairs
+        .into_iter()
+        .filter_map(|pair| {
+            let (x, y) = (values(a, pair), values(b, pair));
+            if x.is_empty() || y.is_empty() {
+                return None;
+            }
+            let (u, mann_whitney_p) = mann_whitney(&x, &y);
+            let (ks_d, ks_p) = kolmogorov_smirnov(&x, &y);
+            let (nx, ny) = (x.len() as Float, y.len() as Float);
+            let (mx, my) = (mean(&x), mean(&y));
+            let pooled = ((nx - 1.0) * variance(&x) + (ny - 1.0) * variance(&y)) / (nx + ny - 2.0);
+            Some(Comparison {
+                statistic: pair.0.to_string(),
+                key: pair.1.to_string(),
+                n: (x.len(), y.len()),
+                mean: (mx, my),
+                mann_whitney_u: u,
+                mann_whitney_p,
+                ks_d,
+                ks_p,
+                cliffs_delta: 2.0 * u / (nx * ny) - 1.0,
+                cohens_d: (mx - my) / pooled.sqrt(),
+            })
+        })
+        .collect()
+}
+
+/// Write one row per comparison
+pub fn write_comparisons_csv<W: Write>(
+    w: W,
+    comparisons: &[Comparison],
+    dialect: CsvDialect,
+) -> io::Result<()> {
+    let mut csv = CsvWriter::new(w, dialect);
+    csv.header([
+        "statistic",
+        "key",
+        "n_a",
+        "n_b",
+        "mean_a",
+        "mean_b",
+        "mann_whitney_u",
+        "mann_whitney_p",
+        "ks_d",
+        "ks_p",
+        "cliffs_delta",
+        "cohens_d",
+    ])?;
+    for c in comparisons {
+        csv.record([
+            &c.statistic as &dyn std::fmt::Display,
+            &c.key,
+            &c.n.0,
+            &c.n.1,
+            &c.mean.0,
+            &c.mean.1,
+            &c.mann_whitney_u,
+            &c.mann_whitney_p,
+            &c.ks_d,
+            &c.ks_p,
+            &c.cliffs_delta,
+            &c.cohens_d,
+        ])?;
+    }
+    Ok(())
+}
+
+fn mean(x: &[Float]) -> Float {
+    x.iter().sum::<Float>() / x.len() as Float
+}
+
+/// Sample variance, 0 for a single value
+fn variance(x: &[Float]) -> Float {
+    if x.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(x);
+    x.iter().map(|v| (v - m).powi(2)).sum::<Float>() / (x.len() - 1) as Float
+}
+
+/// Complementary error function, fractional error below 1.2e-7 (Numerical Recipes, with
+/// the coefficients rounded to single precision)
+fn erfc(x: Float) -> Float {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let coefficients = [
+        -1.2655122,
+        1.0000237,
+        0.37409196,
+        0.09678418,
+        -0.18628806,
+        0.27886807,
+        -1.135204,
+        1.4885159,
+        -0.82215223,
+        0.17087277,
+    ];
+    let poly = coefficients.iter().rev().fold(0.0, |acc, &c| acc * t + c);
+    let r = t * (-z * z + poly).exp();
+    if x >= 0.0 { r } else { 2.0 - r }
+}
+
+/// Mann-Whitney U of `a` and its two sided p-value
+pub fn mann_whitney(a: &[Float], b: &[Float]) -> (Float, Float) {
+    let mut all: Vec<(Float, bool)> = a.iter().map(|&v| (v, true)).collect();
+    all.extend(b.iter().map(|&v| (v, false)));
+    all.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // average ranks over ties
+    let (mut rank_sum, mut ties) = (0.0, 0.0);
+    let mut i = 0;
+    while i < all.len() {
+        let j = i + all[i..].iter().take_while(|v| v.0 == all[i].0).count();
+        let rank = (i + j + 1) as Float / 2.0;
+        rank_sum += rank * all[i..j].iter().filter(|v| v.1).count() as Float;
+        let t = (j - i) as Float;
+        ties += t * t * t - t;
+        i = j;
+    }
+
+    let (na, nb) = (a.len() as Float, b.len() as Float);
+    let n = na + nb;
+    let u = rank_sum - na * (na + 1.0) / 2.0;
+    let sigma = (na * nb / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)))).sqrt();
+    if sigma.is_nan() || sigma <= 0.0 {
+        return (u, 1.0);
+    }
+    // continuity corrected
+    let z = ((u - na * nb / 2.0).abs() - 0.5).max(0.0) / sigma;
+    (u, erfc(z / Float::sqrt(2.0)).min(1.0))
+}
+
+/// Two sample Kolmogorov-Smirnov distance and its asymptotic two sided p-value
+pub fn kolmogorov_smirnov(a: &[Float], b: &[Float]) -> (Float, Float) {
+    let mut a = a.to_vec();
+    let mut b = b.to_vec();
+    a.sort_by(Float::total_cmp);
+    b.sort_by(Float::total_cmp);
+    let (na, nb) = (a.len() as Float, b.len() as Float);
+
+    let (mut i, mut j, mut d) = (0, 0, 0.0 as Float);
+    while i < a.len() && j < b.len() {
+        let x = a[i].min(b[j]);
+        while i < a.len() && a[i] == x {
+            i += 1;
+        }
+        while j < b.len() && b[j] == x {
+            j += 1;
+        }
+        d = d.max((i as Float / na - j as Float / nb).abs());
+    }
+
+    let en = (na * nb / (na + nb)).sqrt();
+    let lambda = (en + 0.12 + 0.11 / en) * d;
+    let mut p = 0.0;
+    for k in 1..=100 {
+        let term = 2.0 * (-2.0 * (k * k) as Float * lambda * lambda).exp();
+        p += if k % 2 == 1 { term } else { -term };
+        if term < 1e-10 {
+            break;
+        }
+    }
+    // the series does not converge for tiny distances, where the samples agree
+    (d, if lambda < 0.2 { 1.0 } else { p.clamp(0.0, 1.0) })
+}
+
+#[test]
+fn test_labeling_log_likelihood() {
+    use crate::{pmatrix::SiteRole, tree::Node};
+
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 1.0);
+    tree.add_child(a, 3, 0, 1.0);
+    tree.add_child(a, 4, 1, 1.0);
+
+    let pmatrix = PMatrix::new_with_initial_conditions(2, 0.2);
+    let expected = 2.0 * Float::ln(0.8) + 2.0 * Float::ln(0.2);
+    assert!((labeling_log_likelihood(&tree, &pmatrix) - expected).abs() < 1e-5);
+
+    // fewer migrations are more likely with a small migration probability
+    tree.nodes[4].label = 0;
+    assert!(labeling_log_likelihood(&tree, &pmatrix) > expected);
+
+    // site 1 can not be left, site 0 below 2 is impossible
+    let pmatrix = pmatrix.with_roles(&[SiteRole::Free, SiteRole::Absorbing]);
+    tree.add_child(2, 5, 0, 1.0);
+    assert_eq!(
+        labeling_log_likelihood(&tree, &pmatrix),
+        Float::NEG_INFINITY
+    );
+}
+
+#[test]
+fn test_ancestral_marginals() {
+    use crate::tree::Node;
+
+    let pmatrix = PMatrix::new_with_initial_conditions(3, 0.3);
+    let t = pmatrix.transition_probabilities(0.7);
+    for row in t.rows() {
+        assert!((row.sum() - 1.0).abs() < 1e-5);
+    }
+    assert_eq!(
+        pmatrix.transition_probabilities(0.0),
+        Array2::<Float>::eye(3)
+    );
+    // two steps of half the length make one step
+    let half = pmatrix.transition_probabilities(0.35);
+    assert!((half.dot(&half) - &t).iter().all(|d| d.abs() < 1e-5));
+
+    // cherry under the root: the internal node sees two leaves in site 1
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 0, 1.0);
+    tree.add_child(a, 3, 1, 0.5);
+    tree.add_child(a, 4, 1, 0.5);
+    let prior = [1.0 / 3.0; 3];
+    let marginals = ancestral_marginals(&tree, &pmatrix, &prior);
+    for row in marginals.rows() {
+        assert!((row.sum() - 1.0).abs() < 1e-5);
+    }
+    assert_eq!(marginals.row(3).to_vec(), [0.0, 1.0, 0.0]);
+    assert!(marginals[[a, 1]] > marginals[[a, 0]]);
+    assert!((marginals[[a, 0]] - marginals[[a, 2]]).abs() > 1e-3);
+
+    // a single branch matches Bayes' rule by hand
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    tree.add_child(0, 1, 2, 1.0);
+    let marginals = ancestral_marginals(&tree, &pmatrix, &prior);
+    let t = pmatrix.transition_probabilities(1.0);
+    let column = t.column(2).to_owned() / t.column(2).sum();
+    assert!(
+        (marginals.row(0).to_owned() - column)
+            .iter()
+            .all(|d| d.abs() < 1e-5)
+    );
+
+    let mut csv = vec![];
+    let sites = ["a", "b", "c"].map(String::from);
+    write_ancestral_csv(&mut csv, &tree, &marginals, &sites, CsvDialect::default()).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    assert!(csv.starts_with("node,a,b,c\n0,"));
+    assert_eq!(csv.lines().count(), 2);
+}
+
+#[test]
+fn test_replicate_summaries() {
+    use crate::simulations::{Parameters, Simulations};
+
+    let params = Parameters {
+        generations: 5,
+        sites: 3,
+        migration_probability: 0.3,
+        absorbing: vec![2],
+        ..Default::default()
+    };
+    let sites = params.site_names();
+    let summaries: Vec<_> = (0..2)
+        .map(|r| {
+            let params = Parameters {
+                seed: r as u64,
+                ..params.clone()
+            };
+            let result = Phylogeny::simulate(&params, &mut ());
+            ReplicateSummary::new(r, &result, &params, &sites)
+        })
+        .collect();
+
+    let summary = &summaries[0];
+    assert_eq!(summary.get("leaves", ""), Some(32.0));
+    let pairs: Float = summary
+        .statistics
+        .iter()
+        .filter(|s| s.name == "migrations" && !s.key.is_empty())
+        .map(|s| s.value)
+        .sum();
+    assert_eq!(summary.get("migrations", ""), Some(pairs));
+    // the absorbing site closes no loop, at most 0 -> 1 -> 0
+    assert!(summary.get("migration_cycles", "").unwrap() <= 1.0);
+    assert!(summary.get("colless", "").is_some());
+    let height = summary.get("height", "").unwrap();
+    assert!(height > 0.0 && height < summary.get("total_branch_length", "").unwrap());
+    assert!(summary.get("gamma", "").is_some());
+
+    let mut csv = vec![];
+    write_summaries_csv(&mut csv, &summaries, CsvDialect::default()).unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let mut lines = csv.lines();
+    let header = lines.next().unwrap();
+    assert!(header.starts_with("replicate,seed,absorbing,birth_rate,"));
+    assert!(header.ends_with(",pattern,statistic,key,value"));
+    let columns = header.split(',').count();
+    assert!(lines.clone().all(|l| l.split(',').count() == columns));
+    assert_eq!(
+        lines.count(),
+        summaries.iter().map(|s| s.statistics.len()).sum::<usize>()
+    );
+    assert!(csv.lines().nth(1).unwrap().starts_with("0,0,2,0.2,"));
+}
+
+#[test]
+fn test_compare() {
+    assert!((erfc(0.0) - 1.0).abs() < 1e-6);
+    assert!((erfc(1.0) - 0.157299).abs() < 1e-5);
+    assert!((erfc(-1.0) - 1.842701).abs() < 1e-5);
+
+    // by hand: the tied 3s share rank 3.5, U = 2.5 and z = (|2.5 - 12.5| - 0.5) / 4.773
+    let (u, p) = mann_whitney(&[1.0, 2.0, 3.0, 4.0, 5.0], &[3.0, 6.0, 7.0, 8.0, 9.0]);
+    assert_eq!(u, 2.5);
+    assert!((p - 0.0465).abs() < 1e-3, "{p}");
+    assert_eq!(mann_whitney(&[1.0, 1.0], &[1.0]).1, 1.0);
+
+    let (d, p) = kolmogorov_smirnov(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]);
+    assert_eq!((d, p), (0.0, 1.0));
+    let low: Vec<Float> = (0..30).map(|i| i as Float).collect();
+    let high: Vec<Float> = (0..30).map(|i| i as Float + 20.0).collect();
+    let (d, p) = kolmogorov_smirnov(&low, &high);
+    assert!((d - 20.0 / 30.0).abs() < 1e-6);
+    assert!(p < 1e-4);
+
+    // two migration probabilities summarized, written and read back
+    use crate::simulations::{Parameters, Simulations};
+    let summaries = |m: f64| {
+        (0..8)
+            .map(|r| {
+                let params = Parameters {
+                    generations: 6,
+                    sites: 3,
+                    migration_probability: m,
+                    seed: r,
+                    ..Default::default()
+                };
+                let result = Phylogeny::simulate(&params, &mut ());
+                ReplicateSummary::new(r as usize, &result, &params, &params.site_names())
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut csv = vec![];
+    write_summaries_csv(&mut csv, &summaries(0.01), CsvDialect::default()).unwrap();
+    let rare = read_summaries_csv(&csv[..], CsvDialect::default()).unwrap();
+    assert_eq!(rare.len(), 8);
+    assert_eq!(rare[3].statistics, summaries(0.01)[3].statistics);
+    assert_eq!(rare[3].pattern, summaries(0.01)[3].pattern);
+
+    let comparisons = compare(&rare, &summaries(0.3), &["migrations".to_string()]);
+    assert_eq!(
+        (
+            comparisons[0].statistic.as_str(),
+            comparisons[0].key.as_str()
+        ),
+        ("migrations", "")
+    );
+    let total = &comparisons[0];
+    assert_eq!(total.n, (8, 8));
+    assert!(total.mean.0 < total.mean.1);
+    assert_eq!(total.cliffs_delta, -1.0);
+    assert!(total.mann_whitney_p < 0.01 && total.ks_p < 0.01);
+    assert!(comparisons.iter().all(|c| c.statistic == "migrations"));
+}
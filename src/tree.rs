@@ -1,35 +1,77 @@
-///! This is a first stab at creating a rust implementation of a weighted phylogenetic tree
-///!
-///! Trees nodes will be generic so that they can be used to simulate different models
+//! This is a first stab at creating a rust implementation of a weighted phylogenetic tree
+//!
+//! Trees nodes will be generic so that they can be used to simulate different models
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt::{self, Display},
     fs,
-    io::{self, Write},
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    str::FromStr,
 };
 
-use serde::Serialize;
+use ndarray::Array2;
+use rand::Rng;
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    Float,
+    output::{CsvDialect, CsvWriter},
+};
 
 /// A simple recursive style tree structure for tree building algorithms like NJ and UPGMA
-#[derive(Serialize)]
+///
+/// Displays as a Newick string and parses back from one, branch lengths are optional
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Tree<N> {
     /// Data about the node - simple version is letting this be `usize` so that it is an id
     node: N,
     /// Set of children and their distances to the parent
-    children: Vec<(Tree<N>, Option<f64>)>,
+    children: Vec<(Tree<N>, Option<Float>)>,
 }
 
-impl<N: Clone + Display> Display for Tree<N> {
+impl<N: Display> Display for Tree<N> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
+        self.fmt_newick(f)?;
+        write!(f, ";")
+    }
+}
+
+impl<N: Display> Tree<N> {
+    fn fmt_newick(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.children.is_empty() {
+            write!(f, "(")?;
+            for (i, (child, dist)) in self.children.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                child.fmt_newick(f)?;
+                if let Some(dist) = dist {
+                    write!(f, ":{dist}")?;
+                }
+            }
+            write!(f, ")")?;
+        }
+        let name = self.node.to_string();
+        if name.contains(|c: char| c.is_whitespace() || NEWICK_SPECIAL.contains(c)) {
+            write!(f, "'{}'", name.replace('\'', "''"))
+        } else {
+            write!(f, "{name}")
+        }
+    }
+
+    /// The nested format `Display` wrote before it became Newick, e.g. `(0:((1:0.5)(2:0.7)))`
+    pub fn debug_format(&self) -> String {
+        format!(
             "({}:({}))",
             self.node,
             self.children
                 .iter()
                 .map(|(t, dist)| if !t.children.is_empty() {
                     match dist {
-                        Some(dist) => format!("({t}:{dist})"),
-                        None => format!("({t})"),
+                        Some(dist) => format!("({}:{dist})", t.debug_format()),
+                        None => format!("({})", t.debug_format()),
                     }
                 } else {
                     match dist {
@@ -42,14 +84,149 @@ impl<N: Clone + Display> Display for Tree<N> {
     }
 }
 
+/// Characters with a meaning in Newick, names holding any of them are quoted
+const NEWICK_SPECIAL: &str = "()[]':;,";
+
+impl<N: FromStr> FromStr for Tree<N>
+where
+    N::Err: Display,
+{
+    type Err = String;
+
+    /// Parse a Newick string, skipping whitespace and `[..]` comments such as NHX
+    /// attributes. A length on the root branch is accepted but dropped as the tree has
+    /// nowhere to keep it.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut newick = NewickReader { s, pos: 0 };
+        let (tree, _) = newick.subtree()?;
+        newick.expect(';')?;
+        newick.skip()?;
+        match newick.pos == s.len() {
+            true => Ok(tree),
+            false => Err(format!("text after the tree at byte {}", newick.pos)),
+        }
+    }
+}
+
+/// Recursive descent over a Newick string, `pos` is the byte offset of the next token
+struct NewickReader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> NewickReader<'a> {
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    /// Skip whitespace and comments
+    fn skip(&mut self) -> Result<(), String> {
+        loop {
+            let trimmed = self.rest().trim_start();
+            self.pos = self.s.len() - trimmed.len();
+            if !trimmed.starts_with('[') {
+                return Ok(());
+            }
+            match trimmed.find(']') {
+                Some(end) => self.pos += end + 1,
+                None => return Err(format!("unclosed comment at byte {}", self.pos)),
+            }
+        }
+    }
+
+    fn eat(&mut self, c: char) -> Result<bool, String> {
+        self.skip()?;
+        let found = self.rest().starts_with(c);
+        if found {
+            self.pos += c.len_utf8();
+        }
+        Ok(found)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c)? {
+            true => Ok(()),
+            false => Err(format!("expected '{c}' at byte {}", self.pos)),
+        }
+    }
+
+    /// Unquoted token up to the next special character, possibly empty
+    fn token(&mut self) -> &str {
+        let start = self.pos;
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| c.is_whitespace() || NEWICK_SPECIAL.contains(c))
+            .unwrap_or(rest.len());
+        self.pos += end;
+        &self.s[start..self.pos]
+    }
+
+    /// Node name, either a token or quoted with `''` standing for a quote
+    fn name(&mut self) -> Result<String, String> {
+        if !self.eat('\'')? {
+            return Ok(self.token().to_string());
+        }
+        let mut name = String::new();
+        loop {
+            let end = self
+                .rest()
+                .find('\'')
+                .ok_or_else(|| format!("unclosed quote at byte {}", self.pos))?;
+            name.push_str(&self.rest()[..end]);
+            self.pos += end + 1;
+            if !self.rest().starts_with('\'') {
+                return Ok(name);
+            }
+            name.push('\'');
+            self.pos += 1;
+        }
+    }
+
+    /// A node with its descendants and the length of the branch above it
+    fn subtree<N: FromStr>(&mut self) -> Result<(Tree<N>, Option<Float>), String>
+    where
+        N::Err: Display,
+    {
+        let mut children = vec![];
+        if self.eat('(')? {
+            loop {
+                children.push(self.subtree()?);
+                if !self.eat(',')? {
+                    break;
+                }
+            }
+            self.expect(')')?;
+        }
+        self.skip()?;
+        let at = self.pos;
+        let name = self.name()?;
+        let node = name
+            .parse()
+            .map_err(|e| format!("node '{name}' at byte {at}: {e}"))?;
+        let length = match self.eat(':')? {
+            true => {
+                self.skip()?;
+                let at = self.pos;
+                let length = self.token();
+                let length = length
+                    .parse()
+                    .map_err(|e| format!("branch length '{length}' at byte {at}: {e}"))?;
+                Some(length)
+            }
+            false => None,
+        };
+        Ok((Tree { node, children }, length))
+    }
+}
+
 impl<N: Clone> Tree<N> {
     /// Create a new phylogeny with no children
-    fn new(node: N, children: Vec<(Self, Option<f64>)>) -> Self {
+    pub fn new(node: N, children: Vec<(Self, Option<Float>)>) -> Self {
         Self { node, children }
     }
 
     /// Construct a new leaf - phylogeny without any children
-    fn new_leaf(node: N) -> Self {
+    pub fn new_leaf(node: N) -> Self {
         Self {
             node,
             children: vec![],
@@ -57,7 +234,7 @@ impl<N: Clone> Tree<N> {
     }
 
     /// Join to phylogenies with a given parent
-    fn join_with_parent(parent: N, l: Self, ld: f64, r: Self, rd: f64) -> Self {
+    pub fn join_with_parent(parent: N, l: Self, ld: Float, r: Self, rd: Float) -> Self {
         // this is for bottom up construction like NJ or UPGMA
         Self {
             node: parent,
@@ -66,28 +243,48 @@ impl<N: Clone> Tree<N> {
     }
 }
 
-/// Representation of a node
-#[derive(Debug, Serialize)]
+/// Payload of a node, its links and branch are kept by the [`Phylogeny`]
+#[derive(Debug, Clone, Serialize)]
 pub struct Node<N, L> {
     pub data: N,
     pub label: L,
-    parent: Option<usize>,
-    children: Vec<(usize, f64)>,
+    /// Free form attributes, boxed so nodes without any stay small
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Box<Metadata>>,
 }
 
+/// Attributes of a node beyond its site label, e.g. driver genotype, deme coordinates or
+/// sampling time
+pub type Metadata = BTreeMap<String, String>;
+
 impl<N, L> Node<N, L> {
     pub fn root(data: N, label: L) -> Self {
         Self {
             data,
             label,
-            parent: None,
-            children: vec![],
+            metadata: None,
         }
     }
 
     pub fn update_label(&mut self, l: L) {
         self.label = l
     }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_deref()
+    }
+
+    /// Value of the metadata entry `key`
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.metadata()?.get(key).map(|v| v.as_str())
+    }
+
+    /// Set the metadata entry `key`, replacing any previous value
+    pub fn set_meta<T: ToString>(&mut self, key: &str, value: T) {
+        self.metadata
+            .get_or_insert_with(Default::default)
+            .insert(key.to_string(), value.to_string());
+    }
 }
 
 impl<N: Clone + Display, L: Display> Display for Node<N, L> {
@@ -97,30 +294,66 @@ impl<N: Clone + Display, L: Display> Display for Node<N, L> {
 }
 
 /// Simulation data structure for building a phylogeny top down best for
-/// simulation like tree construction as branching process
-#[derive(Debug, Serialize)]
+/// simulation like tree construction as branching process.
+///
+/// Nodes are stored as a struct of arrays indexed by node: the payloads in `nodes`, the
+/// links, branch lengths and liveness in arrays of their own, so a traversal only reads
+/// the arrays it needs. Children are linked first-child/next-sibling, so a node costs no
+/// allocation of its own. Nodes are only ever appended below existing ones and removals
+/// keep the relative order, so every parent comes before its children, children are in
+/// index order and many traversals are a single pass over the indices
+#[derive(Debug, Clone, Serialize)]
 pub struct Phylogeny<N, L> {
     pub nodes: Vec<Node<N, L>>,
-    root_length: f64,
+    /// Parent of every node, [`NO_NODE`] for the root
+    parents: Vec<usize>,
+    first_child: Vec<usize>,
+    /// Kept to append children without walking the siblings
+    last_child: Vec<usize>,
+    next_sibling: Vec<usize>,
+    /// Length of the branch above every node, the root branch included
+    lengths: Vec<Float>,
+    /// Lineages removed by culling are kept in the tree but marked dead
+    alive: Vec<bool>,
     pub root: usize,
 }
 
+/// Missing link: parent of the root, children of a leaf, sibling of a last child
+const NO_NODE: usize = usize::MAX;
+
+/// Children of a node with the lengths of their branches, see [`Phylogeny::children`]
+#[derive(Debug, Clone)]
+pub struct Children<'a> {
+    next: usize,
+    next_sibling: &'a [usize],
+    lengths: &'a [Float],
+}
+
+impl Iterator for Children<'_> {
+    type Item = (usize, Float);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let child = Some(self.next).filter(|&c| c != NO_NODE)?;
+        self.next = self.next_sibling[child];
+        Some((child, self.lengths[child]))
+    }
+}
+
 impl<N: Clone + Display, L: Display> Display for Phylogeny<N, L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fn write_node<N: fmt::Display, L: fmt::Display>(
+        fn write_node<N: fmt::Display, L>(
             f: &mut fmt::Formatter<'_>,
-            nodes: &[Node<N, L>],
+            tree: &Phylogeny<N, L>,
             node_idx: usize,
         ) -> fmt::Result {
-            let node = &nodes[node_idx];
-            write!(f, "{}", node.data)?;
-            if !node.children.is_empty() {
+            write!(f, "{}", tree.nodes[node_idx].data)?;
+            if !tree.is_leaf(node_idx) {
                 write!(f, "(")?;
-                for (i, (child_idx, dist)) in node.children.iter().enumerate() {
+                for (i, (child_idx, dist)) in tree.children(node_idx).enumerate() {
                     if i > 0 {
                         write!(f, ",")?;
                     }
-                    write_node(f, nodes, *child_idx)?;
+                    write_node(f, tree, child_idx)?;
                     write!(f, ":{}", dist)?;
                 }
                 write!(f, ")")?;
@@ -129,119 +362,1155 @@ impl<N: Clone + Display, L: Display> Display for Phylogeny<N, L> {
         }
 
         write!(f, "(")?;
-        write_node(f, &self.nodes, self.root)?;
+        write_node(f, self, self.root)?;
         write!(f, ")")
     }
 }
 
 impl<N: Display + Clone, L: Display + Clone> Phylogeny<N, L> {
-    pub fn write_csv<W: Write>(&self, mut w: W) -> io::Result<()> {
-        writeln!(w, "parent,child,length")?;
+    /// Write the edge list as `parent,child,length`
+    pub fn write_csv<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_csv_with(w, CsvDialect::default())
+    }
+
+    pub fn write_tsv<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_csv_with(w, CsvDialect::tsv())
+    }
+
+    /// Write the edge list in any csv dialect
+    pub fn write_csv_with<W: Write>(&self, w: W, dialect: CsvDialect) -> io::Result<()> {
+        let mut csv = CsvWriter::new(w, dialect);
+        csv.header(["parent", "child", "length"])?;
         for (p, c, len) in self.edges() {
-            writeln!(w, "{},{},{}", p, c, len)?;
+            csv.record([&p as &dyn Display, &c, &len])?;
         }
         Ok(())
     }
 
-    pub fn write_tsv<W: Write>(&self, mut w: W) -> io::Result<()> {
-        writeln!(w, "parent\tchild\tlength")?;
-        for (p, c, len) in self.edges() {
-            writeln!(w, "{}\t{}\t{}", p, c, len)?;
+    pub fn write_csv_vertex_labeling<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_csv_vertex_labeling_with(w, CsvDialect::default())
+    }
+
+    pub fn write_csv_vertex_labeling_with<W: Write>(
+        &self,
+        w: W,
+        dialect: CsvDialect,
+    ) -> io::Result<()> {
+        let mut csv = CsvWriter::new(w, dialect);
+        csv.header(["vertex", "label"])?;
+        for (i, n) in self.nodes.iter().enumerate() {
+            csv.record([&i as &dyn Display, &n.label])?;
         }
         Ok(())
     }
 
-    pub fn write_csv_vertex_labeling<W: Write>(&self, mut w: W) -> io::Result<()> {
-        writeln!(w, "vertex,label")?;
+    /// Write one row per node with its parent, label, generation, time from the root,
+    /// number of leaves below it, whether it is a leaf and whether its lineage is alive.
+    /// The root has an empty parent
+    pub fn write_node_table<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_node_table_with(w, CsvDialect::default())
+    }
+
+    pub fn write_node_table_with<W: Write>(&self, w: W, dialect: CsvDialect) -> io::Result<()> {
+        let mut csv = CsvWriter::new(w, dialect);
+        csv.header([
+            "node",
+            "parent",
+            "label",
+            "generation",
+            "time",
+            "leaves",
+            "is_leaf",
+            "alive",
+        ])?;
+        let depths = self.depths();
+        let times = self.times_from_root();
+        let leaves = self.subtree_leaves();
         for (i, n) in self.nodes.iter().enumerate() {
-            writeln!(w, "{},{}", i, n.label)?;
+            let parent = self.parent(i).map_or(String::new(), |p| p.to_string());
+            csv.record([
+                &i as &dyn Display,
+                &parent,
+                &n.label,
+                &depths[i],
+                &times[i],
+                &leaves[i],
+                &self.is_leaf(i),
+                &self.alive[i],
+            ])?;
         }
         Ok(())
     }
 
-    pub fn write_csv_leaf_labeling<W: Write>(&self, mut w: W) -> io::Result<()> {
-        writeln!(w, "leaf,label")?;
-        for n in self.leaves() {
-            writeln!(w, "{},{}", n, self.nodes[n].label)?;
+    /// Write the site label of every leaf keyed by its node index, the same index as in
+    /// [`Self::write_csv`] and [`Self::write_csv_vertex_labeling`]. `rank` numbers the
+    /// leaves from 0 in node order
+    pub fn write_csv_leaf_labeling<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_csv_leaf_labeling_with(w, CsvDialect::default())
+    }
+
+    pub fn write_csv_leaf_labeling_with<W: Write>(
+        &self,
+        w: W,
+        dialect: CsvDialect,
+    ) -> io::Result<()> {
+        let mut csv = CsvWriter::new(w, dialect);
+        csv.header(["leaf", "rank", "label"])?;
+        for (rank, n) in self.leaves().enumerate() {
+            csv.record([&n as &dyn Display, &rank, &self.nodes[n].label])?;
         }
         Ok(())
     }
+
+    /// Write the tree in Newick format with node data as names
+    pub fn write_newick<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_newick_impl(w, false)
+    }
+
+    /// Write the tree in New Hampshire eXtended format: Newick with the site label and
+    /// metadata of every node in a `[&&NHX:site=..:key=value]` comment
+    pub fn write_nhx<W: Write>(&self, w: W) -> io::Result<()> {
+        self.write_newick_impl(w, true)
+    }
+
+    /// Write a NEXUS file with the tree in a trees block, rooted and named `simulation`
+    pub fn write_nexus<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "#NEXUS")?;
+        writeln!(w, "BEGIN TREES;")?;
+        write!(w, "    TREE simulation = [&R] ")?;
+        self.write_newick(&mut w)?;
+        writeln!(w, "END;")
+    }
+
+    fn write_newick_impl<W: Write>(&self, mut w: W, nhx: bool) -> io::Result<()> {
+        fn write_nhx<N, L: Display, W: Write>(w: &mut W, node: &Node<N, L>) -> io::Result<()> {
+            // the separators of NHX can not appear in keys or values
+            let clean = |s: &str| s.replace([':', '=', '[', ']', ',', '(', ')', ';'], "_");
+            write!(w, "[&&NHX:site={}", clean(&node.label.to_string()))?;
+            for (key, value) in node.metadata().into_iter().flatten() {
+                write!(w, ":{}={}", clean(key), clean(value))?;
+            }
+            write!(w, "]")
+        }
+
+        fn write_node<N: Display, L: Display, W: Write>(
+            w: &mut W,
+            tree: &Phylogeny<N, L>,
+            node_idx: usize,
+            nhx: bool,
+        ) -> io::Result<()> {
+            if !tree.is_leaf(node_idx) {
+                write!(w, "(")?;
+                for (i, (child_idx, dist)) in tree.children(node_idx).enumerate() {
+                    if i > 0 {
+                        write!(w, ",")?;
+                    }
+                    write_node(w, tree, child_idx, nhx)?;
+                    write!(w, ":{dist}")?;
+                    if nhx {
+                        write_nhx(w, &tree.nodes[child_idx])?;
+                    }
+                }
+                write!(w, ")")?;
+            }
+            write!(w, "{}", tree.nodes[node_idx].data)
+        }
+
+        write_node(&mut w, self, self.root, nhx)?;
+        write!(w, ":{}", self.root_length())?;
+        if nhx {
+            write_nhx(&mut w, &self.nodes[self.root])?;
+        }
+        writeln!(w, ";")
+    }
+}
+
+/// Version of the JSON schema written by [`Phylogeny::json_dump`]. Bump this whenever a
+/// field is added, removed or changes meaning so downstream parsers can detect it.
+pub const JSON_FORMAT_VERSION: u32 = 3;
+
+/// Top level object of the JSON output
+#[derive(Serialize)]
+struct JsonDocument<'a, P, N, L> {
+    format_version: u32,
+    parameters: &'a P,
+    sites: &'a [String],
+    tree: JsonNode<'a, N, L>,
+}
+
+/// Node of the JSON output - kept apart from [`Node`] so the schema does not follow
+/// changes to the internal representation
+#[derive(Serialize)]
+struct JsonNode<'a, N, L> {
+    id: usize,
+    data: &'a N,
+    label: &'a L,
+    length: Float,
+    /// Edges from the root
+    generation: usize,
+    /// Sum of branch lengths from the root
+    time: Float,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Metadata>,
+    children: Vec<JsonNode<'a, N, L>>,
 }
 
 impl<N: Serialize + Clone, L: Serialize + Clone> Phylogeny<N, L> {
-    /// Dump json to fil
-    pub fn json_dump(&self, fname: &str) -> io::Result<()> {
-        fs::write(
-            fname,
-            serde_json::to_string_pretty(&self.to_tree()).unwrap(),
-        )
+    /// Dump json to file
+    pub fn json_dump<P: Serialize>(
+        &self,
+        fname: &str,
+        parameters: &P,
+        sites: &[String],
+    ) -> io::Result<()> {
+        self.write_json(fs::File::create(fname)?, parameters, sites)
+    }
+
+    /// Write the versioned json document - parameters, site names and the tree
+    pub fn write_json<W: Write, P: Serialize>(
+        &self,
+        mut w: W,
+        parameters: &P,
+        sites: &[String],
+    ) -> io::Result<()> {
+        let doc = JsonDocument {
+            format_version: JSON_FORMAT_VERSION,
+            parameters,
+            sites,
+            tree: self.json_node(self.root, self.root_length(), 0, 0.0),
+        };
+        serde_json::to_writer_pretty(&mut w, &doc)?;
+        writeln!(w)
+    }
+
+    fn json_node(
+        &self,
+        idx: usize,
+        length: Float,
+        generation: usize,
+        time: Float,
+    ) -> JsonNode<'_, N, L> {
+        let node = &self.nodes[idx];
+
+        JsonNode {
+            id: idx,
+            data: &node.data,
+            label: &node.label,
+            length,
+            generation,
+            time,
+            metadata: node.metadata(),
+            children: self
+                .children(idx)
+                .map(|(child_idx, dist)| {
+                    self.json_node(child_idx, dist, generation + 1, time + dist)
+                })
+                .collect(),
+        }
     }
 }
 
-impl<N: Clone, L: Clone> Phylogeny<N, L> {
-    /// Build a new phylogeny with a given root
-    pub fn new(root: Node<N, L>, root_length: f64) -> Self {
-        Self {
-            nodes: vec![root],
-            root_length,
-            root: 0,
+/// The parts of the JSON output [`Phylogeny::read_json`] needs
+#[derive(Deserialize)]
+struct JsonInput<N, L> {
+    format_version: u32,
+    tree: JsonInputNode<N, L>,
+}
+
+#[derive(Deserialize)]
+struct JsonInputNode<N, L> {
+    data: N,
+    label: L,
+    length: Float,
+    metadata: Option<Metadata>,
+    children: Vec<JsonInputNode<N, L>>,
+}
+
+impl<N: DeserializeOwned + Clone, L: DeserializeOwned + Clone> Phylogeny<N, L> {
+    /// Read the tree of a JSON document written by [`Phylogeny::write_json`], with its
+    /// node data, labels, lengths and metadata. Nodes are numbered level by level, which
+    /// is creation order for simulated trees
+    pub fn read_json<R: Read>(r: R) -> Result<Self, String> {
+        let doc: JsonInput<N, L> = serde_json::from_reader(r).map_err(|e| e.to_string())?;
+        if doc.format_version > JSON_FORMAT_VERSION {
+            return Err(format!(
+                "JSON format {} is newer than {JSON_FORMAT_VERSION}",
+                doc.format_version
+            ));
         }
+        let node = |n: JsonInputNode<N, L>| {
+            let mut node = Node::root(n.data, n.label);
+            node.metadata = n.metadata.map(Box::new);
+            (node, n.length, n.children)
+        };
+        let (root, length, children) = node(doc.tree);
+        let mut tree = Phylogeny::new(root, length);
+        let mut level = vec![(0, children)];
+        while !level.is_empty() {
+            let mut next = vec![];
+            for (parent, children) in level {
+                for child in children {
+                    let (child, length, children) = node(child);
+                    next.push((tree.push(child, parent, length), children));
+                }
+            }
+            level = next;
+        }
+        Ok(tree)
+    }
+}
+
+/// Genealogy of a sample of nodes, see [`Phylogeny::sampled_genealogy`]
+#[derive(Debug)]
+pub struct Genealogy<N, L> {
+    pub tree: Phylogeny<N, L>,
+    /// Index in the original tree of every node
+    pub original: Vec<usize>,
+    /// Whether every node was in the sample, internal nodes that were not are the unsampled
+    /// ancestors where sampled lineages coalesce
+    pub sampled: Vec<bool>,
+}
+
+/// A subtree cut out of a tree, see [`Phylogeny::detach_subtree`]
+#[derive(Debug)]
+pub struct Detached<N, L> {
+    /// The subtree, its root branch is the branch it hung from
+    pub tree: Phylogeny<N, L>,
+    /// Index before detaching of every node of `tree`
+    pub original: Vec<usize>,
+    /// Index after detaching of every node of the remaining tree, `None` for the nodes
+    /// that were detached
+    pub remap: Vec<Option<usize>>,
+}
+
+impl<N, L> Genealogy<N, L> {
+    /// Nodes of the genealogy that stand for unsampled ancestors
+    pub fn unsampled_ancestors(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.tree.nodes.len()).filter(|&i| !self.sampled[i])
     }
+}
 
-    /// Get an iterator over leaves
-    pub fn leaves(&self) -> impl Iterator<Item = usize> + '_ {
-        self.nodes
-            .iter()
-            .enumerate()
-            .filter_map(|(i, n)| match n.children.is_empty() {
-                true => Some(i),
-                false => None,
-            })
+impl<N: Clone, L: Clone> Phylogeny<N, L> {
+    /// Build a new phylogeny with a given root
+    pub fn new(root: Node<N, L>, root_length: Float) -> Self {
+        let mut tree = Self::with_capacity(1);
+        tree.push(root, NO_NODE, root_length);
+        tree
     }
 
     /// Add a new child to a given parent
-    pub fn add_child(&mut self, parent: usize, data: N, label: L, dist: f64) -> usize {
-        let id = self.nodes.len();
-        // store the new node without any children
-        self.nodes.push(Node {
-            data,
-            label,
-            parent: Some(parent),
-            children: vec![],
-        });
-        // add the new as a child
-        self.nodes[parent].children.push((id, dist));
-        id
+    pub fn add_child(&mut self, parent: usize, data: N, label: L, dist: Float) -> usize {
+        self.push(Node::root(data, label), parent, dist)
     }
 
     pub fn to_tree(&self) -> Tree<N> {
         self.build_tree(self.root)
     }
 
-    fn build_tree(&self, idx: usize) -> Tree<N> {
-        let node = &self.nodes[idx];
+    /// The genealogy induced by `sample`, usually leaves: the sampled nodes and the
+    /// ancestors where at least two sampled lineages meet, rooted at their most recent
+    /// common ancestor. Unary ancestors are dissolved and their branches summed, so
+    /// branch times are those of the original tree; the root branch runs from the top of
+    /// the original root branch. Node data, labels and metadata are kept. Sampled internal
+    /// nodes stay as sampled ancestors. `None` for an empty sample
+    pub fn sampled_genealogy(&self, sample: &[usize]) -> Option<Genealogy<N, L>> {
+        let mut in_sample = vec![false; self.nodes.len()];
+        for &idx in sample {
+            in_sample[idx] = true;
+        }
+        // sampled nodes in and below every node
+        let mut below = vec![0; self.nodes.len()];
+        for idx in (0..self.nodes.len()).rev() {
+            below[idx] += in_sample[idx] as usize;
+            if let Some(parent) = self.parent(idx) {
+                below[parent] += below[idx];
+            }
+        }
+        let sampled_children = |idx: usize| self.children(idx).filter(|&(c, _)| below[c] > 0);
+        let kept = |idx: usize| in_sample[idx] || sampled_children(idx).count() > 1;
+        // follows the only sampled child until a kept node
+        let descend = |mut idx: usize, mut length: Float| {
+            while !kept(idx) {
+                let (child, len) = sampled_children(idx).next().unwrap();
+                idx = child;
+                length += len;
+            }
+            (idx, length)
+        };
+        if below[self.root] == 0 {
+            return None;
+        }
+
+        let (root, root_length) = descend(self.root, self.root_length());
+        let copy = |idx: usize| Node {
+            data: self.nodes[idx].data.clone(),
+            label: self.nodes[idx].label.clone(),
+            metadata: self.nodes[idx].metadata.clone(),
+        };
+        let mut genealogy = Genealogy {
+            tree: Phylogeny::new(copy(root), root_length),
+            original: vec![root],
+            sampled: vec![in_sample[root]],
+        };
+        genealogy.tree.alive[0] = self.alive[root];
+        let mut stack = vec![(0, root)];
+        while let Some((new, idx)) = stack.pop() {
+            for (child, len) in sampled_children(idx) {
+                let (child, length) = descend(child, len);
+                let node = copy(child);
+                let id = genealogy.tree.add_child(new, node.data, node.label, length);
+                genealogy.tree.alive[id] = self.alive[child];
+                genealogy.tree.nodes[id].metadata = node.metadata;
+                genealogy.original.push(child);
+                genealogy.sampled.push(in_sample[child]);
+                stack.push((id, child));
+            }
+        }
+        Some(genealogy)
+    }
 
+    /// Hang the subtree below the root of `subtree` below `idx`, with the data, labels,
+    /// metadata and liveness of its nodes. The new nodes come after all others; returns the
+    /// index of every node of `subtree`, `idx` for its root
+    pub fn graft(&mut self, idx: usize, subtree: &Phylogeny<N, L>) -> Vec<usize> {
+        let mut index = vec![idx; subtree.nodes.len()];
+        // parents come before their children
+        for i in (0..subtree.nodes.len()).filter(|&i| i != subtree.root) {
+            let parent = index[subtree.parents[i]];
+            let id = self.push(subtree.nodes[i].clone(), parent, subtree.lengths[i]);
+            self.alive[id] = subtree.alive[i];
+            index[i] = id;
+        }
+        debug_assert_eq!(self.validate(), Ok(()));
+        index
+    }
+
+    fn build_tree(&self, idx: usize) -> Tree<N> {
         Tree {
-            node: node.data.clone(),
-            children: node
-                .children
-                .iter()
-                .map(|&(child_idx, dist)| (self.build_tree(child_idx), Some(dist)))
+            node: self.nodes[idx].data.clone(),
+            children: self
+                .children(idx)
+                .map(|(child_idx, dist)| (self.build_tree(child_idx), Some(dist)))
                 .collect(),
         }
     }
 }
 
 impl<N, L> Phylogeny<N, L> {
-    pub fn edges(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
-        self.nodes
-            .iter()
-            .enumerate()
-            .flat_map(|(parent_idx, node)| {
-                node.children
-                    .iter()
-                    .map(move |&(child_idx, len)| (parent_idx, child_idx, len))
+    /// Empty tree with room for `capacity` nodes, only valid once a root is pushed
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            nodes: Vec::with_capacity(capacity),
+            parents: Vec::with_capacity(capacity),
+            first_child: Vec::with_capacity(capacity),
+            last_child: Vec::with_capacity(capacity),
+            next_sibling: Vec::with_capacity(capacity),
+            lengths: Vec::with_capacity(capacity),
+            alive: Vec::with_capacity(capacity),
+            root: 0,
+        }
+    }
+
+    /// Reserve room for `additional` more nodes in every array, e.g. the nodes a
+    /// simulation is known to add
+    pub fn reserve(&mut self, additional: usize) {
+        self.nodes.reserve(additional);
+        self.parents.reserve(additional);
+        self.first_child.reserve(additional);
+        self.last_child.reserve(additional);
+        self.next_sibling.reserve(additional);
+        self.lengths.reserve(additional);
+        self.alive.reserve(additional);
+    }
+
+    /// Append a living node without children as the last child of `parent`
+    fn push(&mut self, node: Node<N, L>, parent: usize, length: Float) -> usize {
+        let id = self.nodes.len();
+        debug_assert!(
+            parent < id || parent == NO_NODE,
+            "parent {parent} is not a node"
+        );
+        self.nodes.push(node);
+        self.parents.push(parent);
+        self.first_child.push(NO_NODE);
+        self.last_child.push(NO_NODE);
+        self.next_sibling.push(NO_NODE);
+        self.lengths.push(length);
+        self.alive.push(true);
+        if parent != NO_NODE {
+            match self.last_child[parent] {
+                NO_NODE => self.first_child[parent] = id,
+                last => self.next_sibling[last] = id,
+            }
+            self.last_child[parent] = id;
+        }
+        id
+    }
+
+    /// Move the nodes with `part` set into a tree of their own, both trees keeping the
+    /// relative order of their nodes with `new_index` the index of every node in the tree
+    /// it ends up in. Links between the two parts are cut; roots are left to the caller.
+    /// Children are in index order, so pushing in order links them in the same order
+    fn split_off(&mut self, part: &[bool], new_index: &[usize]) -> Self {
+        let mut trees = [Self::with_capacity(0), Self::with_capacity(0)];
+        let nodes = std::mem::take(&mut self.nodes);
+        for (i, node) in nodes.into_iter().enumerate() {
+            let tree = &mut trees[part[i] as usize];
+            let parent = Some(self.parents[i])
+                .filter(|&p| p != NO_NODE && part[p] == part[i])
+                .map_or(NO_NODE, |p| new_index[p]);
+            tree.push(node, parent, self.lengths[i]);
+            tree.alive[new_index[i]] = self.alive[i];
+        }
+        let [kept, split] = trees;
+        *self = Self {
+            root: self.root,
+            ..kept
+        };
+        split
+    }
+
+    /// Check the invariants every operation keeps, a failure is a bug:
+    ///
+    /// - every node array has an entry per node and the root is a node
+    /// - the root has no parent and every other node has one
+    /// - every parent comes before its children, so there are no cycles
+    /// - the children of every node are linked in index order and are exactly the nodes
+    ///   whose parent it is
+    pub fn validate(&self) -> Result<(), String> {
+        let n = self.nodes.len();
+        let lengths = [
+            self.parents.len(),
+            self.first_child.len(),
+            self.last_child.len(),
+            self.next_sibling.len(),
+            self.lengths.len(),
+            self.alive.len(),
+        ];
+        if lengths.iter().any(|&l| l != n) {
+            return Err(format!("node arrays of lengths {lengths:?} for {n} nodes"));
+        }
+        if self.root >= n {
+            return Err(format!("root {} is not one of the {n} nodes", self.root));
+        }
+        // last child of every node linked so far
+        let mut last = vec![NO_NODE; n];
+        for i in 0..n {
+            match self.parent(i) {
+                None if i != self.root => {
+                    return Err(format!("node {i} has no parent but is not the root"));
+                }
+                None => {}
+                Some(p) if i == self.root => return Err(format!("the root has parent {p}")),
+                Some(p) if p >= i => return Err(format!("node {i} comes before its parent {p}")),
+                Some(p) => {
+                    let linked = match last[p] {
+                        NO_NODE => self.first_child[p],
+                        previous => self.next_sibling[previous],
+                    };
+                    if linked != i {
+                        return Err(format!("node {i} is not linked as a child of {p}"));
+                    }
+                    last[p] = i;
+                }
+            }
+        }
+        for (p, &last) in last.iter().enumerate() {
+            let open = last != NO_NODE && self.next_sibling[last] != NO_NODE;
+            let extra = last == NO_NODE && self.first_child[p] != NO_NODE;
+            if open || extra || self.last_child[p] != last {
+                return Err(format!("node {p} links children that are not its own"));
+            }
+        }
+        match self.next_sibling[self.root] {
+            NO_NODE => Ok(()),
+            sibling => Err(format!("the root has sibling {sibling}")),
+        }
+    }
+
+    /// Fold from the leaves up, `f` gets every node and the values of its children
+    fn fold<T>(&self, mut f: impl FnMut(&Node<N, L>, Vec<T>) -> T) -> T {
+        let mut values: Vec<Option<T>> = (0..self.nodes.len()).map(|_| None).collect();
+        // children come after their parent
+        for idx in (0..self.nodes.len()).rev() {
+            let children = self
+                .children(idx)
+                .map(|(c, _)| values[c].take().unwrap())
+                .collect();
+            values[idx] = Some(f(&self.nodes[idx], children));
+        }
+        values[self.root].take().unwrap()
+    }
+
+    /// Hash of the unordered topology, independent of node data, labels, branch lengths
+    /// and the order of children. Stable between runs, so it can key topologies across
+    /// replicates
+    pub fn topology_hash(&self) -> u64 {
+        self.fold(|_, children| subtree_hash((), children))
+    }
+
+    /// [`Self::topology_hash`] that also tells apart trees with different site labels
+    pub fn labeled_topology_hash(&self) -> u64
+    where
+        L: Hash,
+    {
+        self.fold(|node, children| subtree_hash(&node.label, children))
+    }
+
+    /// Same unordered topology as `other`, ignoring node data, labels and branch lengths
+    pub fn is_isomorphic<M, K>(&self, other: &Phylogeny<M, K>) -> bool {
+        let mut ids = ShapeIds::new();
+        let a = self.fold(|_, children| shape_id(&mut ids, (), children));
+        let b = other.fold(|_, children| shape_id(&mut ids, (), children));
+        a == b
+    }
+
+    /// Same unordered topology as `other` with the same labels on matching nodes
+    pub fn is_labeled_isomorphic(&self, other: &Self) -> bool
+    where
+        L: Hash + Eq + Clone,
+    {
+        let mut ids = ShapeIds::new();
+        let a = self.fold(|node, children| shape_id(&mut ids, node.label.clone(), children));
+        let b = other.fold(|node, children| shape_id(&mut ids, node.label.clone(), children));
+        a == b
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = (usize, usize, Float)> + '_ {
+        (0..self.nodes.len()).flat_map(move |parent_idx| {
+            self.children(parent_idx)
+                .map(move |(child_idx, len)| (parent_idx, child_idx, len))
+        })
+    }
+
+    /// Mark a lineage as dead, it stays in the tree
+    pub fn kill(&mut self, idx: usize) {
+        self.alive[idx] = false
+    }
+
+    /// Whether the lineage of a node was alive at the end of the simulation
+    pub fn is_alive(&self, idx: usize) -> bool {
+        self.alive[idx]
+    }
+
+    /// Cut the subtree below `idx` out of the tree. Both trees are re-indexed keeping
+    /// the relative order of their nodes, so parents still come before children; the
+    /// returned maps translate the old indices. Panics for the root
+    pub fn detach_subtree(&mut self, idx: usize) -> Detached<N, L> {
+        assert_ne!(idx, self.root, "the root cannot be detached");
+        let mut detached = vec![false; self.nodes.len()];
+        let mut stack = vec![idx];
+        while let Some(i) = stack.pop() {
+            detached[i] = true;
+            stack.extend(self.children(i).map(|(c, _)| c));
+        }
+        let new_index = renumber(&detached);
+        let mut tree = self.split_off(&detached, &new_index);
+        tree.root = new_index[idx];
+        self.root = new_index[self.root];
+        debug_assert_eq!(self.validate(), Ok(()));
+        debug_assert_eq!(tree.validate(), Ok(()));
+
+        Detached {
+            tree,
+            original: (0..detached.len()).filter(|&i| detached[i]).collect(),
+            remap: (0..detached.len())
+                .map(|i| (!detached[i]).then_some(new_index[i]))
+                .collect(),
+        }
+    }
+
+    /// Remove every node below `idx`, which becomes a leaf, returning the new index of
+    /// every node as in [`Self::detach_subtree`]
+    pub fn remove_descendants(&mut self, idx: usize) -> Vec<Option<usize>> {
+        let mut below = vec![false; self.nodes.len()];
+        let mut stack: Vec<_> = self.children(idx).map(|(c, _)| c).collect();
+        while let Some(i) = stack.pop() {
+            below[i] = true;
+            stack.extend(self.children(i).map(|(c, _)| c));
+        }
+        let new_index = renumber(&below);
+        self.split_off(&below, &new_index);
+        self.root = new_index[self.root];
+        debug_assert_eq!(self.validate(), Ok(()));
+        (0..below.len())
+            .map(|i| (!below[i]).then_some(new_index[i]))
+            .collect()
+    }
+
+    /// Remove the dead lineages, every node without an extant leaf below it, so exports
+    /// only show the ancestry of extant lineages. Unary ancestors are kept, so depths are
+    /// still generations. Returns the new index of every node as in
+    /// [`Self::detach_subtree`]. Without extant leaves only the root is left
+    pub fn prune_dead(&mut self) -> Vec<Option<usize>> {
+        let mut keep = vec![false; self.nodes.len()];
+        for leaf in self.extant_leaves().collect::<Vec<_>>() {
+            let mut node = Some(leaf);
+            while let Some(i) = node.filter(|&i| !keep[i]) {
+                keep[i] = true;
+                node = self.parent(i);
+            }
+        }
+        keep[self.root] = true;
+
+        let dropped: Vec<bool> = keep.iter().map(|k| !k).collect();
+        let new_index = renumber(&dropped);
+        self.split_off(&dropped, &new_index);
+        self.root = new_index[self.root];
+        debug_assert_eq!(self.validate(), Ok(()));
+        (0..keep.len())
+            .map(|i| keep[i].then_some(new_index[i]))
+            .collect()
+    }
+
+    /// Remove the leaf `idx` and its branch, returning the new index of every node as in
+    /// [`Self::detach_subtree`]. Panics for the root or an internal node
+    pub fn remove_leaf(&mut self, idx: usize) -> Vec<Option<usize>> {
+        assert!(self.is_leaf(idx), "node {idx} is not a leaf");
+        self.detach_subtree(idx).remap
+    }
+
+    /// Get an iterator over leaves, dead ones included, the same as [`Self::all_leaves`]
+    pub fn leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        self.all_leaves()
+    }
+
+    /// Leaves of lineages alive at the end of the simulation
+    pub fn extant_leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        self.all_leaves().filter(|&l| self.alive[l])
+    }
+
+    /// Every leaf, including lineages that died or were culled
+    pub fn all_leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.nodes.len()).filter(|&i| self.is_leaf(i))
+    }
+
+    /// Multiply every branch length, the root branch included, by `factor`, e.g. to
+    /// convert generations into time or mutation units
+    pub fn scale_branch_lengths(&mut self, factor: Float) {
+        for len in &mut self.lengths {
+            *len *= factor;
+        }
+    }
+
+    /// Extend the branch of every leaf so all leaves are as far from the root as the
+    /// furthest one. Leaves of culled lineages are extended too
+    pub fn make_ultrametric(&mut self) {
+        let times = self.times_from_root();
+        let leaves: Vec<_> = self.leaves().collect();
+        let height = leaves.iter().map(|&l| times[l]).fold(0.0, Float::max);
+        for leaf in leaves {
+            if leaf != self.root {
+                self.lengths[leaf] += height - times[leaf];
+            }
+        }
+    }
+
+    /// Length of the branch above the root
+    pub fn root_length(&self) -> Float {
+        self.lengths[self.root]
+    }
+
+    /// Length of the branch above a node, the root branch for the root
+    pub fn branch_length(&self, idx: usize) -> Float {
+        self.lengths[idx]
+    }
+
+    /// Parent of a node, `None` for the root
+    pub fn parent(&self, idx: usize) -> Option<usize> {
+        Some(self.parents[idx]).filter(|&p| p != NO_NODE)
+    }
+
+    /// Children of a node and their branch lengths
+    pub fn children(&self, idx: usize) -> Children<'_> {
+        Children {
+            next: self.first_child[idx],
+            next_sibling: &self.next_sibling,
+            lengths: &self.lengths,
+        }
+    }
+
+    /// Number of children of a node, walks the children
+    pub fn child_count(&self, idx: usize) -> usize {
+        self.children(idx).count()
+    }
+
+    /// Whether a node has no children
+    pub fn is_leaf(&self, idx: usize) -> bool {
+        self.first_child[idx] == NO_NODE
+    }
+
+    /// Node indices in preorder - every parent comes before its children
+    pub fn preorder(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![self.root];
+        while let Some(idx) = stack.pop() {
+            order.push(idx);
+            // the next sibling waits until the subtree below the first child is done
+            if idx != self.root && self.next_sibling[idx] != NO_NODE {
+                stack.push(self.next_sibling[idx]);
+            }
+            if self.first_child[idx] != NO_NODE {
+                stack.push(self.first_child[idx]);
+            }
+        }
+        order
+    }
+
+    /// Generation of a node, the number of edges between it and the root. Walks up to
+    /// the root, use [`Self::depths`] for every node
+    pub fn generation(&self, idx: usize) -> usize {
+        self.ancestry(idx).count() - 1
+    }
+
+    /// Sum of branch lengths between the root and a node. Walks up to the root, use
+    /// [`Self::times_from_root`] for every node
+    pub fn time_from_root(&self, idx: usize) -> Float {
+        self.ancestry(idx)
+            .filter(|&i| i != self.root)
+            .map(|i| self.lengths[i])
+            .sum()
+    }
+
+    /// A node and its ancestors up to the root
+    fn ancestry(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(idx), |&i| self.parent(i))
+    }
+
+    /// Number of edges between the root and each node, i.e. the generation of the node
+    pub fn depths(&self) -> Vec<usize> {
+        let mut depths = vec![0; self.nodes.len()];
+        // parents come before their children
+        for idx in 0..self.nodes.len() {
+            if let Some(parent) = self.parent(idx) {
+                depths[idx] = depths[parent] + 1;
+            }
+        }
+        depths
+    }
+
+    /// Sum of branch lengths between the root and each node, the root is at 0
+    pub fn times_from_root(&self) -> Vec<Float> {
+        let mut times = vec![0.0; self.nodes.len()];
+        for idx in 0..self.nodes.len() {
+            if let Some(parent) = self.parent(idx) {
+                times[idx] = times[parent] + self.lengths[idx];
+            }
+        }
+        times
+    }
+
+    /// Number of leaves below each node, a leaf counts itself
+    pub fn subtree_leaves(&self) -> Vec<usize> {
+        let mut leaves = vec![0; self.nodes.len()];
+        // children come after their parent, so they are done when it is reached
+        for idx in (0..self.nodes.len()).rev() {
+            leaves[idx] += self.is_leaf(idx) as usize;
+            if let Some(parent) = self.parent(idx) {
+                leaves[parent] += leaves[idx];
+            }
+        }
+        leaves
+    }
+
+    /// Colless imbalance, the sum over internal nodes of the difference between the
+    /// largest and smallest child subtree in leaves. 0 for a fully balanced tree, for
+    /// polytomies this is the generalization to the extreme children
+    pub fn colless_index(&self) -> usize {
+        let leaves = self.subtree_leaves();
+        (0..self.nodes.len())
+            .filter(|&i| !self.is_leaf(i))
+            .map(|i| {
+                let sizes = self.children(i).map(|(c, _)| leaves[c]);
+                sizes.clone().max().unwrap() - sizes.min().unwrap()
             })
+            .sum()
+    }
+
+    /// Sackin imbalance, the sum of the depths of all leaves
+    pub fn sackin_index(&self) -> usize {
+        let depths = self.depths();
+        self.leaves().map(|l| depths[l]).sum()
+    }
+
+    /// Time from the root to the furthest leaf, without the root branch
+    pub fn height(&self) -> Float {
+        let times = self.times_from_root();
+        self.leaves().map(|l| times[l]).fold(0.0, Float::max)
+    }
+
+    /// Sum of all branch lengths, without the root branch
+    pub fn total_branch_length(&self) -> Float {
+        self.edges().map(|(_, _, length)| length).sum()
+    }
+
+    /// Summed length of the branches to leaves over that of the branches to internal
+    /// nodes, `None` without internal branches
+    pub fn external_internal_ratio(&self) -> Option<Float> {
+        let (external, internal) = self.edges().fold((0.0, 0.0), |(e, i), (_, child, len)| {
+            match self.is_leaf(child) {
+                true => (e + len, i),
+                false => (e, i + len),
+            }
+        });
+        (internal > 0.0).then(|| external / internal)
+    }
+
+    /// Pybus-Harvey gamma statistic of the internode intervals, negative when splits
+    /// concentrate near the root and about standard normal under a pure birth process.
+    /// Lineages are taken to only ever split and last until the height of the tree, as
+    /// in an ultrametric tree; for the reconstructed tree of extant lineages use
+    /// [`Self::prune_dead`] and [`Self::make_ultrametric`] first. A split into `c`
+    /// children adds `c - 1` lineages. `None` below 3 leaves
+    pub fn gamma_statistic(&self) -> Option<Float> {
+        let times = self.times_from_root();
+        let mut splits: Vec<(Float, usize)> = (0..self.nodes.len())
+            .filter(|&i| !self.is_leaf(i))
+            .map(|i| (times[i], self.child_count(i) - 1))
+            .collect();
+        splits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let height = self.height();
+
+        // T_i = sum over the intervals up to the i-th split of lineages x interval length
+        let mut lineages = 1;
+        let mut elapsed = 0.0;
+        let mut previous = splits.first()?.0;
+        let mut partial = vec![];
+        for (time, added) in splits {
+            elapsed += lineages as Float * (time - previous);
+            previous = time;
+            // one term per added lineage, zero length intervals for polytomies
+            for _ in 0..added {
+                partial.push(elapsed);
+                lineages += 1;
+            }
+        }
+        let n = lineages;
+        if n < 3 {
+            return None;
+        }
+        let total = elapsed + n as Float * (height - previous);
+        // the first term is the start, at 2 lineages, the last one would be T itself
+        let inner: Float = partial[1..n - 1].iter().sum();
+        let m = (n - 2) as Float;
+        Some((inner / m - total / 2.0) / (total * (1.0 / (12.0 * m)).sqrt()))
+    }
+}
+
+/// Index of every node within its part, the nodes with `part` unset and those with it set
+/// numbered separately in order
+fn renumber(part: &[bool]) -> Vec<usize> {
+    let mut counts = [0, 0];
+    part.iter()
+        .map(|&p| {
+            counts[p as usize] += 1;
+            counts[p as usize] - 1
+        })
+        .collect()
+}
+
+/// FNV-1a, unlike the std hasher its output does not change between runs or releases
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of a subtree from the key of its root and the hashes of its children, in any
+/// order
+fn subtree_hash<K: Hash>(key: K, mut children: Vec<u64>) -> u64 {
+    children.sort_unstable();
+    let mut h = Fnv::default();
+    key.hash(&mut h);
+    children.hash(&mut h);
+    h.finish()
+}
+
+/// Ids of rooted unordered subtree shapes (AHU encoding), shared between the trees being
+/// compared so equal ids mean isomorphic subtrees
+type ShapeIds<K> = HashMap<(K, Vec<usize>), usize>;
+
+fn shape_id<K: Hash + Eq>(ids: &mut ShapeIds<K>, key: K, mut children: Vec<usize>) -> usize {
+    children.sort_unstable();
+    let next = ids.len();
+    *ids.entry((key, children)).or_insert(next)
+}
+
+impl<N> Tree<N> {
+    fn fold<T>(&self, f: &mut impl FnMut(&N, Vec<T>) -> T) -> T {
+        let children = self.children.iter().map(|(c, _)| c.fold(f)).collect();
+        f(&self.node, children)
+    }
+
+    /// Hash of the unordered topology, independent of node data, branch lengths and the
+    /// order of children
+    pub fn topology_hash(&self) -> u64 {
+        self.fold(&mut |_, children| subtree_hash((), children))
+    }
+
+    /// Same unordered topology as `other`, ignoring node data and branch lengths
+    pub fn is_isomorphic<M>(&self, other: &Tree<M>) -> bool {
+        let mut ids = ShapeIds::new();
+        let a = self.fold(&mut |_, children| shape_id(&mut ids, (), children));
+        let b = other.fold(&mut |_, children| shape_id(&mut ids, (), children));
+        a == b
+    }
+}
+
+/// Rooted binary topology under construction, kept as parent and children links so
+/// leaves can be attached anywhere before conversion to a [`Phylogeny`]
+struct Topology {
+    parent: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// Length of the branch above each node
+    lengths: Vec<Float>,
+    root: usize,
+}
+
+impl Topology {
+    fn leaf() -> Self {
+        Self {
+            parent: vec![None],
+            children: vec![vec![]],
+            lengths: vec![0.0],
+            root: 0,
+        }
+    }
+
+    fn push(&mut self, parent: Option<usize>) -> usize {
+        self.parent.push(parent);
+        self.children.push(vec![]);
+        self.lengths.push(0.0);
+        self.parent.len() - 1
+    }
+
+    /// Renumber in preorder into a phylogeny with node data equal to the index and every
+    /// node on site 0
+    fn into_phylogeny(self) -> Phylogeny<usize, usize> {
+        let mut tree = Phylogeny::new(Node::root(0, 0), self.lengths[self.root]);
+        let mut stack = vec![(self.root, 0)];
+        while let Some((old, new)) = stack.pop() {
+            for &child in self.children[old].iter().rev() {
+                let id = tree.nodes.len();
+                tree.add_child(new, id, 0, self.lengths[child]);
+                stack.push((child, id));
+            }
+        }
+        tree
+    }
+}
+
+impl Phylogeny<usize, usize> {
+    /// Uniformly random rooted binary topology with `leaves` leaves (the proportional to
+    /// distinguishable arrangements model), a null for statistics of migration histories.
+    /// Every branch has length 1 and every node is on site 0
+    pub fn random_pda<R: Rng>(leaves: usize, rng: &mut R) -> Self {
+        assert!(leaves > 0, "a tree needs at least one leaf");
+        let mut t = Topology::leaf();
+        for _ in 1..leaves {
+            // attaching to any of the 2k - 1 branches, the one above the root included,
+            // keeps every topology equally likely
+            let x = rng.gen_range(0..t.parent.len());
+            let parent = t.parent[x];
+            let u = t.push(parent);
+            let leaf = t.push(Some(u));
+            match parent {
+                Some(p) => {
+                    let slot = t.children[p].iter().position(|&c| c == x).unwrap();
+                    t.children[p][slot] = u;
+                }
+                None => t.root = u,
+            }
+            t.parent[x] = Some(u);
+            t.children[u] = vec![x, leaf];
+        }
+        t.lengths.iter_mut().for_each(|l| *l = 1.0);
+        t.lengths[t.root] = 0.0;
+        t.into_phylogeny()
+    }
+
+    /// Pure birth tree grown until it has `leaves` leaves, each lineage splitting at
+    /// `birth_rate`. Stops an exponential waiting time after the last split so no branch
+    /// has length zero. Every node is on site 0, i.e. there is no migration
+    pub fn random_yule<R: Rng>(leaves: usize, birth_rate: Float, rng: &mut R) -> Self {
+        assert!(leaves > 0, "a tree needs at least one leaf");
+        let mut t = Topology::leaf();
+        let mut born = vec![0.0];
+        let mut extant = vec![0];
+        let mut time = 0.0;
+        loop {
+            let k = extant.len() as Float;
+            time += Exp::new(k * birth_rate).unwrap().sample(rng);
+            if extant.len() == leaves {
+                break;
+            }
+            let x = extant.swap_remove(rng.gen_range(0..extant.len()));
+            t.lengths[x] = time - born[x];
+            for _ in 0..2 {
+                let child = t.push(Some(x));
+                t.children[x].push(child);
+                born.push(time);
+                extant.push(child);
+            }
+        }
+        for x in extant {
+            t.lengths[x] = time - born[x];
+        }
+        t.lengths[t.root] = 0.0;
+        t.into_phylogeny()
+    }
+}
+
+impl<N> Phylogeny<N, usize> {
+    /// Multiply the branch above every node by the clock rate of the node's site, for
+    /// sites evolving at different speeds
+    pub fn apply_site_clock(&mut self, rates: &[Float]) {
+        for (length, node) in self.lengths.iter_mut().zip(&self.nodes) {
+            *length *= rates[node.label];
+        }
+    }
+
+    /// Number of `leaves` of each site below every node (nodes x sites), a leaf counts
+    /// itself
+    pub fn site_counts_below(&self, leaves: &[usize], sites: usize) -> Array2<usize> {
+        let mut counts = Array2::zeros((self.nodes.len(), sites));
+        for &leaf in leaves {
+            counts[[leaf, self.nodes[leaf].label]] += 1;
+        }
+        // children come after their parent, so they are done when it is reached
+        for idx in (0..self.nodes.len()).rev() {
+            if let Some(parent) = self.parent(idx) {
+                for site in 0..sites {
+                    counts[[parent, site]] += counts[[idx, site]];
+                }
+            }
+        }
+        counts
+    }
+
+    /// Number of nodes of each site at each generation (generations x sites)
+    pub fn site_counts_by_generation(&self, sites: usize) -> Array2<usize> {
+        let depths = self.depths();
+        let generations = depths.iter().max().map_or(1, |d| d + 1);
+        let mut counts = Array2::zeros((generations, sites));
+        for (node, depth) in self.nodes.iter().zip(depths) {
+            counts[[depth, node.label]] += 1;
+        }
+        counts
     }
 }
 
@@ -260,6 +1529,7 @@ fn build_phylogeny() {
             idx += 1;
         }
     }
+    tree.validate().unwrap();
 
     println!("{:#?}", tree);
 }
@@ -269,5 +1539,474 @@ fn build_tree() {
     let leaf1 = Tree::new_leaf(1);
     let leaf2 = Tree::new_leaf(2);
     let tree = Tree::join_with_parent(0, leaf1, 0.5, leaf2, 0.7);
-    println!("{}", tree);
+    assert_eq!(tree.to_string(), "(1:0.5,2:0.7)0;");
+    assert_eq!(tree.debug_format(), "(0:((1:0.5)(2:0.7)))");
+    let tree = Tree::new(5, vec![(tree, Some(0.1))]);
+    assert_eq!(tree.to_string(), "((1:0.5,2:0.7)0:0.1)5;");
+    assert_eq!(tree.debug_format(), "(5:(((0:((1:0.5)(2:0.7))):0.1)))");
+}
+
+#[test]
+fn newick_round_trip() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut phylogeny = Phylogeny::new(root, 0.1);
+    let c = phylogeny.add_child(0, 1, 0, 0.5);
+    phylogeny.add_child(0, 2, 1, 1.0 / 3.0);
+    phylogeny.add_child(c, 3, 1, 0.25);
+    phylogeny.add_child(c, 4, 1, 1e-7);
+    let tree = phylogeny.to_tree();
+    assert_eq!(tree.to_string().parse::<Tree<usize>>(), Ok(tree.clone()));
+
+    // the writer of phylogenies and NHX comments read back as the same tree
+    let mut buf = vec![];
+    phylogeny.write_nhx(&mut buf).unwrap();
+    let nhx = String::from_utf8(buf).unwrap();
+    assert_eq!(nhx.parse::<Tree<usize>>(), Ok(tree));
+
+    // names that need quoting, missing lengths and whitespace
+    let tree = Tree::new(
+        "root".to_string(),
+        vec![
+            (Tree::new_leaf("a b".to_string()), Some(1.5)),
+            (Tree::new_leaf("it's".to_string()), None),
+            (Tree::new_leaf(String::new()), Some(2.0)),
+        ],
+    );
+    let newick = tree.to_string();
+    assert_eq!(newick, "('a b':1.5,'it''s',:2)root;");
+    assert_eq!(newick.parse::<Tree<String>>(), Ok(tree.clone()));
+    let spaced = " ( 'a b' : 1.5 , 'it''s' , : 2 ) root ;\n";
+    assert_eq!(spaced.parse::<Tree<String>>(), Ok(tree));
+
+    for bad in ["(1,2)0", "(1,2;", "(1:x)0;", "(1,2)0; 3", "(a)0;", "(1[)0;"] {
+        assert!(bad.parse::<Tree<usize>>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn json_schema() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+
+    let mut buf = vec![];
+    let sites = vec!["0".to_string(), "1".to_string()];
+    tree.write_json(&mut buf, &"params", &sites).unwrap();
+
+    let v: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(v["format_version"], JSON_FORMAT_VERSION);
+    assert_eq!(v["sites"][1], "1");
+    assert_eq!(v["tree"]["length"], 0.1);
+    assert_eq!(v["tree"]["children"][1]["label"], 1);
+    assert_eq!(v["tree"]["children"][1]["length"], 0.7);
+    assert_eq!(v["tree"]["children"][1]["generation"], 1);
+    assert_eq!(v["tree"]["children"][1]["time"], 0.7);
+
+    tree.nodes[2].set_meta("state", 1);
+    let mut buf = vec![];
+    tree.write_json(&mut buf, &"params", &sites).unwrap();
+    let read = Phylogeny::<usize, usize>::read_json(&buf[..]).unwrap();
+    read.validate().unwrap();
+    assert!(read.edges().eq(tree.edges()));
+    assert_eq!(read.root_length(), 0.1);
+    assert_eq!(read.nodes[2].label, 1);
+    assert_eq!(read.nodes[2].meta("state"), Some("1"));
+    assert!(Phylogeny::<usize, usize>::read_json(&b"{}"[..]).is_err());
+}
+
+#[test]
+fn newick() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let c = tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+    tree.add_child(c, 3, 1, 0.25);
+    tree.add_child(c, 4, 1, 0.75);
+
+    let mut buf = vec![];
+    tree.write_newick(&mut buf).unwrap();
+    assert_eq!(
+        String::from_utf8(buf).unwrap(),
+        "((3:0.25,4:0.75)1:0.5,2:0.7)0:0.1;\n"
+    );
+}
+
+#[test]
+fn traversals() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let c = tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+    tree.add_child(c, 3, 1, 0.25);
+
+    assert_eq!(tree.preorder(), vec![0, 1, 3, 2]);
+    assert_eq!(tree.depths(), vec![0, 1, 1, 2]);
+    assert_eq!(tree.times_from_root(), vec![0.0, 0.5, 0.7, 0.75]);
+    assert_eq!((tree.generation(3), tree.time_from_root(3)), (2, 0.75));
+    assert_eq!((tree.generation(0), tree.time_from_root(0)), (0, 0.0));
+    assert_eq!(
+        tree.site_counts_by_generation(2),
+        Array2::from_shape_vec((3, 2), vec![1, 0, 1, 1, 0, 1]).unwrap()
+    );
+    assert_eq!((tree.colless_index(), tree.sackin_index()), (0, 3));
+
+    // caterpillar of 4 leaves, the most unbalanced tree
+    let d = tree.add_child(3, 4, 1, 0.1);
+    tree.add_child(3, 5, 1, 0.1);
+    tree.add_child(d, 6, 1, 0.1);
+    tree.add_child(d, 7, 1, 0.1);
+    assert_eq!((tree.colless_index(), tree.sackin_index()), (3, 12));
+}
+
+#[test]
+fn output_joins() {
+    use std::collections::HashMap;
+
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let a = tree.add_child(0, 1, 1, 0.5);
+    tree.add_child(0, 2, 0, 0.5);
+    tree.add_child(a, 3, 2, 0.25);
+    tree.add_child(a, 4, 1, 0.25);
+
+    let csv = |write: &dyn Fn(&mut Vec<u8>) -> io::Result<()>| {
+        let mut buf = vec![];
+        write(&mut buf).unwrap();
+        String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').map(|f| f.to_string()).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+    let edges = csv(&|w| tree.write_csv(w));
+    let vertices: HashMap<_, _> = csv(&|w| tree.write_csv_vertex_labeling(w))
+        .into_iter()
+        .map(|r| (r[0].clone(), r[1].clone()))
+        .collect();
+    let leaves = csv(&|w| tree.write_csv_leaf_labeling(w));
+
+    let table = csv(&|w| tree.write_node_table(w));
+    assert_eq!(table[0], ["0", "", "0", "0", "0", "3", "false", "true"]);
+    assert_eq!(table[3], ["3", "1", "2", "2", "0.75", "1", "true", "true"]);
+
+    // every leaf is a child in the edgelist and never a parent, and has the same label
+    // as in the vertex labeling
+    assert_eq!(leaves.len(), 3);
+    for (rank, leaf) in leaves.iter().enumerate() {
+        assert!(edges.iter().any(|e| e[1] == leaf[0]));
+        assert!(edges.iter().all(|e| e[0] != leaf[0]));
+        assert_eq!(leaf[1], rank.to_string());
+        assert_eq!(vertices[&leaf[0]], leaf[2]);
+    }
+}
+
+#[test]
+fn random_topologies() {
+    use rand::SeedableRng;
+
+    let mut rng = crate::model::SimRng::seed_from_u64(2);
+    for leaves in [1, 2, 7] {
+        for tree in [
+            Phylogeny::random_pda(leaves, &mut rng),
+            Phylogeny::random_yule(leaves, 0.5, &mut rng),
+        ] {
+            tree.validate().unwrap();
+            assert_eq!(tree.leaves().count(), leaves);
+            assert_eq!(tree.nodes.len(), 2 * leaves - 1);
+            assert!(tree.nodes.iter().enumerate().all(|(i, n)| n.data == i));
+            assert!(tree.edges().all(|(_, _, l)| l > 0.0));
+        }
+    }
+
+    // 3 of the 15 rooted topologies of 4 leaves split 2|2 at the root, so PDA trees are
+    // balanced with probability 1/5 where Yule trees are with probability 1/3
+    let is_balanced =
+        |tree: &Phylogeny<usize, usize>| tree.children(0).all(|(c, _)| !tree.is_leaf(c));
+    let n = 3000;
+    let (mut pda, mut yule) = (0, 0);
+    for _ in 0..n {
+        pda += is_balanced(&Phylogeny::random_pda(4, &mut rng)) as usize;
+        yule += is_balanced(&Phylogeny::random_yule(4, 1.0, &mut rng)) as usize;
+    }
+    for (count, p) in [(pda, 0.2), (yule, 1.0 / 3.0)] {
+        let expected = n as Float * p;
+        assert!((count as Float - expected).abs() < 4.0 * (expected * (1.0 - p)).sqrt());
+    }
+}
+
+#[test]
+fn isomorphism() {
+    // ((a, b), c) and (c', (b', a')) with other data, lengths and child order
+    let mut t1 = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let x = t1.add_child(0, 1, 0, 1.0);
+    t1.add_child(x, 2, 1, 1.0);
+    t1.add_child(x, 3, 2, 1.0);
+    t1.add_child(0, 4, 1, 1.0);
+
+    let mut t2 = Phylogeny::new(Node::<usize, usize>::root(9, 0), 0.5);
+    t2.add_child(0, 8, 1, 3.0);
+    let y = t2.add_child(0, 7, 0, 2.0);
+    t2.add_child(y, 6, 2, 0.1);
+    t2.add_child(y, 5, 1, 0.2);
+
+    assert!(t1.is_isomorphic(&t2));
+    assert!(t1.is_labeled_isomorphic(&t2));
+    assert_eq!(t1.topology_hash(), t2.topology_hash());
+    assert_eq!(t1.labeled_topology_hash(), t2.labeled_topology_hash());
+
+    // same shape, the cherry is on another site
+    t2.nodes[y].update_label(1);
+    assert!(t1.is_isomorphic(&t2));
+    assert!(!t1.is_labeled_isomorphic(&t2));
+    assert_ne!(t1.labeled_topology_hash(), t2.labeled_topology_hash());
+
+    // a caterpillar of four leaves against a balanced tree of four
+    let cat = Tree::join_with_parent(
+        0,
+        Tree::join_with_parent(1, Tree::new_leaf(2), 1.0, Tree::new_leaf(3), 1.0),
+        1.0,
+        Tree::new_leaf(4),
+        1.0,
+    );
+    let cat4 = Tree::join_with_parent(0, cat, 1.0, Tree::new_leaf(5), 1.0);
+    let cherry = || Tree::join_with_parent(1, Tree::new_leaf(2), 1.0, Tree::new_leaf(3), 1.0);
+    let balanced = Tree::join_with_parent(0, cherry(), 1.0, cherry(), 1.0);
+    assert!(!cat4.is_isomorphic(&balanced));
+    assert_ne!(cat4.topology_hash(), balanced.topology_hash());
+    assert_eq!(t1.to_tree().topology_hash(), t2.to_tree().topology_hash());
+}
+
+#[test]
+fn branch_length_transforms() {
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 1.0);
+    let a = tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 0.5);
+    tree.add_child(a, 3, 1, 2.0);
+    tree.add_child(a, 4, 0, 0.5);
+
+    tree.scale_branch_lengths(2.0);
+    assert_eq!(tree.root_length(), 2.0);
+    assert_eq!(tree.times_from_root(), [0.0, 2.0, 1.0, 6.0, 3.0]);
+
+    tree.make_ultrametric();
+    let times = tree.times_from_root();
+    assert!(tree.leaves().all(|l| times[l] == 6.0));
+    // internal branches are left alone
+    assert_eq!(times[a], 2.0);
+
+    tree.apply_site_clock(&[1.0, 0.5]);
+    assert_eq!(tree.times_from_root(), [0.0, 2.0, 3.0, 4.0, 6.0]);
+}
+
+#[test]
+fn node_metadata() {
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    let a = tree.add_child(0, 1, 1, 1.0);
+    tree.add_child(0, 2, 0, 2.0);
+    tree.nodes[a].set_meta("driver", "KRAS");
+    tree.nodes[a].set_meta("x", 3);
+
+    assert_eq!(tree.nodes[a].meta("driver"), Some("KRAS"));
+    assert_eq!(tree.nodes[a].meta("y"), None);
+    assert!(tree.nodes[0].metadata().is_none());
+
+    let mut nhx = vec![];
+    tree.write_nhx(&mut nhx).unwrap();
+    assert_eq!(
+        String::from_utf8(nhx).unwrap(),
+        "(1:1[&&NHX:site=1:driver=KRAS:x=3],2:2[&&NHX:site=0])0:0[&&NHX:site=0];\n"
+    );
+
+    let mut json = vec![];
+    tree.write_json(&mut json, &(), &[]).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["format_version"], 3);
+    assert_eq!(json["tree"]["children"][0]["metadata"]["driver"], "KRAS");
+    assert!(json["tree"].get("metadata").is_none());
+}
+
+#[test]
+fn polytomies() {
+    use rand::SeedableRng;
+
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    for i in 1..=3 {
+        tree.add_child(0, i, 0, i as Float);
+    }
+    tree.add_child(2, 4, 1, 1.0);
+    tree.add_child(2, 5, 1, 1.0);
+    tree.add_child(2, 6, 1, 1.0);
+    tree.add_child(2, 7, 1, 1.0);
+
+    assert_eq!(tree.leaves().count(), 6);
+    assert_eq!(tree.preorder(), [0, 1, 2, 4, 5, 6, 7, 3]);
+    assert_eq!(tree.subtree_leaves(), [6, 1, 4, 1, 1, 1, 1, 1]);
+    assert_eq!(tree.depths(), [0, 1, 1, 1, 2, 2, 2, 2]);
+
+    let mut nexus = vec![];
+    tree.write_nexus(&mut nexus).unwrap();
+    assert_eq!(
+        String::from_utf8(nexus).unwrap(),
+        "#NEXUS\nBEGIN TREES;\n    TREE simulation = [&R] (1:1,(4:1,5:1,6:1,7:1)2:2,3:3)0:0;\nEND;\n"
+    );
+    let mut rng = crate::model::SimRng::seed_from_u64(6);
+    assert!(!tree.is_isomorphic(&Phylogeny::random_pda(6, &mut rng)));
+}
+
+#[test]
+fn sampled_genealogy() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let a = tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+    let c = tree.add_child(a, 3, 1, 0.25);
+    tree.add_child(a, 4, 1, 0.75);
+    tree.add_child(c, 5, 2, 1.0);
+    tree.add_child(c, 6, 2, 2.0);
+    tree.nodes[5].set_meta("x", 1);
+
+    let genealogy = tree.sampled_genealogy(&[5, 6, 2]).unwrap();
+    genealogy.tree.validate().unwrap();
+    assert_eq!(genealogy.original, [0, 3, 2, 5, 6]);
+    assert_eq!(genealogy.sampled, [false, false, true, true, true]);
+    assert_eq!(genealogy.unsampled_ancestors().collect::<Vec<_>>(), [0, 1]);
+    assert_eq!(
+        genealogy.tree.children(0).collect::<Vec<_>>(),
+        [(1, 0.75), (2, 0.7)]
+    );
+    assert_eq!(genealogy.tree.nodes[3].meta("x"), Some("1"));
+
+    // rooted at the most recent common ancestor, with the branch above it
+    let genealogy = tree.sampled_genealogy(&[5, 4]).unwrap();
+    assert_eq!(genealogy.original, [1, 5, 4]);
+    assert!((genealogy.tree.root_length() - 0.6).abs() < 1e-6);
+    assert_eq!(
+        genealogy.tree.children(0).collect::<Vec<_>>(),
+        [(1, 1.25), (2, 0.75)]
+    );
+
+    // a sampled internal node is a sampled ancestor
+    let genealogy = tree.sampled_genealogy(&[3, 5]).unwrap();
+    assert_eq!(genealogy.original, [3, 5]);
+    assert_eq!(genealogy.unsampled_ancestors().count(), 0);
+    assert!(tree.sampled_genealogy(&[]).is_none());
+}
+
+#[test]
+fn detach_subtree() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let c = tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+    tree.add_child(c, 3, 1, 0.25);
+    tree.add_child(c, 4, 1, 0.75);
+
+    let detached = tree.detach_subtree(c);
+    tree.validate().unwrap();
+    detached.tree.validate().unwrap();
+    assert_eq!(detached.original, [1, 3, 4]);
+    assert_eq!(detached.remap, [Some(0), None, Some(1), None, None]);
+    let mut newick = vec![];
+    detached.tree.write_newick(&mut newick).unwrap();
+    assert_eq!(
+        String::from_utf8(newick).unwrap(),
+        "(3:0.25,4:0.75)1:0.5;\n"
+    );
+    assert_eq!(detached.tree.parent(1), Some(0));
+    assert_eq!(tree.children(0).collect::<Vec<_>>(), [(1, 0.7)]);
+    assert_eq!(tree.nodes[1].data, 2);
+
+    assert_eq!(tree.remove_leaf(1), [Some(0), None]);
+    tree.validate().unwrap();
+    assert_eq!(tree.nodes.len(), 1);
+    assert!(tree.is_leaf(0));
+}
+
+#[test]
+fn graft() {
+    let mut tree = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.1);
+    let a = tree.add_child(0, 1, 0, 0.5);
+    tree.add_child(0, 2, 1, 0.7);
+    tree.add_child(a, 3, 1, 0.25);
+    tree.add_child(a, 4, 1, 0.75);
+
+    assert_eq!(
+        tree.remove_descendants(a),
+        [Some(0), Some(1), Some(2), None, None]
+    );
+    tree.validate().unwrap();
+    assert!(tree.is_leaf(1));
+    let mut subtree = Phylogeny::new(Node::root(10, 0), 0.3);
+    let b = subtree.add_child(0, 11, 2, 1.0);
+    subtree.add_child(b, 12, 2, 2.0);
+    subtree.kill(2);
+    assert_eq!(tree.graft(1, &subtree), [1, 3, 4]);
+    tree.validate().unwrap();
+    assert_eq!(tree.children(1).collect::<Vec<_>>(), [(3, 1.0)]);
+    assert_eq!(tree.parent(4), Some(3));
+    assert_eq!(tree.nodes[4].label, 2);
+    assert!(!tree.is_alive(4));
+    assert_eq!(tree.root_length(), 0.1);
+
+    // broken links are caught
+    let mut broken = tree.clone();
+    broken.parents[2] = 3;
+    assert_eq!(
+        broken.validate(),
+        Err("node 2 comes before its parent 3".to_string())
+    );
+    let mut broken = tree.clone();
+    broken.next_sibling[1] = 3;
+    assert!(broken.validate().is_err());
+    let mut broken = tree;
+    broken.parents[0] = 1;
+    assert!(broken.validate().is_err());
+}
+
+#[test]
+fn prune_dead() {
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.1);
+    let a = tree.add_child(0, 1, 0, 0.5);
+    let b = tree.add_child(0, 2, 1, 0.7);
+    tree.add_child(a, 3, 1, 0.25);
+    let d = tree.add_child(a, 4, 1, 0.75);
+    tree.kill(b);
+    tree.kill(d);
+    assert_eq!(tree.all_leaves().collect::<Vec<_>>(), [2, 3, 4]);
+    assert_eq!(tree.extant_leaves().collect::<Vec<_>>(), [3]);
+
+    // the unary ancestor of the extant leaf stays
+    assert_eq!(tree.prune_dead(), [Some(0), Some(1), None, Some(2), None]);
+    tree.validate().unwrap();
+    assert_eq!(tree.depths(), [0, 1, 2]);
+    assert_eq!(tree.children(1).collect::<Vec<_>>(), [(2, 0.25)]);
+    assert_eq!(tree.nodes[2].data, 3);
+}
+
+#[test]
+fn shape_statistics() {
+    // balanced and ultrametric, the second splits both at time 1
+    let root = Node::<usize, usize>::root(0, 0);
+    let mut tree = Phylogeny::new(root, 0.5);
+    for i in 0..2 {
+        let c = tree.add_child(0, i + 1, 0, 1.0);
+        tree.add_child(c, 3 + 2 * i, 0, 1.0);
+        tree.add_child(c, 4 + 2 * i, 0, 1.0);
+    }
+    assert_eq!(tree.height(), 2.0);
+    assert_eq!(tree.total_branch_length(), 6.0);
+    assert_eq!(tree.external_internal_ratio(), Some(2.0));
+    // g2 = 1, g3 = 0, g4 = 1: T = 6, ((2 + 2) / 2 - 3) / (6 sqrt(1 / 24))
+    let gamma = tree.gamma_statistic().unwrap();
+    assert!(
+        (gamma + (2.0 as Float / 3.0).sqrt()).abs() < 1e-5,
+        "{gamma}"
+    );
+
+    let cherry = Phylogeny::new(Node::<usize, usize>::root(0, 0), 0.0);
+    assert_eq!(cherry.gamma_statistic(), None);
+    assert_eq!(cherry.external_internal_ratio(), None);
 }
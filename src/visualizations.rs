@@ -1,12 +1,28 @@
-use std::{fs::File, io::Write, process::Command};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+};
 
 use ndarray::Array2;
-use petgraph::{dot::Dot, graph::Graph};
+use petgraph::{graph::Graph, visit::EdgeRef};
 
-pub fn graph_from_edge_matrix(m: Array2<i32>) -> Graph<usize, i32> {
+use crate::{
+    Float,
+    analysis::{Seeding, seedings},
+    tree::Phylogeny,
+};
+
+/// Graph of the positive entries of `m`, self-loops included. [`to_dot`] only draws these
+/// with [`DotStyle::self_loops`]
+pub fn graph_from_edge_matrix(m: Array2<i64>) -> Graph<usize, i64> {
     // m is a square matrix
     let n = m.nrows();
-    let mut g = Graph::<usize, i32>::new();
+    let mut g = Graph::<usize, i64>::new();
 
     // Add nodes
     let nodes: Vec<_> = (0..n).map(|i| g.add_node(i)).collect();
@@ -14,7 +30,7 @@ pub fn graph_from_edge_matrix(m: Array2<i32>) -> Graph<usize, i32> {
     // Add edges
     for i in 0..n {
         for j in 0..n {
-            if i != j && m[[i, j]] > 0 {
+            if m[[i, j]] > 0 {
                 g.add_edge(nodes[i], nodes[j], m[[i, j]]);
             }
         }
@@ -23,22 +39,1090 @@ pub fn graph_from_edge_matrix(m: Array2<i32>) -> Graph<usize, i32> {
     g
 }
 
-pub fn save_graph_png(g: &Graph<usize, i32>, out: &str) -> std::io::Result<()> {
-    // 1. Write DOT to a temporary file
-    let dot = format!("{:?}", Dot::new(g));
-    let dot_path = format!("{out}_mig_graph.dot");
+/// Migration multigraph of a labeled tree, e.g. an imported or reconstructed one: a node
+/// per site up to the largest label and an edge per migration edge of the tree, weighted
+/// by its [`Seeding`] with the node, generation and time of the migration. The edges from
+/// the common ancestor of several founders are not migrations
+pub fn graph_from_phylogeny<N>(tree: &Phylogeny<N, usize>) -> Graph<usize, Seeding> {
+    let sites = tree.nodes.iter().map(|n| n.label + 1).max().unwrap_or(0);
+    let mut g = Graph::new();
+    let nodes: Vec<_> = (0..sites).map(|i| g.add_node(i)).collect();
+    for s in seedings(tree) {
+        g.add_edge(nodes[s.source], nodes[s.target], s);
+    }
+    g
+}
+
+/// Count the parallel edges of a multigraph into one edge per pair of sites, the graph
+/// [`graph_from_edge_matrix`] builds from the migration matrix, to draw with [`to_dot`]
+pub fn count_migrations<E>(g: &Graph<usize, E>) -> Graph<usize, i64> {
+    let mut counted = g.map(|_, &site| site, |_, _| 0);
+    counted.clear_edges();
+    for e in g.edge_references() {
+        match counted.find_edge(e.source(), e.target()) {
+            Some(edge) => counted[edge] += 1,
+            None => {
+                counted.add_edge(e.source(), e.target(), 1);
+            }
+        }
+    }
+    counted
+}
+
+/// Direction of the graph layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RankDir {
+    #[default]
+    TopBottom,
+    LeftRight,
+    BottomTop,
+    RightLeft,
+}
+
+impl fmt::Display for RankDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RankDir::TopBottom => write!(f, "TB"),
+            RankDir::LeftRight => write!(f, "LR"),
+            RankDir::BottomTop => write!(f, "BT"),
+            RankDir::RightLeft => write!(f, "RL"),
+        }
+    }
+}
+
+impl FromStr for RankDir {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "TB" => Ok(RankDir::TopBottom),
+            "LR" => Ok(RankDir::LeftRight),
+            "BT" => Ok(RankDir::BottomTop),
+            "RL" => Ok(RankDir::RightLeft),
+            _ => Err(format!("unknown rankdir `{s}`, expected TB, LR, BT or RL")),
+        }
+    }
+}
+
+/// Colors of the sites, shared by every figure so a site looks the same in the tree, the
+/// migration graph and the frequency plots. Site `i` takes color `i` of the palette,
+/// cycling past its end, unless it was given a color of its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<String>,
+    sites: BTreeMap<usize, String>,
+}
+
+impl Palette {
+    /// Colors in the given order, `#rrggbb` or any other color Graphviz and SVG know
+    pub fn new(colors: Vec<String>) -> Self {
+        Self {
+            colors,
+            sites: BTreeMap::new(),
+        }
+    }
+
+    /// Okabe and Ito (2008), told apart under every common color vision deficiency, with
+    /// grey in place of black so labels stay readable on it
+    pub fn okabe_ito() -> Self {
+        Self::from_slice(&[
+            "#e69f00", "#56b4e9", "#009e73", "#f0e442", "#0072b2", "#d55e00", "#cc79a7", "#999999",
+        ])
+    }
+
+    /// Paul Tol's bright scheme, colorblind safe
+    pub fn tol() -> Self {
+        Self::from_slice(&[
+            "#4477aa", "#ee6677", "#228833", "#ccbb44", "#66ccee", "#aa3377", "#bbbbbb",
+        ])
+    }
+
+    /// ColorBrewer Set3, the colors before the colorblind safe default. Several are hard
+    /// to tell apart without full color vision
+    pub fn set3() -> Self {
+        Self::from_slice(&[
+            "#8dd3c7", "#ffffb3", "#bebada", "#fb8072", "#80b1d3", "#fdb462", "#b3de69", "#fccde5",
+        ])
+    }
+
+    fn from_slice(colors: &[&str]) -> Self {
+        Self::new(colors.iter().map(|c| c.to_string()).collect())
+    }
+
+    /// Give `site` its own `#rrggbb` color in place of the one of the palette
+    pub fn set_site_color(&mut self, site: usize, color: &str) -> Result<(), String> {
+        if hex_rgb(color).is_none() {
+            return Err(format!("`{color}` is not a `#rrggbb` color"));
+        }
+        self.sites.insert(site, color.to_ascii_lowercase());
+        Ok(())
+    }
+
+    /// Color of `site`, white from an empty palette
+    pub fn color(&self, site: usize) -> &str {
+        if let Some(color) = self.sites.get(&site) {
+            return color;
+        }
+        match self.colors.is_empty() {
+            true => "white",
+            false => &self.colors[site % self.colors.len()],
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::okabe_ito()
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// `okabe-ito`, `tol`, `set3` or comma separated `#rrggbb` colors
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "okabe-ito" => Ok(Self::okabe_ito()),
+            "tol" => Ok(Self::tol()),
+            "set3" => Ok(Self::set3()),
+            _ if s.starts_with('#') => {
+                let colors: Vec<_> = s
+                    .split(',')
+                    .map(|c| c.trim().to_ascii_lowercase())
+                    .collect();
+                match colors.iter().find(|c| hex_rgb(c).is_none()) {
+                    Some(c) => Err(format!("`{c}` is not a `#rrggbb` color")),
+                    None => Ok(Self::new(colors)),
+                }
+            }
+            _ => Err(format!(
+                "unknown palette `{s}`, expected okabe-ito, tol, set3 or `#rrggbb` colors"
+            )),
+        }
+    }
+}
+
+/// What the edges of the migration graph are labeled with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EdgeLabels {
+    /// Number of migrations
+    #[default]
+    Counts,
+    /// Fraction of the drawn edges out of the source site
+    Proportions,
+}
+
+impl fmt::Display for EdgeLabels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EdgeLabels::Counts => write!(f, "counts"),
+            EdgeLabels::Proportions => write!(f, "proportions"),
+        }
+    }
+}
+
+impl FromStr for EdgeLabels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "counts" => Ok(EdgeLabels::Counts),
+            "proportions" => Ok(EdgeLabels::Proportions),
+            _ => Err(format!(
+                "unknown edge labels `{s}`, expected counts or proportions"
+            )),
+        }
+    }
+}
+
+/// Styling of the emitted DOT. Edges are colored by direction relative to the primary
+/// site: primary to metastasis, metastasis to metastasis, and reseeding of the primary
+#[derive(Debug, Clone)]
+pub struct DotStyle {
+    /// Fill color of each site
+    pub palette: Palette,
+    /// Site of the primary tumor
+    pub primary: usize,
+    pub primary_edge_color: String,
+    pub metastasis_edge_color: String,
+    pub reseeding_edge_color: String,
+    pub self_loop_color: String,
+    pub rankdir: RankDir,
+    pub node_shape: String,
+    pub node_font_size: f64,
+    pub edge_font_size: f64,
+    /// Draw the edges of a site to itself, the lineages that stay, dropped by default
+    pub self_loops: bool,
+    pub edge_labels: EdgeLabels,
+    /// Leave the edges whose label would be below this unlabeled
+    pub label_threshold: f64,
+}
+
+impl Default for DotStyle {
+    fn default() -> Self {
+        Self {
+            palette: Palette::default(),
+            primary: 0,
+            primary_edge_color: "#1b9e77".to_string(),
+            metastasis_edge_color: "#7570b3".to_string(),
+            reseeding_edge_color: "#d95f02".to_string(),
+            self_loop_color: "#999999".to_string(),
+            rankdir: RankDir::default(),
+            node_shape: "circle".to_string(),
+            node_font_size: 14.0,
+            edge_font_size: 12.0,
+            self_loops: false,
+            edge_labels: EdgeLabels::default(),
+            label_threshold: 0.0,
+        }
+    }
+}
+
+impl DotStyle {
+    pub fn site_color(&self, site: usize) -> &str {
+        self.palette.color(site)
+    }
+
+    fn edge_color(&self, from: usize, to: usize) -> &str {
+        if from == to {
+            &self.self_loop_color
+        } else if from == self.primary {
+            &self.primary_edge_color
+        } else if to == self.primary {
+            &self.reseeding_edge_color
+        } else {
+            &self.metastasis_edge_color
+        }
+    }
+}
+
+/// Emit the migration graph as DOT with the given style
+pub fn to_dot(g: &Graph<usize, i64>, style: &DotStyle) -> String {
+    let mut dot = String::from("digraph {\n");
+    dot.push_str(&format!("    rankdir={};\n", style.rankdir));
+    dot.push_str(&format!(
+        "    node [shape={}, style=filled, fontsize={}];\n",
+        style.node_shape, style.node_font_size
+    ));
+    dot.push_str(&format!("    edge [fontsize={}];\n", style.edge_font_size));
+
+    for i in g.node_indices() {
+        let site = g[i];
+        dot.push_str(&format!(
+            "    {} [label=\"{site}\", fillcolor=\"{}\"];\n",
+            i.index(),
+            style.site_color(site)
+        ));
+    }
+
+    let drawn: Vec<_> = g
+        .edge_references()
+        .filter(|e| style.self_loops || e.source() != e.target())
+        .collect();
+    let mut out = vec![0; g.node_count()];
+    for e in &drawn {
+        out[e.source().index()] += e.weight();
+    }
+    for e in drawn {
+        let (from, to) = (g[e.source()], g[e.target()]);
+        let (value, label) = match style.edge_labels {
+            EdgeLabels::Counts => (*e.weight() as f64, e.weight().to_string()),
+            EdgeLabels::Proportions => {
+                let p = *e.weight() as f64 / out[e.source().index()] as f64;
+                (p, format!("{p:.2}"))
+            }
+        };
+        let label = match value < style.label_threshold {
+            true => "",
+            false => &label,
+        };
+        dot.push_str(&format!(
+            "    {} -> {} [label=\"{label}\", color=\"{}\"];\n",
+            e.source().index(),
+            e.target().index(),
+            style.edge_color(from, to)
+        ));
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+/// Image formats Graphviz can render figures to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl ImageFormat {
+    /// File extension (and Graphviz `-T` output type) of the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Svg => "svg",
+            ImageFormat::Pdf => "pdf",
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "svg" => Ok(ImageFormat::Svg),
+            "pdf" => Ok(ImageFormat::Pdf),
+            _ => Err(format!(
+                "unknown image format `{s}`, expected png, svg or pdf"
+            )),
+        }
+    }
+}
+
+/// Errors from rendering a graph with Graphviz
+#[derive(Debug)]
+pub enum RenderError {
+    /// The `dot` binary is not on the `PATH` - the DOT file was still written
+    GraphvizNotFound,
+    /// `dot` ran but exited unsuccessfully - the DOT file was still written
+    GraphvizFailed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::GraphvizNotFound => write!(
+                f,
+                "Graphviz `dot` was not found on PATH, only the DOT file was written. \
+                 Install Graphviz (`apt install graphviz`, `brew install graphviz` or \
+                 `conda install -c conda-forge graphviz`) to render images"
+            ),
+            RenderError::GraphvizFailed { status, stderr } => {
+                write!(f, "Graphviz failed ({status}): {}", stderr.trim())
+            }
+            RenderError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<io::Error> for RenderError {
+    fn from(e: io::Error) -> Self {
+        RenderError::Io(e)
+    }
+}
+
+/// Check whether Graphviz `dot` can be run
+pub fn graphviz_available() -> bool {
+    Command::new("dot")
+        .arg("-V")
+        .output()
+        .is_ok_and(|o| o.status.success())
+}
+
+/// Write `<stem>.dot` and render it to `<stem>.png` with the default style
+pub fn save_graph_png(g: &Graph<usize, i64>, stem: &Path) -> Result<(), RenderError> {
+    save_graph(g, stem, ImageFormat::Png, &DotStyle::default())
+}
+
+/// Write `<stem>.dot` and render it to `<stem>.<format>`. If Graphviz is missing or fails
+/// the DOT file is kept so it can be rendered elsewhere
+pub fn save_graph(
+    g: &Graph<usize, i64>,
+    stem: &Path,
+    format: ImageFormat,
+    style: &DotStyle,
+) -> Result<(), RenderError> {
+    // 1. Write DOT next to the image
+    let dot = to_dot(g, style);
+    let dot_path = stem.with_extension("dot");
 
     let mut file = File::create(&dot_path)?;
     file.write_all(dot.as_bytes())?;
 
     // 2. Call Graphviz
-    let status = Command::new("dot")
-        .args(["-Tpng", &dot_path, "-o", &format!("{out}_migration_graph.png")])
-        .status()?;
+    if !graphviz_available() {
+        return Err(RenderError::GraphvizNotFound);
+    }
+
+    let output = Command::new("dot")
+        .arg(format!("-T{}", format.extension()))
+        .arg(&dot_path)
+        .arg("-o")
+        .arg(stem.with_extension(format.extension()))
+        .output()?;
 
-    if !status.success() {
-        panic!("Graphviz failed");
+    if !output.status.success() {
+        return Err(RenderError::GraphvizFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Render DOT source to an SVG string by piping it through Graphviz
+pub fn dot_to_svg(dot: &str) -> Result<String, RenderError> {
+    if !graphviz_available() {
+        return Err(RenderError::GraphvizNotFound);
+    }
+
+    let mut child = Command::new("dot")
+        .arg("-Tsvg")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(dot.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    if !output.status.success() {
+        return Err(RenderError::GraphvizFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Draw a phylogeny as an SVG phylogram with branches colored by the site of the child
+pub fn tree_svg<N>(tree: &Phylogeny<N, usize>, style: &DotStyle) -> String {
+    const WIDTH: Float = 800.0;
+
+    let (elements, height) = phylogram(tree, style, WIDTH, 0.0);
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{height}\">\n{elements}</svg>\n"
+    )
+}
+
+/// Branches and nodes of the phylogram of [`tree_svg`] in `width`, moved right by
+/// `left`, and the height they take
+fn phylogram<N>(
+    tree: &Phylogeny<N, usize>,
+    style: &DotStyle,
+    width: Float,
+    left: Float,
+) -> (String, Float) {
+    const MARGIN: Float = 10.0;
+    const LEAF_SPACING: Float = 6.0;
+
+    let order = tree.preorder();
+    let times = tree.times_from_root();
+    let max_time = times.iter().cloned().fold(Float::EPSILON, Float::max);
+
+    // leaves are spread evenly top to bottom in preorder, parents sit between children
+    let mut y = vec![0.0; order.len()];
+    let mut next_leaf = 0.0;
+    for &idx in &order {
+        if tree.is_leaf(idx) {
+            y[idx] = MARGIN + next_leaf * LEAF_SPACING;
+            next_leaf += 1.0;
+        }
+    }
+    for &idx in order.iter().rev() {
+        if !tree.is_leaf(idx) {
+            let sum: Float = tree.children(idx).map(|(c, _)| y[c]).sum();
+            y[idx] = sum / tree.child_count(idx) as Float;
+        }
+    }
+
+    let x = |idx: usize| left + MARGIN + times[idx] / max_time * (width - 2.0 * MARGIN);
+    let height = 2.0 * MARGIN + next_leaf.max(1.0) * LEAF_SPACING;
+
+    let mut svg = String::new();
+    for (parent, child, _) in tree.edges() {
+        svg.push_str(&format!(
+            "<path d=\"M{:.1},{:.1} V{:.1} H{:.1}\" stroke=\"{}\" fill=\"none\"/>\n",
+            x(parent),
+            y[parent],
+            y[child],
+            x(child),
+            style.site_color(tree.nodes[child].label)
+        ));
+    }
+    for &idx in &order {
+        svg.push_str(&format!(
+            "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"2\" fill=\"{}\"/>\n",
+            x(idx),
+            y[idx],
+            style.site_color(tree.nodes[idx].label)
+        ));
+    }
+    (svg, height)
+}
+
+/// The site-colored phylogram of a tree beside its migration graph, in one SVG with a
+/// legend of the sites and the kinds of migration shared by both. Sites sit on a circle
+/// in the colors of their branches, every pair of sites a migration connects is an arrow
+/// labeled with the number of migrations, bent towards the center so the edges bundle
+/// there and the two directions between a pair stay apart. Needs no Graphviz
+pub fn combined_svg<N>(tree: &Phylogeny<N, usize>, sites: &[String], style: &DotStyle) -> String {
+    const TREE_WIDTH: Float = 600.0;
+    const GRAPH_SIZE: Float = 400.0;
+    const SITE_RADIUS: Float = 18.0;
+    const LEGEND_ROW: Float = 20.0;
+
+    let (phylogram, tree_height) = phylogram(tree, style, TREE_WIDTH, 0.0);
+    let graph = count_migrations(&graph_from_phylogeny(tree));
+    let n = sites.len().max(graph.node_count());
+    let name = |site: usize| sites.get(site).cloned().unwrap_or_else(|| site.to_string());
+
+    let center = (TREE_WIDTH + GRAPH_SIZE / 2.0, GRAPH_SIZE / 2.0);
+    let radius = GRAPH_SIZE / 2.0 - 2.0 * SITE_RADIUS;
+    let position = |site: usize| {
+        let angle = std::f64::consts::TAU as Float * site as Float / n.max(1) as Float
+            - std::f64::consts::FRAC_PI_2 as Float;
+        (
+            center.0 + radius * angle.cos(),
+            center.1 + radius * angle.sin(),
+        )
+    };
+    let kinds = [
+        ("primary to metastasis", &style.primary_edge_color),
+        ("metastasis to metastasis", &style.metastasis_edge_color),
+        ("reseeding", &style.reseeding_edge_color),
+    ];
+
+    let legend_top = tree_height.max(GRAPH_SIZE);
+    let height = legend_top + LEGEND_ROW * (n.max(kinds.len()) + 1) as Float;
+    let width = TREE_WIDTH + GRAPH_SIZE;
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height:.1}\">\n<defs>\n"
+    );
+    for (i, (_, color)) in kinds.iter().enumerate() {
+        svg.push_str(&format!(
+            "<marker id=\"arrow{i}\" viewBox=\"0 0 10 10\" refX=\"10\" refY=\"5\" markerWidth=\"6\" markerHeight=\"6\" orient=\"auto\"><path d=\"M0,0 L10,5 L0,10 z\" fill=\"{color}\"/></marker>\n"
+        ));
+    }
+    svg.push_str("</defs>\n");
+    svg.push_str(&phylogram);
+
+    for e in graph.edge_references() {
+        let (from, to) = (graph[e.source()], graph[e.target()]);
+        let ((x1, y1), (x2, y2)) = (position(from), position(to));
+        // halfway to the center, and off to the left of the direction of travel
+        let (mx, my) = ((x1 + x2) / 2.0, (y1 + y2) / 2.0);
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let length = dx.hypot(dy).max(Float::EPSILON);
+        let (cx, cy) = (
+            (mx + center.0) / 2.0 + dy / length * SITE_RADIUS,
+            (my + center.1) / 2.0 - dx / length * SITE_RADIUS,
+        );
+        // end the curve at the rim of the site circles
+        let rim = |(x, y): (Float, Float)| {
+            let d = (cx - x).hypot(cy - y).max(Float::EPSILON);
+            (
+                x + (cx - x) / d * SITE_RADIUS,
+                y + (cy - y) / d * SITE_RADIUS,
+            )
+        };
+        let ((sx, sy), (tx, ty)) = (rim((x1, y1)), rim((x2, y2)));
+        let color = style.edge_color(from, to);
+        let kind = kinds.iter().position(|(_, c)| *c == color).unwrap_or(0);
+        svg.push_str(&format!(
+            "<path d=\"M{sx:.1},{sy:.1} Q{cx:.1},{cy:.1} {tx:.1},{ty:.1}\" stroke=\"{color}\" stroke-width=\"2\" fill=\"none\" marker-end=\"url(#arrow{kind})\"><title>{} to {}</title></path>\n",
+            xml_escape(&name(from)),
+            xml_escape(&name(to))
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{cx:.1}\" y=\"{cy:.1}\" font-size=\"{}\" text-anchor=\"middle\">{}</text>\n",
+            style.edge_font_size,
+            e.weight()
+        ));
+    }
+    for site in 0..n {
+        let (x, y) = position(site);
+        svg.push_str(&format!(
+            "<circle cx=\"{x:.1}\" cy=\"{y:.1}\" r=\"{SITE_RADIUS}\" fill=\"{}\" stroke=\"black\"><title>{}</title></circle>\n",
+            style.site_color(site),
+            xml_escape(&name(site))
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{x:.1}\" y=\"{:.1}\" font-size=\"{}\" text-anchor=\"middle\">{site}</text>\n",
+            y + style.node_font_size as Float / 3.0,
+            style.node_font_size
+        ));
+    }
+
+    // sites on the left, kinds of migration on the right
+    for site in 0..n {
+        let y = legend_top + LEGEND_ROW * (site + 1) as Float;
+        svg.push_str(&format!(
+            "<rect x=\"10\" y=\"{:.1}\" width=\"12\" height=\"12\" fill=\"{}\"/><text x=\"28\" y=\"{y:.1}\" font-size=\"12\">{site} {}</text>\n",
+            y - 11.0,
+            style.site_color(site),
+            xml_escape(&name(site))
+        ));
+    }
+    for (i, (kind, color)) in kinds.iter().enumerate() {
+        let y = legend_top + LEGEND_ROW * (i + 1) as Float;
+        svg.push_str(&format!(
+            "<path d=\"M{TREE_WIDTH},{:.1} h24\" stroke=\"{color}\" stroke-width=\"2\"/><text x=\"{}\" y=\"{y:.1}\" font-size=\"12\">{kind}</text>\n",
+            y - 5.0,
+            TREE_WIDTH + 30.0
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Line chart of site frequencies per generation from a generations x sites count matrix
+pub fn frequency_svg(counts: &Array2<usize>, sites: &[String], style: &DotStyle) -> String {
+    const WIDTH: f64 = 600.0;
+    const HEIGHT: f64 = 300.0;
+    const MARGIN: f64 = 30.0;
+
+    let generations = counts.nrows();
+    let x = |g: usize| MARGIN + g as f64 / (generations.max(2) - 1) as f64 * (WIDTH - 2.0 * MARGIN);
+    let y = |f: f64| HEIGHT - MARGIN - f * (HEIGHT - 2.0 * MARGIN);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\">\n"
+    );
+    svg.push_str(&format!(
+        "<path d=\"M{MARGIN},{MARGIN} V{} H{}\" stroke=\"black\" fill=\"none\"/>\n",
+        HEIGHT - MARGIN,
+        WIDTH - MARGIN
+    ));
+    for (site, name) in sites.iter().enumerate() {
+        let points: Vec<_> = counts
+            .rows()
+            .into_iter()
+            .enumerate()
+            .map(|(g, row)| {
+                let total = row.sum().max(1) as f64;
+                format!("{:.1},{:.1}", x(g), y(row[site] as f64 / total))
+            })
+            .collect();
+        svg.push_str(&format!(
+            "<polyline points=\"{}\" stroke=\"{}\" stroke-width=\"2\" fill=\"none\"><title>{name}</title></polyline>\n",
+            points.join(" "),
+            style.site_color(site)
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Stacked-area chart of a generations x sites count matrix, the height of each band is
+/// the number of lineages of its site and the top edge is the population size
+pub fn stacked_area_svg(counts: &Array2<usize>, sites: &[String], style: &DotStyle) -> String {
+    const WIDTH: f64 = 600.0;
+    const HEIGHT: f64 = 300.0;
+    const MARGIN: f64 = 30.0;
+
+    let generations = counts.nrows();
+    let largest = counts
+        .rows()
+        .into_iter()
+        .map(|r| r.sum())
+        .max()
+        .unwrap_or(0);
+    let x = |g: usize| MARGIN + g as f64 / (generations.max(2) - 1) as f64 * (WIDTH - 2.0 * MARGIN);
+    let y = |c: usize| HEIGHT - MARGIN - c as f64 / largest.max(1) as f64 * (HEIGHT - 2.0 * MARGIN);
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{WIDTH}\" height=\"{HEIGHT}\">\n"
+    );
+    svg.push_str(&format!(
+        "<path d=\"M{MARGIN},{MARGIN} V{} H{}\" stroke=\"black\" fill=\"none\"/>\n",
+        HEIGHT - MARGIN,
+        WIDTH - MARGIN
+    ));
+    // lineages in the sites below the current band, per generation
+    let mut below = vec![0; generations];
+    for (site, name) in sites.iter().enumerate() {
+        let top: Vec<usize> = below
+            .iter()
+            .zip(counts.column(site))
+            .map(|(b, c)| b + c)
+            .collect();
+        let upper = top.iter().enumerate().map(|(g, &c)| (g, c));
+        let lower = below.iter().enumerate().rev().map(|(g, &c)| (g, c));
+        let points: Vec<_> = upper
+            .chain(lower)
+            .map(|(g, c)| format!("{:.1},{:.1}", x(g), y(c)))
+            .collect();
+        svg.push_str(&format!(
+            "<polygon points=\"{}\" fill=\"{}\" stroke=\"none\"><title>{name}</title></polygon>\n",
+            points.join(" "),
+            style.site_color(site)
+        ));
+        below = top;
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// `#rrggbb` as red, green and blue, `None` for other color syntaxes
+fn hex_rgb(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#').filter(|h| h.len() == 6)?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write the tree as PhyloXML with node data as clade names. Every clade is colored with
+/// the color of its site in `style` and carries the site name as a `cancer_sims:site`
+/// property, so Archaeopteryx and iTOL show the site coloring on import
+pub fn write_phyloxml<N: fmt::Display + Clone, W: Write>(
+    mut w: W,
+    tree: &Phylogeny<N, usize>,
+    sites: &[String],
+    style: &DotStyle,
+) -> io::Result<()> {
+    fn write_clade<N: fmt::Display + Clone, W: Write>(
+        w: &mut W,
+        tree: &Phylogeny<N, usize>,
+        idx: usize,
+        length: Float,
+        depth: usize,
+        sites: &[String],
+        style: &DotStyle,
+    ) -> io::Result<()> {
+        let indent = "  ".repeat(depth);
+        let node = &tree.nodes[idx];
+        writeln!(w, "{indent}<clade>")?;
+        writeln!(
+            w,
+            "{indent}  <name>{}</name>",
+            xml_escape(&node.data.to_string())
+        )?;
+        writeln!(w, "{indent}  <branch_length>{length}</branch_length>")?;
+        if let Some([r, g, b]) = hex_rgb(style.site_color(node.label)) {
+            writeln!(
+                w,
+                "{indent}  <color><red>{r}</red><green>{g}</green><blue>{b}</blue></color>"
+            )?;
+        }
+        let site = sites
+            .get(node.label)
+            .map_or(node.label.to_string(), |s| xml_escape(s));
+        writeln!(
+            w,
+            "{indent}  <property ref=\"cancer_sims:site\" datatype=\"xsd:string\" applies_to=\"clade\">{site}</property>"
+        )?;
+        for (child, length) in tree.children(idx) {
+            write_clade(w, tree, child, length, depth + 1, sites, style)?;
+        }
+        writeln!(w, "{indent}</clade>")
+    }
+
+    writeln!(w, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        w,
+        "<phyloxml xmlns=\"http://www.phyloxml.org\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xsi:schemaLocation=\"http://www.phyloxml.org http://www.phyloxml.org/1.20/phyloxml.xsd\">"
+    )?;
+    writeln!(w, "<phylogeny rooted=\"true\">")?;
+    write_clade(&mut w, tree, tree.root, tree.root_length(), 1, sites, style)?;
+    writeln!(w, "</phylogeny>")?;
+    writeln!(w, "</phyloxml>")
+}
+
+/// Write the legend lines of an iTOL dataset, one entry per site
+fn write_itol_legend<W: Write>(w: &mut W, sites: &[String], style: &DotStyle) -> io::Result<()> {
+    let colors: Vec<_> = (0..sites.len()).map(|s| style.site_color(s)).collect();
+    writeln!(w, "LEGEND_TITLE\tSite")?;
+    writeln!(w, "LEGEND_SHAPES\t{}", vec!["1"; sites.len()].join("\t"))?;
+    writeln!(w, "LEGEND_COLORS\t{}", colors.join("\t"))?;
+    writeln!(w, "LEGEND_LABELS\t{}", sites.join("\t"))
+}
+
+/// Write an iTOL color strip dataset with the site of every leaf, keyed by the leaf
+/// names of [`Phylogeny::write_newick`]. Drop it on the tree in iTOL to show the sites
+pub fn write_itol_strip<N: fmt::Display + Clone, W: Write>(
+    mut w: W,
+    tree: &Phylogeny<N, usize>,
+    sites: &[String],
+    style: &DotStyle,
+) -> io::Result<()> {
+    writeln!(w, "DATASET_COLORSTRIP")?;
+    writeln!(w, "SEPARATOR TAB")?;
+    writeln!(w, "DATASET_LABEL\tSite")?;
+    writeln!(w, "COLOR\t{}", style.site_color(style.primary))?;
+    write_itol_legend(&mut w, sites, style)?;
+    writeln!(w, "DATA")?;
+    for leaf in tree.leaves() {
+        let node = &tree.nodes[leaf];
+        let site = sites.get(node.label).map_or("", |s| s.as_str());
+        writeln!(w, "{}\t{}\t{site}", node.data, style.site_color(node.label))?;
+    }
+    Ok(())
+}
+
+/// Write an iTOL `TREE_COLORS` file shading the label range of every leaf with the color
+/// of its site
+pub fn write_itol_ranges<N: fmt::Display + Clone, W: Write>(
+    mut w: W,
+    tree: &Phylogeny<N, usize>,
+    sites: &[String],
+    style: &DotStyle,
+) -> io::Result<()> {
+    writeln!(w, "TREE_COLORS")?;
+    writeln!(w, "SEPARATOR TAB")?;
+    writeln!(w, "DATA")?;
+    for leaf in tree.leaves() {
+        let node = &tree.nodes[leaf];
+        let site = sites.get(node.label).map_or("", |s| s.as_str());
+        writeln!(
+            w,
+            "{}\trange\t{}\t{site}",
+            node.data,
+            style.site_color(node.label)
+        )?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_dot_fallback() {
+    let m = Array2::from_shape_vec((2, 2), vec![3, 1, 0, 2]).unwrap();
+    let g = graph_from_edge_matrix(m);
+    let stem = std::env::temp_dir().join("test_dot_fallback");
+
+    match save_graph(&g, &stem, ImageFormat::Svg, &DotStyle::default()) {
+        Ok(_) => assert!(stem.with_extension("svg").exists()),
+        Err(RenderError::GraphvizNotFound) => assert!(!graphviz_available()),
+        Err(e) => panic!("{e}"),
+    }
+    assert!(stem.with_extension("dot").exists());
+}
+
+#[test]
+fn test_image_format() {
+    assert_eq!("SVG".parse::<ImageFormat>(), Ok(ImageFormat::Svg));
+    assert!("gif".parse::<ImageFormat>().is_err());
+    assert_eq!(ImageFormat::Pdf.to_string(), "pdf");
+}
+
+#[test]
+fn test_dot_style() {
+    // primary -> 1, 1 -> 2 and reseeding 2 -> primary
+    let m = Array2::from_shape_vec((3, 3), vec![0, 4, 0, 0, 0, 2, 1, 0, 0]).unwrap();
+    let g = graph_from_edge_matrix(m);
+    let style = DotStyle {
+        rankdir: RankDir::LeftRight,
+        ..Default::default()
+    };
+    let dot = to_dot(&g, &style);
+
+    assert!(dot.contains("rankdir=LR;"));
+    assert!(dot.contains("0 [label=\"0\", fillcolor=\"#e69f00\"];"));
+    assert!(dot.contains("0 -> 1 [label=\"4\", color=\"#1b9e77\"];"));
+    assert!(dot.contains("1 -> 2 [label=\"2\", color=\"#7570b3\"];"));
+    assert!(dot.contains("2 -> 0 [label=\"1\", color=\"#d95f02\"];"));
+}
+
+#[test]
+fn test_edge_labels() {
+    // 0 stays 6 times and seeds 1 three times and 2 once, 1 stays twice
+    let m = Array2::from_shape_vec((3, 3), vec![6, 3, 1, 0, 2, 0, 0, 0, 0]).unwrap();
+    let g = graph_from_edge_matrix(m);
+    assert_eq!(g.edge_count(), 4);
+    let dot = to_dot(&g, &DotStyle::default());
+    assert_eq!(dot.matches(" -> ").count(), 2);
+    assert!(!dot.contains("0 -> 0"));
+
+    let style = DotStyle {
+        edge_labels: EdgeLabels::Proportions,
+        label_threshold: 0.5,
+        ..Default::default()
+    };
+    let dot = to_dot(&g, &style);
+    assert!(dot.contains("0 -> 1 [label=\"0.75\""));
+    assert!(dot.contains("0 -> 2 [label=\"\""));
+
+    let style = DotStyle {
+        self_loops: true,
+        edge_labels: EdgeLabels::Proportions,
+        ..style
+    };
+    let dot = to_dot(&g, &style);
+    assert!(dot.contains("0 -> 0 [label=\"0.60\", color=\"#999999\"];"));
+    assert!(dot.contains("0 -> 1 [label=\"\""));
+    assert!(dot.contains("1 -> 1 [label=\"1.00\""));
+
+    let style = DotStyle {
+        self_loops: true,
+        label_threshold: 3.0,
+        ..Default::default()
+    };
+    let dot = to_dot(&g, &style);
+    assert!(dot.contains("0 -> 0 [label=\"6\""));
+    assert!(dot.contains("1 -> 1 [label=\"\""));
+    assert_eq!("Proportions".parse(), Ok(EdgeLabels::Proportions));
+    assert!("percent".parse::<EdgeLabels>().is_err());
+}
+
+#[test]
+fn test_graph_from_phylogeny() {
+    use crate::examples::toy_phylogeny;
+
+    let tree = toy_phylogeny();
+    let g = graph_from_phylogeny(&tree);
+    assert_eq!((g.node_count(), g.edge_count()), (3, 3));
+    let reseeding = g
+        .edge_references()
+        .find(|e| g[e.target()] == 0)
+        .unwrap()
+        .weight();
+    assert_eq!((reseeding.source, reseeding.node), (2, 8));
+    assert_eq!((reseeding.generation, reseeding.time), (3, 3.0));
+
+    // a second seeding of the liver is a parallel edge, counted once drawn
+    let mut tree = tree;
+    tree.add_child(3, 9, 2, 1.0);
+    let g = graph_from_phylogeny(&tree);
+    assert_eq!(g.edge_count(), 4);
+    let counted = count_migrations(&g);
+    assert_eq!(counted.edge_count(), 3);
+    let dot = to_dot(&counted, &DotStyle::default());
+    assert!(dot.contains("0 -> 2 [label=\"2\""));
+    assert!(dot.contains("2 -> 0 [label=\"1\""));
+}
+
+#[test]
+fn test_tree_svg() {
+    use crate::tree::Node;
+
+    let mut tree = Phylogeny::new(Node::root(0usize, 0usize), 0.0);
+    tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 2.0);
+
+    let svg = tree_svg(&tree, &DotStyle::default());
+    assert_eq!(svg.matches("<path").count(), 2);
+    assert_eq!(svg.matches("<circle").count(), 3);
+    assert!(svg.contains("#56b4e9"));
+}
+
+#[test]
+fn test_combined_svg() {
+    use crate::examples::{toy_phylogeny, toy_site_names};
+
+    let svg = combined_svg(&toy_phylogeny(), &toy_site_names(), &DotStyle::default());
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"1000\""));
+    assert!(svg.ends_with("</svg>\n"));
+    // arrowheads, the branches of the phylogram, an arrow per seeded pair of sites and
+    // the legend of the kinds of migration
+    assert_eq!(svg.matches("<path d=\"M").count(), 3 + 8 + 3 + 3);
+    assert!(svg.contains("stroke=\"#d95f02\" stroke-width=\"2\" fill=\"none\" marker-end=\"url(#arrow2)\"><title>liver to primary</title>"));
+    assert_eq!(svg.matches("<rect").count(), 3);
+    assert!(svg.contains(">1 lymph node</text>"));
+    assert!(svg.contains(">reseeding</text>"));
+}
+
+#[test]
+fn test_stacked_area_svg() {
+    let counts = Array2::from_shape_vec((3, 2), vec![1, 0, 1, 1, 2, 2]).unwrap();
+    let sites = ["a", "b"].map(String::from);
+    let svg = stacked_area_svg(&counts, &sites, &DotStyle::default());
+    assert_eq!(svg.matches("<polygon").count(), 2);
+    // the top band ends at the largest population, at the top margin
+    assert!(svg.contains("<title>b</title>"));
+    assert!(svg.contains("570.0,30.0"));
+}
+
+#[test]
+fn test_phyloxml() {
+    use crate::tree::Node;
+
+    let mut tree = Phylogeny::new(Node::root(0usize, 0usize), 0.5);
+    tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 2.0);
+    let sites = ["primary".to_string(), "a<b".to_string()];
+
+    let mut buf = vec![];
+    write_phyloxml(&mut buf, &tree, &sites, &DotStyle::default()).unwrap();
+    let xml = String::from_utf8(buf).unwrap();
+    assert_eq!(xml.matches("<clade>").count(), 3);
+    assert_eq!(
+        xml.matches("<clade>").count(),
+        xml.matches("</clade>").count()
+    );
+    assert!(xml.contains("<branch_length>2</branch_length>"));
+    // #56b4e9 of the second site
+    assert!(xml.contains("<color><red>86</red><green>180</green><blue>233</blue></color>"));
+    assert!(xml.contains(">a&lt;b</property>"));
+    assert_eq!(hex_rgb("white"), None);
+}
+
+#[test]
+fn test_itol() {
+    use crate::tree::Node;
+
+    let mut tree = Phylogeny::new(Node::root(0usize, 0usize), 0.5);
+    tree.add_child(0, 1, 0, 1.0);
+    tree.add_child(0, 2, 1, 2.0);
+    let sites = ["primary".to_string(), "liver".to_string()];
+    let style = DotStyle::default();
+
+    let mut buf = vec![];
+    write_itol_strip(&mut buf, &tree, &sites, &style).unwrap();
+    let strip = String::from_utf8(buf).unwrap();
+    assert!(strip.starts_with("DATASET_COLORSTRIP\nSEPARATOR TAB\n"));
+    assert!(strip.contains("LEGEND_LABELS\tprimary\tliver\n"));
+    assert!(strip.ends_with("DATA\n1\t#e69f00\tprimary\n2\t#56b4e9\tliver\n"));
+
+    let mut buf = vec![];
+    write_itol_ranges(&mut buf, &tree, &sites, &style).unwrap();
+    let ranges = String::from_utf8(buf).unwrap();
+    assert!(ranges.ends_with("2\trange\t#56b4e9\tliver\n"));
+}
+
+#[test]
+fn test_palette() {
+    let mut palette = Palette::default();
+    assert_eq!(palette.color(0), "#e69f00");
+    assert_eq!(palette.color(8), palette.color(0));
+    palette.set_site_color(2, "#FF0000").unwrap();
+    assert_eq!(palette.color(2), "#ff0000");
+    assert_eq!(palette.color(10), "#009e73");
+    assert!(palette.set_site_color(1, "red").is_err());
+
+    assert_eq!("set3".parse::<Palette>().unwrap().color(1), "#ffffb3");
+    assert_eq!("TOL".parse::<Palette>().unwrap(), Palette::tol());
+    let custom: Palette = "#000000, #ffffff".parse().unwrap();
+    assert_eq!(custom.color(3), "#ffffff");
+    assert!("#000000,#fff".parse::<Palette>().is_err());
+    assert!("viridis".parse::<Palette>().is_err());
+    assert_eq!(Palette::new(vec![]).color(0), "white");
+
+    // every figure colors a site the same
+    let style = DotStyle {
+        palette,
+        ..Default::default()
+    };
+    let tree = crate::examples::toy_phylogeny();
+    let sites = crate::examples::toy_site_names();
+    let dot = to_dot(&count_migrations(&graph_from_phylogeny(&tree)), &style);
+    assert!(dot.contains("fillcolor=\"#ff0000\""));
+    assert!(tree_svg(&tree, &style).contains("#ff0000"));
+    assert!(combined_svg(&tree, &sites, &style).contains("#ff0000"));
+}
@@ -0,0 +1,160 @@
+//! Every figure drawn without Graphviz, rendered from fixed data and compared with the
+//! svg under `tests/golden/figures`. Numbers are compared within a tolerance so the
+//! figures also match in single precision, everything else byte for byte. A drawing
+//! change fails here until the files are regenerated with
+//! `UPDATE_GOLDEN=1 cargo test --test figures` and the new svg are looked at.
+#![cfg(feature = "viz")]
+
+use std::{fs, path::Path};
+
+use cancer_sims::{
+    animation::{frame_name, frame_svg, generation_states},
+    examples::{toy_phylogeny, toy_site_names},
+    prelude::*,
+    visualizations::{combined_svg, frequency_svg, stacked_area_svg, tree_svg},
+};
+
+/// Largest difference of two numbers of a figure, in pixels or font points
+const TOLERANCE: f64 = 0.05;
+
+/// Split `s` into text and the numbers in it, so `x="1.5"` becomes `x="`, 1.5 and `"`.
+/// Digits of hex colors are split off too and so compared as numbers, which still tells
+/// any two colors apart
+fn tokens(s: &str) -> Vec<Result<f64, &str>> {
+    let bytes = s.as_bytes();
+    let number_start = |i: usize| {
+        bytes[i].is_ascii_digit()
+            || (bytes[i] == b'-' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+    };
+    let mut tokens = vec![];
+    let (mut i, mut text) = (0, 0);
+    while i < bytes.len() {
+        if !number_start(i) {
+            i += 1;
+            continue;
+        }
+        if text < i {
+            tokens.push(Err(&s[text..i]));
+        }
+        let start = i;
+        i += 1;
+        while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+            i += 1;
+        }
+        tokens.push(Ok(s[start..i].parse().unwrap_or(f64::NAN)));
+        text = i;
+    }
+    if text < bytes.len() {
+        tokens.push(Err(&s[text..]));
+    }
+    tokens
+}
+
+/// Every tag of `svg` is closed in order
+fn assert_well_formed(name: &str, svg: &str) {
+    let mut open = vec![];
+    for tag in svg.split('<').skip(1) {
+        let tag = &tag[..tag
+            .find('>')
+            .unwrap_or_else(|| panic!("{name}: unclosed <{tag}"))];
+        if tag.starts_with('?') || tag.starts_with('!') || tag.ends_with('/') {
+            continue;
+        }
+        match tag.strip_prefix('/') {
+            Some(closing) => assert_eq!(open.pop(), Some(closing), "{name}: stray </{closing}>"),
+            None => open.push(tag.split_whitespace().next().unwrap_or(tag)),
+        }
+    }
+    assert!(open.is_empty(), "{name}: unclosed {open:?}");
+    assert!(
+        svg.starts_with("<svg") || svg.starts_with("<?xml"),
+        "{name}"
+    );
+}
+
+/// Compare `actual` with `tests/golden/figures/<name>`, or overwrite it with
+/// `UPDATE_GOLDEN` set
+fn check(name: &str, actual: &str) {
+    assert_well_formed(name, actual);
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden/figures")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{e}: {}, run with UPDATE_GOLDEN=1", path.display()));
+    let (expected_tokens, actual_tokens) = (tokens(&expected), tokens(actual));
+    let same = expected_tokens.len() == actual_tokens.len()
+        && expected_tokens
+            .iter()
+            .zip(&actual_tokens)
+            .all(|pair| match pair {
+                (Ok(a), Ok(b)) => (a - b).abs() <= TOLERANCE,
+                (Err(a), Err(b)) => a == b,
+                _ => false,
+            });
+    assert!(
+        same,
+        "{name} changed, run with UPDATE_GOLDEN=1 and review the figure\n--- expected\n{expected}\n--- actual\n{actual}"
+    );
+}
+
+#[test]
+fn figure_tree() {
+    let svg = tree_svg(&toy_phylogeny(), &DotStyle::default());
+    // a branch per node below the root, every node colored by its site
+    assert_eq!(svg.matches("<circle").count(), 9);
+    check("tree.svg", &svg);
+}
+
+#[test]
+fn figure_combined() {
+    let svg = combined_svg(&toy_phylogeny(), &toy_site_names(), &DotStyle::default());
+    for site in toy_site_names() {
+        assert!(svg.contains(&site), "{site} is not in the legend");
+    }
+    check("figure.svg", &svg);
+}
+
+#[test]
+fn figure_leaf_counts() {
+    let sites = toy_site_names();
+    let counts = &toy_phylogeny().site_counts_by_generation(sites.len());
+    let style = DotStyle::default();
+
+    let svg = frequency_svg(counts, &sites, &style);
+    check("frequencies.svg", &svg);
+    let svg = stacked_area_svg(counts, &sites, &style);
+    assert_eq!(svg.matches("<polygon").count(), sites.len());
+    check("leaf_counts.svg", &svg);
+}
+
+#[test]
+fn figure_frames() {
+    let sites = toy_site_names();
+    let states = generation_states(&toy_phylogeny(), sites.len());
+    assert_eq!(states.len(), 4);
+    for state in &states {
+        let svg = frame_svg(state, &sites, &DotStyle::default());
+        check(&frame_name(state.generation), &svg);
+    }
+}
+
+#[test]
+fn figure_tokens() {
+    assert_eq!(
+        tokens("x=\"-1.5\" fill=\"#e69f00\""),
+        [
+            Err("x=\""),
+            Ok(-1.5),
+            Err("\" fill=\"#e"),
+            Ok(69.0),
+            Err("f"),
+            Ok(0.0),
+            Err("\"")
+        ]
+    );
+}
@@ -0,0 +1,76 @@
+//! Golden file tests of every writer on a small fixed seed simulation. A format change
+//! fails here until the files under `tests/golden` are regenerated with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and the diff is reviewed.
+//!
+//! Branch lengths print differently in single precision, so these only run with `f64`.
+#![cfg(not(feature = "f32"))]
+
+use std::{fs, io, path::Path};
+
+use cancer_sims::{output::write_matrix_csv, prelude::*};
+
+fn simulation() -> SimulationResult<Phylogeny<usize, usize>> {
+    let params = Parameters {
+        generations: 4,
+        sites: 3,
+        migration_probability: 0.2,
+        seed: 7,
+        max_population: Some(12),
+        ..Default::default()
+    };
+    Phylogeny::simulate(&params, &mut ())
+}
+
+/// Compare `actual` with `tests/golden/<name>`, or overwrite it with `UPDATE_GOLDEN` set
+fn check<F>(name: &str, write: F)
+where
+    F: FnOnce(&mut Vec<u8>) -> io::Result<()>,
+{
+    let mut actual = vec![];
+    write(&mut actual).unwrap();
+    let actual = String::from_utf8(actual).unwrap();
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{e}: {}, run with UPDATE_GOLDEN=1", path.display()));
+    assert!(
+        expected == actual,
+        "{name} changed, run with UPDATE_GOLDEN=1 and review the diff\n--- expected\n{expected}\n--- actual\n{actual}"
+    );
+}
+
+#[test]
+fn golden_tree_writers() {
+    let result = simulation();
+    let tree = result.tree();
+    let sites = result.params().site_names();
+
+    check("edgelist.csv", |w| tree.write_csv(w));
+    check("edgelist.tsv", |w| tree.write_tsv(w));
+    check("vertex_labeling.csv", |w| tree.write_csv_vertex_labeling(w));
+    check("leaf_labeling.csv", |w| tree.write_csv_leaf_labeling(w));
+    check("nodes.csv", |w| tree.write_node_table(w));
+    check("tree.nwk", |w| tree.write_newick(w));
+    check("tree.nhx", |w| tree.write_nhx(w));
+    check("tree.json", |w| tree.write_json(w, result.params(), &sites));
+    check("migration_matrix.csv", |w| {
+        write_matrix_csv(w, result.migration_matrix(), &sites)
+    });
+}
+
+#[cfg(feature = "viz")]
+#[test]
+fn golden_dot() {
+    use cancer_sims::visualizations::{graph_from_edge_matrix, to_dot};
+
+    let g = graph_from_edge_matrix(simulation().migration_matrix().clone());
+    check("migration_graph.dot", |w| {
+        io::Write::write_all(w, to_dot(&g, &DotStyle::default()).as_bytes())
+    });
+}